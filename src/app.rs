@@ -1,38 +1,515 @@
 use crate::types::*;
-use crate::config::ConfigManager;
-use crate::conversation::ConversationManager;
-use crate::filesystem::FileSystemManager;
-use crate::rag::RagEngine;
+use crate::config::{AppConfig, ConfigManager, ConfigWatcher};
+use crate::conversation::{
+    code_blocks, context_boundary, language_extension, ConversationManager, ConversationSummary,
+    DEFAULT_RECONNECT_DELAY,
+};
+use crate::diff::unified_diff;
+use crate::filesystem::{
+    read_image, read_tail, spawn_indexing, CancellationToken, FilePreview, FileSystemManager, IndexEvent,
+    IndexProgress, IndexReport, IndexSummary, DEFAULT_SOURCES_EXPORT, LOG_TAIL_BYTES,
+};
+use crate::llm::{
+    create_llm_client, read_file_tool, resolved_model, supports_images, CachingClient, ClientOptions,
+    DebugTranscript, LastResponse, LlmClient, ResponseCache, RetryingClient, DEFAULT_SERVER_RETRY_DELAY,
+    READ_FILE_TOOL,
+};
+use crate::rag::{CorpusStats, KeywordRetriever, RagEngine};
+use crate::tokenizer::{tokenizer_for_model, Tokenizer};
+use crate::ui::{AppDisplayData, Theme};
+use chrono::Utc;
+use futures::stream::{self, StreamExt};
+use std::collections::BTreeMap;
+use std::io::Write;
+use std::path::PathBuf;
+use std::sync::{Arc, PoisonError, RwLock, RwLockReadGuard, RwLockWriteGuard};
+use tokio::sync::mpsc::{error::TryRecvError, UnboundedReceiver};
+use tracing::warn;
 
 // Main application controller that orchestrates all components
 pub struct AppController {
     conversation_manager: ConversationManager,
     rag_engine: RagEngine,
     config_manager: ConfigManager,
-    file_manager: FileSystemManager,
+    file_manager: Arc<RwLock<FileSystemManager>>,
+    // Built on first use; a provider that fails to build is reported on each
+    // attempt to use it rather than stopping the app from starting
+    llm_client: LazyClient,
+    response_cache: Arc<ResponseCache>,
+    // Filled by clients only while retain_last_response is set
+    last_response: Arc<LastResponse>,
+    // Set by /temp and /max-tokens for this session only, never saved to the config file
+    provider_overrides: ProviderOverrides,
+    config_watcher: Option<ConfigWatcher>,
+    file_preview: Option<FilePreview>,
+    conversation_list: Option<Vec<ConversationSummary>>,
+    // Scroll offset and message count of the conversation just loaded, for the UI to restore
+    restored_scroll: Option<(usize, usize)>,
+    // Replies of the last /compare, for the side-by-side view
+    comparison: Option<Vec<ComparisonReply>>,
+    clipboard: Option<String>,
+    status_notice: Option<String>,
+    // Read-only view: input is ignored apart from scrolling, help and exit
+    view_only: bool,
+    // A repeated message held back until the user confirms it, with its provisional flag and context
+    pending_duplicate: Option<(String, bool, TurnContext)>,
+    confirmation: Option<String>,
+    // Events from the running background index, the token that stops it, and its latest progress
+    index_events: Option<UnboundedReceiver<IndexEvent>>,
+    index_cancel: Option<CancellationToken>,
+    indexing_progress: Option<IndexProgress>,
+    // Log file followed by /logs, with its size when last shown
+    log_tail: Option<(PathBuf, Option<u64>)>,
+    // Whether the setup wizard should open: once on start without a provider, or on /setup
+    setup_requested: bool,
+    // Set by /patterns until the UI opens the pattern editor
+    patterns_requested: bool,
 }
 
 impl AppController {
     pub fn new() -> Result<Self, AppError> {
-        let config_manager = ConfigManager::new()?;
-        let file_manager = FileSystemManager::new();
-        let conversation_manager = ConversationManager::new()?;
-        let rag_engine = RagEngine::new();
+        Self::from_config(ConfigManager::new()?)
+    }
+
+    pub fn from_config(config_manager: ConfigManager) -> Result<Self, AppError> {
+        let mut file_manager = FileSystemManager::new();
+        file_manager.set_include_patterns(config_manager.get_config().include_patterns.clone())?;
+        file_manager.set_exclude_patterns(config_manager.get_config().exclude_patterns.clone())?;
+        file_manager.set_max_file_size_bytes(config_manager.get_config().max_file_size_bytes);
+        file_manager.set_reject_oversized_files(config_manager.get_config().reject_oversized_sources);
+        file_manager.set_allowed_roots(config_manager.get_config().allowed_roots.clone());
+        for source in &config_manager.get_config().data_sources {
+            let patterns = config_manager.source_patterns(source).cloned();
+            let (include, exclude) = patterns
+                .map(|patterns| (patterns.include_patterns, patterns.exclude_patterns))
+                .unwrap_or_default();
+            if let Err(e) = file_manager.add_source_with_patterns(source.clone(), include, exclude) {
+                warn!("Skipping data source {:?}: {}", source, e);
+            }
+        }
+        let file_manager = Arc::new(RwLock::new(file_manager));
+
+        let mut conversation_manager = ConversationManager::new()?;
+        conversation_manager
+            .set_system_prompt(config_manager.effective_system_prompt().map(str::to_string));
+
+        let config = config_manager.get_config();
+        conversation_manager.set_auto_fence_code(config.auto_fence_code);
+        conversation_manager.set_trim_blank_lines(config.trim_response_blank_lines);
+        conversation_manager.set_storage_path(config.conversation_storage_path.clone());
+        conversation_manager.set_auto_save(config.auto_save_conversations);
+        conversation_manager.set_naming(config.conversation_naming);
+        conversation_manager.set_max_history_messages(config.max_history_messages);
+        conversation_manager.set_context_template(config.context_file_template.clone());
+        conversation_manager.set_context_line_numbers(config.context_line_numbers);
+        conversation_manager
+            .set_stream_reconnect(config.stream_reconnect_attempts, DEFAULT_RECONNECT_DELAY);
+        let mut rag_engine = RagEngine::new();
+        rag_engine.set_file_manager(file_manager.clone());
+        rag_engine.set_enabled(config.rag_enabled_default);
+        rag_engine.set_retriever(Box::new(keyword_retriever(config, file_manager.clone())));
+        rag_engine.set_injection_patterns(config.injection_patterns.clone())?;
+
+        let response_cache = Arc::new(match &config.response_cache_path {
+            Some(path) => ResponseCache::with_path(path.clone()),
+            None => ResponseCache::new(),
+        });
+
+        let setup_requested = config.llm_provider.is_none();
+        let config_watcher = if config.watch_config_file {
+            Some(config_manager.watch()?)
+        } else {
+            None
+        };
 
         Ok(Self {
             conversation_manager,
             rag_engine,
             config_manager,
             file_manager,
+            llm_client: None,
+            response_cache,
+            last_response: Arc::new(LastResponse::new()),
+            provider_overrides: ProviderOverrides::default(),
+            config_watcher,
+            file_preview: None,
+            conversation_list: None,
+            restored_scroll: None,
+            comparison: None,
+            clipboard: None,
+            status_notice: None,
+            view_only: false,
+            pending_duplicate: None,
+            confirmation: None,
+            setup_requested,
+            patterns_requested: false,
+            index_events: None,
+            index_cancel: None,
+            indexing_progress: None,
+            log_tail: None,
         })
     }
 
+    fn files(&self) -> RwLockReadGuard<'_, FileSystemManager> {
+        self.file_manager.read().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn files_mut(&self) -> RwLockWriteGuard<'_, FileSystemManager> {
+        self.file_manager.write().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Replaces the client built from config, e.g. for embedding or tests
+    pub fn with_llm_client(mut self, client: Box<dyn LlmClient>) -> Self {
+        self.llm_client = Some(Ok(client));
+        self
+    }
+
+    fn ensure_llm_client(&mut self) {
+        if self.llm_client.is_none() {
+            let config = self.session_config();
+            self.llm_client = Some(build_llm_client(&config, &self.response_cache, &self.last_response));
+        }
+    }
+
+    // The config with this session's provider overrides applied
+    fn session_config(&self) -> AppConfig {
+        let mut config = self.config_manager.get_config().clone();
+        if let Some(provider) = config.llm_provider.as_mut() {
+            self.provider_overrides.apply(provider);
+        }
+        config
+    }
+
+    // Token counter for the configured model
+    fn tokenizer(&self) -> Box<dyn Tokenizer> {
+        let model = self.config_manager.get_config().llm_provider.as_ref().map(resolved_model);
+        tokenizer_for_model(model.as_deref().unwrap_or_default())
+    }
+
+    pub fn config(&self) -> &crate::config::AppConfig {
+        self.config_manager.get_config()
+    }
+
+    /// Drops the current conversation so the next message starts a new one
+    pub fn clear_conversation(&mut self) {
+        self.conversation_manager.clear_conversation();
+    }
+
+    /// Overrides `auto_save_conversations` for this run
+    pub fn set_auto_save(&mut self, enabled: bool) {
+        self.conversation_manager.set_auto_save(enabled);
+    }
+
+    /// Snapshot of the state the UI renders
+    pub fn display_data(&self) -> AppDisplayData {
+        let config = self.config_manager.get_config();
+        let messages = if config.show_provisional_messages {
+            self.conversation_manager.display_messages()
+        } else {
+            self.conversation_manager.get_messages().to_vec()
+        };
+        let omitted = self.conversation_manager.omitted_message_count();
+        let omitted_marker = (omitted > 0).then(|| (context_boundary(&messages), omitted));
+        AppDisplayData {
+            messages,
+            omitted_marker,
+            provisional_mode: self.conversation_manager.is_provisional_mode(),
+            scratch: self.conversation_manager.is_scratch(),
+            rag_enabled: self.rag_engine.is_enabled(),
+            assistant_name: config.assistant_name.clone(),
+            user_name: config.user_name.clone(),
+            theme: Theme::from_env(config.monochrome),
+            banner: self.banner(),
+            max_content_width: config.max_content_width,
+            collapse_system_messages: config.collapse_system_messages,
+            code_line_numbers: config.code_line_numbers,
+            auto_scroll: config.auto_scroll,
+            view_only: self.view_only,
+            indexing_progress: self.indexing_progress,
+            ..AppDisplayData::default()
+        }
+    }
+
+    // Summary of the active settings shown on a fresh conversation
+    fn banner(&self) -> Option<String> {
+        let config = self.config_manager.get_config();
+        if !config.show_banner || !self.conversation_manager.get_messages().is_empty() {
+            return None;
+        }
+
+        let model = config
+            .llm_provider
+            .as_ref()
+            .map_or_else(|| "no provider configured".to_string(), resolved_model);
+        Some(format!(
+            "Model: {} | RAG: {} | Sources: {}\nType a message to start, or press F1 for help.",
+            model,
+            if self.rag_engine.is_enabled() { "ON" } else { "OFF" },
+            self.files().list_sources().len()
+        ))
+    }
+
+    /// Takes the preview produced by the last `/open`, for the UI to display
+    pub fn take_file_preview(&mut self) -> Option<FilePreview> {
+        self.file_preview.take()
+    }
+
+    /// A fresh view of the log opened by `/logs` when the file has changed
+    /// since it was last shown, for the UI to swap into the open preview
+    pub fn poll_log_tail(&mut self) -> Option<FilePreview> {
+        let (path, shown_size) = self.log_tail.as_mut()?;
+        let size = std::fs::metadata(&*path).ok().map(|metadata| metadata.len());
+        if size == *shown_size {
+            return None;
+        }
+        *shown_size = size;
+        Some(log_preview(path))
+    }
+
+    /// Records the UI's scroll offset on the current conversation
+    pub fn set_scroll_position(&mut self, position: usize) {
+        self.conversation_manager.set_scroll_position(position);
+    }
+
+    pub fn scroll_position(&self) -> usize {
+        self.conversation_manager.scroll_position()
+    }
+
+    /// Takes the listing produced by the last `/conversations`, for the picker
+    pub fn take_conversation_list(&mut self) -> Option<Vec<ConversationSummary>> {
+        self.conversation_list.take()
+    }
+
+    /// Takes the stored scroll offset and message count of the conversation
+    /// loaded last, for the UI to restore
+    pub fn take_restored_scroll(&mut self) -> Option<(usize, usize)> {
+        self.restored_scroll.take()
+    }
+
+    /// Takes the replies produced by the last `/compare`, for the split view
+    pub fn take_comparison(&mut self) -> Option<Vec<ComparisonReply>> {
+        self.comparison.take()
+    }
+
+    /// Takes text a command asked to place on the system clipboard
+    pub fn take_clipboard(&mut self) -> Option<String> {
+        self.clipboard.take()
+    }
+
+    /// Takes a warning raised while handling the last input, for the status area
+    pub fn take_status_notice(&mut self) -> Option<String> {
+        self.status_notice.take()
+    }
+
+    /// Takes a question the controller needs answered through the confirm dialog
+    pub fn take_confirmation(&mut self) -> Option<String> {
+        self.confirmation.take()
+    }
+
+    /// Whether the UI should open the setup wizard; true at most once per request
+    pub fn take_setup_request(&mut self) -> bool {
+        std::mem::take(&mut self.setup_requested)
+    }
+
+    /// The include and exclude patterns to open the editor with, once per /patterns
+    pub fn take_patterns_request(&mut self) -> Option<(Vec<String>, Vec<String>)> {
+        if !std::mem::take(&mut self.patterns_requested) {
+            return None;
+        }
+        let config = self.config_manager.get_config();
+        Some((config.include_patterns.clone(), config.exclude_patterns.clone()))
+    }
+
+    /// Saves a change made in the pattern editor and applies the new lists to
+    /// the file manager; indexed files are only refiltered by /reindex
+    pub fn apply_pattern_edit(&mut self, edit: PatternEdit) -> Result<String, AppError> {
+        let status = match &edit {
+            PatternEdit::Add(kind, pattern) => format!("Added {} pattern {}", kind.as_str(), pattern),
+            PatternEdit::Remove(kind, pattern) => format!("Removed {} pattern {}", kind.as_str(), pattern),
+            PatternEdit::Move(kind, pattern, _) => format!("Moved {} pattern {}", kind.as_str(), pattern),
+        };
+        self.config_manager.apply_pattern_edit(edit)?;
+        let config = self.config_manager.get_config();
+        let mut files = self.files_mut();
+        files.set_include_patterns(config.include_patterns.clone())?;
+        files.set_exclude_patterns(config.exclude_patterns.clone())?;
+        Ok(format!("{}; /reindex applies it to indexed files", status))
+    }
+
+    /// Saves the conversation when the idle timeout fires, whether or not
+    /// auto-save is on. In scratch it is the parked main conversation that is saved.
+    pub fn save_on_idle(&mut self) -> Result<String, AppError> {
+        if self.conversation_manager.save_main_conversation()? {
+            Ok("Saved the conversation after inactivity".to_string())
+        } else {
+            Ok("Idle: nothing to save".to_string())
+        }
+    }
+
+    /// Validates and saves the provider entered in the setup wizard
+    pub fn apply_setup(&mut self, provider: LlmProvider) -> Result<String, AppError> {
+        let status = format!("Saved {:?} provider", provider.provider_type);
+        self.config_manager.set_llm_provider(provider)?;
+        self.llm_client = None;
+        Ok(status)
+    }
+
+    // Stores a tag change right away when conversations are auto-saved; an
+    // empty conversation keeps its tags until its first turn is saved
+    fn save_tags(&mut self) -> Result<(), AppError> {
+        let auto_save = self.config_manager.get_config().auto_save_conversations;
+        if auto_save && !self.conversation_manager.get_messages().is_empty() {
+            self.conversation_manager.save_conversation()?;
+        }
+        Ok(())
+    }
+
+    /// Starts indexing all sources on a background task. Returns false when a
+    /// run is already in progress.
+    pub fn start_indexing(&mut self) -> bool {
+        if self.index_events.is_some() {
+            return false;
+        }
+        let cancel = CancellationToken::new();
+        let (_, events) = spawn_indexing(self.file_manager.clone(), cancel.clone());
+        self.index_events = Some(events);
+        self.index_cancel = Some(cancel);
+        self.indexing_progress = Some(IndexProgress { processed: 0, total: 0 });
+        true
+    }
+
+    /// Asks the background index to stop after the current file; what it
+    /// indexed so far is kept. Returns false when no run is in progress.
+    pub fn cancel_indexing(&mut self) -> bool {
+        match &self.index_cancel {
+            Some(cancel) => {
+                cancel.cancel();
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Applies the background index's progress without waiting. Returns a
+    /// status message once the run has finished.
+    pub fn poll_indexing(&mut self) -> Option<String> {
+        loop {
+            match self.index_events.as_mut()?.try_recv() {
+                Ok(event) => {
+                    if let Some(status) = self.apply_index_event(event) {
+                        return Some(status);
+                    }
+                }
+                Err(TryRecvError::Empty) => return None,
+                Err(TryRecvError::Disconnected) => {
+                    return self.apply_index_event(IndexEvent::Finished(Err(
+                        FileSystemError::Indexing("Indexing task stopped".to_string()),
+                    )));
+                }
+            }
+        }
+    }
+
+    /// Waits for the background index to finish, returning its status message
+    pub async fn wait_for_indexing(&mut self) -> Option<String> {
+        loop {
+            let event = self.index_events.as_mut()?.recv().await.unwrap_or_else(|| {
+                IndexEvent::Finished(Err(FileSystemError::Indexing("Indexing task stopped".to_string())))
+            });
+            if let Some(status) = self.apply_index_event(event) {
+                return Some(status);
+            }
+        }
+    }
+
+    fn apply_index_event(&mut self, event: IndexEvent) -> Option<String> {
+        match event {
+            IndexEvent::Progress(progress) => {
+                self.indexing_progress = Some(progress);
+                None
+            }
+            IndexEvent::Finished(result) => {
+                self.index_events = None;
+                self.index_cancel = None;
+                self.indexing_progress = None;
+                Some(match result {
+                    Ok(report) => reindex_status(&report),
+                    Err(e) => format!("Indexing failed: {}", e),
+                })
+            }
+        }
+    }
+
+    /// Reloads the config if the file changed on disk, applying the settings
+    /// that are safe to change live. Returns a status message when a reload ran.
+    pub fn poll_config_changes(&mut self) -> Option<String> {
+        if !self.config_watcher.as_ref()?.has_changed() {
+            return None;
+        }
+
+        let retry_attempts = self.config_manager.get_config().server_retry_attempts;
+        let reload = match self.config_manager.reload() {
+            Ok(reload) => reload,
+            Err(e) => return Some(format!("Config reload failed, keeping previous config: {}", e)),
+        };
+
+        let config = self.config_manager.get_config();
+        let mut file_manager = self.file_manager.write().unwrap_or_else(PoisonError::into_inner);
+        // Patterns, like the injection patterns below, were compiled by
+        // validate_config before the reload was accepted
+        let _ = file_manager.set_include_patterns(config.include_patterns.clone());
+        let _ = file_manager.set_exclude_patterns(config.exclude_patterns.clone());
+        file_manager.set_allowed_roots(config.allowed_roots.clone());
+        file_manager.set_max_file_size_bytes(config.max_file_size_bytes);
+        file_manager.set_reject_oversized_files(config.reject_oversized_sources);
+        drop(file_manager);
+        // The retry wrapper is part of the client, which is rebuilt on next use
+        if config.server_retry_attempts != retry_attempts {
+            self.llm_client = None;
+        }
+        let _ = self.rag_engine.set_injection_patterns(config.injection_patterns.clone());
+        self.rag_engine
+            .set_retriever(Box::new(keyword_retriever(config, self.file_manager.clone())));
+        self.conversation_manager
+            .set_system_prompt(self.config_manager.effective_system_prompt().map(str::to_string));
+        self.conversation_manager.set_auto_fence_code(config.auto_fence_code);
+        self.conversation_manager.set_trim_blank_lines(config.trim_response_blank_lines);
+        self.conversation_manager.set_auto_save(config.auto_save_conversations);
+        self.conversation_manager.set_naming(config.conversation_naming);
+        self.conversation_manager.set_max_history_messages(config.max_history_messages);
+        self.conversation_manager.set_context_template(config.context_file_template.clone());
+        self.conversation_manager.set_context_line_numbers(config.context_line_numbers);
+        self.conversation_manager
+            .set_stream_reconnect(config.stream_reconnect_attempts, DEFAULT_RECONNECT_DELAY);
+
+        if reload.restart_required.is_empty() {
+            Some("Config reloaded".to_string())
+        } else {
+            Some(format!(
+                "Config reloaded; restart to apply: {}",
+                reload.restart_required.join(", ")
+            ))
+        }
+    }
+
     pub async fn process_user_input(&mut self, input: UserInput) -> Result<String, AppError> {
         match input {
             UserInput::Message(content) => {
-                // TODO: Process message through conversation manager and RAG if enabled
-                Ok(format!("Received message: {}", content))
+                let provisional = self.conversation_manager.is_provisional_mode();
+                self.send_unless_duplicate(content, provisional, TurnContext::Retrieved, Delivery::Complete)
+                    .await
+            }
+            UserInput::MessageAs { content, provisional } => {
+                self.send_unless_duplicate(content, provisional, TurnContext::Retrieved, Delivery::Complete)
+                    .await
             }
+            UserInput::Confirm(confirmed) => match self.pending_duplicate.take() {
+                Some((content, provisional, context)) if confirmed => {
+                    self.send_turn(content, provisional, context, Delivery::Complete).await
+                }
+                Some(_) => Ok("Duplicate message not sent".to_string()),
+                None => Ok("Nothing to confirm".to_string()),
+            },
             UserInput::Command(command) => {
                 self.handle_command(command).await
             }
@@ -43,35 +520,1813 @@ impl AppController {
         }
     }
 
+    // Holds back a message that repeats the previous one moments later,
+    // asking for confirmation instead of sending it
+    async fn send_unless_duplicate(
+        &mut self,
+        content: String,
+        provisional: bool,
+        context: TurnContext,
+        delivery: Delivery<'_>,
+    ) -> Result<String, AppError> {
+        if self.conversation_manager.is_duplicate(&content, Utc::now()) {
+            self.pending_duplicate = Some((content, provisional, context));
+            self.confirmation =
+                Some("You just sent this same message. Send it again?".to_string());
+            return Ok("Duplicate message awaiting confirmation".to_string());
+        }
+        self.send_turn(content, provisional, context, delivery).await
+    }
+
+    // Sends one user turn. Every way of sending a message ends up here, so
+    // context, the file tool and storage work the same; only the delivery of
+    // the reply differs.
+    async fn send_turn(
+        &mut self,
+        content: String,
+        provisional: bool,
+        context: TurnContext,
+        delivery: Delivery<'_>,
+    ) -> Result<String, AppError> {
+        self.ensure_llm_client();
+        let client = match ready_client(&self.llm_client) {
+            Ok(client) => client,
+            Err(reason) => return Ok(reason),
+        };
+        let context = match context {
+            TurnContext::Plain => None,
+            TurnContext::Retrieved if self.rag_engine.is_enabled() => {
+                Some(self.rag_engine.process_query(content.clone(), client).await?)
+            }
+            TurnContext::Retrieved => None,
+            TurnContext::Files(paths) => Some(self.read_context_files(&content, paths)?),
+        };
+        self.status_notice = context.as_ref().and_then(injection_warning);
+
+        // Tool rounds need whole replies, so with the file tool on the answer
+        // is passed on in one piece
+        if self.config_manager.get_config().file_tool_enabled {
+            let file_manager = self.file_manager.clone();
+            let response = self
+                .conversation_manager
+                .send_message_with_tools(
+                    content,
+                    provisional,
+                    context.as_ref(),
+                    client,
+                    &[read_file_tool()],
+                    move |call| run_tool(&file_manager, call),
+                )
+                .await?;
+            if let Delivery::Stream(on_token) = delivery {
+                on_token(&response);
+            }
+            return Ok(response);
+        }
+        let response = match delivery {
+            Delivery::Complete => {
+                self.conversation_manager
+                    .send_message_with_context(content, provisional, context.as_ref(), client)
+                    .await?
+            }
+            Delivery::Stream(on_token) => {
+                self.conversation_manager
+                    .stream_message_with_context(content, provisional, context.as_ref(), client, on_token)
+                    .await?
+            }
+        };
+        Ok(response)
+    }
+
+    // The given files as a turn's context, recorded on the stored message
+    // like retrieved ones. They are read under the same checks as the file tool.
+    fn read_context_files(&self, query: &str, paths: Vec<PathBuf>) -> Result<RagContext, AppError> {
+        let mut selected_files = Vec::new();
+        let mut file_contents = BTreeMap::new();
+        let files = self.files();
+        for path in paths {
+            if !file_contents.contains_key(&path) {
+                file_contents.insert(path.clone(), files.read_source_file(&path)?);
+                selected_files.push(path);
+            }
+        }
+        Ok(RagContext {
+            query: query.to_string(),
+            available_files: Vec::new(),
+            keywords: Vec::new(),
+            search_results: Vec::new(),
+            selected_files,
+            file_contents,
+            flagged_files: Vec::new(),
+            excerpted_files: Vec::new(),
+        })
+    }
+
+    // Builds the client for callers outside the TUI, which get an unusable
+    // provider as an error rather than a status
+    fn require_llm_client(&mut self) -> Result<(), AppError> {
+        self.ensure_llm_client();
+        ready_client(&self.llm_client).map_err(LlmError::Api)?;
+        Ok(())
+    }
+
+    /// Sends a message with the given files as its context, whatever the RAG
+    /// toggle; retrieval doesn't run. The files are recorded on the stored
+    /// message like retrieved ones. Nothing is sent if a file can't be read
+    /// or lies outside the configured sources.
+    pub async fn process_message_with_context(
+        &mut self,
+        content: String,
+        context_files: Vec<PathBuf>,
+    ) -> Result<String, AppError> {
+        self.require_llm_client()?;
+        let provisional = self.conversation_manager.is_provisional_mode();
+        self.send_unless_duplicate(content, provisional, TurnContext::Files(context_files), Delivery::Complete)
+            .await
+    }
+
+    /// Sends a message like `process_user_input`, streaming the final answer
+    /// through `on_token`. Retrieval steps, if enabled, run to completion first.
+    pub async fn process_message_streaming<F>(
+        &mut self,
+        content: String,
+        mut on_token: F,
+    ) -> Result<String, AppError>
+    where
+        F: FnMut(&str) + Send,
+    {
+        self.require_llm_client()?;
+        let provisional = self.conversation_manager.is_provisional_mode();
+        self.send_unless_duplicate(content, provisional, TurnContext::Retrieved, Delivery::Stream(&mut on_token))
+            .await
+    }
+
+    /// Sends `message` outside the TUI and writes the reply to `out`. With
+    /// `stream` each token is written and flushed as it arrives; otherwise the
+    /// reply is written once complete.
+    pub async fn run_one_shot<W: Write + Send>(
+        &mut self,
+        message: String,
+        stream: bool,
+        out: &mut W,
+    ) -> Result<String, AppError> {
+        let write_error = |e: std::io::Error| TuiError::Rendering(format!("Failed to write reply: {}", e));
+        if !stream {
+            self.require_llm_client()?;
+            let provisional = self.conversation_manager.is_provisional_mode();
+            let response = self
+                .send_unless_duplicate(message, provisional, TurnContext::Retrieved, Delivery::Complete)
+                .await?;
+            writeln!(out, "{}", response).map_err(write_error)?;
+            return Ok(response);
+        }
+
+        // A failed write stops output; the reply is still stored
+        let mut written = Ok(());
+        let response = self
+            .process_message_streaming(message, |token| {
+                if written.is_ok() {
+                    written = out.write_all(token.as_bytes()).and_then(|_| out.flush());
+                }
+            })
+            .await?;
+        written.and_then(|_| writeln!(out)).map_err(write_error)?;
+        Ok(response)
+    }
+
     pub async fn handle_command(&mut self, command: Command) -> Result<String, AppError> {
         match command {
-            Command::Help => Ok("Help: Available commands: /help, /config, /clear, /toggle-rag, /toggle-provisional, /add-source, /remove-source, /list-sources, /exit".to_string()),
+            Command::Help => Ok("Help: Available commands: /help, /config, /clear, /reset, /toggle-rag, /toggle-provisional, /toggle-autoscroll, /scratch, /view, /image, /add-source, /remove-source, /list-sources, /clear-cache, /reload-prompt, /set-system, /clear-system, /reindex, /open, /diff, /save-block, /conversations, /tag, /untag, /load, /index-status, /rag-stats, /export-sources, /patterns, /raw, /show-prompt, /prefill, /temp, /max-tokens, /once-max, /redo, /compare, /setup, /copy-conversation, /ping, /last-raw, /logs, /exit".to_string()),
             Command::Config => Ok("Configuration management - TODO".to_string()),
             Command::Clear => {
-                // TODO: Clear conversation history
+                self.clear_conversation();
                 Ok("Conversation cleared".to_string())
             }
+            Command::Reset => {
+                let config = self.config_manager.get_config();
+                self.conversation_manager.reset(config.provisional_mode_default)?;
+                self.rag_engine.set_enabled(config.rag_enabled_default);
+                self.view_only = false;
+                self.pending_duplicate = None;
+                self.confirmation = None;
+                self.comparison = None;
+                self.file_preview = None;
+                self.restored_scroll = None;
+                // /temp and /max-tokens fall back to the saved provider settings
+                self.provider_overrides = ProviderOverrides::default();
+                self.llm_client = None;
+                Ok("Session reset: conversation cleared and toggles back to config defaults".to_string())
+            }
             Command::ToggleRag => {
-                // TODO: Toggle RAG functionality
-                Ok("RAG toggled".to_string())
+                self.rag_engine.toggle_enabled();
+                let state = if self.rag_engine.is_enabled() { "enabled" } else { "disabled" };
+                Ok(format!("RAG {}", state))
             }
             Command::ToggleProvisional => {
-                // TODO: Toggle provisional mode
-                Ok("Provisional mode toggled".to_string())
+                self.conversation_manager.toggle_provisional_mode();
+                let state = if self.conversation_manager.is_provisional_mode() { "enabled" } else { "disabled" };
+                Ok(format!("Provisional mode {}", state))
+            }
+            Command::ToggleScratch => {
+                if self.conversation_manager.toggle_scratch() {
+                    Ok("Scratch conversation: replies here are discarded when you switch back".to_string())
+                } else {
+                    Ok("Back to the main conversation".to_string())
+                }
+            }
+            Command::ToggleView => {
+                self.view_only = !self.view_only;
+                if self.view_only {
+                    let key = self.config_manager.get_config().keybindings.toggle_view;
+                    Ok(format!(
+                        "Read-only view: input is ignored until Ctrl+{} is pressed",
+                        key.to_ascii_uppercase()
+                    ))
+                } else {
+                    Ok("Read-only view off".to_string())
+                }
+            }
+            Command::AttachImage(path) => {
+                let provider = self.config_manager.get_config().llm_provider.as_ref();
+                if let Some(provider) = provider.filter(|provider| !supports_images(provider)) {
+                    return Err(CommandError::InvalidArgument(format!(
+                        "Model '{}' does not accept images; switch to a vision model such as gpt-4o",
+                        resolved_model(provider)
+                    ))
+                    .into());
+                }
+                let image = read_image(&path)?;
+                let status = format!("Attached {} to the next message", image.name);
+                self.conversation_manager.attach_image(image);
+                Ok(status)
+            }
+            Command::ToggleAutoScroll => {
+                let config = self.config_manager.get_config_mut();
+                config.auto_scroll = !config.auto_scroll;
+                let state = if config.auto_scroll { "enabled" } else { "disabled" };
+                Ok(format!("Auto-scroll {}", state))
             }
             Command::AddSource(path) => {
-                // TODO: Add data source
-                Ok(format!("Added source: {:?}", path))
+                let (include, exclude) = self
+                    .config_manager
+                    .source_patterns(&path)
+                    .cloned()
+                    .map(|patterns| (patterns.include_patterns, patterns.exclude_patterns))
+                    .unwrap_or_default();
+                self.files_mut().add_source_with_patterns(path.clone(), include, exclude)?;
+                let oversized = self.files().oversized_file(&path);
+                self.status_notice = oversized.map(|size| {
+                    format!(
+                        "Warning: {:?} is {} bytes, over the file size limit; it will not be indexed",
+                        path, size
+                    )
+                });
+                self.config_manager.add_data_source(path.clone())?;
+                let report = self.files_mut().reindex_source(&path)?;
+                Ok(format!("Added source: {:?} ({} files indexed)", path, report.indexed))
             }
             Command::RemoveSource(path) => {
-                // TODO: Remove data source
+                self.files_mut().remove_source(&path)?;
+                self.config_manager.remove_data_source(&path)?;
                 Ok(format!("Removed source: {:?}", path))
             }
             Command::ListSources => {
-                // TODO: List configured sources
-                Ok("Data sources: TODO".to_string())
+                let files = self.files();
+                let sources = files.list_sources();
+                if sources.is_empty() {
+                    return Ok("No data sources configured".to_string());
+                }
+                let listing: Vec<String> = sources
+                    .iter()
+                    .map(|source| format!("{:?}", source.path))
+                    .collect();
+                Ok(format!("Data sources: {}", listing.join(", ")))
+            }
+            Command::ClearCache => {
+                let cleared = self.response_cache.len();
+                self.response_cache.clear();
+                Ok(format!("Cleared {} cached responses", cleared))
+            }
+            Command::ReloadPrompt => {
+                self.config_manager.reload_system_prompt()?;
+                self.conversation_manager.set_system_prompt(
+                    self.config_manager.effective_system_prompt().map(str::to_string),
+                );
+                Ok("System prompt reloaded".to_string())
+            }
+            Command::SetSystem(prompt) => {
+                self.config_manager.update_system_prompt(Some(prompt))?;
+                self.conversation_manager.set_system_prompt(
+                    self.config_manager.effective_system_prompt().map(str::to_string),
+                );
+                Ok("System prompt set; it applies from the next message".to_string())
+            }
+            Command::ClearSystem => {
+                self.config_manager.update_system_prompt(None)?;
+                let fallback = self.config_manager.effective_system_prompt().map(str::to_string);
+                let status = if fallback.is_some() {
+                    "System prompt cleared; the system prompt file applies again"
+                } else {
+                    "System prompt cleared"
+                };
+                self.conversation_manager.set_system_prompt(fallback);
+                Ok(status.to_string())
+            }
+            Command::Reindex(Some(path)) => {
+                let report = self.files_mut().reindex_source(&path)?;
+                Ok(reindex_status(&report))
+            }
+            Command::Reindex(None) => {
+                if self.start_indexing() {
+                    Ok("Indexing all sources in the background".to_string())
+                } else {
+                    Ok("Indexing is already running".to_string())
+                }
+            }
+            Command::Open(path) => {
+                let preview = self.files().preview_file(&path)?;
+                let message = if preview.truncated {
+                    format!("Opened {:?} (truncated)", path)
+                } else {
+                    format!("Opened {:?}", path)
+                };
+                self.file_preview = Some(preview);
+                Ok(message)
+            }
+            Command::Diff(old_path, new_path) => {
+                let (old, new) = {
+                    let files = self.files();
+                    (files.read_source_file(&old_path)?, files.read_source_file(&new_path)?)
+                };
+                let old_label = old_path.display().to_string();
+                let new_label = new_path.display().to_string();
+                let diff = unified_diff(&old_label, &old, &new_label, &new);
+                if diff.is_empty() {
+                    return Ok(format!("{} and {} are identical", old_label, new_label));
+                }
+
+                let added = diff.lines().filter(|line| line.starts_with('+')).count() - 1;
+                let removed = diff.lines().filter(|line| line.starts_with('-')).count() - 1;
+                self.file_preview = Some(FilePreview::from_diff(
+                    format!("{} -> {}", old_label, new_label),
+                    diff,
+                ));
+                Ok(format!("Compared {} and {}: +{} -{}", old_label, new_label, added, removed))
+            }
+            Command::SaveBlock(index, path) => {
+                let blocks = self
+                    .conversation_manager
+                    .get_messages()
+                    .iter()
+                    .rev()
+                    .find(|message| matches!(message.role, MessageRole::Assistant))
+                    .map(|message| code_blocks(&message.content))
+                    .unwrap_or_default();
+                let Some(block) = blocks.get(index.wrapping_sub(1)) else {
+                    return Err(CommandError::InvalidArgument(format!(
+                        "Code block {} is out of range; the last reply has {}",
+                        index,
+                        blocks.len()
+                    ))
+                    .into());
+                };
+
+                let mut path = path;
+                if path.extension().is_none() {
+                    if let Some(extension) = block.language.as_deref().and_then(language_extension) {
+                        path.set_extension(extension);
+                    }
+                }
+                std::fs::write(&path, &block.content).map_err(|e| {
+                    FileSystemError::FileAccess(format!("Failed to write {:?}: {}", path, e))
+                })?;
+                Ok(format!("Saved code block {} to {}", index, path.display()))
+            }
+            Command::Conversations(tag) => {
+                let summaries = self.conversation_manager.list_conversations(tag.as_deref())?;
+                let message = match tag {
+                    Some(tag) => format!("{} stored conversations tagged {}", summaries.len(), tag),
+                    None => format!("{} stored conversations", summaries.len()),
+                };
+                self.conversation_list = Some(summaries);
+                Ok(message)
+            }
+            Command::Tag(tag) => {
+                if !self.conversation_manager.add_tag(&tag) {
+                    return Ok(format!("Conversation is already tagged {}", tag));
+                }
+                self.save_tags()?;
+                Ok(format!("Tagged conversation {}", tag))
+            }
+            Command::Untag(tag) => {
+                if !self.conversation_manager.remove_tag(&tag) {
+                    return Ok(format!("Conversation isn't tagged {}", tag));
+                }
+                self.save_tags()?;
+                Ok(format!("Removed tag {}", tag))
+            }
+            Command::LoadConversation(id) => {
+                let auto_save = self.config_manager.get_config().auto_save_conversations;
+                if auto_save && !self.conversation_manager.get_messages().is_empty() {
+                    self.conversation_manager.save_conversation()?;
+                }
+                self.conversation_manager.load_conversation(&id)?;
+                let message_count = self.conversation_manager.get_messages().len();
+                self.restored_scroll = Some((self.conversation_manager.scroll_position(), message_count));
+                Ok(format!("Loaded conversation {} ({} messages)", id, message_count))
+            }
+            Command::IndexStatus => Ok(format_index_summary(&self.files().index_summary())),
+            Command::RagStats => {
+                let stats = self.rag_engine.corpus_stats()?;
+                let status = format!(
+                    "RAG stats: {} files, {} queries tracked",
+                    stats.summary.file_count, stats.tracked_queries
+                );
+                self.file_preview = Some(FilePreview::from_text("RAG corpus stats", format_corpus_stats(&stats)));
+                Ok(status)
+            }
+            Command::ExportSources(path) => {
+                let path = path.unwrap_or_else(|| PathBuf::from(DEFAULT_SOURCES_EXPORT));
+                let count = self.files().export_index(&path)?;
+                Ok(format!("Exported {} indexed files to {}", count, path.display()))
+            }
+            Command::Raw(content) => {
+                let provisional = self.conversation_manager.is_provisional_mode();
+                self.send_turn(content, provisional, TurnContext::Plain, Delivery::Complete).await
+            }
+            Command::ShowPrompt(content) => {
+                // Retrieval still queries the model to pick files; only the final request is held back
+                self.ensure_llm_client();
+                let context = match ready_client(&self.llm_client) {
+                    Ok(client) if self.rag_engine.is_enabled() => {
+                        Some(self.rag_engine.process_query(content.clone(), client).await?)
+                    }
+                    _ => None,
+                };
+                self.status_notice = context.as_ref().and_then(injection_warning);
+                let prompt = self.conversation_manager.assemble_prompt(content, context.as_ref());
+                self.file_preview = Some(FilePreview::from_text("Outgoing prompt", format_prompt(&prompt)));
+                let tokenizer = self.tokenizer();
+                let tokens: usize = prompt.iter().map(|message| tokenizer.count(&message.content)).sum();
+                Ok(format!("Outgoing prompt has {} messages, ~{} tokens", prompt.len(), tokens))
+            }
+            Command::Prefill(text) => {
+                let status = format!("Next reply will start with: {}", text);
+                self.conversation_manager.set_prefill(Some(text));
+                Ok(status)
+            }
+            Command::Temperature(temperature) => {
+                ConfigManager::validate_temperature(temperature)
+                    .map_err(|e| CommandError::InvalidArgument(e.to_string()))?;
+                if self.config_manager.get_config().llm_provider.is_none() {
+                    return Ok("No LLM provider configured".to_string());
+                }
+                self.provider_overrides.temperature = Some(temperature);
+                // Rebuilt on next use, so the request and the cache key pick it up
+                self.llm_client = None;
+                Ok(format!("Temperature set to {}", temperature))
+            }
+            Command::MaxTokens(max_tokens) => {
+                ConfigManager::validate_max_tokens(max_tokens)
+                    .map_err(|e| CommandError::InvalidArgument(e.to_string()))?;
+                if self.config_manager.get_config().llm_provider.is_none() {
+                    return Ok("No LLM provider configured".to_string());
+                }
+                self.provider_overrides.max_tokens = Some(max_tokens);
+                self.llm_client = None;
+                Ok(format!("Max tokens set to {}", max_tokens))
+            }
+            Command::OnceMax(max_tokens, content) => {
+                ConfigManager::validate_max_tokens(max_tokens)
+                    .map_err(|e| CommandError::InvalidArgument(e.to_string()))?;
+                let mut config = self.session_config();
+                let Some(provider) = config.llm_provider.as_mut() else {
+                    return Ok("No LLM provider configured".to_string());
+                };
+                provider.max_tokens = Some(max_tokens);
+
+                // The turn runs on a client built for it; the configured one is put back after
+                let once_client = build_llm_client(&config, &self.response_cache, &self.last_response);
+                let configured_client = self.llm_client.replace(once_client);
+                let provisional = self.conversation_manager.is_provisional_mode();
+                let result = self.send_turn(content, provisional, TurnContext::Retrieved, Delivery::Complete).await;
+                self.llm_client = configured_client;
+                result
+            }
+            Command::Redo(instruction) => {
+                self.ensure_llm_client();
+                let client = match ready_client(&self.llm_client) {
+                    Ok(client) => client,
+                    Err(reason) => return Ok(reason),
+                };
+                Ok(self.conversation_manager.redo_last_turn(instruction, client).await?)
+            }
+            Command::LastRaw => {
+                if !self.config_manager.get_config().retain_last_response {
+                    return Ok("Set retain_last_response = true to keep the last raw API response".to_string());
+                }
+                let Some(body) = self.last_response.get() else {
+                    return Ok("No API response received yet".to_string());
+                };
+                self.file_preview = Some(FilePreview::from_text("Last raw API response", body));
+                Ok("Showing the last raw API response".to_string())
+            }
+            Command::Compare(content) => {
+                let config = self.config_manager.get_config();
+                if config.provider_profiles.is_empty() {
+                    return Ok("No provider_profiles configured to compare".to_string());
+                }
+                let clients: Vec<_> = config
+                    .provider_profiles
+                    .iter()
+                    .map(|profile| {
+                        let client = match ConfigManager::resolve_provider(profile.provider.clone()) {
+                            Ok(provider) => {
+                                let config = AppConfig { llm_provider: Some(provider), ..config.clone() };
+                                build_llm_client(&config, &self.response_cache, &self.last_response)
+                            }
+                            Err(e) => Err(format!("Invalid provider profile: {}", e)),
+                        };
+                        (profile.name.clone(), client)
+                    })
+                    .collect();
+                // Nothing is stored; each profile sees the conversation so far plus the message
+                let prompt = self.conversation_manager.assemble_prompt(content, None);
+                let replies = compare_responses(&clients, &prompt).await;
+                let failed = replies.iter().filter(|reply| reply.response.is_err()).count();
+                let status = format!("Compared {} profiles, {} failed", replies.len(), failed);
+                self.comparison = Some(replies);
+                Ok(status)
+            }
+            Command::Logs => {
+                let Some(path) = self.config_manager.get_config().log_file.clone() else {
+                    return Ok("No log file configured; set log_file to write logs to a file".to_string());
+                };
+                let size = std::fs::metadata(&path).ok().map(|metadata| metadata.len());
+                self.file_preview = Some(log_preview(&path));
+                self.log_tail = Some((path.clone(), size));
+                Ok(format!("Following {}", path.display()))
+            }
+            Command::Patterns => {
+                self.patterns_requested = true;
+                Ok("Opening pattern editor".to_string())
+            }
+            Command::Setup => {
+                self.setup_requested = true;
+                Ok("Opening setup".to_string())
+            }
+            Command::CopyConversation => {
+                let config = self.config_manager.get_config();
+                let (markdown, count) = self
+                    .conversation_manager
+                    .export_markdown(&config.user_name, &config.assistant_name);
+                if count == 0 {
+                    return Ok("Nothing to copy".to_string());
+                }
+                self.clipboard = Some(markdown);
+                Ok(format!("Copied {} messages to the clipboard", count))
+            }
+            Command::Ping => {
+                self.ensure_llm_client();
+                match ready_client(&self.llm_client) {
+                    Ok(client) => Ok(format_health_check(client.health_check().await)),
+                    Err(reason) => Ok(reason),
+                }
             }
             Command::Exit => Ok("Exiting application".to_string()),
         }
     }
-}
\ No newline at end of file
+}
+
+// Builds the configured client, retrying server errors and wrapped in the
+// response cache as configured.
+// Errors are kept as the status text shown when a send needs the client.
+fn build_llm_client(
+    config: &AppConfig,
+    response_cache: &Arc<ResponseCache>,
+    last_response: &Arc<LastResponse>,
+) -> BuiltClient {
+    let provider = config
+        .llm_provider
+        .as_ref()
+        .ok_or_else(|| "No LLM provider configured".to_string())?;
+    let client_options = ClientOptions {
+        transcript: config
+            .debug_transcript_path
+            .clone()
+            .map(|path| {
+                let max_kb = config.debug_transcript_max_kb;
+                let max_body_bytes = (max_kb > 0).then(|| max_kb as usize * 1024);
+                Arc::new(DebugTranscript::new(path).with_limits(config.debug_transcript_bodies, max_body_bytes))
+            }),
+        last_response: config.retain_last_response.then(|| last_response.clone()),
+    };
+    let mut client = create_llm_client(provider, &client_options)
+        .map_err(|e| format!("LLM provider unavailable: {}", e))?;
+    if config.server_retry_attempts > 0 {
+        client = Box::new(RetryingClient::new(
+            client,
+            config.server_retry_attempts,
+            DEFAULT_SERVER_RETRY_DELAY,
+        ));
+    }
+    if config.response_cache_enabled {
+        let sent = LlmProvider {
+            model: resolved_model(provider),
+            ..provider.clone()
+        };
+        Ok(Box::new(CachingClient::new(client, response_cache.clone(), &sent)))
+    } else {
+        Ok(client)
+    }
+}
+
+// A client built from config, or why it couldn't be
+pub type BuiltClient = Result<Box<dyn LlmClient>, String>;
+
+// Profiles asked at once by /compare; the rest wait for a free slot
+const MAX_CONCURRENT_COMPARISONS: usize = 4;
+
+/// Sends the same messages to each labelled client, a few at a time, and
+/// returns the replies in client order. A client that failed to build or to
+/// answer gets its error recorded without holding up the others.
+pub async fn compare_responses(
+    clients: &[(String, BuiltClient)],
+    messages: &[Message],
+) -> Vec<ComparisonReply> {
+    stream::iter(clients)
+        .map(|(profile, client)| async move {
+            let response = match client {
+                Ok(client) => client.send_message(messages).await.map_err(|e| e.to_string()),
+                Err(reason) => Err(reason.clone()),
+            };
+            ComparisonReply { profile: profile.clone(), response }
+        })
+        .buffered(MAX_CONCURRENT_COMPARISONS)
+        .collect()
+        .await
+}
+
+type LazyClient = Option<BuiltClient>;
+
+// Where a turn's file context comes from
+#[derive(Debug)]
+enum TurnContext {
+    // None, whatever the RAG toggle
+    Plain,
+    // The retrieval workflow, when RAG is enabled
+    Retrieved,
+    // Files the caller picked; retrieval doesn't run
+    Files(Vec<PathBuf>),
+}
+
+// How a reply is handed back: once complete, or token by token as it arrives
+enum Delivery<'a> {
+    Complete,
+    Stream(&'a mut (dyn FnMut(&str) + Send)),
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+struct ProviderOverrides {
+    temperature: Option<f32>,
+    max_tokens: Option<u32>,
+}
+
+impl ProviderOverrides {
+    fn apply(&self, provider: &mut LlmProvider) {
+        if let Some(temperature) = self.temperature {
+            provider.temperature = Some(temperature);
+        }
+        if let Some(max_tokens) = self.max_tokens {
+            provider.max_tokens = Some(max_tokens);
+        }
+    }
+}
+
+fn ready_client(client: &LazyClient) -> Result<&dyn LlmClient, String> {
+    match client {
+        Some(Ok(client)) => Ok(client.as_ref()),
+        Some(Err(reason)) => Err(reason.clone()),
+        None => Err("No LLM provider configured".to_string()),
+    }
+}
+
+// Runs a tool call from the model, returning its output or an error the
+// model can read and react to
+fn run_tool(file_manager: &RwLock<FileSystemManager>, call: &ToolCall) -> String {
+    if call.name != READ_FILE_TOOL {
+        return format!("Error: unknown tool '{}'", call.name);
+    }
+
+    let arguments: serde_json::Value = serde_json::from_str(&call.arguments).unwrap_or_default();
+    let Some(path) = arguments["path"].as_str() else {
+        return "Error: missing 'path' argument".to_string();
+    };
+    let files = file_manager.read().unwrap_or_else(PoisonError::into_inner);
+    match files.read_source_file(std::path::Path::new(path)) {
+        Ok(content) => content,
+        Err(e) => format!("Error: {}", e),
+    }
+}
+
+// The end of the log file, or a note on why it can't be shown
+fn log_preview(path: &std::path::Path) -> FilePreview {
+    let content = match read_tail(path, LOG_TAIL_BYTES) {
+        Ok(content) => content,
+        Err(_) if !path.exists() => "The log file has not been written yet".to_string(),
+        Err(e) => e.to_string(),
+    };
+    FilePreview::from_tail(path.to_path_buf(), content)
+}
+
+fn reindex_status(report: &IndexReport) -> String {
+    let outcome = if report.cancelled { "Indexing cancelled after" } else { "Reindexed" };
+    format!(
+        "{} {} files: {} added, {} updated, {} removed",
+        outcome, report.indexed, report.added, report.updated, report.removed
+    )
+}
+
+// Status warning naming retrieved files that were sent as untrusted data
+fn injection_warning(context: &RagContext) -> Option<String> {
+    if context.flagged_files.is_empty() {
+        return None;
+    }
+    let names: Vec<String> = context
+        .flagged_files
+        .iter()
+        .map(|path| {
+            path.file_name()
+                .unwrap_or(path.as_os_str())
+                .to_string_lossy()
+                .to_string()
+        })
+        .collect();
+    Some(format!(
+        "Warning: possible prompt injection in {}; sent as untrusted data",
+        names.join(", ")
+    ))
+}
+
+// Plain-text dump of a request, one section per message
+fn format_prompt(messages: &[Message]) -> String {
+    let sections: Vec<String> = messages
+        .iter()
+        .map(|message| {
+            let mut header = format!("--- {} ---", message.role.as_str());
+            if !message.context_files.is_empty() {
+                let files: Vec<String> = message
+                    .context_files
+                    .iter()
+                    .map(|path| path.display().to_string())
+                    .collect();
+                header.push_str(&format!("\n[context files: {}]", files.join(", ")));
+            }
+            format!("{}\n{}", header, message.content)
+        })
+        .collect();
+    sections.join("\n\n")
+}
+
+// Status line for /ping, telling a bad key apart from an unreachable endpoint
+fn format_health_check(result: Result<(), LlmError>) -> String {
+    match result {
+        Ok(()) => "Provider connection OK".to_string(),
+        Err(LlmError::Authentication) => {
+            "Authentication failed: check the configured API key".to_string()
+        }
+        Err(LlmError::Network(e)) => format!("Network error: {}", e),
+        Err(e) => format!("Provider error: {}", e),
+    }
+}
+
+// One-line description of the index, e.g. "12 files (10 indexable), 48.2 KiB; markdown: 7, rs: 5"
+fn format_index_summary(summary: &IndexSummary) -> String {
+    if summary.file_count == 0 {
+        return "Index is empty".to_string();
+    }
+
+    let mut by_type: Vec<(&str, usize)> = summary
+        .by_type
+        .iter()
+        .map(|(file_type, count)| (file_type.label(), *count))
+        .collect();
+    by_type.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(b.0)));
+    let breakdown: Vec<String> = by_type
+        .iter()
+        .map(|(label, count)| format!("{}: {}", label, count))
+        .collect();
+
+    format!(
+        "{} files ({} indexable), {}; {}",
+        summary.file_count,
+        summary.indexable_count,
+        format_size(summary.total_bytes),
+        breakdown.join(", ")
+    )
+}
+
+// The keyword retriever with the RAG settings from the config
+fn keyword_retriever(config: &AppConfig, file_manager: Arc<RwLock<FileSystemManager>>) -> KeywordRetriever {
+    let mut retriever = KeywordRetriever::new(file_manager);
+    retriever.set_candidate_limits(config.rag_min_relevance, config.rag_max_candidates);
+    retriever.set_prompts(config.rag_keyword_prompt.clone(), config.rag_selection_prompt.clone());
+    retriever.set_max_rounds(config.rag_max_rounds);
+    retriever
+}
+
+// Report shown by /rag-stats
+fn format_corpus_stats(stats: &CorpusStats) -> String {
+    let mut report = vec![
+        format_index_summary(&stats.summary),
+        format!("Estimated tokens: ~{}", stats.estimated_tokens),
+        format!("Average file size: {}", format_size(stats.average_file_bytes)),
+        String::new(),
+    ];
+    if stats.selection_counts.is_empty() {
+        report.push(format!("No files selected in the last {} queries", stats.tracked_queries));
+    } else {
+        report.push(format!("Files selected in the last {} queries:", stats.tracked_queries));
+        report.extend(
+            stats
+                .selection_counts
+                .iter()
+                .map(|(path, count)| format!("{:>4}  {}", count, path.display())),
+        );
+    }
+    report.join("\n")
+}
+
+fn format_size(bytes: u64) -> String {
+    const UNITS: [&str; 4] = ["B", "KiB", "MiB", "GiB"];
+    let mut size = bytes as f64;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{} B", bytes)
+    } else {
+        format!("{:.1} {}", size, UNITS[unit])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::llm::mock::{MockLlmClient, MockServer};
+    use crate::llm::OpenAiClient;
+    use crate::ui::{PatternEditor, TuiState};
+    use crossterm::event::{KeyCode, KeyEvent, KeyModifiers};
+    use std::fs;
+    use tempfile::TempDir;
+
+    // Builds a controller whose config and conversations live in a temp dir,
+    // isolated from the user's files
+    fn test_controller(temp_dir: &TempDir) -> AppController {
+        let mut config_manager = ConfigManager::from_path(temp_dir.path().join("config.toml"))
+            .expect("Failed to create ConfigManager");
+        config_manager.get_config_mut().conversation_storage_path =
+            temp_dir.path().join("conversations");
+        AppController::from_config(config_manager).expect("Failed to create AppController")
+    }
+
+    #[tokio::test]
+    async fn test_reindex_command_picks_up_new_file() {
+        let temp_dir = TempDir::new().expect("Failed to create temp dir");
+        let source = temp_dir.path().join("docs");
+        fs::create_dir_all(&source).unwrap();
+        fs::write(source.join("a.md"), "first").unwrap();
+        let mut app = test_controller(&temp_dir);
+
+        let added = app
+            .handle_command(Command::AddSource(source.clone()))
+            .await
+            .expect("Failed to add source");
+        assert!(added.contains("1 files indexed"));
+
+        fs::write(source.join("b.md"), "second").unwrap();
+        let status = app
+            .handle_command(Command::Reindex(None))
+            .await
+            .expect("Failed to reindex");
+        assert_eq!(status, "Indexing all sources in the background");
+        assert!(app.display_data().indexing_progress.is_some());
+        let again = app.handle_command(Command::Reindex(None)).await.unwrap();
+        assert_eq!(again, "Indexing is already running");
+
+        let report = app.wait_for_indexing().await.expect("Expected a finished run");
+        assert_eq!(report, "Reindexed 2 files: 1 added, 0 updated, 0 removed");
+        assert!(app.display_data().indexing_progress.is_none());
+        assert_eq!(app.files().get_indexed_files().len(), 2);
+        assert!(!app.cancel_indexing());
+
+        fs::write(source.join("c.md"), "third").unwrap();
+        app.handle_command(Command::Reindex(None)).await.unwrap();
+        assert!(app.cancel_indexing());
+        app.wait_for_indexing().await.expect("Expected a finished run");
+        assert!(!app.cancel_indexing());
+    }
+
+    #[tokio::test]
+    async fn test_index_status_reports_counts() {
+        let temp_dir = TempDir::new().expect("Failed to create temp dir");
+        let source = temp_dir.path().join("docs");
+        fs::create_dir_all(&source).unwrap();
+        fs::write(source.join("a.md"), "a".repeat(1536)).unwrap();
+        fs::write(source.join("b.md"), "b").unwrap();
+        fs::write(source.join("c.txt"), "c").unwrap();
+        let mut app = test_controller(&temp_dir);
+
+        assert_eq!(app.handle_command(Command::IndexStatus).await.unwrap(), "Index is empty");
+        app.handle_command(Command::AddSource(source)).await.unwrap();
+        let status = app.handle_command(Command::IndexStatus).await.unwrap();
+        assert_eq!(status, "3 files (3 indexable), 1.5 KiB; markdown: 2, text: 1");
+    }
+
+    #[tokio::test]
+    async fn test_raw_command_skips_retrieval() {
+        let temp_dir = TempDir::new().expect("Failed to create temp dir");
+        let source = temp_dir.path().join("docs");
+        fs::create_dir_all(&source).unwrap();
+        fs::write(source.join("a.md"), "alpha").unwrap();
+        let client = Arc::new(MockLlmClient::new());
+        let mut app = test_controller(&temp_dir).with_llm_client(Box::new(client.clone()));
+        app.handle_command(Command::AddSource(source)).await.unwrap();
+        app.handle_command(Command::ToggleRag).await.unwrap();
+
+        app.handle_command(Command::Raw("Just answer".to_string()))
+            .await
+            .expect("Failed to send raw message");
+        assert_eq!(client.call_count(), 1);
+        let request = client.requests.lock().unwrap()[0].clone();
+        assert_eq!(request.last().unwrap().content, "Just answer");
+
+        app.process_user_input(UserInput::Message("With retrieval".to_string()))
+            .await
+            .expect("Failed to send message");
+        // Keyword extraction runs before the final answer
+        assert!(client.call_count() > 2);
+        assert!(app.rag_engine.is_enabled());
+    }
+
+    #[tokio::test]
+    async fn test_rag_final_answer_streams() {
+        let temp_dir = TempDir::new().expect("Failed to create temp dir");
+        let source = temp_dir.path().join("docs");
+        fs::create_dir_all(&source).unwrap();
+        fs::write(source.join("a.md"), "alpha").unwrap();
+        let client = Arc::new(MockLlmClient::new());
+        let mut app = test_controller(&temp_dir).with_llm_client(Box::new(client.clone()));
+        app.handle_command(Command::AddSource(source.clone())).await.unwrap();
+        app.handle_command(Command::ToggleRag).await.unwrap();
+        client.push_response(Ok("alpha".to_string()));
+        client.push_response(Ok(source.join("a.md").canonicalize().unwrap().display().to_string()));
+        client.push_response(Ok("It says alpha".to_string()));
+
+        let mut tokens = Vec::new();
+        let response = app
+            .process_message_streaming("What does a.md say?".to_string(), |token| {
+                tokens.push(token.to_string())
+            })
+            .await
+            .expect("Failed to stream message");
+
+        assert_eq!(response, "It says alpha");
+        assert_eq!(tokens, vec!["It ", "says ", "alpha"]);
+        assert_eq!(*client.methods.lock().unwrap(), vec!["send", "structured", "stream"]);
+        let final_request = client.requests.lock().unwrap()[2].clone();
+        assert!(final_request.iter().any(|message| message.content.contains("# File:")));
+    }
+
+    #[tokio::test]
+    async fn test_read_file_tool_feeds_content_back() {
+        let temp_dir = TempDir::new().expect("Failed to create temp dir");
+        let source = temp_dir.path().join("docs");
+        fs::create_dir_all(&source).unwrap();
+        let notes = source.join("notes.md");
+        fs::write(&notes, "The launch code is 1234").unwrap();
+        let client = Arc::new(MockLlmClient::new());
+        let mut app = test_controller(&temp_dir).with_llm_client(Box::new(client.clone()));
+        app.config_manager.get_config_mut().file_tool_enabled = true;
+        app.handle_command(Command::AddSource(source)).await.unwrap();
+        client.push_tool_call(ToolCall {
+            id: "call_1".to_string(),
+            name: "read_file".to_string(),
+            arguments: serde_json::json!({ "path": notes }).to_string(),
+        });
+        client.push_response(Ok("The code is 1234".to_string()));
+
+        let response = app
+            .process_user_input(UserInput::Message("What is the launch code?".to_string()))
+            .await
+            .expect("Failed to send message");
+
+        assert_eq!(response, "The code is 1234");
+        assert_eq!(*client.methods.lock().unwrap(), vec!["tool", "send"]);
+        let follow_up = client.requests.lock().unwrap()[1].clone();
+        let result = follow_up.last().unwrap();
+        assert!(matches!(result.role, MessageRole::Tool));
+        assert_eq!(result.content, "The launch code is 1234");
+
+        let stored = app.conversation_manager.get_messages();
+        assert_eq!(stored.len(), 4);
+        assert_eq!(stored[3].content, "The code is 1234");
+    }
+
+    #[tokio::test]
+    async fn test_streaming_uses_the_file_tool_and_duplicate_check() {
+        let temp_dir = TempDir::new().expect("Failed to create temp dir");
+        let notes = temp_dir.path().join("notes.md");
+        fs::write(&notes, "The launch code is 1234").unwrap();
+        let client = Arc::new(MockLlmClient::new());
+        let mut app = test_controller(&temp_dir).with_llm_client(Box::new(client.clone()));
+        app.config_manager.get_config_mut().file_tool_enabled = true;
+        client.push_tool_call(ToolCall {
+            id: "call_1".to_string(),
+            name: "read_file".to_string(),
+            arguments: serde_json::json!({ "path": notes }).to_string(),
+        });
+        client.push_response(Ok("The code is 1234".to_string()));
+
+        let mut tokens = Vec::new();
+        let response = app
+            .process_message_streaming("Launch code?".to_string(), |token| tokens.push(token.to_string()))
+            .await
+            .unwrap();
+        assert_eq!(response, "The code is 1234");
+        assert_eq!(tokens, vec!["The code is 1234"]);
+        assert_eq!(*client.methods.lock().unwrap(), vec!["tool", "send"]);
+
+        let status = app.process_message_streaming("Launch code?".to_string(), |_| {}).await.unwrap();
+        assert_eq!(status, "Duplicate message awaiting confirmation");
+        assert_eq!(client.call_count(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_show_prompt_assembles_without_sending() {
+        let temp_dir = TempDir::new().unwrap();
+        let source = temp_dir.path().join("docs");
+        fs::create_dir_all(&source).unwrap();
+        fs::write(source.join("a.md"), "alpha notes").unwrap();
+        let client = Arc::new(MockLlmClient::new());
+        let mut app = test_controller(&temp_dir).with_llm_client(Box::new(client.clone()));
+        app.conversation_manager.set_system_prompt(Some("Be brief.".to_string()));
+        app.process_user_input(UserInput::Message("Earlier question".to_string()))
+            .await
+            .unwrap();
+        app.handle_command(Command::AddSource(source.clone())).await.unwrap();
+        app.handle_command(Command::ToggleRag).await.unwrap();
+        client.push_response(Ok("alpha".to_string()));
+        client.push_response(Ok(source.join("a.md").canonicalize().unwrap().display().to_string()));
+
+        let status = app
+            .handle_command(Command::ShowPrompt("What is alpha?".to_string()))
+            .await
+            .unwrap();
+        assert!(status.starts_with("Outgoing prompt has 5 messages, ~"));
+        // One earlier send plus keyword extraction and file selection; no final request
+        assert_eq!(client.call_count(), 3);
+        assert_eq!(app.conversation_manager.get_messages().len(), 2);
+
+        let dump = app.take_file_preview().expect("Expected the prompt preview").content;
+        assert!(dump.starts_with("--- system ---\nBe brief."));
+        assert!(dump.contains("--- user ---\nEarlier question"));
+        assert!(dump.contains("--- assistant ---\nmock response"));
+        assert!(dump.contains("alpha notes"));
+        assert!(dump.contains("[context files: "));
+        assert!(dump.ends_with("What is alpha?"));
+    }
+
+    #[tokio::test]
+    async fn test_banner_summarizes_settings_until_first_message() {
+        let temp_dir = TempDir::new().unwrap();
+        let source = temp_dir.path().join("docs");
+        fs::create_dir_all(&source).unwrap();
+        let mut app = test_controller(&temp_dir).with_llm_client(Box::new(MockLlmClient::new()));
+        app.handle_command(Command::AddSource(source)).await.unwrap();
+
+        let banner = app.display_data().banner.expect("Expected a banner");
+        assert!(banner.starts_with("Model: no provider configured | RAG: OFF | Sources: 1"));
+        assert!(banner.contains("F1 for help"));
+
+        app.process_user_input(UserInput::Message("Hi".to_string())).await.unwrap();
+        assert!(app.display_data().banner.is_none());
+        // The banner never reaches the model
+        assert!(app.conversation_manager.get_messages().iter().all(|m| !m.content.contains("F1")));
+
+        app.handle_command(Command::Clear).await.unwrap();
+        app.config_manager.get_config_mut().show_banner = false;
+        assert!(app.display_data().banner.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_repeated_message_waits_for_confirmation() {
+        let temp_dir = TempDir::new().unwrap();
+        let client = Arc::new(MockLlmClient::new());
+        let mut app = test_controller(&temp_dir).with_llm_client(Box::new(client.clone()));
+
+        app.process_user_input(UserInput::Message("Hi".to_string())).await.unwrap();
+        let status = app.process_user_input(UserInput::Message("Hi".to_string())).await.unwrap();
+        assert_eq!(status, "Duplicate message awaiting confirmation");
+        assert!(app.take_confirmation().is_some());
+        assert_eq!(client.call_count(), 1);
+
+        let status = app.process_user_input(UserInput::Confirm(false)).await.unwrap();
+        assert_eq!(status, "Duplicate message not sent");
+        assert_eq!(client.call_count(), 1);
+
+        app.process_user_input(UserInput::Message("Hi".to_string())).await.unwrap();
+        app.process_user_input(UserInput::Confirm(true)).await.unwrap();
+        assert_eq!(client.call_count(), 2);
+        assert_eq!(app.conversation_manager.get_messages().len(), 4);
+    }
+
+    #[tokio::test]
+    async fn test_provisional_turns_are_displayed_but_not_stored() {
+        let temp_dir = TempDir::new().unwrap();
+        let client = Arc::new(MockLlmClient::with_responses(vec!["Kept", "Scratch answer"]));
+        let mut app = test_controller(&temp_dir).with_llm_client(Box::new(client.clone()));
+
+        app.process_user_input(UserInput::Message("Keep this".to_string())).await.unwrap();
+        app.process_user_input(UserInput::MessageAs {
+            content: "Throwaway".to_string(),
+            provisional: true,
+        })
+        .await
+        .unwrap();
+
+        let shown: Vec<(String, bool)> = app
+            .display_data()
+            .messages
+            .into_iter()
+            .map(|message| (message.content, message.provisional))
+            .collect();
+        assert_eq!(
+            shown,
+            vec![
+                ("Keep this".to_string(), false),
+                ("Kept".to_string(), false),
+                ("Throwaway".to_string(), true),
+                ("Scratch answer".to_string(), true),
+            ]
+        );
+        assert_eq!(app.conversation_manager.get_messages().len(), 2);
+
+        // Later turns do not send the provisional exchange again
+        app.process_user_input(UserInput::Message("Next".to_string())).await.unwrap();
+        let request = client.requests.lock().unwrap()[2].clone();
+        assert!(request.iter().all(|message| message.content != "Throwaway"));
+
+        app.config_manager.get_config_mut().show_provisional_messages = false;
+        assert!(app.display_data().messages.iter().all(|message| !message.provisional));
+    }
+
+    #[tokio::test]
+    async fn test_copy_conversation_puts_markdown_on_clipboard() {
+        let temp_dir = TempDir::new().unwrap();
+        let client = Arc::new(MockLlmClient::with_responses(vec!["Four"]));
+        let mut app = test_controller(&temp_dir).with_llm_client(Box::new(client.clone()));
+        assert_eq!(app.handle_command(Command::CopyConversation).await.unwrap(), "Nothing to copy");
+
+        app.process_user_input(UserInput::Message("2+2?".to_string())).await.unwrap();
+        app.handle_command(Command::ToggleProvisional).await.unwrap();
+        app.process_user_input(UserInput::Message("Scratch".to_string())).await.unwrap();
+
+        let status = app.handle_command(Command::CopyConversation).await.unwrap();
+        assert_eq!(status, "Copied 2 messages to the clipboard");
+        let (expected, _) = app.conversation_manager.export_markdown("You", "Assistant");
+        assert_eq!(app.take_clipboard(), Some(expected));
+        assert!(app.take_clipboard().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_ping_reports_authentication_failure() {
+        let temp_dir = TempDir::new().unwrap();
+        let server = MockServer::start(vec![(401, r#"{"error":{}}"#.to_string())]).await;
+        let client = OpenAiClient::new("bad".to_string(), "gpt-4".to_string())
+            .with_base_url(server.base_url.clone());
+        let mut app = test_controller(&temp_dir).with_llm_client(Box::new(client));
+
+        let report = app.handle_command(Command::Ping).await.unwrap();
+        assert_eq!(report, "Authentication failed: check the configured API key");
+        assert_eq!(server.request_bodies()[0]["max_tokens"], 1);
+    }
+
+    #[tokio::test]
+    async fn test_alt_enter_sends_provisional_message() {
+        let temp_dir = TempDir::new().unwrap();
+        let client = Arc::new(MockLlmClient::new());
+        let mut app = test_controller(&temp_dir).with_llm_client(Box::new(client.clone()));
+        assert!(!app.conversation_manager.is_provisional_mode());
+
+        let mut state = TuiState {
+            input_buffer: "Throwaway question".to_string(),
+            ..TuiState::default()
+        };
+        let action = state
+            .handle_key(KeyEvent::new(KeyCode::Enter, KeyModifiers::ALT))
+            .unwrap();
+        let Some(UserAction::SendMessage { content, provisional: Some(provisional) }) = action else {
+            panic!("Expected a provisional send, got {:?}", action);
+        };
+
+        let response = app
+            .process_user_input(UserInput::MessageAs { content, provisional })
+            .await
+            .unwrap();
+        assert_eq!(response, "mock response");
+        assert!(app.conversation_manager.get_messages().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_open_command_stores_preview() {
+        let temp_dir = TempDir::new().expect("Failed to create temp dir");
+        let path = temp_dir.path().join("notes.md");
+        fs::write(&path, "hello").unwrap();
+        let mut app = test_controller(&temp_dir);
+
+        let message = app
+            .handle_command(Command::Open(path.clone()))
+            .await
+            .expect("Failed to open file");
+        assert_eq!(message, format!("Opened {:?}", path));
+
+        let preview = app.take_file_preview().expect("Expected a preview");
+        assert_eq!(preview.content, "hello");
+        assert!(app.take_file_preview().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_diff_command_previews_changes_between_sources() {
+        let temp_dir = TempDir::new().unwrap();
+        let source = temp_dir.path().join("docs");
+        fs::create_dir_all(&source).unwrap();
+        let old = source.join("old.txt");
+        let new = source.join("new.txt");
+        fs::write(&old, "keep\nremove me\n").unwrap();
+        fs::write(&new, "keep\nadd me\n").unwrap();
+        let mut app = test_controller(&temp_dir);
+        app.handle_command(Command::AddSource(source.clone())).await.unwrap();
+
+        let status = app.handle_command(Command::Diff(old.clone(), new.clone())).await.unwrap();
+        assert!(status.ends_with(": +1 -1"));
+        let preview = app.take_file_preview().expect("Expected a diff preview");
+        assert!(preview.diff);
+        assert!(preview.content.contains("-remove me\n+add me"));
+
+        let missing = source.join("missing.txt");
+        let err = app.handle_command(Command::Diff(old, missing)).await.unwrap_err();
+        assert!(err.to_string().contains("missing.txt"));
+    }
+
+    #[tokio::test]
+    async fn test_unsupported_provider_leaves_other_commands_working() {
+        let temp_dir = TempDir::new().unwrap();
+        let source = temp_dir.path().join("docs");
+        fs::create_dir_all(&source).unwrap();
+        let mut config_manager =
+            ConfigManager::from_path(temp_dir.path().join("config.toml")).unwrap();
+        config_manager.get_config_mut().conversation_storage_path =
+            temp_dir.path().join("conversations");
+        config_manager.get_config_mut().llm_provider = Some(LlmProvider {
+            provider_type: ProviderType::Local,
+            api_key: "unused".to_string(),
+            model: "llama3".to_string(),
+            base_url: None,
+            max_tokens: None,
+            temperature: None,
+            stop: Vec::new(),
+            reasoning_effort: None,
+            thinking_budget_tokens: None,
+            echo_prefix: None,
+        });
+        let mut app = AppController::from_config(config_manager).expect("Startup should not fail");
+
+        assert!(app.handle_command(Command::Help).await.is_ok());
+        app.handle_command(Command::AddSource(source)).await.unwrap();
+        assert!(app.handle_command(Command::ListSources).await.unwrap().contains("docs"));
+
+        let status = app.process_user_input(UserInput::Message("Hi".to_string())).await.unwrap();
+        assert_eq!(status, "LLM provider unavailable: API error: Local models not yet supported");
+        assert!(app.conversation_manager.get_messages().is_empty());
+        // Reported again on later attempts, not only the first
+        let status = app.handle_command(Command::Ping).await.unwrap();
+        assert!(status.starts_with("LLM provider unavailable"));
+    }
+
+    #[tokio::test]
+    async fn test_temp_command_updates_provider_and_rejects_out_of_range() {
+        let temp_dir = TempDir::new().unwrap();
+        let mut app = test_controller(&temp_dir);
+        app.config_manager.get_config_mut().llm_provider = Some(LlmProvider {
+            provider_type: ProviderType::Echo,
+            api_key: String::new(),
+            model: String::new(),
+            base_url: None,
+            max_tokens: None,
+            temperature: None,
+            stop: Vec::new(),
+            reasoning_effort: None,
+            thinking_budget_tokens: None,
+            echo_prefix: None,
+        });
+
+        let status = app.handle_command(Command::Temperature(0.7)).await.unwrap();
+        assert_eq!(status, "Temperature set to 0.7");
+        let provider = app.session_config().llm_provider.unwrap();
+        assert_eq!(provider.temperature, Some(0.7));
+
+        let err = app.handle_command(Command::Temperature(2.5)).await.unwrap_err();
+        assert!(matches!(err, AppError::Command(CommandError::InvalidArgument(_))));
+        let provider = app.session_config().llm_provider.unwrap();
+        assert_eq!(provider.temperature, Some(0.7));
+
+        // A later save of unrelated settings leaves the session override out of the file
+        app.handle_command(Command::SetSystem("Be brief.".to_string())).await.unwrap();
+        let saved = ConfigManager::from_path(temp_dir.path().join("config.toml")).unwrap();
+        assert_eq!(saved.get_config().llm_provider.as_ref().unwrap().temperature, None);
+    }
+
+    #[tokio::test]
+    async fn test_setup_saves_provider_and_reopens_on_command() {
+        let temp_dir = TempDir::new().expect("Failed to create temp dir");
+        let mut app = test_controller(&temp_dir);
+        assert!(app.take_setup_request());
+        assert!(!app.take_setup_request());
+
+        let provider = LlmProvider {
+            provider_type: ProviderType::OpenAi,
+            api_key: "sk-test".to_string(),
+            model: String::new(),
+            base_url: None,
+            max_tokens: None,
+            temperature: None,
+            stop: Vec::new(),
+            reasoning_effort: None,
+            thinking_budget_tokens: None,
+            echo_prefix: None,
+        };
+        app.apply_setup(provider.clone()).unwrap();
+        assert_eq!(app.config().llm_provider.as_ref(), Some(&provider));
+        let saved = ConfigManager::from_path(temp_dir.path().join("config.toml")).unwrap();
+        assert_eq!(saved.get_config().llm_provider.as_ref(), Some(&provider));
+
+        let invalid = LlmProvider { api_key: " ".to_string(), ..provider.clone() };
+        assert!(app.apply_setup(invalid).is_err());
+        assert_eq!(app.config().llm_provider.as_ref(), Some(&provider));
+
+        app.handle_command(Command::Setup).await.unwrap();
+        assert!(app.take_setup_request());
+    }
+
+    #[tokio::test]
+    async fn test_pattern_editor_actions_update_config() {
+        let temp_dir = TempDir::new().expect("Failed to create temp dir");
+        let mut app = test_controller(&temp_dir);
+        assert!(app.take_patterns_request().is_none());
+        app.handle_command(Command::Patterns).await.unwrap();
+        let (include, exclude) = app.take_patterns_request().unwrap();
+
+        let mut editor = PatternEditor::new(include, exclude);
+        editor.switch_list();
+        for c in "generated/(".chars() {
+            editor.push_char(c);
+        }
+        assert!(editor.add().is_none());
+        let invalid = PatternEdit::Add(PatternKind::Exclude, "generated/(".to_string());
+        assert!(app.apply_pattern_edit(invalid).is_err());
+        assert!(!app.config().exclude_patterns.contains(&"generated/(".to_string()));
+
+        editor.pop_char();
+        let edit = editor.add().unwrap();
+        app.apply_pattern_edit(edit).unwrap();
+        assert_eq!(app.config().exclude_patterns, editor.exclude);
+        let saved = ConfigManager::from_path(temp_dir.path().join("config.toml")).unwrap();
+        assert!(saved.get_config().exclude_patterns.contains(&"generated/".to_string()));
+
+        let edit = editor.remove().unwrap();
+        assert_eq!(edit, PatternEdit::Remove(PatternKind::Exclude, "generated/".to_string()));
+        app.apply_pattern_edit(edit).unwrap();
+        assert_eq!(app.config().exclude_patterns, editor.exclude);
+    }
+
+    #[tokio::test]
+    async fn test_save_block_writes_nth_code_block() {
+        let temp_dir = TempDir::new().expect("Failed to create temp dir");
+        let reply = "First:\n```rust\nfn main() {}\n```\nThen:\n```python\nprint('hi')\n```\n";
+        let client = MockLlmClient::with_responses(vec![reply]);
+        let mut app = test_controller(&temp_dir).with_llm_client(Box::new(client));
+        app.process_user_input(UserInput::Message("Write code".to_string())).await.unwrap();
+
+        // Without an extension, the fence language supplies one
+        let status = app
+            .handle_command(Command::SaveBlock(2, temp_dir.path().join("script")))
+            .await
+            .unwrap();
+        let saved = temp_dir.path().join("script.py");
+        assert_eq!(status, format!("Saved code block 2 to {}", saved.display()));
+        assert_eq!(fs::read_to_string(&saved).unwrap(), "print('hi')\n");
+
+        let err = app
+            .handle_command(Command::SaveBlock(3, temp_dir.path().join("x.rs")))
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("Code block 3 is out of range; the last reply has 2"));
+        assert!(!temp_dir.path().join("x.rs").exists());
+    }
+
+    #[tokio::test]
+    async fn test_image_attaches_to_next_message_on_vision_models_only() {
+        let temp_dir = TempDir::new().unwrap();
+        let image_path = temp_dir.path().join("chart.png");
+        fs::write(&image_path, b"\x89PNG").unwrap();
+        let client = Arc::new(MockLlmClient::new());
+        let mut app = test_controller(&temp_dir).with_llm_client(Box::new(client.clone()));
+        let mut provider = LlmProvider {
+            provider_type: ProviderType::OpenAi,
+            api_key: "sk-test".to_string(),
+            model: "gpt-3.5-turbo".to_string(),
+            base_url: None,
+            max_tokens: None,
+            temperature: None,
+            stop: Vec::new(),
+            reasoning_effort: None,
+            thinking_budget_tokens: None,
+            echo_prefix: None,
+        };
+        app.config_manager.get_config_mut().llm_provider = Some(provider.clone());
+
+        let err = app.handle_command(Command::AttachImage(image_path.clone())).await.unwrap_err();
+        assert!(matches!(err, AppError::Command(CommandError::InvalidArgument(_))));
+
+        provider.model = "gpt-4o".to_string();
+        app.config_manager.get_config_mut().llm_provider = Some(provider);
+        let status = app.handle_command(Command::AttachImage(image_path)).await.unwrap();
+        assert_eq!(status, "Attached chart.png to the next message");
+        app.process_user_input(UserInput::Message("What is this?".to_string())).await.unwrap();
+        app.process_user_input(UserInput::Message("And now?".to_string())).await.unwrap();
+
+        let requests = client.requests.lock().unwrap().clone();
+        let sent = requests[0].last().unwrap();
+        assert_eq!(sent.images.len(), 1);
+        assert_eq!(sent.images[0].media_type, "image/png");
+        assert!(requests[1].last().unwrap().images.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_reset_restores_config_defaults_and_drops_staged_input() {
+        let temp_dir = TempDir::new().unwrap();
+        let image_path = temp_dir.path().join("chart.png");
+        fs::write(&image_path, b"\x89PNG").unwrap();
+        let mut app = test_controller(&temp_dir);
+        let config = app.config_manager.get_config_mut();
+        config.rag_enabled_default = true;
+        config.provisional_mode_default = true;
+        config.llm_provider = Some(LlmProvider {
+            provider_type: ProviderType::Echo,
+            api_key: String::new(),
+            model: String::new(),
+            base_url: None,
+            max_tokens: Some(1024),
+            temperature: None,
+            stop: Vec::new(),
+            reasoning_effort: None,
+            thinking_budget_tokens: None,
+            echo_prefix: None,
+        });
+
+        app.process_user_input(UserInput::Message("Main question".to_string())).await.unwrap();
+        app.handle_command(Command::Temperature(0.3)).await.unwrap();
+        app.handle_command(Command::MaxTokens(64)).await.unwrap();
+        app.process_user_input(UserInput::Message("Main question".to_string())).await.unwrap();
+        app.handle_command(Command::ShowPrompt("Draft".to_string())).await.unwrap();
+        app.handle_command(Command::ToggleScratch).await.unwrap();
+        app.handle_command(Command::ToggleView).await.unwrap();
+        app.conversation_manager.attach_image(read_image(&image_path).unwrap());
+        app.handle_command(Command::Prefill("Sure".to_string())).await.unwrap();
+
+        app.handle_command(Command::Reset).await.unwrap();
+        assert!(app.rag_engine.is_enabled());
+        assert!(app.conversation_manager.is_provisional_mode());
+        assert!(!app.conversation_manager.is_scratch());
+        assert!(app.conversation_manager.pending_images().is_empty());
+        assert!(app.conversation_manager.get_messages().is_empty());
+        assert!(!app.display_data().view_only);
+        assert!(app.take_confirmation().is_none());
+        assert!(app.take_file_preview().is_none());
+        assert!(app.take_comparison().is_none());
+        let provider = app.session_config().llm_provider.unwrap();
+        assert_eq!((provider.temperature, provider.max_tokens), (None, Some(1024)));
+
+        // Leaving scratch again does not bring the old conversation back
+        app.handle_command(Command::ToggleScratch).await.unwrap();
+        app.handle_command(Command::ToggleScratch).await.unwrap();
+        assert!(app.conversation_manager.get_messages().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_idle_save_in_scratch_writes_the_main_conversation() {
+        let temp_dir = TempDir::new().unwrap();
+        let mut app = test_controller(&temp_dir).with_llm_client(Box::new(MockLlmClient::new()));
+        app.conversation_manager.set_auto_save(false);
+        let stored = temp_dir.path().join("conversations");
+
+        app.handle_command(Command::ToggleScratch).await.unwrap();
+        app.process_user_input(UserInput::Message("Throwaway".to_string())).await.unwrap();
+        assert_eq!(app.save_on_idle().unwrap(), "Idle: nothing to save");
+        assert!(!stored.exists());
+
+        app.handle_command(Command::ToggleScratch).await.unwrap();
+        app.process_user_input(UserInput::Message("Keep this".to_string())).await.unwrap();
+        app.handle_command(Command::ToggleScratch).await.unwrap();
+        assert_eq!(app.save_on_idle().unwrap(), "Saved the conversation after inactivity");
+        assert_eq!(fs::read_dir(&stored).unwrap().count(), 1);
+        assert!(app.conversation_manager.is_scratch());
+    }
+
+    #[tokio::test]
+    async fn test_load_conversation_hands_saved_scroll_to_ui() {
+        let temp_dir = TempDir::new().unwrap();
+        let client = MockLlmClient::with_responses(vec!["One", "Two"]);
+        let mut app = test_controller(&temp_dir).with_llm_client(Box::new(client));
+        app.process_user_input(UserInput::Message("First".to_string())).await.unwrap();
+        app.process_user_input(UserInput::Message("Second".to_string())).await.unwrap();
+        app.set_scroll_position(1);
+        app.conversation_manager.save_conversation().unwrap();
+        let id = app.conversation_manager.current_conversation_id().to_string();
+        app.clear_conversation();
+        assert!(app.take_restored_scroll().is_none());
+
+        app.handle_command(Command::LoadConversation(id)).await.unwrap();
+        assert_eq!(app.take_restored_scroll(), Some((1, 4)));
+        assert!(app.take_restored_scroll().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_set_system_persists_and_applies_to_next_turn() {
+        let temp_dir = TempDir::new().expect("Failed to create temp dir");
+        let client = Arc::new(MockLlmClient::with_responses(vec!["One", "Two"]));
+        let mut app = test_controller(&temp_dir).with_llm_client(Box::new(client.clone()));
+
+        app.handle_command(Command::SetSystem("Answer in French.".to_string())).await.unwrap();
+        assert_eq!(app.config().global_system_prompt.as_deref(), Some("Answer in French."));
+        let saved = ConfigManager::from_path(temp_dir.path().join("config.toml")).unwrap();
+        assert_eq!(saved.get_config().global_system_prompt.as_deref(), Some("Answer in French."));
+        app.process_user_input(UserInput::Message("Hello".to_string())).await.unwrap();
+
+        app.handle_command(Command::ClearSystem).await.unwrap();
+        assert!(app.config().global_system_prompt.is_none());
+        app.process_user_input(UserInput::Message("Again".to_string())).await.unwrap();
+
+        let requests = client.requests.lock().unwrap().clone();
+        assert!(matches!(requests[0][0].role, MessageRole::System));
+        assert_eq!(requests[0][0].content, "Answer in French.");
+        assert!(requests[1].iter().all(|message| !matches!(message.role, MessageRole::System)));
+    }
+
+    #[tokio::test]
+    async fn test_redo_sends_instruction_and_replaces_reply() {
+        let temp_dir = TempDir::new().expect("Failed to create temp dir");
+        let client = Arc::new(MockLlmClient::with_responses(vec!["A long answer", "Short"]));
+        let mut app = test_controller(&temp_dir).with_llm_client(Box::new(client.clone()));
+        let err = app.handle_command(Command::Redo(None)).await.unwrap_err();
+        assert!(matches!(err, AppError::Conversation(ConversationError::History(_))));
+
+        app.process_user_input(UserInput::Message("Explain traits".to_string())).await.unwrap();
+        let instruction = "Make it more concise".to_string();
+        let response = app.handle_command(Command::Redo(Some(instruction))).await.unwrap();
+        assert_eq!(response, "Short");
+
+        let requests = client.requests.lock().unwrap().clone();
+        let redo: Vec<&str> = requests[1].iter().map(|m| m.content.as_str()).collect();
+        assert_eq!(redo, vec!["Explain traits", "A long answer", "Make it more concise"]);
+        let data = app.display_data();
+        let stored: Vec<&str> = data.messages.iter().map(|m| m.content.as_str()).collect();
+        assert_eq!(stored, vec!["Explain traits", "Short"]);
+    }
+
+    // Records each write separately so tests can see output arrive in pieces
+    #[derive(Default)]
+    struct ChunkWriter {
+        chunks: Vec<String>,
+    }
+
+    impl Write for ChunkWriter {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.chunks.push(String::from_utf8_lossy(buf).into_owned());
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_one_shot_streams_tokens_as_they_arrive() {
+        let temp_dir = TempDir::new().expect("Failed to create temp dir");
+        let client = Arc::new(MockLlmClient::with_responses(vec!["Whole reply"]));
+        client.push_stream(vec![Ok("Hel".to_string()), Ok("lo".to_string())]);
+        let mut app = test_controller(&temp_dir).with_llm_client(Box::new(client.clone()));
+
+        let mut out = ChunkWriter::default();
+        let response = app.run_one_shot("Hi".to_string(), true, &mut out).await.unwrap();
+        assert_eq!(response, "Hello");
+        assert_eq!(out.chunks, vec!["Hel", "lo", "\n"]);
+
+        let mut out = ChunkWriter::default();
+        app.run_one_shot("Again".to_string(), false, &mut out).await.unwrap();
+        assert_eq!(out.chunks.concat(), "Whole reply\n");
+        assert_eq!(*client.methods.lock().unwrap(), vec!["stream", "send"]);
+    }
+
+    #[tokio::test]
+    async fn test_last_raw_shows_retained_response_body() {
+        let temp_dir = TempDir::new().unwrap();
+        let reply = r#"{"choices":[{"message":{"role":"assistant","content":"Key sk-test"}}]}"#.to_string();
+        let server = MockServer::start(vec![(200, reply)]).await;
+        let mut app = test_controller(&temp_dir);
+        let status = app.handle_command(Command::LastRaw).await.unwrap();
+        assert!(status.contains("retain_last_response"));
+
+        let config = app.config_manager.get_config_mut();
+        config.retain_last_response = true;
+        config.llm_provider = Some(LlmProvider {
+            provider_type: ProviderType::OpenAi,
+            api_key: "sk-test".to_string(),
+            model: "gpt-4".to_string(),
+            base_url: Some(server.base_url.clone()),
+            max_tokens: None,
+            temperature: None,
+            stop: Vec::new(),
+            reasoning_effort: None,
+            thinking_budget_tokens: None,
+            echo_prefix: None,
+        });
+        let status = app.handle_command(Command::LastRaw).await.unwrap();
+        assert_eq!(status, "No API response received yet");
+        app.process_user_input(UserInput::Message("Hi".to_string())).await.unwrap();
+
+        app.handle_command(Command::LastRaw).await.unwrap();
+        let preview = app.take_file_preview().unwrap();
+        assert!(preview.content.starts_with("HTTP 200\n{\"choices\""));
+        assert!(preview.content.contains("Key [REDACTED]"));
+        assert!(!preview.content.contains("sk-test"));
+    }
+
+    #[tokio::test]
+    async fn test_compare_attributes_each_reply_to_its_profile() {
+        let first = MockLlmClient::with_responses(vec!["From first"]);
+        let second = MockLlmClient::with_responses(vec!["From second"]);
+        let failing = MockLlmClient::new();
+        failing.push_response(Err(LlmError::RateLimit));
+        let clients: Vec<(String, BuiltClient)> = vec![
+            ("fast".to_string(), Ok(Box::new(first))),
+            ("broken".to_string(), Ok(Box::new(failing))),
+            ("missing".to_string(), Err("LLM provider unavailable".to_string())),
+            ("smart".to_string(), Ok(Box::new(second))),
+        ];
+
+        let replies = compare_responses(&clients, &[]).await;
+        let profiles: Vec<&str> = replies.iter().map(|reply| reply.profile.as_str()).collect();
+        assert_eq!(profiles, vec!["fast", "broken", "missing", "smart"]);
+        assert_eq!(replies[0].response, Ok("From first".to_string()));
+        assert!(replies[1].response.is_err());
+        assert_eq!(replies[2].response, Err("LLM provider unavailable".to_string()));
+        assert_eq!(replies[3].response, Ok("From second".to_string()));
+
+        let temp_dir = TempDir::new().expect("Failed to create temp dir");
+        let mut app = test_controller(&temp_dir);
+        let status = app.handle_command(Command::Compare("Hi".to_string())).await.unwrap();
+        assert_eq!(status, "No provider_profiles configured to compare");
+        assert!(app.take_comparison().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_compare_resolves_and_validates_profiles() {
+        let temp_dir = TempDir::new().unwrap();
+        let reply = r#"{"choices":[{"message":{"role":"assistant","content":"Resolved"}}]}"#.to_string();
+        let server = MockServer::start(vec![(200, reply)]).await;
+        std::env::set_var("LLM_TUI_COMPARE_TEST_URL", &server.base_url);
+        let profile = |name: &str, base_url: &str, temperature| ProviderProfile {
+            name: name.to_string(),
+            provider: LlmProvider {
+                provider_type: ProviderType::OpenAi,
+                api_key: "sk-test".to_string(),
+                model: "gpt-4".to_string(),
+                base_url: Some(base_url.to_string()),
+                max_tokens: None,
+                temperature,
+                stop: Vec::new(),
+                reasoning_effort: None,
+                thinking_budget_tokens: None,
+                echo_prefix: None,
+            },
+        };
+        let mut app = test_controller(&temp_dir);
+        app.config_manager.get_config_mut().provider_profiles = vec![
+            profile("env", "${LLM_TUI_COMPARE_TEST_URL}", None),
+            profile("hot", "${LLM_TUI_COMPARE_TEST_URL}", Some(5.0)),
+        ];
+
+        let status = app.handle_command(Command::Compare("Hi".to_string())).await.unwrap();
+        assert_eq!(status, "Compared 2 profiles, 1 failed");
+        let replies = app.take_comparison().unwrap();
+        assert_eq!(replies[0].response, Ok("Resolved".to_string()));
+        assert!(replies[1].response.as_ref().unwrap_err().contains("temperature"));
+        assert_eq!(server.request_bodies().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_explicit_context_files_reach_prompt_and_are_recorded() {
+        let temp_dir = TempDir::new().expect("Failed to create temp dir");
+        let source = temp_dir.path().join("docs");
+        fs::create_dir_all(&source).unwrap();
+        let notes = source.join("notes.md");
+        fs::write(&notes, "The secret sauce is paprika").unwrap();
+        let outside = temp_dir.path().join("secrets.md");
+        fs::write(&outside, "Not for the model").unwrap();
+        let client = Arc::new(MockLlmClient::with_responses(vec!["Paprika"]));
+        let mut app = test_controller(&temp_dir).with_llm_client(Box::new(client.clone()));
+        app.handle_command(Command::AddSource(source.clone())).await.unwrap();
+        assert!(!app.rag_engine.is_enabled());
+
+        let missing = source.join("missing.md");
+        let result = app.process_message_with_context("Sauce?".to_string(), vec![missing]).await;
+        assert!(matches!(result, Err(AppError::FileSystem(_))));
+        let result = app.process_message_with_context("Sauce?".to_string(), vec![outside]).await;
+        assert!(matches!(result, Err(AppError::FileSystem(FileSystemError::PermissionDenied(_)))));
+        assert_eq!(client.call_count(), 0);
+
+        let response = app
+            .process_message_with_context("Sauce?".to_string(), vec![notes.clone(), notes.clone()])
+            .await
+            .unwrap();
+        assert_eq!(response, "Paprika");
+        let request = client.requests.lock().unwrap()[0].clone();
+        let context_messages: Vec<&Message> =
+            request.iter().filter(|m| m.content.contains("secret sauce is paprika")).collect();
+        assert_eq!(context_messages.len(), 1);
+        assert_eq!(app.conversation_manager.get_messages()[0].context_files, vec![notes]);
+    }
+
+    #[tokio::test]
+    async fn test_max_tokens_persists_and_once_max_applies_to_one_turn() {
+        let temp_dir = TempDir::new().unwrap();
+        let reply = r#"{"choices":[{"message":{"role":"assistant","content":"Ok"}}]}"#.to_string();
+        let server = MockServer::start(vec![(200, reply.clone()), (200, reply.clone()), (200, reply)]).await;
+        let mut app = test_controller(&temp_dir);
+        app.config_manager.get_config_mut().llm_provider = Some(LlmProvider {
+            provider_type: ProviderType::OpenAi,
+            api_key: "sk-test".to_string(),
+            model: "gpt-4".to_string(),
+            base_url: Some(server.base_url.clone()),
+            max_tokens: None,
+            temperature: None,
+            stop: Vec::new(),
+            reasoning_effort: None,
+            thinking_budget_tokens: None,
+            echo_prefix: None,
+        });
+
+        app.handle_command(Command::OnceMax(4096, "Go long".to_string())).await.unwrap();
+        assert_eq!(app.session_config().llm_provider.unwrap().max_tokens, None);
+        app.process_user_input(UserInput::Message("Short".to_string())).await.unwrap();
+
+        let status = app.handle_command(Command::MaxTokens(256)).await.unwrap();
+        assert_eq!(status, "Max tokens set to 256");
+        assert_eq!(app.session_config().llm_provider.unwrap().max_tokens, Some(256));
+        app.process_user_input(UserInput::Message("Again".to_string())).await.unwrap();
+
+        let bodies = server.request_bodies();
+        assert_eq!(bodies[0]["max_tokens"], 4096);
+        assert!(bodies[1].get("max_tokens").is_none());
+        assert_eq!(bodies[2]["max_tokens"], 256);
+
+        let err = app.handle_command(Command::MaxTokens(0)).await.unwrap_err();
+        assert!(matches!(err, AppError::Command(CommandError::InvalidArgument(_))));
+        assert_eq!(app.session_config().llm_provider.unwrap().max_tokens, Some(256));
+    }
+
+    #[tokio::test]
+    async fn test_logs_command_follows_log_file() {
+        let temp_dir = TempDir::new().unwrap();
+        let log = temp_dir.path().join("app.log");
+        let mut app = test_controller(&temp_dir);
+        let status = app.handle_command(Command::Logs).await.unwrap();
+        assert!(status.starts_with("No log file configured"));
+
+        app.config_manager.get_config_mut().log_file = Some(log.clone());
+        app.handle_command(Command::Logs).await.unwrap();
+        let preview = app.take_file_preview().expect("Expected the log preview");
+        assert!(preview.tail);
+        assert_eq!(preview.content, "The log file has not been written yet");
+        assert!(app.poll_log_tail().is_none());
+
+        fs::write(&log, "INFO started\n").unwrap();
+        assert_eq!(app.poll_log_tail().unwrap().content, "INFO started\n");
+        assert!(app.poll_log_tail().is_none());
+    }
+}
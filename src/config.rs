@@ -1,19 +1,106 @@
 use crate::types::*;
+use crate::filesystem::canonical_path;
+use crate::conversation::ConversationNaming;
+use crate::llm::{
+    default_model, max_stop_sequences, model_mismatch_warning, TranscriptBodies, ANTHROPIC_MIN_THINKING_BUDGET,
+    DEFAULT_TRANSCRIPT_MAX_KB, REASONING_EFFORT_LEVELS,
+};
+use notify::{RecommendedWatcher, RecursiveMode, Watcher};
 use serde::{Deserialize, Serialize};
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{channel, Receiver};
 use regex::Regex;
+use tracing::warn;
 
 // Application configuration structure
 #[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
 pub struct AppConfig {
     pub llm_provider: Option<LlmProvider>,
     pub global_system_prompt: Option<String>,
+    pub system_prompt_file: Option<PathBuf>,
     pub rag_enabled_default: bool,
     pub provisional_mode_default: bool,
     pub data_sources: Vec<PathBuf>,
     pub include_patterns: Vec<String>,
     pub exclude_patterns: Vec<String>,
     pub conversation_storage_path: PathBuf,
+    pub conversation_naming: ConversationNaming, // Uuid, or Titled for `<date>_<slug>.json` files
+    pub response_cache_enabled: bool,
+    pub response_cache_path: Option<PathBuf>,
+    pub debug_transcript_path: Option<PathBuf>,
+    pub debug_transcript_bodies: TranscriptBodies, // "requests", "responses" or "both"
+    pub debug_transcript_max_kb: u64, // Longer transcript bodies are truncated; 0 logs them whole
+    pub retain_last_response: bool, // Keep the last raw API response body in memory for /last-raw
+    pub log_file: Option<PathBuf>, // Where logs are written, and what /logs shows; stderr when unset
+    pub watch_config_file: bool,
+    pub max_file_size_bytes: u64,
+    pub reject_oversized_sources: bool, // Refuse single-file sources over max_file_size_bytes; otherwise warn
+    pub rag_min_relevance: f32,
+    pub rag_max_candidates: usize,
+    pub auto_fence_code: bool,
+    pub trim_response_blank_lines: bool, // Drop blank lines models put before or after a reply
+    pub auto_save_conversations: bool,
+    pub assistant_name: String,
+    pub user_name: String,
+    pub frame_interval_ms: u64,
+    pub allowed_roots: Vec<PathBuf>,
+    pub file_tool_enabled: bool,
+    pub max_history_messages: Option<usize>,
+    pub monochrome: bool, // NO_COLOR in the environment also enables it
+    pub enable_mouse: bool, // Capture the mouse for scrolling; off keeps the terminal's text selection
+    pub idle_timeout_minutes: Option<u64>, // Save the conversation after this long without input; None disables
+    pub exit_on_idle: bool, // Also exit when the idle timeout fires
+    pub stream_reconnect_attempts: u32, // 0 disables reconnecting dropped streams
+    pub server_retry_attempts: u32, // Retries of requests failing with HTTP 5xx; 0 disables
+    pub injection_patterns: Vec<String>, // Case-insensitive regexes flagging retrieved content
+    pub show_banner: bool, // Settings summary shown on an empty conversation
+    pub context_file_template: String, // Layout of each RAG file; {path} and {content} are filled in
+    pub rag_keyword_prompt: String, // Keyword request of the first RAG step; {query} and {files} are filled in
+    pub rag_selection_prompt: String, // File selection request; {query} and {files} (the candidates) are filled in
+    pub rag_max_rounds: usize, // Retrieval rounds per query; later rounds run when the model asks for more files
+    pub show_provisional_messages: bool, // Keep this session's provisional turns on screen
+    pub max_content_width: Option<u16>, // Reading column for messages; None uses the full width
+    pub source_patterns: Vec<SourcePatternOverride>, // Per-source replacements for the global patterns
+    pub provider_profiles: Vec<ProviderProfile>, // Named providers that /compare sends the same prompt to
+    pub keybindings: KeyBindings,
+    pub collapse_system_messages: bool, // Show system messages by their first line only
+    pub auto_scroll: bool, // Jump to the newest message when one arrives
+    pub thinking_placeholder: String, // Shown with animated dots until the first token; empty for none
+    pub code_line_numbers: bool, // Line-number gutter on fenced code blocks in messages
+    pub context_line_numbers: bool, // Number the lines of files added as RAG context
+}
+
+// Letters pressed with Ctrl for the mode toggles
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct KeyBindings {
+    pub toggle_rag: char,
+    pub toggle_provisional: char,
+    pub toggle_scratch: char,
+    pub toggle_view: char,
+}
+
+impl Default for KeyBindings {
+    fn default() -> Self {
+        Self {
+            toggle_rag: 'g',
+            toggle_provisional: 'p',
+            toggle_scratch: 's',
+            toggle_view: 'r',
+        }
+    }
+}
+
+// Include/exclude patterns used instead of the global lists for one data source;
+// a list left unset falls back to the global one
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SourcePatternOverride {
+    pub path: PathBuf,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub include_patterns: Option<Vec<String>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub exclude_patterns: Option<Vec<String>>,
 }
 
 impl Default for AppConfig {
@@ -21,6 +108,7 @@ impl Default for AppConfig {
         Self {
             llm_provider: None,
             global_system_prompt: None,
+            system_prompt_file: None,
             rag_enabled_default: false,
             provisional_mode_default: false,
             data_sources: Vec::new(),
@@ -39,6 +127,115 @@ impl Default for AppConfig {
                 r"\.DS_Store$".to_string(),
             ],
             conversation_storage_path: PathBuf::from("conversations"),
+            conversation_naming: ConversationNaming::Uuid,
+            response_cache_enabled: false,
+            response_cache_path: None,
+            debug_transcript_path: None,
+            debug_transcript_bodies: TranscriptBodies::Both,
+            debug_transcript_max_kb: DEFAULT_TRANSCRIPT_MAX_KB,
+            retain_last_response: false,
+            log_file: None,
+            watch_config_file: false,
+            max_file_size_bytes: crate::filesystem::DEFAULT_MAX_FILE_SIZE_BYTES,
+            reject_oversized_sources: true,
+            rag_min_relevance: 0.0,
+            rag_max_candidates: crate::rag::DEFAULT_MAX_CANDIDATES,
+            auto_fence_code: false,
+            trim_response_blank_lines: true,
+            auto_save_conversations: true,
+            assistant_name: "Assistant".to_string(),
+            user_name: "You".to_string(),
+            frame_interval_ms: crate::ui::DEFAULT_FRAME_INTERVAL_MS,
+            allowed_roots: Vec::new(),
+            file_tool_enabled: false,
+            max_history_messages: None,
+            monochrome: false,
+            enable_mouse: true,
+            idle_timeout_minutes: None,
+            exit_on_idle: false,
+            stream_reconnect_attempts: 0,
+            server_retry_attempts: 2,
+            injection_patterns: crate::rag::DEFAULT_INJECTION_PATTERNS
+                .iter()
+                .map(|pattern| pattern.to_string())
+                .collect(),
+            show_banner: true,
+            context_file_template: crate::rag::DEFAULT_CONTEXT_FILE_TEMPLATE.to_string(),
+            rag_keyword_prompt: crate::rag::DEFAULT_KEYWORD_PROMPT.to_string(),
+            rag_selection_prompt: crate::rag::DEFAULT_SELECTION_PROMPT.to_string(),
+            rag_max_rounds: 1,
+            show_provisional_messages: true,
+            max_content_width: None,
+            source_patterns: Vec::new(),
+            provider_profiles: Vec::new(),
+            keybindings: KeyBindings::default(),
+            collapse_system_messages: false,
+            auto_scroll: true,
+            thinking_placeholder: "Thinking".to_string(),
+            code_line_numbers: false,
+            context_line_numbers: false,
+        }
+    }
+}
+
+// Handle for a file watch on the config path; dropping it stops watching
+pub struct ConfigWatcher {
+    _watcher: RecommendedWatcher,
+    events: Receiver<()>,
+}
+
+impl ConfigWatcher {
+    /// Returns true if the config file changed since the last call
+    pub fn has_changed(&self) -> bool {
+        let mut changed = false;
+        while self.events.try_recv().is_ok() {
+            changed = true;
+        }
+        changed
+    }
+}
+
+// Outcome of reloading the config file while running
+#[derive(Debug, Default)]
+pub struct ConfigReload {
+    /// Settings that changed on disk but only take effect after a restart
+    pub restart_required: Vec<&'static str>,
+}
+
+// On-disk syntax of the config file, chosen by its extension
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum ConfigFormat {
+    Toml,
+    Yaml,
+}
+
+// File names looked for in the config directory, in order of preference
+const CONFIG_FILE_NAMES: &[&str] = &["config.toml", "config.yaml", "config.yml"];
+
+impl ConfigFormat {
+    // `.yaml` and `.yml` select YAML; anything else is read as TOML
+    fn from_path(path: &Path) -> Self {
+        match path.extension().and_then(|extension| extension.to_str()) {
+            Some(extension)
+                if extension.eq_ignore_ascii_case("yaml") || extension.eq_ignore_ascii_case("yml") =>
+            {
+                ConfigFormat::Yaml
+            }
+            _ => ConfigFormat::Toml,
+        }
+    }
+
+    fn parse(self, content: &str) -> Result<AppConfig, String> {
+        match self {
+            ConfigFormat::Toml => toml::from_str(content).map_err(|e| e.to_string()),
+            ConfigFormat::Yaml => serde_yaml::from_str(content).map_err(|e| e.to_string()),
+        }
+    }
+
+    fn serialize(self, config: &AppConfig) -> Result<String, String> {
+        match self {
+            ConfigFormat::Toml => toml::to_string_pretty(config).map_err(|e| e.to_string()),
+            ConfigFormat::Yaml => serde_yaml::to_string(config).map_err(|e| e.to_string()),
         }
     }
 }
@@ -47,20 +244,134 @@ impl Default for AppConfig {
 pub struct ConfigManager {
     config_path: PathBuf,
     config: AppConfig,
+    system_prompt_file_content: Option<String>,
+    // The provider as written in the file, kept when it references environment
+    // variables so saving does not write the resolved secrets back
+    provider_template: Option<LlmProvider>,
 }
 
 impl ConfigManager {
     pub fn new() -> Result<Self, ConfigError> {
-        let config_path = Self::get_config_path();
+        Self::from_path(Self::get_config_path())
+    }
+
+    pub fn from_path(config_path: PathBuf) -> Result<Self, ConfigError> {
         let mut config = Self::load_config_from_file(&config_path)?;
+        let provider_template = config.llm_provider.clone();
         
         // Validate the loaded configuration
         Self::validate_config(&mut config)?;
+        let system_prompt_file_content = Self::read_system_prompt_file(&config)?;
+
+        let provider_template =
+            provider_template.filter(|raw| config.llm_provider.as_ref() != Some(raw));
 
         Ok(Self {
             config_path,
             config,
+            system_prompt_file_content,
+            provider_template,
+        })
+    }
+
+    /// Watches the config file for external edits. The parent directory is
+    /// watched because editors often replace the file rather than writing it.
+    pub fn watch(&self) -> Result<ConfigWatcher, ConfigError> {
+        let (sender, events) = channel();
+        let file_name = self.config_path.file_name().map(|name| name.to_os_string());
+
+        let mut watcher = notify::recommended_watcher(move |event: notify::Result<notify::Event>| {
+            if let Ok(event) = event {
+                let touches_config = event
+                    .paths
+                    .iter()
+                    .any(|path| path.file_name().map(|name| name.to_os_string()) == file_name);
+                if touches_config && (event.kind.is_create() || event.kind.is_modify()) {
+                    let _ = sender.send(());
+                }
+            }
         })
+        .map_err(|e| ConfigError::FileError(format!("Failed to create config watcher: {}", e)))?;
+
+        let watch_dir = self
+            .config_path
+            .parent()
+            .filter(|parent| !parent.as_os_str().is_empty())
+            .map(PathBuf::from)
+            .unwrap_or_else(|| PathBuf::from("."));
+        std::fs::create_dir_all(&watch_dir).map_err(|e| {
+            ConfigError::FileError(format!("Failed to create config directory: {}", e))
+        })?;
+        watcher
+            .watch(&watch_dir, RecursiveMode::NonRecursive)
+            .map_err(|e| ConfigError::FileError(format!("Failed to watch config file: {}", e)))?;
+
+        Ok(ConfigWatcher {
+            _watcher: watcher,
+            events,
+        })
+    }
+
+    /// Re-reads and re-validates the config file. On failure the previous
+    /// configuration is kept and the error is returned.
+    pub fn reload(&mut self) -> Result<ConfigReload, ConfigError> {
+        let mut config = Self::load_config_from_file(&self.config_path)?;
+        let provider_template = config.llm_provider.clone();
+        Self::validate_config(&mut config)?;
+        let system_prompt_file_content = Self::read_system_prompt_file(&config)?;
+
+        let mut reload = ConfigReload::default();
+        let old = &self.config;
+        if old.llm_provider != config.llm_provider {
+            reload.restart_required.push("llm_provider");
+        }
+        if old.conversation_storage_path != config.conversation_storage_path {
+            reload.restart_required.push("conversation_storage_path");
+        }
+        if old.response_cache_enabled != config.response_cache_enabled
+            || old.response_cache_path != config.response_cache_path
+        {
+            reload.restart_required.push("response_cache");
+        }
+        if old.debug_transcript_path != config.debug_transcript_path {
+            reload.restart_required.push("debug_transcript_path");
+        }
+        if old.debug_transcript_bodies != config.debug_transcript_bodies
+            || old.debug_transcript_max_kb != config.debug_transcript_max_kb
+        {
+            reload.restart_required.push("debug_transcript_limits");
+        }
+        if old.retain_last_response != config.retain_last_response {
+            reload.restart_required.push("retain_last_response");
+        }
+        if old.log_file != config.log_file {
+            reload.restart_required.push("log_file");
+        }
+        // The terminal and input loop read these once at startup
+        if old.enable_mouse != config.enable_mouse {
+            reload.restart_required.push("enable_mouse");
+        }
+        if old.frame_interval_ms != config.frame_interval_ms {
+            reload.restart_required.push("frame_interval_ms");
+        }
+        if old.keybindings != config.keybindings {
+            reload.restart_required.push("keybindings");
+        }
+        if old.idle_timeout_minutes != config.idle_timeout_minutes || old.exit_on_idle != config.exit_on_idle {
+            reload.restart_required.push("idle_timeout");
+        }
+        if old.thinking_placeholder != config.thinking_placeholder {
+            reload.restart_required.push("thinking_placeholder");
+        }
+        for setting in &reload.restart_required {
+            warn!("Config setting '{}' changed; restart to apply it", setting);
+        }
+
+        self.provider_template =
+            provider_template.filter(|raw| config.llm_provider.as_ref() != Some(raw));
+        self.config = config;
+        self.system_prompt_file_content = system_prompt_file_content;
+        Ok(reload)
     }
 
     pub fn load_config() -> Result<AppConfig, ConfigError> {
@@ -72,13 +383,20 @@ impl ConfigManager {
 
     fn get_config_path() -> PathBuf {
         // Try to use XDG config directory, fallback to current directory
-        if let Ok(config_dir) = std::env::var("XDG_CONFIG_HOME") {
-            PathBuf::from(config_dir).join("llm-tui-assistant").join("config.toml")
+        let config_dir = if let Ok(config_dir) = std::env::var("XDG_CONFIG_HOME") {
+            PathBuf::from(config_dir).join("llm-tui-assistant")
         } else if let Ok(home_dir) = std::env::var("HOME") {
-            PathBuf::from(home_dir).join(".config").join("llm-tui-assistant").join("config.toml")
+            PathBuf::from(home_dir).join(".config").join("llm-tui-assistant")
         } else {
-            PathBuf::from("config.toml")
-        }
+            PathBuf::new()
+        };
+
+        // Use whichever config file exists, creating a TOML one otherwise
+        CONFIG_FILE_NAMES
+            .iter()
+            .map(|name| config_dir.join(name))
+            .find(|path| path.exists())
+            .unwrap_or_else(|| config_dir.join(CONFIG_FILE_NAMES[0]))
     }
 
     fn load_config_from_file(path: &PathBuf) -> Result<AppConfig, ConfigError> {
@@ -90,7 +408,7 @@ impl ConfigManager {
             ConfigError::FileError(format!("Failed to read config file: {}", e))
         })?;
 
-        toml::from_str(&content).map_err(|e| {
+        ConfigFormat::from_path(path).parse(&content).map_err(|e| {
             ConfigError::Serialization(format!("Failed to parse config file: {}", e))
         })
     }
@@ -103,7 +421,18 @@ impl ConfigManager {
             })?;
         }
 
-        let content = toml::to_string_pretty(&self.config).map_err(|e| {
+        // Write `${VAR}` references back instead of the values they resolved to,
+        // unless the provider was changed since loading
+        let mut config = self.config.clone();
+        let provider = config.llm_provider.as_mut();
+        if let (Some(template), Some(provider)) = (&self.provider_template, provider) {
+            if Self::interpolate_provider(template.clone()).ok().as_ref() == Some(provider) {
+                *provider = template.clone();
+            }
+        }
+
+        let format = ConfigFormat::from_path(&self.config_path);
+        let content = format.serialize(&config).map_err(|e| {
             ConfigError::Serialization(format!("Failed to serialize config: {}", e))
         })?;
 
@@ -112,6 +441,15 @@ impl ConfigManager {
         })
     }
 
+    /// Pattern overrides configured for `source`, if any
+    pub fn source_patterns(&self, source: &Path) -> Option<&SourcePatternOverride> {
+        let source = canonical_path(source);
+        self.config
+            .source_patterns
+            .iter()
+            .find(|patterns| canonical_path(&patterns.path) == source)
+    }
+
     pub fn get_config(&self) -> &AppConfig {
         &self.config
     }
@@ -130,16 +468,50 @@ impl ConfigManager {
         self.save_config()
     }
 
+    /// Returns the system prompt to send, preferring the inline string over the file
+    pub fn effective_system_prompt(&self) -> Option<&str> {
+        self.config
+            .global_system_prompt
+            .as_deref()
+            .or(self.system_prompt_file_content.as_deref())
+    }
+
+    /// Re-reads `system_prompt_file` from disk, keeping the old content on failure
+    pub fn reload_system_prompt(&mut self) -> Result<(), ConfigError> {
+        self.system_prompt_file_content = Self::read_system_prompt_file(&self.config)?;
+        Ok(())
+    }
+
+    fn read_system_prompt_file(config: &AppConfig) -> Result<Option<String>, ConfigError> {
+        let Some(path) = &config.system_prompt_file else {
+            return Ok(None);
+        };
+
+        std::fs::read_to_string(path).map(Some).map_err(|e| {
+            ConfigError::FileError(format!("Failed to read system prompt file {:?}: {}", path, e))
+        })
+    }
+
+    /// Validates and saves a new provider, e.g. one entered in the setup wizard
+    pub fn set_llm_provider(&mut self, provider: LlmProvider) -> Result<(), ConfigError> {
+        Self::validate_llm_provider(&provider)?;
+        self.config.llm_provider = Some(provider);
+        self.provider_template = None;
+        self.save_config()
+    }
+
     pub fn add_data_source(&mut self, path: PathBuf) -> Result<(), ConfigError> {
-        if !self.config.data_sources.contains(&path) {
+        let path = canonical_path(&path);
+        if !self.config.data_sources.iter().any(|source| canonical_path(source) == path) {
             self.config.data_sources.push(path);
             self.save_config()?;
         }
         Ok(())
     }
 
-    pub fn remove_data_source(&mut self, path: &PathBuf) -> Result<(), ConfigError> {
-        self.config.data_sources.retain(|p| p != path);
+    pub fn remove_data_source(&mut self, path: &Path) -> Result<(), ConfigError> {
+        let path = canonical_path(path);
+        self.config.data_sources.retain(|p| canonical_path(p) != path);
         self.save_config()
     }
 
@@ -194,6 +566,36 @@ impl ConfigManager {
         self.save_config()
     }
 
+    /// Moves a pattern one place up or down its list; the ends stay put
+    pub fn move_pattern(&mut self, kind: PatternKind, pattern: &str, up: bool) -> Result<(), ConfigError> {
+        let patterns = match kind {
+            PatternKind::Include => &mut self.config.include_patterns,
+            PatternKind::Exclude => &mut self.config.exclude_patterns,
+        };
+        let Some(index) = patterns.iter().position(|p| p == pattern) else {
+            return Ok(());
+        };
+        let target = if up { index.checked_sub(1) } else { Some(index + 1) };
+        match target.filter(|target| *target < patterns.len()) {
+            Some(target) => {
+                patterns.swap(index, target);
+                self.save_config()
+            }
+            None => Ok(()),
+        }
+    }
+
+    /// Applies a change from the pattern editor with the pattern methods above
+    pub fn apply_pattern_edit(&mut self, edit: PatternEdit) -> Result<(), ConfigError> {
+        match edit {
+            PatternEdit::Add(PatternKind::Include, pattern) => self.add_include_pattern(pattern),
+            PatternEdit::Add(PatternKind::Exclude, pattern) => self.add_exclude_pattern(pattern),
+            PatternEdit::Remove(PatternKind::Include, pattern) => self.remove_include_pattern(&pattern),
+            PatternEdit::Remove(PatternKind::Exclude, pattern) => self.remove_exclude_pattern(&pattern),
+            PatternEdit::Move(kind, pattern, up) => self.move_pattern(kind, &pattern, up),
+        }
+    }
+
     /// Validates the configuration and applies fixes where possible
     fn validate_config(config: &mut AppConfig) -> Result<(), ConfigError> {
         // Validate regex patterns
@@ -209,6 +611,24 @@ impl ConfigManager {
             })?;
         }
 
+        for source in &config.source_patterns {
+            let patterns = source.include_patterns.iter().chain(&source.exclude_patterns).flatten();
+            for pattern in patterns {
+                Regex::new(pattern).map_err(|e| {
+                    ConfigError::Validation(format!(
+                        "Invalid pattern '{}' for source {:?}: {}",
+                        pattern, source.path, e
+                    ))
+                })?;
+            }
+        }
+
+        for pattern in &config.injection_patterns {
+            crate::rag::compile_injection_pattern(pattern).map_err(|e| {
+                ConfigError::Validation(format!("Invalid injection pattern '{}': {}", pattern, e))
+            })?;
+        }
+
         // Validate data sources exist and are accessible
         let mut valid_sources = Vec::new();
         for source in &config.data_sources {
@@ -218,9 +638,79 @@ impl ConfigManager {
         }
         config.data_sources = valid_sources;
 
-        // Validate LLM provider configuration if present
-        if let Some(ref provider) = config.llm_provider {
-            Self::validate_llm_provider(provider)?;
+        if !(0.0..=1.0).contains(&config.rag_min_relevance) {
+            return Err(ConfigError::Validation(
+                "rag_min_relevance must be between 0.0 and 1.0".to_string()
+            ));
+        }
+
+        if config.idle_timeout_minutes == Some(0) {
+            return Err(ConfigError::Validation(
+                "idle_timeout_minutes must be greater than 0".to_string()
+            ));
+        }
+
+        if config.rag_max_rounds == 0 {
+            return Err(ConfigError::Validation(
+                "rag_max_rounds must be at least 1".to_string()
+            ));
+        }
+
+        if config.frame_interval_ms == 0 {
+            return Err(ConfigError::Validation(
+                "frame_interval_ms must be greater than 0".to_string()
+            ));
+        }
+
+        // Ctrl+C and Ctrl+L are taken by exit and redraw
+        let bindings = &config.keybindings;
+        let keys = [
+            bindings.toggle_rag,
+            bindings.toggle_provisional,
+            bindings.toggle_scratch,
+            bindings.toggle_view,
+        ];
+        for key in keys {
+            if !key.is_ascii_lowercase() || key == 'c' || key == 'l' {
+                return Err(ConfigError::Validation(format!(
+                    "Keybinding '{}' must be a lowercase letter other than c and l",
+                    key
+                )));
+            }
+        }
+        if keys.iter().enumerate().any(|(i, key)| keys[..i].contains(key)) {
+            return Err(ConfigError::Validation(
+                "toggle_rag, toggle_provisional, toggle_scratch and toggle_view need different keys"
+                    .to_string()
+            ));
+        }
+
+        if config.max_content_width == Some(0) {
+            return Err(ConfigError::Validation(
+                "max_content_width must be greater than 0".to_string()
+            ));
+        }
+
+        // Validate the system prompt file exists so a typo fails loudly
+        if let Some(ref path) = config.system_prompt_file {
+            if !path.is_file() {
+                return Err(ConfigError::Validation(format!(
+                    "System prompt file not found: {:?}",
+                    path
+                )));
+            }
+        }
+
+        // Resolve environment references, then validate the LLM provider if present
+        if let Some(provider) = config.llm_provider.take() {
+            config.llm_provider = Some(Self::resolve_provider(provider)?);
+        }
+        // Profiles keep their references, so a save doesn't write secrets out;
+        // /compare resolves them again when it runs
+        for profile in &config.provider_profiles {
+            Self::resolve_provider(profile.provider.clone()).map_err(|e| {
+                ConfigError::Validation(format!("Provider profile '{}': {}", profile.name, e))
+            })?;
         }
 
         // Ensure conversation storage path is valid
@@ -231,20 +721,57 @@ impl ConfigManager {
         Ok(())
     }
 
-    fn validate_llm_provider(provider: &LlmProvider) -> Result<(), ConfigError> {
-        // Validate API key is not empty
-        if provider.api_key.trim().is_empty() {
+    /// Expands `${VAR}` references in a provider and validates the result
+    pub fn resolve_provider(provider: LlmProvider) -> Result<LlmProvider, ConfigError> {
+        let provider = Self::interpolate_provider(provider)?;
+        Self::validate_llm_provider(&provider)?;
+        Ok(provider)
+    }
+
+    // Expands `${VAR}` in the provider's string settings
+    fn interpolate_provider(mut provider: LlmProvider) -> Result<LlmProvider, ConfigError> {
+        provider.api_key = interpolate_env(&provider.api_key)?;
+        provider.model = interpolate_env(&provider.model)?;
+        provider.base_url = provider.base_url.as_deref().map(interpolate_env).transpose()?;
+        Ok(provider)
+    }
+
+    pub fn validate_temperature(temperature: f32) -> Result<(), ConfigError> {
+        if !(0.0..=2.0).contains(&temperature) {
+            return Err(ConfigError::Validation(
+                "LLM provider temperature must be between 0.0 and 2.0".to_string()
+            ));
+        }
+        Ok(())
+    }
+
+    pub fn validate_max_tokens(max_tokens: u32) -> Result<(), ConfigError> {
+        if max_tokens == 0 {
+            return Err(ConfigError::Validation(
+                "LLM provider max_tokens must be greater than 0".to_string()
+            ));
+        }
+        Ok(())
+    }
+
+    pub fn validate_llm_provider(provider: &LlmProvider) -> Result<(), ConfigError> {
+        // Validate API key is not empty (the offline echo provider needs none)
+        if !matches!(provider.provider_type, ProviderType::Echo) && provider.api_key.trim().is_empty() {
             return Err(ConfigError::Validation(
                 "LLM provider API key cannot be empty".to_string()
             ));
         }
 
-        // Validate model name is not empty
-        if provider.model.trim().is_empty() {
+        // An empty model falls back to the provider default; one that looks like it
+        // belongs to another provider is only a warning, since new names appear often
+        if provider.model.trim().is_empty() && default_model(&provider.provider_type).is_none() {
             return Err(ConfigError::Validation(
                 "LLM provider model name cannot be empty".to_string()
             ));
         }
+        if let Some(warning) = model_mismatch_warning(provider) {
+            warn!("{}", warning);
+        }
 
         // Validate base URL if provided
         if let Some(ref base_url) = provider.base_url {
@@ -255,20 +782,47 @@ impl ConfigManager {
             }
         }
 
-        // Validate temperature range
         if let Some(temp) = provider.temperature {
-            if temp < 0.0 || temp > 2.0 {
-                return Err(ConfigError::Validation(
-                    "LLM provider temperature must be between 0.0 and 2.0".to_string()
-                ));
-            }
+            Self::validate_temperature(temp)?;
         }
 
-        // Validate max_tokens
         if let Some(max_tokens) = provider.max_tokens {
-            if max_tokens == 0 {
+            Self::validate_max_tokens(max_tokens)?;
+        }
+
+        if provider.stop.iter().any(String::is_empty) {
+            return Err(ConfigError::Validation(
+                "LLM provider stop sequences cannot be empty".to_string()
+            ));
+        }
+        if let Some(limit) = max_stop_sequences(&provider.provider_type) {
+            if provider.stop.len() > limit {
+                return Err(ConfigError::Validation(format!(
+                    "LLM provider accepts at most {} stop sequences",
+                    limit
+                )));
+            }
+        }
+
+        // Reasoning settings are only sent to the provider they belong to
+        if let Some(effort) = &provider.reasoning_effort {
+            if !REASONING_EFFORT_LEVELS.contains(&effort.as_str()) {
+                return Err(ConfigError::Validation(format!(
+                    "LLM provider reasoning_effort must be one of: {}",
+                    REASONING_EFFORT_LEVELS.join(", ")
+                )));
+            }
+        }
+        if let Some(budget) = provider.thinking_budget_tokens {
+            if budget < ANTHROPIC_MIN_THINKING_BUDGET {
+                return Err(ConfigError::Validation(format!(
+                    "LLM provider thinking_budget_tokens must be at least {}",
+                    ANTHROPIC_MIN_THINKING_BUDGET
+                )));
+            }
+            if provider.max_tokens.is_some_and(|max_tokens| max_tokens <= budget) {
                 return Err(ConfigError::Validation(
-                    "LLM provider max_tokens must be greater than 0".to_string()
+                    "LLM provider max_tokens must be greater than thinking_budget_tokens".to_string()
                 ));
             }
         }
@@ -284,6 +838,8 @@ impl ConfigManager {
     /// Resets configuration to defaults
     pub fn reset_to_defaults(&mut self) -> Result<(), ConfigError> {
         self.config = AppConfig::default();
+        self.system_prompt_file_content = None;
+        self.provider_template = None;
         self.save_config()
     }
 
@@ -294,6 +850,28 @@ impl ConfigManager {
     }
 }
 
+/// Replaces each `${VAR}` in `value` with that environment variable, failing
+/// on variables that are unset or references that are not closed
+pub fn interpolate_env(value: &str) -> Result<String, ConfigError> {
+    let mut result = String::with_capacity(value.len());
+    let mut rest = value;
+    while let Some(start) = rest.find("${") {
+        result.push_str(&rest[..start]);
+        let after = &rest[start + 2..];
+        let end = after.find('}').ok_or_else(|| {
+            ConfigError::Validation(format!("Unclosed environment reference in '{}'", value))
+        })?;
+        let name = &after[..end];
+        let resolved = std::env::var(name).map_err(|_| {
+            ConfigError::Validation(format!("Environment variable '{}' is not set", name))
+        })?;
+        result.push_str(&resolved);
+        rest = &after[end + 1..];
+    }
+    result.push_str(rest);
+    Ok(result)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -309,6 +887,10 @@ mod tests {
                 base_url: None,
                 max_tokens: Some(4000),
                 temperature: Some(0.7),
+                stop: Vec::new(),
+                reasoning_effort: None,
+                thinking_budget_tokens: None,
+                echo_prefix: None,
             }),
             global_system_prompt: Some("You are a helpful assistant.".to_string()),
             rag_enabled_default: true,
@@ -317,6 +899,7 @@ mod tests {
             include_patterns: vec![r"\.txt$".to_string(), r"\.md$".to_string()],
             exclude_patterns: vec![r"\.git/".to_string()],
             conversation_storage_path: PathBuf::from("test_conversations"),
+            ..AppConfig::default()
         }
     }
 
@@ -328,6 +911,10 @@ mod tests {
             base_url: Some("invalid-url".to_string()), // Invalid: not http/https
             max_tokens: Some(0), // Invalid: zero tokens
             temperature: Some(3.0), // Invalid: out of range
+            stop: Vec::new(),
+            reasoning_effort: None,
+            thinking_budget_tokens: None,
+            echo_prefix: None,
         }
     }
 
@@ -343,6 +930,19 @@ mod tests {
         assert!(!config.include_patterns.is_empty());
         assert!(!config.exclude_patterns.is_empty());
         assert_eq!(config.conversation_storage_path, PathBuf::from("conversations"));
+        assert!(!config.response_cache_enabled);
+        assert!(config.response_cache_path.is_none());
+        assert!(config.debug_transcript_path.is_none());
+    }
+
+    #[test]
+    fn test_config_missing_fields_use_defaults() {
+        let config: AppConfig = toml::from_str("rag_enabled_default = true")
+            .expect("Failed to deserialize partial config");
+
+        assert!(config.rag_enabled_default);
+        assert!(!config.response_cache_enabled);
+        assert_eq!(config.conversation_storage_path, PathBuf::from("conversations"));
     }
 
     #[test]
@@ -364,7 +964,6 @@ mod tests {
     #[test]
     fn test_config_manager_new_with_nonexistent_file() {
         let temp_dir = TempDir::new().expect("Failed to create temp dir");
-        let config_path = temp_dir.path().join("nonexistent").join("config.toml");
         
         // Mock the config path by setting environment variable
         std::env::set_var("XDG_CONFIG_HOME", temp_dir.path());
@@ -434,11 +1033,35 @@ mod tests {
             base_url: Some("https://api.openai.com".to_string()),
             max_tokens: Some(4000),
             temperature: Some(0.7),
+            stop: Vec::new(),
+            reasoning_effort: None,
+            thinking_budget_tokens: None,
+            echo_prefix: None,
         };
         
         assert!(ConfigManager::validate_llm_provider(&provider).is_ok());
     }
 
+    #[test]
+    fn test_llm_provider_validation_reasoning_settings() {
+        let provider = LlmProvider {
+            reasoning_effort: Some("medium".to_string()),
+            thinking_budget_tokens: Some(2048),
+            echo_prefix: None,
+            ..create_test_config().llm_provider.unwrap()
+        };
+        assert!(ConfigManager::validate_llm_provider(&provider).is_ok());
+
+        let effort = LlmProvider { reasoning_effort: Some("extreme".to_string()), ..provider.clone() };
+        let err = ConfigManager::validate_llm_provider(&effort).unwrap_err();
+        assert!(err.to_string().contains("minimal, low, medium, high"));
+
+        let small = LlmProvider { thinking_budget_tokens: Some(512), ..provider.clone() };
+        assert!(ConfigManager::validate_llm_provider(&small).is_err());
+        let over = LlmProvider { thinking_budget_tokens: Some(4000), ..provider };
+        assert!(ConfigManager::validate_llm_provider(&over).is_err());
+    }
+
     #[test]
     fn test_llm_provider_validation_invalid_api_key() {
         let mut provider = create_invalid_llm_provider();
@@ -450,16 +1073,81 @@ mod tests {
     }
 
     #[test]
-    fn test_llm_provider_validation_invalid_model() {
-        let mut provider = create_invalid_llm_provider();
-        provider.api_key = "valid-key".to_string();
-        provider.model = "".to_string();
+    fn test_llm_provider_validation_echo_without_api_key() {
+        let provider = LlmProvider {
+            provider_type: ProviderType::Echo,
+            api_key: "".to_string(),
+            model: "echo".to_string(),
+            base_url: None,
+            max_tokens: None,
+            temperature: None,
+            stop: Vec::new(),
+            reasoning_effort: None,
+            thinking_budget_tokens: None,
+            echo_prefix: None,
+        };
+
+        assert!(ConfigManager::validate_llm_provider(&provider).is_ok());
+    }
+
+    #[test]
+    fn test_llm_provider_validation_empty_model() {
+        let mut provider = LlmProvider {
+            provider_type: ProviderType::OpenAi,
+            api_key: "valid-key".to_string(),
+            model: "".to_string(),
+            base_url: None,
+            max_tokens: None,
+            temperature: None,
+            stop: Vec::new(),
+            reasoning_effort: None,
+            thinking_budget_tokens: None,
+            echo_prefix: None,
+        };
         
+        // Hosted providers fall back to a default model
+        assert!(ConfigManager::validate_llm_provider(&provider).is_ok());
+        assert_eq!(crate::llm::resolved_model(&provider), "gpt-4o-mini");
+
+        provider.provider_type = ProviderType::Local;
         let result = ConfigManager::validate_llm_provider(&provider);
         assert!(result.is_err());
         assert!(result.unwrap_err().to_string().contains("model name cannot be empty"));
     }
 
+    #[test]
+    fn test_llm_provider_model_mismatch_warns_without_failing() {
+        let provider = LlmProvider {
+            provider_type: ProviderType::Anthropic,
+            api_key: "valid-key".to_string(),
+            model: "gpt-4".to_string(),
+            base_url: None,
+            max_tokens: None,
+            temperature: None,
+            stop: Vec::new(),
+            reasoning_effort: None,
+            thinking_budget_tokens: None,
+            echo_prefix: None,
+        };
+
+        assert!(ConfigManager::validate_llm_provider(&provider).is_ok());
+        let warning = model_mismatch_warning(&provider).expect("Expected a mismatch warning");
+        assert!(warning.contains("gpt-4"));
+        assert!(warning.contains("claude-"));
+
+        let matching = LlmProvider { model: "claude-3-opus-20240229".to_string(), ..provider.clone() };
+        assert!(model_mismatch_warning(&matching).is_none());
+
+        // OpenAI-compatible servers host arbitrary model names
+        let compatible = LlmProvider {
+            provider_type: ProviderType::OpenAi,
+            model: "llama3".to_string(),
+            base_url: Some("http://localhost:11434/v1".to_string()),
+            ..provider
+        };
+        assert!(model_mismatch_warning(&compatible).is_none());
+    }
+
     #[test]
     fn test_llm_provider_validation_invalid_base_url() {
         let mut provider = create_invalid_llm_provider();
@@ -472,6 +1160,23 @@ mod tests {
         assert!(result.unwrap_err().to_string().contains("must start with http"));
     }
 
+    #[test]
+    fn test_llm_provider_validation_stop_sequences() {
+        let mut provider = create_test_config().llm_provider.unwrap();
+        provider.provider_type = ProviderType::OpenAi;
+        provider.stop = vec!["END".to_string(), String::new()];
+        let result = ConfigManager::validate_llm_provider(&provider);
+        assert!(result.unwrap_err().to_string().contains("stop sequences cannot be empty"));
+
+        provider.stop = (1..=5).map(|n| format!("STOP{}", n)).collect();
+        let result = ConfigManager::validate_llm_provider(&provider);
+        assert!(result.unwrap_err().to_string().contains("at most 4 stop sequences"));
+
+        provider.provider_type = ProviderType::Anthropic;
+        provider.model = "claude-3-haiku".to_string();
+        assert!(ConfigManager::validate_llm_provider(&provider).is_ok());
+    }
+
     #[test]
     fn test_llm_provider_validation_invalid_temperature() {
         let mut provider = create_invalid_llm_provider();
@@ -486,6 +1191,18 @@ mod tests {
         assert!(result.unwrap_err().to_string().contains("temperature must be between"));
     }
 
+    #[test]
+    fn test_provider_profiles_are_validated() {
+        let mut config = create_test_config();
+        let mut provider = config.llm_provider.clone().unwrap();
+        provider.base_url = Some("${LLM_TUI_TEST_PROFILE_UNSET}".to_string());
+        config.provider_profiles = vec![ProviderProfile { name: "remote".to_string(), provider }];
+
+        let err = ConfigManager::validate_config(&mut config).unwrap_err().to_string();
+        assert!(err.contains("Provider profile 'remote'"));
+        assert!(err.contains("LLM_TUI_TEST_PROFILE_UNSET"));
+    }
+
     #[test]
     fn test_llm_provider_validation_invalid_max_tokens() {
         let mut provider = create_invalid_llm_provider();
@@ -548,6 +1265,22 @@ mod tests {
         std::env::remove_var("XDG_CONFIG_HOME");
     }
 
+    #[test]
+    fn test_data_source_spellings_dedup() {
+        let temp_dir = TempDir::new().expect("Failed to create temp dir");
+        let docs = temp_dir.path().join("docs");
+        fs::create_dir_all(&docs).unwrap();
+        let mut manager = ConfigManager::from_path(temp_dir.path().join("config.toml"))
+            .expect("Failed to create ConfigManager");
+
+        manager.add_data_source(docs.clone()).unwrap();
+        manager.add_data_source(temp_dir.path().join("docs/./")).unwrap();
+        assert_eq!(manager.get_config().data_sources.len(), 1);
+
+        manager.remove_data_source(&temp_dir.path().join("docs/../docs")).unwrap();
+        assert!(manager.get_config().data_sources.is_empty());
+    }
+
     #[test]
     fn test_pattern_management() {
         let temp_dir = TempDir::new().expect("Failed to create temp dir");
@@ -584,6 +1317,11 @@ mod tests {
         let result = ConfigManager::validate_config(&mut config);
         assert!(result.is_err());
         assert!(result.unwrap_err().to_string().contains("Invalid include pattern"));
+
+        let mut config = AppConfig::default();
+        config.injection_patterns.push("(unclosed".to_string());
+        let result = ConfigManager::validate_config(&mut config);
+        assert!(result.unwrap_err().to_string().contains("Invalid injection pattern"));
     }
 
     #[test]
@@ -633,6 +1371,127 @@ mod tests {
         std::env::remove_var("XDG_CONFIG_HOME");
     }
 
+    #[test]
+    fn test_system_prompt_file_is_effective_prompt() {
+        let temp_dir = TempDir::new().expect("Failed to create temp dir");
+        let prompt_path = temp_dir.path().join("prompt.txt");
+        fs::write(&prompt_path, "You are a pirate.").expect("Failed to write prompt file");
+
+        let config = AppConfig {
+            system_prompt_file: Some(prompt_path.clone()),
+            ..AppConfig::default()
+        };
+        let config_path = temp_dir.path().join("config.toml");
+        fs::write(&config_path, toml::to_string_pretty(&config).unwrap())
+            .expect("Failed to write config file");
+
+        let mut manager = ConfigManager::from_path(config_path).expect("Failed to create ConfigManager");
+        assert_eq!(manager.effective_system_prompt(), Some("You are a pirate."));
+
+        // Reloading picks up edits without restarting
+        fs::write(&prompt_path, "You are a poet.").expect("Failed to rewrite prompt file");
+        manager.reload_system_prompt().expect("Failed to reload prompt");
+        assert_eq!(manager.effective_system_prompt(), Some("You are a poet."));
+
+        // The inline prompt takes precedence over the file
+        manager.get_config_mut().global_system_prompt = Some("Inline".to_string());
+        assert_eq!(manager.effective_system_prompt(), Some("Inline"));
+    }
+
+    #[test]
+    fn test_missing_system_prompt_file_fails_validation() {
+        let mut config = AppConfig {
+            system_prompt_file: Some(PathBuf::from("/nonexistent/prompt.txt")),
+            ..AppConfig::default()
+        };
+
+        let result = ConfigManager::validate_config(&mut config);
+        assert!(result.is_err());
+        assert!(result.unwrap_err().to_string().contains("System prompt file not found"));
+    }
+
+    #[test]
+    fn test_reload_applies_changes_and_flags_restart() {
+        let temp_dir = TempDir::new().expect("Failed to create temp dir");
+        let config_path = temp_dir.path().join("config.toml");
+        let mut manager = ConfigManager::from_path(config_path.clone())
+            .expect("Failed to create ConfigManager");
+
+        let updated = AppConfig {
+            rag_enabled_default: true,
+            conversation_storage_path: PathBuf::from("elsewhere"),
+            ..AppConfig::default()
+        };
+        fs::write(&config_path, toml::to_string_pretty(&updated).unwrap())
+            .expect("Failed to write config file");
+
+        let reload = manager.reload().expect("Failed to reload config");
+        assert!(manager.get_config().rag_enabled_default);
+        assert_eq!(reload.restart_required, vec!["conversation_storage_path"]);
+
+        let terminal = AppConfig {
+            enable_mouse: !updated.enable_mouse,
+            frame_interval_ms: updated.frame_interval_ms + 10,
+            idle_timeout_minutes: Some(5),
+            ..updated
+        };
+        fs::write(&config_path, toml::to_string_pretty(&terminal).unwrap())
+            .expect("Failed to write config file");
+
+        let reload = manager.reload().expect("Failed to reload config");
+        assert_eq!(reload.restart_required, vec!["enable_mouse", "frame_interval_ms", "idle_timeout"]);
+    }
+
+    #[test]
+    fn test_reload_keeps_previous_config_on_invalid_file() {
+        let temp_dir = TempDir::new().expect("Failed to create temp dir");
+        let config_path = temp_dir.path().join("config.toml");
+        let mut manager = ConfigManager::from_path(config_path.clone())
+            .expect("Failed to create ConfigManager");
+
+        fs::write(&config_path, "include_patterns = [\"[invalid\"]")
+            .expect("Failed to write config file");
+
+        assert!(manager.reload().is_err());
+        assert_eq!(manager.get_config().include_patterns, AppConfig::default().include_patterns);
+    }
+
+    #[test]
+    fn test_transcript_limit_of_zero_is_unlimited() {
+        let temp_dir = TempDir::new().expect("Failed to create temp dir");
+        let config_path = temp_dir.path().join("config.toml");
+        fs::write(&config_path, "debug_transcript_max_kb = 0").expect("Failed to write config file");
+
+        let manager = ConfigManager::from_path(config_path).expect("Failed to create ConfigManager");
+        assert_eq!(manager.get_config().debug_transcript_max_kb, 0);
+        assert_eq!(AppConfig::default().debug_transcript_max_kb, DEFAULT_TRANSCRIPT_MAX_KB);
+    }
+
+    #[test]
+    fn test_watcher_detects_config_write() {
+        let temp_dir = TempDir::new().expect("Failed to create temp dir");
+        let config_path = temp_dir.path().join("config.toml");
+        let mut manager = ConfigManager::from_path(config_path.clone())
+            .expect("Failed to create ConfigManager");
+        let watcher = manager.watch().expect("Failed to watch config");
+
+        let updated = AppConfig {
+            global_system_prompt: Some("Reloaded".to_string()),
+            ..AppConfig::default()
+        };
+        fs::write(&config_path, toml::to_string_pretty(&updated).unwrap())
+            .expect("Failed to write config file");
+
+        let deadline = std::time::Instant::now() + std::time::Duration::from_secs(5);
+        while !watcher.has_changed() {
+            assert!(std::time::Instant::now() < deadline, "Config change was not detected");
+            std::thread::sleep(std::time::Duration::from_millis(20));
+        }
+
+        manager.reload().expect("Failed to reload config");
+        assert_eq!(manager.effective_system_prompt(), Some("Reloaded"));
+    }
+
     #[test]
     fn test_malformed_config_file() {
         let temp_dir = TempDir::new().expect("Failed to create temp dir");
@@ -652,4 +1511,102 @@ mod tests {
         
         std::env::remove_var("XDG_CONFIG_HOME");
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_load_yaml_config() {
+        let temp_dir = TempDir::new().expect("Failed to create temp dir");
+        let config_path = temp_dir.path().join("config.yaml");
+        fs::write(
+            &config_path,
+            "rag_enabled_default: true\nuser_name: Ada\nrag_max_candidates: 7\n",
+        )
+        .expect("Failed to write config file");
+
+        let manager = ConfigManager::from_path(config_path).expect("Failed to create ConfigManager");
+        let config = manager.get_config();
+        assert!(config.rag_enabled_default);
+        assert_eq!(config.user_name, "Ada");
+        assert_eq!(config.rag_max_candidates, 7);
+        // Unset keys keep their defaults
+        assert_eq!(config.assistant_name, "Assistant");
+    }
+
+    #[test]
+    fn test_yaml_config_round_trips_in_yaml() {
+        let temp_dir = TempDir::new().expect("Failed to create temp dir");
+        let config_path = temp_dir.path().join("config.yml");
+        let mut manager = ConfigManager::from_path(config_path.clone())
+            .expect("Failed to create ConfigManager");
+        manager.get_config_mut().user_name = "Grace".to_string();
+        manager.get_config_mut().max_history_messages = Some(40);
+        manager.save_config().expect("Failed to save config");
+
+        let content = fs::read_to_string(&config_path).expect("Failed to read config file");
+        assert!(content.contains("user_name: Grace"));
+        assert!(toml::from_str::<AppConfig>(&content).is_err());
+
+        let reloaded = ConfigManager::from_path(config_path).expect("Failed to reload config");
+        assert_eq!(reloaded.get_config().user_name, "Grace");
+        assert_eq!(reloaded.get_config().max_history_messages, Some(40));
+    }
+
+    #[test]
+    fn test_malformed_yaml_config_is_serialization_error() {
+        let temp_dir = TempDir::new().expect("Failed to create temp dir");
+        let config_path = temp_dir.path().join("config.yaml");
+        fs::write(&config_path, "user_name: [unclosed").expect("Failed to write invalid config");
+
+        let result = ConfigManager::from_path(config_path);
+        assert!(matches!(result, Err(ConfigError::Serialization(_))));
+    }
+
+    #[test]
+    fn test_interpolate_env_resolves_variables() {
+        std::env::set_var("LLM_TUI_TEST_INTERP_KEY", "sk-secret");
+        std::env::set_var("LLM_TUI_TEST_INTERP_HOST", "example.com");
+
+        assert_eq!(interpolate_env("${LLM_TUI_TEST_INTERP_KEY}").unwrap(), "sk-secret");
+        assert_eq!(
+            interpolate_env("https://${LLM_TUI_TEST_INTERP_HOST}/v1").unwrap(),
+            "https://example.com/v1"
+        );
+        assert_eq!(interpolate_env("no references").unwrap(), "no references");
+
+        std::env::remove_var("LLM_TUI_TEST_INTERP_KEY");
+        std::env::remove_var("LLM_TUI_TEST_INTERP_HOST");
+    }
+
+    #[test]
+    fn test_interpolate_env_names_missing_variable() {
+        let err = interpolate_env("${LLM_TUI_TEST_INTERP_UNSET}").unwrap_err();
+        assert!(matches!(err, ConfigError::Validation(_)));
+        assert!(err.to_string().contains("LLM_TUI_TEST_INTERP_UNSET"));
+
+        assert!(interpolate_env("${LLM_TUI_TEST_INTERP_UNSET").is_err());
+    }
+
+    #[test]
+    fn test_env_api_key_is_resolved_on_load_and_kept_on_save() {
+        std::env::set_var("LLM_TUI_TEST_LOAD_KEY", "sk-from-env");
+        let temp_dir = TempDir::new().expect("Failed to create temp dir");
+        let config_path = temp_dir.path().join("config.toml");
+        let mut config = create_test_config();
+        config.llm_provider.as_mut().unwrap().api_key = "${LLM_TUI_TEST_LOAD_KEY}".to_string();
+        fs::write(&config_path, toml::to_string_pretty(&config).unwrap())
+            .expect("Failed to write config file");
+
+        let mut manager = ConfigManager::from_path(config_path.clone())
+            .expect("Failed to create ConfigManager");
+        assert_eq!(manager.get_config().llm_provider.as_ref().unwrap().api_key, "sk-from-env");
+
+        manager.get_config_mut().user_name = "Ada".to_string();
+        manager.save_config().expect("Failed to save config");
+        let content = fs::read_to_string(&config_path).expect("Failed to read config file");
+        assert!(content.contains("${LLM_TUI_TEST_LOAD_KEY}"));
+        assert!(!content.contains("sk-from-env"));
+
+        std::env::remove_var("LLM_TUI_TEST_LOAD_KEY");
+        let err = ConfigManager::from_path(config_path).map(|_| ()).unwrap_err();
+        assert!(err.to_string().contains("LLM_TUI_TEST_LOAD_KEY"));
+    }
+}
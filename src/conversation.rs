@@ -1,16 +1,33 @@
 use crate::types::*;
-use crate::llm::LlmClient;
+use crate::llm::{LlmClient, LlmReply, ToolSpec};
+use crate::rag::{format_context, DEFAULT_CONTEXT_FILE_TEMPLATE};
 use chrono::{DateTime, Utc};
-use std::path::PathBuf;
+use futures::StreamExt;
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use tracing::warn;
 use uuid::Uuid;
 
 // Conversation structure to hold message history and metadata
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Conversation {
     pub id: String,
     pub messages: Vec<Message>,
     pub created_at: DateTime<Utc>,
     pub provisional_mode: bool,
+    // Where the reader left off, restored when the conversation is loaded again
+    #[serde(default)]
+    pub scroll_position: usize,
+    // Freeform labels for finding the conversation with /conversations <tag>
+    #[serde(default)]
+    pub tags: Vec<String>,
+}
+
+impl Default for Conversation {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Conversation {
@@ -20,6 +37,81 @@ impl Conversation {
             messages: Vec::new(),
             created_at: Utc::now(),
             provisional_mode: false,
+            scroll_position: 0,
+            tags: Vec::new(),
+        }
+    }
+}
+
+// Upper bound on tool calls answered within a single turn
+const MAX_TOOL_ROUNDS: usize = 5;
+
+// Wait before the first reconnection of a dropped stream
+pub const DEFAULT_RECONNECT_DELAY: Duration = Duration::from_millis(500);
+
+// How soon a repeat of the previous user message counts as an accidental resend
+pub const DUPLICATE_MESSAGE_WINDOW: Duration = Duration::from_secs(10);
+
+// Longest first-message preview shown when listing conversations
+const PREVIEW_CHARS: usize = 40;
+const UNREADABLE_PREVIEW: &str = "(unreadable; load it to move it aside)";
+
+// Longest title slug used in a conversation file name
+const MAX_SLUG_CHARS: usize = 50;
+
+// How stored conversation files are named; the id inside the file is what
+// identifies a conversation either way
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub enum ConversationNaming {
+    // `<id>.json`
+    #[default]
+    Uuid,
+    // `<date>_<slug of the first user message>.json`, with a counter on collisions
+    Titled,
+}
+
+// Listing entry for a stored conversation
+#[derive(Debug, Clone, PartialEq)]
+pub struct ConversationSummary {
+    pub id: String,
+    pub preview: String,
+    pub created_at: DateTime<Utc>,
+    pub message_count: usize,
+    pub tags: Vec<String>,
+}
+
+impl ConversationSummary {
+    fn from_conversation(conversation: &Conversation) -> Self {
+        let first_line = conversation
+            .messages
+            .iter()
+            .find(|message| matches!(message.role, MessageRole::User))
+            .and_then(|message| message.content.lines().next())
+            .unwrap_or("(empty)");
+        let mut preview: String = first_line.chars().take(PREVIEW_CHARS).collect();
+        if first_line.chars().count() > PREVIEW_CHARS {
+            preview.push_str("...");
+        }
+
+        Self {
+            id: conversation.id.clone(),
+            preview,
+            created_at: conversation.created_at,
+            message_count: conversation.messages.len(),
+            tags: conversation.tags.clone(),
+        }
+    }
+
+    // Entry for a stored file that doesn't parse, so the user can pick it and
+    // have it moved aside; `id` is the file name, which `load_conversation` accepts
+    fn unreadable(path: &Path) -> Self {
+        let modified = std::fs::metadata(path).and_then(|metadata| metadata.modified());
+        Self {
+            id: path.file_stem().unwrap_or_default().to_string_lossy().into_owned(),
+            preview: UNREADABLE_PREVIEW.to_string(),
+            created_at: modified.map_or(DateTime::UNIX_EPOCH, DateTime::from),
+            message_count: 0,
+            tags: Vec::new(),
         }
     }
 }
@@ -28,6 +120,30 @@ impl Conversation {
 pub struct ConversationManager {
     current_conversation: Conversation,
     storage_path: PathBuf,
+    system_prompt: Option<String>,
+    auto_fence_code: bool,
+    trim_blank_lines: bool,
+    auto_save: bool,
+    max_history_messages: Option<usize>,
+    prefill: Option<String>,
+    stream_reconnect_attempts: u32,
+    reconnect_base_delay: Duration,
+    context_template: String,
+    // Number the lines of retrieved files so replies can cite them
+    context_line_numbers: bool,
+    naming: ConversationNaming,
+    // Id and file of the last conversation saved or loaded, so a titled
+    // conversation keeps its file name across saves
+    saved_file: Option<(String, PathBuf)>,
+    // Provisional turns of this session, shown but never stored or sent again
+    provisional_messages: Vec<Message>,
+    // Messages dropped by the history cap since the conversation was opened
+    omitted_messages: usize,
+    // Images attached to the next user message
+    pending_images: Vec<ImageAttachment>,
+    // The main conversation, with its provisional turns and omitted count,
+    // set aside while a scratch conversation is active
+    parked: Option<(Conversation, Vec<Message>, usize)>,
 }
 
 impl ConversationManager {
@@ -35,38 +151,631 @@ impl ConversationManager {
         Ok(Self {
             current_conversation: Conversation::new(),
             storage_path: PathBuf::from("conversations"),
+            system_prompt: None,
+            auto_fence_code: false,
+            trim_blank_lines: true,
+            auto_save: false,
+            max_history_messages: None,
+            prefill: None,
+            stream_reconnect_attempts: 0,
+            reconnect_base_delay: DEFAULT_RECONNECT_DELAY,
+            context_template: DEFAULT_CONTEXT_FILE_TEMPLATE.to_string(),
+            context_line_numbers: false,
+            naming: ConversationNaming::Uuid,
+            saved_file: None,
+            provisional_messages: Vec::new(),
+            omitted_messages: 0,
+            pending_images: Vec::new(),
+            parked: None,
         })
     }
 
+    pub fn set_system_prompt(&mut self, prompt: Option<String>) {
+        self.system_prompt = prompt;
+    }
+
+    pub fn set_storage_path(&mut self, storage_path: PathBuf) {
+        self.storage_path = storage_path;
+    }
+
+    /// Saves the conversation after every stored turn so a crash loses at most
+    /// the turn in flight
+    pub fn set_auto_save(&mut self, enabled: bool) {
+        self.auto_save = enabled;
+    }
+
+    /// Sets how newly stored conversations are named; files already written
+    /// keep their names
+    pub fn set_naming(&mut self, naming: ConversationNaming) {
+        self.naming = naming;
+    }
+
+    /// Sets the layout of each retrieved file in the prompt, with `{path}`
+    /// and `{content}` placeholders
+    pub fn set_context_template(&mut self, template: String) {
+        self.context_template = template;
+    }
+
+    pub fn set_context_line_numbers(&mut self, enabled: bool) {
+        self.context_line_numbers = enabled;
+    }
+
+    /// Caps how many messages the active conversation retains; `None` keeps everything
+    pub fn set_max_history_messages(&mut self, max: Option<usize>) {
+        self.max_history_messages = max;
+    }
+
+    /// Makes the next reply start with `prefill`, which the model continues from.
+    /// Applies to one send only; the stored reply includes the prefill.
+    pub fn set_prefill(&mut self, prefill: Option<String>) {
+        self.prefill = prefill;
+    }
+
+    /// Reconnects a dropped stream up to `attempts` times, waiting `base_delay`
+    /// before the first retry and doubling it for each one after
+    pub fn set_stream_reconnect(&mut self, attempts: u32, base_delay: Duration) {
+        self.stream_reconnect_attempts = attempts;
+        self.reconnect_base_delay = base_delay;
+    }
+
+    /// Wraps messages that look like pasted code in a fenced block before sending
+    pub fn set_auto_fence_code(&mut self, enabled: bool) {
+        self.auto_fence_code = enabled;
+    }
+
+    /// Drops blank lines before and after each reply, which some models add
+    pub fn set_trim_blank_lines(&mut self, enabled: bool) {
+        self.trim_blank_lines = enabled;
+    }
+
     pub async fn send_message(
         &mut self,
         content: String,
         provisional: bool,
-        _llm_client: &dyn LlmClient,
+        llm_client: &dyn LlmClient,
     ) -> Result<String, ConversationError> {
+        self.send_message_with_context(content, provisional, None, llm_client)
+            .await
+    }
+
+    /// Sends a message with retrieved file contents attached for this turn only.
+    /// The files are recorded on the stored message; their contents are not.
+    pub async fn send_message_with_context(
+        &mut self,
+        content: String,
+        provisional: bool,
+        context: Option<&RagContext>,
+        llm_client: &dyn LlmClient,
+    ) -> Result<String, ConversationError> {
+        let (message, mut request) = self.prepare_turn(content, provisional, context);
+        let prefill = self.prefill.take().unwrap_or_default();
+        if !prefill.is_empty() {
+            request.push(partial_reply(prefill.clone()));
+        }
+        let response = prefill + &llm_client.send_message(&request).await?;
+        Ok(self.record_turn(message, Vec::new(), response, provisional))
+    }
+
+    /// Sends a message offering `tools` to the model. Each tool call is run
+    /// through `run_tool` and its output fed back until the model answers;
+    /// the calls and results are stored with the turn.
+    pub async fn send_message_with_tools<F>(
+        &mut self,
+        content: String,
+        provisional: bool,
+        context: Option<&RagContext>,
+        llm_client: &dyn LlmClient,
+        tools: &[ToolSpec],
+        mut run_tool: F,
+    ) -> Result<String, ConversationError>
+    where
+        F: FnMut(&ToolCall) -> String + Send,
+    {
+        let (message, mut request) = self.prepare_turn(content, provisional, context);
+        if self.prefill.take().is_some() {
+            // Tool rounds append to the request, so it can't end in a partial reply
+            warn!("Prefill is not supported with tools enabled; ignoring it");
+        }
+        let mut exchange = Vec::new();
+        for _ in 0..MAX_TOOL_ROUNDS {
+            let call = match llm_client.send_with_tools(&request, tools).await? {
+                LlmReply::Text(response) => {
+                    return Ok(self.record_turn(message, exchange, response, provisional));
+                }
+                LlmReply::ToolCall(call) => call,
+            };
+
+            // The call is recorded as an assistant message, its output as a tool message
+            let output = run_tool(&call);
+            let steps = [(MessageRole::Assistant, String::new()), (MessageRole::Tool, output)];
+            for (role, content) in steps {
+                let message = Message {
+                    role,
+                    content,
+                    timestamp: Utc::now(),
+                    provisional,
+                    context_files: Vec::new(),
+                    tool_call: Some(call.clone()),
+                    images: Vec::new(),
+                };
+                request.push(message.clone());
+                exchange.push(message);
+            }
+        }
+
+        Err(LlmError::Api(format!(
+            "Model kept calling tools after {} rounds",
+            MAX_TOOL_ROUNDS
+        ))
+        .into())
+    }
+
+    /// Streaming variant of `send_message_with_context`: each token is passed to
+    /// `on_token` as it arrives, and the turn is stored once the stream completes.
+    /// A stream that drops midway is reconnected up to the configured number of
+    /// attempts; a stream that still fails stores nothing.
+    pub async fn stream_message_with_context<F>(
+        &mut self,
+        content: String,
+        provisional: bool,
+        context: Option<&RagContext>,
+        llm_client: &dyn LlmClient,
+        mut on_token: F,
+    ) -> Result<String, ConversationError>
+    where
+        F: FnMut(&str) + Send,
+    {
+        let (message, request) = self.prepare_turn(content, provisional, context);
+        let prefill = self.prefill.take().unwrap_or_default();
+        if !prefill.is_empty() {
+            on_token(&prefill);
+        }
+        // The streamed text after the prefill
+        let mut response = String::new();
+        // Bytes of `response` already passed to `on_token`
+        let mut shown = 0;
+        let mut attempt = 0;
+        loop {
+            // Providers that can continue a partial answer get it back as a
+            // trailing assistant message; the rest restart the turn, and the
+            // retried text is held back until it passes what was already shown
+            let mut outgoing = request.clone();
+            if llm_client.supports_continuation() && !response.trim().is_empty() {
+                response.truncate(response.trim_end().len());
+            } else {
+                response.clear();
+            }
+            let partial = format!("{}{}", prefill, response);
+            if !partial.is_empty() {
+                outgoing.push(partial_reply(partial));
+            }
+
+            let error = match llm_client.stream_message(&outgoing).await {
+                Ok(mut stream) => {
+                    let mut error = None;
+                    while let Some(token) = stream.next().await {
+                        match token {
+                            Ok(token) => {
+                                response.push_str(&token);
+                                if response.len() > shown && response.is_char_boundary(shown) {
+                                    on_token(&response[shown..]);
+                                    shown = response.len();
+                                }
+                            }
+                            Err(e) => {
+                                error = Some(e);
+                                break;
+                            }
+                        }
+                    }
+                    match error {
+                        Some(e) => e,
+                        None => break,
+                    }
+                }
+                Err(e) => e,
+            };
+
+            let dropped = matches!(error, LlmError::StreamInterrupted | LlmError::Network(_));
+            if !dropped || attempt >= self.stream_reconnect_attempts {
+                return Err(error.into());
+            }
+            let delay = self.reconnect_base_delay * 2u32.saturating_pow(attempt);
+            attempt += 1;
+            warn!("Stream dropped ({}), reconnecting in {:?}", error, delay);
+            tokio::time::sleep(delay).await;
+        }
+        let response = prefill + &response;
+        Ok(self.record_turn(message, Vec::new(), response, provisional))
+    }
+
+    /// Asks for the last stored reply again and replaces it with the new one.
+    /// With an instruction the model also sees its previous reply followed by
+    /// the instruction, which is sent once and not stored. Retrieved file
+    /// contents from the original turn are not sent again.
+    pub async fn redo_last_turn(
+        &mut self,
+        instruction: Option<String>,
+        llm_client: &dyn LlmClient,
+    ) -> Result<String, ConversationError> {
+        let messages = &self.current_conversation.messages;
+        let last = match messages.last() {
+            Some(message) if matches!(message.role, MessageRole::Assistant) && message.tool_call.is_none() => {
+                messages.len() - 1
+            }
+            _ => return Err(ConversationError::History("No reply to redo".to_string())),
+        };
+
+        let mut request: Vec<Message> = self.system_message().into_iter().collect();
+        match instruction {
+            Some(instruction) => {
+                request.extend(messages.iter().cloned());
+                request.push(Message {
+                    role: MessageRole::User,
+                    content: instruction,
+                    timestamp: Utc::now(),
+                    provisional: true,
+                    context_files: Vec::new(),
+                    tool_call: None,
+                    images: Vec::new(),
+                });
+            }
+            None => request.extend(messages[..last].iter().cloned()),
+        }
+        let response = self.post_process(llm_client.send_message(&request).await?);
+        self.current_conversation.messages[last] = assistant_reply(response.clone());
+
+        if self.auto_save {
+            if let Err(e) = self.save_conversation() {
+                warn!("Auto-save failed: {}", e);
+            }
+        }
+        Ok(response)
+    }
+
+    /// The exact messages a send of `content` would carry, without sending or storing anything
+    pub fn assemble_prompt(&self, content: String, context: Option<&RagContext>) -> Vec<Message> {
+        self.prepare_turn(content, self.is_provisional_mode(), context).1
+    }
+
+    // Builds the user message and the full request: system prompt, history,
+    // retrieved context, then the message itself
+    fn prepare_turn(
+        &self,
+        content: String,
+        provisional: bool,
+        context: Option<&RagContext>,
+    ) -> (Message, Vec<Message>) {
+        let content = if self.auto_fence_code && looks_like_code(&content) {
+            format!("```\n{}\n```", content.trim_end())
+        } else {
+            content
+        };
         let message = Message {
             role: MessageRole::User,
-            content: content.clone(),
+            content,
             timestamp: Utc::now(),
             provisional,
+            context_files: context
+                .map(|context| context.selected_files.clone())
+                .unwrap_or_default(),
+            tool_call: None,
+            images: self.pending_images.clone(),
+        };
+
+        // Provisional messages are sent along with the history but never stored
+        let mut request: Vec<Message> = self.system_message().into_iter().collect();
+        request.extend(self.current_conversation.messages.iter().cloned());
+        let block = context.and_then(|context| {
+            format_context(context, &self.context_template, self.context_line_numbers)
+        });
+        if let Some(block) = block {
+            request.push(Message {
+                role: MessageRole::System,
+                content: block,
+                timestamp: Utc::now(),
+                provisional: true,
+                context_files: Vec::new(),
+                tool_call: None,
+                images: Vec::new(),
+            });
+        }
+        request.push(message.clone());
+        (message, request)
+    }
+
+    fn system_message(&self) -> Option<Message> {
+        self.system_prompt.as_ref().map(|prompt| Message {
+            role: MessageRole::System,
+            content: prompt.clone(),
+            timestamp: Utc::now(),
+            provisional: false,
             context_files: Vec::new(),
+            tool_call: None,
+            images: Vec::new(),
+        })
+    }
+
+    // Cleans up a reply before it is stored and shown
+    fn post_process(&self, response: String) -> String {
+        if self.trim_blank_lines {
+            trim_blank_lines(&response).to_string()
+        } else {
+            response
+        }
+    }
+
+    // Stores the user message, any tool exchange, and the final response,
+    // returning the response as stored
+    fn record_turn(
+        &mut self,
+        message: Message,
+        exchange: Vec<Message>,
+        response: String,
+        provisional: bool,
+    ) -> String {
+        let response = self.post_process(response);
+        self.pending_images.clear();
+        if provisional {
+            self.provisional_messages.push(message);
+            self.provisional_messages.push(Message {
+                provisional: true,
+                ..assistant_reply(response.clone())
+            });
+            return response;
+        }
+
+        self.current_conversation.messages.push(message);
+        self.current_conversation.messages.extend(exchange);
+        self.current_conversation.messages.push(assistant_reply(response.clone()));
+
+        self.trim_history();
+
+        // The response is already in hand; a failed save shouldn't discard it
+        if self.auto_save {
+            if let Err(e) = self.save_conversation() {
+                warn!("Auto-save failed: {}", e);
+            }
+        }
+        response
+    }
+
+    // Drops the oldest non-system messages beyond the cap. A tool result left
+    // at the front without the call it answers is dropped too, since providers
+    // reject orphaned tool messages.
+    fn trim_history(&mut self) {
+        let Some(max) = self.max_history_messages else {
+            return;
         };
 
-        if !provisional {
-            self.current_conversation.messages.push(message);
+        let messages = &mut self.current_conversation.messages;
+        while let Some(oldest) = messages
+            .iter()
+            .position(|message| !matches!(message.role, MessageRole::System))
+        {
+            let over_cap = messages.len() > max;
+            let orphaned = matches!(messages[oldest].role, MessageRole::Tool);
+            if !over_cap && !orphaned {
+                break;
+            }
+            messages.remove(oldest);
+            self.omitted_messages += 1;
         }
+    }
+
+    /// Writes the conversation to storage. A scratch conversation is never
+    /// written, so this does nothing while one is active.
+    pub fn save_conversation(&mut self) -> Result<(), ConversationError> {
+        if self.is_scratch() {
+            return Ok(());
+        }
+        std::fs::create_dir_all(&self.storage_path).map_err(|e| {
+            ConversationError::Storage(format!("Failed to create storage directory: {}", e))
+        })?;
 
-        // TODO: Send to LLM and get response
-        Ok(format!("Echo: {}", content))
+        let content = serde_json::to_string_pretty(&self.current_conversation).map_err(|e| {
+            ConversationError::Storage(format!("Failed to serialize conversation: {}", e))
+        })?;
+
+        let id = &self.current_conversation.id;
+        let path = match (&self.saved_file, self.naming) {
+            (Some((saved_id, path)), _) if saved_id == id => path.clone(),
+            (_, ConversationNaming::Uuid) => self.storage_path.join(format!("{}.json", id)),
+            (_, ConversationNaming::Titled) => {
+                unique_path(&self.storage_path, &titled_file_stem(&self.current_conversation))
+            }
+        };
+        write_atomic(&path, &content).map_err(|e| {
+            ConversationError::Storage(format!("Failed to write conversation {:?}: {}", path, e))
+        })?;
+        self.saved_file = Some((id.clone(), path));
+        Ok(())
     }
 
-    pub fn save_conversation(&self) -> Result<(), ConversationError> {
-        // TODO: Implement conversation persistence
+    /// Writes the main conversation, the parked one while scratch is active.
+    /// Returns whether anything was written; an empty conversation is not.
+    pub fn save_main_conversation(&mut self) -> Result<bool, ConversationError> {
+        let Some((mut main, provisional, omitted)) = self.parked.take() else {
+            if self.current_conversation.messages.is_empty() {
+                return Ok(false);
+            }
+            return self.save_conversation().map(|()| true);
+        };
+        std::mem::swap(&mut main, &mut self.current_conversation);
+        let result = if self.current_conversation.messages.is_empty() {
+            Ok(false)
+        } else {
+            self.save_conversation().map(|()| true)
+        };
+        std::mem::swap(&mut main, &mut self.current_conversation);
+        self.parked = Some((main, provisional, omitted));
+        result
+    }
+
+    /// Summaries of the stored conversations, newest first; with a tag only
+    /// the conversations carrying it. Files that don't parse are listed as
+    /// unreadable, without a tag filter, and left in place.
+    pub fn list_conversations(&self, tag: Option<&str>) -> Result<Vec<ConversationSummary>, ConversationError> {
+        if !self.storage_path.exists() {
+            return Ok(Vec::new());
+        }
+
+        let entries = std::fs::read_dir(&self.storage_path).map_err(|e| {
+            ConversationError::Storage(format!("Failed to read storage directory: {}", e))
+        })?;
+
+        let mut summaries = Vec::new();
+        for entry in entries.flatten() {
+            let path = entry.path();
+            if path.extension().is_none_or(|ext| ext != "json") {
+                continue;
+            }
+            let content = match read_conversation_file(&path) {
+                Ok(content) => content,
+                Err(e) => {
+                    warn!("Skipping stored conversation {:?}: {}", path, e);
+                    continue;
+                }
+            };
+            match serde_json::from_str::<Conversation>(&content) {
+                Ok(conversation) if tag.is_some_and(|tag| !conversation.tags.iter().any(|t| t == tag)) => {}
+                Ok(conversation) => summaries.push(ConversationSummary::from_conversation(&conversation)),
+                Err(_) if tag.is_some() => {}
+                Err(e) => {
+                    warn!("Stored conversation {:?} is unreadable: {}", path, e);
+                    summaries.push(ConversationSummary::unreadable(&path));
+                }
+            }
+        }
+        summaries.sort_by_key(|summary| std::cmp::Reverse(summary.created_at));
+        Ok(summaries)
+    }
+
+    /// Replaces the current conversation with a stored one, leaving any
+    /// scratch conversation; with auto-save on the main conversation is saved first
+    pub fn load_conversation(&mut self, id: &str) -> Result<(), ConversationError> {
+        let path = self.find_conversation_file(id).ok_or_else(|| {
+            ConversationError::Storage(format!("No stored conversation with id {}", id))
+        })?;
+        let conversation = open_conversation(&path)?;
+        self.leave_scratch()?;
+        self.current_conversation = conversation;
+        self.saved_file = Some((id.to_string(), path));
+        self.provisional_messages.clear();
+        self.omitted_messages = 0;
         Ok(())
     }
 
+    // Stored file holding the conversation `id`, whichever scheme named it
+    fn find_conversation_file(&self, id: &str) -> Option<PathBuf> {
+        let by_id = self.storage_path.join(format!("{}.json", id));
+        if by_id.exists() {
+            return Some(by_id);
+        }
+        std::fs::read_dir(&self.storage_path)
+            .ok()?
+            .flatten()
+            .map(|entry| entry.path())
+            .filter(|path| path.extension().is_some_and(|ext| ext == "json"))
+            .find(|path| read_conversation(path).is_ok_and(|conversation| conversation.id == id))
+    }
+
+    pub fn set_scroll_position(&mut self, position: usize) {
+        self.current_conversation.scroll_position = position;
+    }
+
+    pub fn scroll_position(&self) -> usize {
+        self.current_conversation.scroll_position
+    }
+
+    pub fn current_conversation_id(&self) -> &str {
+        &self.current_conversation.id
+    }
+
+    pub fn tags(&self) -> &[String] {
+        &self.current_conversation.tags
+    }
+
+    /// Tags the current conversation; false when the tag is empty or present
+    pub fn add_tag(&mut self, tag: &str) -> bool {
+        let tag = tag.trim();
+        if tag.is_empty() || self.current_conversation.tags.iter().any(|t| t == tag) {
+            return false;
+        }
+        self.current_conversation.tags.push(tag.to_string());
+        true
+    }
+
+    /// Removes a tag from the current conversation; false when it wasn't there
+    pub fn remove_tag(&mut self, tag: &str) -> bool {
+        let before = self.current_conversation.tags.len();
+        self.current_conversation.tags.retain(|t| t != tag.trim());
+        self.current_conversation.tags.len() != before
+    }
+
     pub fn clear_conversation(&mut self) {
         self.current_conversation = Conversation::new();
+        self.pending_images.clear();
+        self.provisional_messages.clear();
+        self.omitted_messages = 0;
+    }
+
+    /// Starts over with a clear conversation: scratch is left and discarded,
+    /// the main conversation is cleared (saved first with auto-save on), and
+    /// nothing is left staged for the next message
+    pub fn reset(&mut self, provisional_mode: bool) -> Result<(), ConversationError> {
+        self.leave_scratch()?;
+        self.clear_conversation();
+        self.prefill = None;
+        self.current_conversation.provisional_mode = provisional_mode;
+        Ok(())
+    }
+
+    // Discards an active scratch conversation and, with auto-save on, saves
+    // the main one it parked before that is replaced
+    fn leave_scratch(&mut self) -> Result<(), ConversationError> {
+        if !self.is_scratch() {
+            return Ok(());
+        }
+        self.toggle_scratch();
+        if !self.auto_save || self.current_conversation.messages.is_empty() {
+            return Ok(());
+        }
+        self.save_conversation()
+    }
+
+    /// Attaches an image to the next message sent; it is sent once and then
+    /// kept with that message
+    pub fn attach_image(&mut self, image: ImageAttachment) {
+        self.pending_images.push(image);
+    }
+
+    pub fn pending_images(&self) -> &[ImageAttachment] {
+        &self.pending_images
+    }
+
+    /// Swaps between the main conversation and a fresh scratch one for
+    /// throwaway questions. Leaving scratch discards it; nothing is merged
+    /// into the main conversation. Returns whether scratch is now active.
+    pub fn toggle_scratch(&mut self) -> bool {
+        match self.parked.take() {
+            Some((main, provisional, omitted)) => {
+                self.current_conversation = main;
+                self.provisional_messages = provisional;
+                self.omitted_messages = omitted;
+            }
+            None => {
+                let main = std::mem::take(&mut self.current_conversation);
+                let provisional = std::mem::take(&mut self.provisional_messages);
+                let omitted = std::mem::take(&mut self.omitted_messages);
+                self.parked = Some((main, provisional, omitted));
+            }
+        }
+        self.is_scratch()
+    }
+
+    pub fn is_scratch(&self) -> bool {
+        self.parked.is_some()
     }
 
     pub fn toggle_provisional_mode(&mut self) {
@@ -77,7 +786,965 @@ impl ConversationManager {
         &self.current_conversation.messages
     }
 
+    /// Stored messages interleaved by time with this session's provisional
+    /// turns, for display only
+    pub fn display_messages(&self) -> Vec<Message> {
+        let mut messages = self.current_conversation.messages.clone();
+        for message in &self.provisional_messages {
+            let at = messages.partition_point(|stored| stored.timestamp <= message.timestamp);
+            messages.insert(at, message.clone());
+        }
+        messages
+    }
+
+    /// How many older messages the history cap has dropped, and so no longer
+    /// reach the model
+    pub fn omitted_message_count(&self) -> usize {
+        self.omitted_messages
+    }
+
     pub fn is_provisional_mode(&self) -> bool {
         self.current_conversation.provisional_mode
     }
-}
\ No newline at end of file
+
+    /// Whether `content` repeats the last stored user message, sent less than
+    /// `DUPLICATE_MESSAGE_WINDOW` before `now`
+    pub fn is_duplicate(&self, content: &str, now: DateTime<Utc>) -> bool {
+        let Some(last) = self
+            .current_conversation
+            .messages
+            .iter()
+            .rev()
+            .find(|message| matches!(message.role, MessageRole::User))
+        else {
+            return false;
+        };
+        let elapsed = (now - last.timestamp).to_std().unwrap_or_default();
+        last.content.trim() == content.trim() && elapsed < DUPLICATE_MESSAGE_WINDOW
+    }
+
+    /// Formats the stored conversation as Markdown, one section per message,
+    /// returning it with the number of messages included. Provisional
+    /// messages are left out.
+    pub fn export_markdown(&self, user_name: &str, assistant_name: &str) -> (String, usize) {
+        let sections: Vec<String> = self
+            .current_conversation
+            .messages
+            .iter()
+            .filter(|message| !message.provisional)
+            .map(|message| {
+                let heading = match (&message.role, &message.tool_call) {
+                    (MessageRole::User, _) => user_name.to_string(),
+                    (MessageRole::Assistant, Some(call)) => {
+                        format!("{} (called {})", assistant_name, call.name)
+                    }
+                    (MessageRole::Assistant, None) => assistant_name.to_string(),
+                    (MessageRole::System, _) => "System".to_string(),
+                    (MessageRole::Tool, Some(call)) => format!("Tool ({})", call.name),
+                    (MessageRole::Tool, None) => "Tool".to_string(),
+                };
+                format!(
+                    "## {} ({})\n\n{}\n",
+                    heading,
+                    message.timestamp.format("%Y-%m-%d %H:%M:%S"),
+                    message.content.trim_end()
+                )
+            })
+            .collect();
+        (sections.join("\n"), sections.len())
+    }
+}
+
+/// Index of the first message the history cap could have trimmed, which is
+/// where omitted messages used to sit: pinned system messages stay ahead of it
+/// and provisional ones are never sent again
+pub fn context_boundary(messages: &[Message]) -> usize {
+    messages
+        .iter()
+        .position(|message| !message.provisional && !matches!(message.role, MessageRole::System))
+        .unwrap_or(messages.len())
+}
+
+// `text` without its leading and trailing whitespace-only lines; the first
+// kept line keeps its indentation and everything between is untouched
+pub fn trim_blank_lines(text: &str) -> &str {
+    let Some(first) = text.find(|c: char| !c.is_whitespace()) else {
+        return "";
+    };
+    let start = text[..first].rfind('\n').map_or(0, |newline| newline + 1);
+    let last = text.trim_end().len();
+    let end = text[last..].find('\n').map_or(text.len(), |newline| last + newline);
+    text[start..end].trim_end_matches('\r')
+}
+
+fn assistant_reply(content: String) -> Message {
+    Message {
+        role: MessageRole::Assistant,
+        content,
+        timestamp: Utc::now(),
+        provisional: false,
+        context_files: Vec::new(),
+        tool_call: None,
+        images: Vec::new(),
+    }
+}
+
+// Assistant text sent for the model to continue rather than stored
+fn partial_reply(content: String) -> Message {
+    Message {
+        role: MessageRole::Assistant,
+        content,
+        timestamp: Utc::now(),
+        provisional: true,
+        context_files: Vec::new(),
+        tool_call: None,
+        images: Vec::new(),
+    }
+}
+
+// Writes beside `path` and renames over it, so a crash mid-save leaves the
+// previous version intact instead of a truncated file
+fn write_atomic(path: &Path, content: &str) -> std::io::Result<()> {
+    let mut temp = path.as_os_str().to_owned();
+    temp.push(".tmp");
+    let temp = PathBuf::from(temp);
+    let result = std::fs::File::create(&temp)
+        .and_then(|mut file| {
+            std::io::Write::write_all(&mut file, content.as_bytes())?;
+            file.sync_all()
+        })
+        .and_then(|_| std::fs::rename(&temp, path));
+    if result.is_err() {
+        let _ = std::fs::remove_file(&temp);
+    }
+    result
+}
+
+// Reads a stored conversation, leaving the file as it is
+fn read_conversation(path: &Path) -> Result<Conversation, ConversationError> {
+    let content = read_conversation_file(path)?;
+    serde_json::from_str(&content).map_err(|e| parse_error(path, e))
+}
+
+// Reads a conversation the user asked to open. A file that doesn't parse is
+// moved aside with a `.corrupt` suffix, where it can be inspected and no
+// longer shows up in the listing.
+fn open_conversation(path: &Path) -> Result<Conversation, ConversationError> {
+    let content = read_conversation_file(path)?;
+    serde_json::from_str(&content).map_err(|e| {
+        let mut quarantined = path.as_os_str().to_owned();
+        quarantined.push(".corrupt");
+        let quarantined = PathBuf::from(quarantined);
+        match std::fs::rename(path, &quarantined) {
+            Ok(()) => warn!("Conversation {:?} is corrupted ({}); moved it to {:?}", path, e, quarantined),
+            Err(rename_error) => warn!("Failed to quarantine corrupted {:?}: {}", path, rename_error),
+        }
+        parse_error(path, e)
+    })
+}
+
+fn read_conversation_file(path: &Path) -> Result<String, ConversationError> {
+    std::fs::read_to_string(path).map_err(|e| {
+        ConversationError::Storage(format!("Failed to read conversation {:?}: {}", path, e))
+    })
+}
+
+fn parse_error(path: &Path, error: serde_json::Error) -> ConversationError {
+    ConversationError::Storage(format!("Failed to parse conversation {:?}: {}", path, error))
+}
+
+/// Lowercase, file-name-safe form of `title`: ASCII letters and digits kept,
+/// everything else collapsed into single dashes. Empty titles become "untitled".
+pub fn slugify(title: &str) -> String {
+    let mut slug = String::new();
+    for c in title.chars() {
+        if c.is_ascii_alphanumeric() {
+            if slug.len() == MAX_SLUG_CHARS {
+                break;
+            }
+            slug.push(c.to_ascii_lowercase());
+        } else if !slug.is_empty() && !slug.ends_with('-') {
+            slug.push('-');
+        }
+    }
+    let slug = slug.trim_end_matches('-');
+    if slug.is_empty() {
+        "untitled".to_string()
+    } else {
+        slug.to_string()
+    }
+}
+
+// File stem for the titled naming scheme: creation date and the slug of the
+// first user message
+fn titled_file_stem(conversation: &Conversation) -> String {
+    let title = conversation
+        .messages
+        .iter()
+        .find(|message| matches!(message.role, MessageRole::User))
+        .map_or("", |message| message.content.as_str());
+    format!("{}_{}", conversation.created_at.format("%Y-%m-%d"), slugify(title))
+}
+
+// `<stem>.json` in `dir`, or `<stem>-2.json`, `<stem>-3.json`, ... when taken
+fn unique_path(dir: &Path, stem: &str) -> PathBuf {
+    let mut path = dir.join(format!("{}.json", stem));
+    let mut counter = 2;
+    while path.exists() {
+        path = dir.join(format!("{}-{}.json", stem, counter));
+        counter += 1;
+    }
+    path
+}
+
+// Line prefixes that mark a statement in common languages
+const CODE_KEYWORDS: &[&str] = &[
+    "fn ", "pub ", "let ", "use ", "impl ", "struct ", "enum ", "def ", "class ", "import ",
+    "from ", "return", "const ", "var ", "function ", "#include", "package ", "if (", "for (",
+    "while (", "} else",
+];
+
+// Conservative heuristic for pasted code: needs several lines, most of which
+// end like statements or start with a keyword. Single lines and text that is
+// already fenced are left alone.
+pub fn looks_like_code(input: &str) -> bool {
+    if input.contains("```") {
+        return false;
+    }
+
+    let lines: Vec<&str> = input.lines().filter(|line| !line.trim().is_empty()).collect();
+    if lines.len() < 2 {
+        return false;
+    }
+
+    let code_lines = lines.iter().filter(|line| is_code_line(line)).count();
+    let indented = lines
+        .iter()
+        .filter(|line| line.starts_with("    ") || line.starts_with('\t'))
+        .count();
+    // Indentation alone is common in prose (quotes, lists), so it only tips the balance
+    code_lines >= 2 && (code_lines + indented / 2) * 10 >= lines.len() * 6
+}
+
+fn is_code_line(line: &str) -> bool {
+    let trimmed = line.trim();
+    trimmed.ends_with([';', '{', '}'])
+        || trimmed.starts_with("//")
+        || CODE_KEYWORDS.iter().any(|keyword| trimmed.starts_with(keyword))
+        || (trimmed.ends_with(':') && trimmed.contains('('))
+}
+
+// A fenced code block from a message, with the language named on its fence
+#[derive(Debug, Clone, PartialEq)]
+pub struct CodeBlock {
+    pub language: Option<String>,
+    pub content: String,
+}
+
+/// Fenced code blocks in a message, in order. A block left open runs to the
+/// end of the message, as it would while a reply is cut off.
+pub fn code_blocks(text: &str) -> Vec<CodeBlock> {
+    let mut blocks = Vec::new();
+    let mut current: Option<(Option<String>, Vec<&str>)> = None;
+    for line in text.lines() {
+        let fence = line.trim_start().strip_prefix("```");
+        match (current.take(), fence) {
+            (None, Some(info)) => {
+                let language = info.split_whitespace().next().map(str::to_string);
+                current = Some((language, Vec::new()));
+            }
+            (Some((language, lines)), Some(rest)) if rest.trim().is_empty() => {
+                blocks.push(CodeBlock { language, content: lines.join("\n") + "\n" });
+            }
+            (Some((language, mut lines)), _) => {
+                lines.push(line);
+                current = Some((language, lines));
+            }
+            (None, None) => {}
+        }
+    }
+    if let Some((language, lines)) = current {
+        blocks.push(CodeBlock { language, content: lines.join("\n") + "\n" });
+    }
+    blocks
+}
+
+/// Prefixes each line with its 1-based number, right-aligned in a gutter
+/// wide enough for the last one
+pub fn number_lines(lines: &[&str]) -> Vec<String> {
+    number_lines_from(lines, 1)
+}
+
+/// Like `number_lines`, for lines that start at line `first` of their file
+pub fn number_lines_from(lines: &[&str], first: usize) -> Vec<String> {
+    let width = (first + lines.len().saturating_sub(1)).to_string().len();
+    lines
+        .iter()
+        .enumerate()
+        .map(|(index, line)| format!("{:>width$} | {}", first + index, line, width = width))
+        .collect()
+}
+
+/// Numbers the lines inside each fenced code block, leaving the fences and
+/// the text around them as they are
+pub fn number_code_blocks(text: &str) -> String {
+    let mut out: Vec<String> = Vec::new();
+    let mut block: Option<Vec<&str>> = None;
+    for line in text.split('\n') {
+        let fence = line.trim_start().strip_prefix("```");
+        match (block.take(), fence) {
+            (None, Some(_)) => {
+                out.push(line.to_string());
+                block = Some(Vec::new());
+            }
+            (Some(lines), Some(rest)) if rest.trim().is_empty() => {
+                out.extend(number_lines(&lines));
+                out.push(line.to_string());
+            }
+            (Some(mut lines), _) => {
+                lines.push(line);
+                block = Some(lines);
+            }
+            (None, None) => out.push(line.to_string()),
+        }
+    }
+    if let Some(lines) = block {
+        out.extend(number_lines(&lines));
+    }
+    out.join("\n")
+}
+
+// File extension for a fence language, for saving a block without one
+pub fn language_extension(language: &str) -> Option<&'static str> {
+    let extension = match language.to_ascii_lowercase().as_str() {
+        "rust" | "rs" => "rs",
+        "python" | "py" => "py",
+        "javascript" | "js" => "js",
+        "typescript" | "ts" => "ts",
+        "bash" | "sh" | "shell" | "zsh" => "sh",
+        "json" => "json",
+        "toml" => "toml",
+        "yaml" | "yml" => "yaml",
+        "html" => "html",
+        "css" => "css",
+        "c" => "c",
+        "cpp" | "c++" => "cpp",
+        "go" => "go",
+        "java" => "java",
+        "ruby" | "rb" => "rb",
+        "markdown" | "md" => "md",
+        "sql" => "sql",
+        _ => return None,
+    };
+    Some(extension)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::llm::mock::MockLlmClient;
+
+    #[tokio::test]
+    async fn test_send_message_prepends_system_prompt() {
+        let mut manager = ConversationManager::new().expect("Failed to create manager");
+        manager.set_system_prompt(Some("You are terse.".to_string()));
+        let client = MockLlmClient::with_responses(vec!["Ok."]);
+
+        let response = manager
+            .send_message("Hello".to_string(), false, &client)
+            .await
+            .expect("Failed to send message");
+        assert_eq!(response, "Ok.");
+
+        let request = &client.requests.lock().unwrap()[0];
+        assert!(matches!(request[0].role, MessageRole::System));
+        assert_eq!(request[0].content, "You are terse.");
+        assert_eq!(request[1].content, "Hello");
+
+        // The system prompt is sent, not stored
+        assert_eq!(manager.get_messages().len(), 2);
+        assert!(matches!(manager.get_messages()[0].role, MessageRole::User));
+    }
+
+    #[tokio::test]
+    async fn test_send_message_with_context_attaches_files() {
+        let mut manager = ConversationManager::new().expect("Failed to create manager");
+        let client = MockLlmClient::new();
+        let path = PathBuf::from("/docs/guide.md");
+        let context = RagContext {
+            query: "How?".to_string(),
+            available_files: Vec::new(),
+            keywords: Vec::new(),
+            search_results: Vec::new(),
+            selected_files: vec![path.clone()],
+            file_contents: [(path.clone(), "Step one".to_string())].into_iter().collect(),
+            flagged_files: Vec::new(),
+            excerpted_files: Vec::new(),
+        };
+
+        manager
+            .send_message_with_context("How?".to_string(), false, Some(&context), &client)
+            .await
+            .expect("Failed to send message");
+
+        let request = &client.requests.lock().unwrap()[0];
+        assert!(matches!(request[0].role, MessageRole::System));
+        assert!(request[0].content.contains("Step one"));
+        assert_eq!(manager.get_messages()[0].context_files, vec![path]);
+        assert!(!manager.get_messages()[0].content.contains("Step one"));
+    }
+
+    #[test]
+    fn test_looks_like_code_detects_code() {
+        assert!(looks_like_code("fn main() {\n    println!(\"hi\");\n}"));
+        assert!(looks_like_code("def add(a, b):\n    return a + b"));
+        assert!(looks_like_code("import os\nfrom sys import argv\nprint(argv)"));
+    }
+
+    #[test]
+    fn test_looks_like_code_ignores_prose() {
+        assert!(!looks_like_code("Can you explain how lifetimes work?"));
+        assert!(!looks_like_code("I tried this yesterday.\nIt did not work; any ideas?\nThanks!"));
+        assert!(!looks_like_code("Shopping list:\n    eggs\n    milk\n    bread"));
+    }
+
+    #[test]
+    fn test_looks_like_code_borderline_cases() {
+        // A single line of code is too little evidence
+        assert!(!looks_like_code("let x = 5;"));
+        // Already fenced input is left alone
+        assert!(!looks_like_code("```\nlet x = 5;\nlet y = 6;\n```"));
+        // A question wrapped around a short snippet stays prose
+        assert!(!looks_like_code("Why does this fail?\nlet x = 5;\nIt says x is unused.\nAny idea?"));
+    }
+
+    #[test]
+    fn test_code_blocks_reads_language_and_unclosed_fence() {
+        let blocks = code_blocks("Intro\n```toml extra\na = 1\n```\n  ```\nopen\n");
+        assert_eq!(
+            blocks,
+            vec![
+                CodeBlock { language: Some("toml".to_string()), content: "a = 1\n".to_string() },
+                CodeBlock { language: None, content: "open\n".to_string() },
+            ]
+        );
+        assert_eq!(language_extension("Rust"), Some("rs"));
+        assert_eq!(language_extension("text"), None);
+    }
+
+    #[test]
+    fn test_number_code_blocks_adds_aligned_gutter() {
+        let code: Vec<String> = (1..=10).map(|n| format!("line {}", n)).collect();
+        let text = format!("Here:\n```rust\n{}\n```\nDone", code.join("\n"));
+
+        let numbered = number_code_blocks(&text);
+        let lines: Vec<&str> = numbered.lines().collect();
+        assert_eq!(lines[0], "Here:");
+        assert_eq!(lines[1], "```rust");
+        assert_eq!(lines[2], " 1 | line 1");
+        assert_eq!(lines[10], " 9 | line 9");
+        assert_eq!(lines[11], "10 | line 10");
+        assert_eq!(lines[12], "```");
+        assert_eq!(lines[13], "Done");
+        // Text outside fences is untouched
+        assert_eq!(number_code_blocks("no code
+here"), "no code
+here");
+    }
+
+    #[tokio::test]
+    async fn test_auto_fence_wraps_code_messages() {
+        let mut manager = ConversationManager::new().expect("Failed to create manager");
+        manager.set_auto_fence_code(true);
+        let client = MockLlmClient::new();
+
+        manager
+            .send_message("let a = 1;\nlet b = 2;\n".to_string(), false, &client)
+            .await
+            .expect("Failed to send message");
+
+        assert_eq!(manager.get_messages()[0].content, "```\nlet a = 1;\nlet b = 2;\n```");
+    }
+
+    #[tokio::test]
+    async fn test_blank_lines_around_reply_are_trimmed() {
+        let mut manager = ConversationManager::new().expect("Failed to create manager");
+        let reply = "\n \n  ```rust\nfn a() {}\n\n\nfn b() {}\n```\n\t\n\n";
+        let client = MockLlmClient::with_responses(vec![reply, reply]);
+
+        let response = manager.send_message("Code?".to_string(), false, &client).await.unwrap();
+        assert_eq!(response, "  ```rust\nfn a() {}\n\n\nfn b() {}\n```");
+        assert_eq!(manager.get_messages()[1].content, response);
+
+        manager.set_trim_blank_lines(false);
+        let response = manager.send_message("Again".to_string(), false, &client).await.unwrap();
+        assert_eq!(response, reply);
+        assert_eq!(trim_blank_lines(" \n\n"), "");
+    }
+
+    #[tokio::test]
+    async fn test_dropped_stream_reconnects_and_continues() {
+        let mut manager = ConversationManager::new().expect("Failed to create manager");
+        manager.set_stream_reconnect(2, Duration::from_millis(1));
+        let client = MockLlmClient::new().with_continuation();
+        client.push_stream(vec![
+            Ok("The answer ".to_string()),
+            Ok("is ".to_string()),
+            Err(LlmError::StreamInterrupted),
+        ]);
+        client.push_stream(vec![Err(LlmError::Network("connection reset".to_string()))]);
+        client.push_stream(vec![Ok(" forty-two.".to_string())]);
+
+        let mut shown = String::new();
+        let response = manager
+            .stream_message_with_context("Question?".to_string(), false, None, &client, |token| {
+                shown.push_str(token)
+            })
+            .await
+            .expect("Stream should recover");
+
+        assert_eq!(response, "The answer is forty-two.");
+        assert_eq!(shown, "The answer is forty-two.");
+        let requests = client.requests.lock().unwrap();
+        assert_eq!(requests.len(), 3);
+        let partial = requests[2].last().unwrap();
+        assert!(matches!(partial.role, MessageRole::Assistant));
+        assert_eq!(partial.content, "The answer is");
+        drop(requests);
+        assert_eq!(manager.get_messages()[1].content, "The answer is forty-two.");
+    }
+
+    #[tokio::test]
+    async fn test_dropped_stream_restarts_without_continuation() {
+        let mut manager = ConversationManager::new().expect("Failed to create manager");
+        manager.set_stream_reconnect(1, Duration::from_millis(1));
+        let client = MockLlmClient::new();
+        client.push_stream(vec![Ok("Partial ".to_string()), Err(LlmError::StreamInterrupted)]);
+        client.push_stream(vec![Ok("Partial ".to_string()), Ok("answer".to_string())]);
+
+        let mut shown = String::new();
+        let response = manager
+            .stream_message_with_context("Question?".to_string(), false, None, &client, |token| {
+                shown.push_str(token)
+            })
+            .await
+            .unwrap();
+        assert_eq!(response, "Partial answer");
+        assert_eq!(shown, "Partial answer");
+        assert_eq!(client.requests.lock().unwrap()[1].len(), 1);
+
+        // Out of attempts: the error surfaces and nothing is stored
+        client.push_stream(vec![Err(LlmError::StreamInterrupted)]);
+        client.push_stream(vec![Err(LlmError::StreamInterrupted)]);
+        let result = manager
+            .stream_message_with_context("Again?".to_string(), false, None, &client, |_| {})
+            .await;
+        assert!(result.is_err());
+        assert_eq!(manager.get_messages().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_export_markdown_skips_provisional_messages() {
+        let mut manager = ConversationManager::new().expect("Failed to create manager");
+        let client = MockLlmClient::with_responses(vec!["Hi there"]);
+        manager.send_message("Hello".to_string(), false, &client).await.unwrap();
+        manager.current_conversation.messages.push(Message {
+            role: MessageRole::User,
+            content: "Scratch".to_string(),
+            timestamp: Utc::now(),
+            provisional: true,
+            context_files: Vec::new(),
+            tool_call: None,
+            images: Vec::new(),
+        });
+
+        let (markdown, count) = manager.export_markdown("Me", "Bot");
+        assert_eq!(count, 2);
+        let time = |i: usize| {
+            manager.get_messages()[i].timestamp.format("%Y-%m-%d %H:%M:%S").to_string()
+        };
+        assert_eq!(
+            markdown,
+            format!("## Me ({})\n\nHello\n\n## Bot ({})\n\nHi there\n", time(0), time(1))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_prefill_starts_the_next_reply_only() {
+        let mut manager = ConversationManager::new().expect("Failed to create manager");
+        let client = MockLlmClient::with_responses(vec!["\"ok\": true}", "plain"]);
+        manager.set_prefill(Some("{".to_string()));
+
+        let response = manager.send_message("JSON please".to_string(), false, &client).await.unwrap();
+        assert_eq!(response, "{\"ok\": true}");
+        assert_eq!(manager.get_messages()[1].content, "{\"ok\": true}");
+        let partial = client.requests.lock().unwrap()[0].last().unwrap().clone();
+        assert!(matches!(partial.role, MessageRole::Assistant));
+        assert_eq!(partial.content, "{");
+
+        let mut shown = String::new();
+        manager.set_prefill(Some("Sure: ".to_string()));
+        let response = manager
+            .stream_message_with_context("Again".to_string(), false, None, &client, |token| {
+                shown.push_str(token)
+            })
+            .await
+            .unwrap();
+        assert_eq!(response, "Sure: plain");
+        assert_eq!(shown, "Sure: plain");
+
+        manager.send_message("No prefill".to_string(), false, &client).await.unwrap();
+        let last_request = client.requests.lock().unwrap()[2].clone();
+        assert!(matches!(last_request.last().unwrap().role, MessageRole::User));
+    }
+
+    #[tokio::test]
+    async fn test_scratch_messages_stay_out_of_main_conversation() {
+        let temp_dir = tempfile::TempDir::new().unwrap();
+        let mut manager = ConversationManager::new().unwrap();
+        manager.set_storage_path(temp_dir.path().to_path_buf());
+        manager.set_auto_save(true);
+        let client = MockLlmClient::with_responses(vec!["Main answer", "Scratch answer"]);
+
+        manager.send_message("Main question".to_string(), false, &client).await.unwrap();
+        let main_id = manager.current_conversation_id().to_string();
+        assert!(manager.toggle_scratch());
+        assert!(manager.get_messages().is_empty());
+        manager.send_message("Throwaway".to_string(), false, &client).await.unwrap();
+        assert_eq!(manager.get_messages().len(), 2);
+        let scratch_id = manager.current_conversation_id().to_string();
+
+        assert!(!manager.toggle_scratch());
+        assert_eq!(manager.current_conversation_id(), main_id);
+        let contents: Vec<&str> = manager.get_messages().iter().map(|m| m.content.as_str()).collect();
+        assert_eq!(contents, vec!["Main question", "Main answer"]);
+        // The scratch turn was never written to storage
+        assert!(!temp_dir.path().join(format!("{}.json", scratch_id)).exists());
+        assert!(temp_dir.path().join(format!("{}.json", main_id)).exists());
+
+        // Each visit starts a fresh scratch conversation
+        manager.toggle_scratch();
+        assert!(manager.get_messages().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_load_and_reset_from_scratch_save_the_parked_conversation_with_auto_save() {
+        let temp_dir = tempfile::TempDir::new().unwrap();
+        let mut manager = ConversationManager::new().unwrap();
+        manager.set_storage_path(temp_dir.path().to_path_buf());
+        let client = MockLlmClient::with_responses(vec!["Stored", "Unsaved", "Scratch", "Kept", "Again"]);
+
+        manager.send_message("First".to_string(), false, &client).await.unwrap();
+        manager.save_conversation().unwrap();
+        let stored_id = manager.current_conversation_id().to_string();
+        manager.clear_conversation();
+        manager.send_message("Never saved".to_string(), false, &client).await.unwrap();
+        let unsaved_id = manager.current_conversation_id().to_string();
+        manager.toggle_scratch();
+        manager.send_message("Throwaway".to_string(), false, &client).await.unwrap();
+
+        // Without auto-save, leaving scratch writes nothing
+        manager.load_conversation(&stored_id).unwrap();
+        assert!(!manager.is_scratch());
+        assert!(!temp_dir.path().join(format!("{}.json", unsaved_id)).exists());
+
+        manager.clear_conversation();
+        manager.send_message("Parked".to_string(), false, &client).await.unwrap();
+        let parked_id = manager.current_conversation_id().to_string();
+        manager.set_auto_save(true);
+        manager.toggle_scratch();
+        manager.send_message("Scratch".to_string(), false, &client).await.unwrap();
+        manager.reset(false).unwrap();
+        assert!(manager.get_messages().is_empty());
+        manager.load_conversation(&parked_id).unwrap();
+        assert_eq!(manager.get_messages().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_auto_save_persists_each_turn() {
+        let temp_dir = tempfile::TempDir::new().expect("Failed to create temp dir");
+        let mut manager = ConversationManager::new().expect("Failed to create manager");
+        manager.set_storage_path(temp_dir.path().to_path_buf());
+        manager.set_auto_save(true);
+        let client = MockLlmClient::new();
+
+        manager.send_message("One".to_string(), false, &client).await.unwrap();
+        manager.send_message("Two".to_string(), false, &client).await.unwrap();
+        manager.send_message("Draft".to_string(), true, &client).await.unwrap();
+
+        let path = temp_dir
+            .path()
+            .join(format!("{}.json", manager.current_conversation.id));
+        let saved: Conversation =
+            serde_json::from_str(&std::fs::read_to_string(path).unwrap()).unwrap();
+        let contents: Vec<&str> = saved.messages.iter().map(|m| m.content.as_str()).collect();
+        assert_eq!(contents.len(), 4);
+        assert_eq!(contents[0], "One");
+        assert_eq!(contents[2], "Two");
+    }
+
+    #[tokio::test]
+    async fn test_list_and_load_stored_conversations() {
+        let temp_dir = tempfile::TempDir::new().expect("Failed to create temp dir");
+        let mut manager = ConversationManager::new().expect("Failed to create manager");
+        manager.set_storage_path(temp_dir.path().to_path_buf());
+        let client = MockLlmClient::new();
+
+        manager.send_message("First topic".to_string(), false, &client).await.unwrap();
+        manager.save_conversation().unwrap();
+        let first_id = manager.current_conversation_id().to_string();
+        manager.clear_conversation();
+        manager.send_message("Second topic".to_string(), false, &client).await.unwrap();
+        manager.save_conversation().unwrap();
+        std::fs::write(temp_dir.path().join("broken.json"), "not json").unwrap();
+
+        let summaries = manager.list_conversations(None).expect("Failed to list");
+        assert_eq!(summaries.len(), 3);
+        assert_eq!(summaries[0].id, "broken");
+        assert_eq!(summaries[1].preview, "Second topic");
+        assert_eq!(summaries[2].message_count, 2);
+
+        manager.load_conversation(&first_id).expect("Failed to load");
+        assert_eq!(manager.current_conversation_id(), first_id);
+        assert_eq!(manager.get_messages()[0].content, "First topic");
+        assert!(manager.load_conversation("missing").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_save_replaces_file_through_temp_rename() {
+        let temp_dir = tempfile::TempDir::new().expect("Failed to create temp dir");
+        let mut manager = ConversationManager::new().expect("Failed to create manager");
+        manager.set_storage_path(temp_dir.path().to_path_buf());
+        let client = MockLlmClient::new();
+        manager.send_message("First".to_string(), false, &client).await.unwrap();
+        manager.save_conversation().unwrap();
+        let path = temp_dir.path().join(format!("{}.json", manager.current_conversation_id()));
+
+        // A crash mid-save leaves only a partial temp file behind
+        let temp_path = temp_dir.path().join(format!("{}.json.tmp", manager.current_conversation_id()));
+        std::fs::write(&temp_path, "{\"id\": \"trunc").unwrap();
+        assert_eq!(read_conversation(&path).unwrap().messages.len(), 2);
+        assert_eq!(manager.list_conversations(None).unwrap().len(), 1);
+
+        manager.send_message("Second".to_string(), false, &client).await.unwrap();
+        manager.save_conversation().unwrap();
+        assert!(!temp_path.exists());
+        assert_eq!(read_conversation(&path).unwrap().messages.len(), 4);
+        let names: Vec<_> = std::fs::read_dir(temp_dir.path()).unwrap().flatten().map(|e| e.file_name()).collect();
+        assert_eq!(names.len(), 1);
+    }
+
+    #[test]
+    fn test_corrupted_conversation_is_quarantined_on_load() {
+        let temp_dir = tempfile::TempDir::new().expect("Failed to create temp dir");
+        let mut manager = ConversationManager::new().expect("Failed to create manager");
+        manager.set_storage_path(temp_dir.path().to_path_buf());
+        let path = temp_dir.path().join("abc.json");
+        std::fs::write(&path, "{\"id\": \"abc\", \"messa").unwrap();
+
+        // Listing offers it for loading without touching it; lookups skip it
+        let listed = manager.list_conversations(None).unwrap();
+        assert_eq!(listed.len(), 1);
+        assert_eq!(listed[0].id, "abc");
+        assert_eq!(listed[0].preview, UNREADABLE_PREVIEW);
+        assert!(manager.list_conversations(Some("rust")).unwrap().is_empty());
+        assert!(manager.load_conversation("other").is_err());
+        assert!(path.exists());
+
+        assert!(manager.load_conversation("abc").is_err());
+        assert!(!path.exists());
+        let quarantined = temp_dir.path().join("abc.json.corrupt");
+        assert_eq!(std::fs::read_to_string(quarantined).unwrap(), "{\"id\": \"abc\", \"messa");
+        assert!(manager.list_conversations(None).unwrap().is_empty());
+        assert!(manager.get_messages().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_list_conversations_filters_by_tag() {
+        let temp_dir = tempfile::TempDir::new().expect("Failed to create temp dir");
+        let mut manager = ConversationManager::new().expect("Failed to create manager");
+        manager.set_storage_path(temp_dir.path().to_path_buf());
+        let client = MockLlmClient::new();
+
+        manager.send_message("Rust question".to_string(), false, &client).await.unwrap();
+        assert!(manager.add_tag(" rust "));
+        assert!(!manager.add_tag("rust"));
+        assert!(!manager.add_tag("  "));
+        assert!(manager.add_tag("work notes"));
+        manager.save_conversation().unwrap();
+        manager.clear_conversation();
+        assert!(manager.tags().is_empty());
+        manager.send_message("Dinner ideas".to_string(), false, &client).await.unwrap();
+        manager.add_tag("work notes");
+        assert!(manager.remove_tag("work notes"));
+        assert!(!manager.remove_tag("work notes"));
+        manager.save_conversation().unwrap();
+
+        let tagged = manager.list_conversations(Some("rust")).unwrap();
+        assert_eq!(tagged.len(), 1);
+        assert_eq!(tagged[0].preview, "Rust question");
+        assert_eq!(tagged[0].tags, vec!["rust", "work notes"]);
+        assert_eq!(manager.list_conversations(Some("work notes")).unwrap().len(), 1);
+        assert!(manager.list_conversations(Some("missing")).unwrap().is_empty());
+        assert_eq!(manager.list_conversations(None).unwrap().len(), 2);
+    }
+
+    #[test]
+    fn test_slugify_strips_unsafe_characters() {
+        assert_eq!(slugify("My Topic"), "my-topic");
+        assert_eq!(slugify("  ../etc/passwd?  "), "etc-passwd");
+        assert_eq!(slugify("What's new in Rust 1.80?!"), "what-s-new-in-rust-1-80");
+        assert_eq!(slugify("café ünïcode"), "caf-n-code");
+        assert_eq!(slugify("???"), "untitled");
+        assert_eq!(slugify(&"a".repeat(80)).len(), MAX_SLUG_CHARS);
+    }
+
+    #[tokio::test]
+    async fn test_titled_naming_appends_counter_on_collision() {
+        let temp_dir = tempfile::TempDir::new().unwrap();
+        let mut manager = ConversationManager::new().unwrap();
+        manager.set_storage_path(temp_dir.path().to_path_buf());
+        manager.set_naming(ConversationNaming::Titled);
+        let client = MockLlmClient::new();
+
+        manager.send_message("My topic".to_string(), false, &client).await.unwrap();
+        manager.save_conversation().unwrap();
+        let first_id = manager.current_conversation_id().to_string();
+        let created_at = manager.current_conversation.created_at;
+        let date = created_at.format("%Y-%m-%d").to_string();
+        let first_path = temp_dir.path().join(format!("{}_my-topic.json", date));
+        assert!(first_path.exists());
+
+        // Saving again keeps the same file
+        manager.send_message("More".to_string(), false, &client).await.unwrap();
+        manager.save_conversation().unwrap();
+        assert_eq!(std::fs::read_dir(temp_dir.path()).unwrap().count(), 1);
+
+        manager.clear_conversation();
+        manager.current_conversation.created_at = created_at;
+        manager.send_message("My topic!".to_string(), false, &client).await.unwrap();
+        manager.save_conversation().unwrap();
+        let second_path = temp_dir.path().join(format!("{}_my-topic-2.json", date));
+        assert!(second_path.exists());
+
+        // The id inside the file still identifies each conversation
+        manager.load_conversation(&first_id).unwrap();
+        assert_eq!(manager.get_messages().len(), 4);
+        let stored = read_conversation(&first_path).unwrap();
+        assert_eq!(stored.id, first_id);
+    }
+
+    #[tokio::test]
+    async fn test_scroll_position_is_restored_when_switching_back() {
+        let temp_dir = tempfile::TempDir::new().expect("Failed to create temp dir");
+        let mut manager = ConversationManager::new().expect("Failed to create manager");
+        manager.set_storage_path(temp_dir.path().to_path_buf());
+        let client = MockLlmClient::new();
+
+        manager.send_message("Long read".to_string(), false, &client).await.unwrap();
+        manager.set_scroll_position(7);
+        manager.save_conversation().unwrap();
+        let first_id = manager.current_conversation_id().to_string();
+
+        manager.clear_conversation();
+        assert_eq!(manager.scroll_position(), 0);
+        manager.set_scroll_position(2);
+        manager.save_conversation().unwrap();
+
+        manager.load_conversation(&first_id).unwrap();
+        assert_eq!(manager.scroll_position(), 7);
+    }
+
+    #[test]
+    fn test_tool_message_round_trips_through_json() {
+        let mut conversation = Conversation::new();
+        conversation.messages.push(Message {
+            role: MessageRole::Tool,
+            content: "42".to_string(),
+            timestamp: Utc::now(),
+            provisional: false,
+            context_files: Vec::new(),
+            tool_call: Some(ToolCall {
+                id: "call_7".to_string(),
+                name: "read_file".to_string(),
+                arguments: String::new(),
+            }),
+            images: Vec::new(),
+        });
+
+        let json = serde_json::to_string(&conversation).unwrap();
+        let restored: Conversation = serde_json::from_str(&json).unwrap();
+        let message = &restored.messages[0];
+        assert!(matches!(message.role, MessageRole::Tool));
+        assert_eq!(message.tool_call.as_ref().unwrap().id, "call_7");
+
+        // Messages stored before tool support have no tool_call field
+        let legacy = r#"{"role":"User","content":"hi","timestamp":"2024-01-01T00:00:00Z","provisional":false,"context_files":[]}"#;
+        let message: Message = serde_json::from_str(legacy).unwrap();
+        assert!(message.tool_call.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_history_cap_drops_oldest_non_system_messages() {
+        let mut manager = ConversationManager::new().expect("Failed to create manager");
+        manager.set_max_history_messages(Some(4));
+        manager.current_conversation.messages.push(Message {
+            role: MessageRole::System,
+            content: "Pinned".to_string(),
+            timestamp: Utc::now(),
+            provisional: false,
+            context_files: Vec::new(),
+            tool_call: None,
+            images: Vec::new(),
+        });
+        let client = MockLlmClient::with_responses(vec!["a1", "a2", "a3"]);
+
+        for question in ["q1", "q2", "q3"] {
+            manager.send_message(question.to_string(), false, &client).await.unwrap();
+        }
+
+        let contents: Vec<&str> = manager.get_messages().iter().map(|m| m.content.as_str()).collect();
+        assert_eq!(contents, vec!["Pinned", "a2", "q3", "a3"]);
+        // q1, a1 and q2 went, and the marker belongs right after the pinned prompt
+        assert_eq!(manager.omitted_message_count(), 3);
+        assert_eq!(context_boundary(manager.get_messages()), 1);
+
+        manager.clear_conversation();
+        assert_eq!(manager.omitted_message_count(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_provisional_message_is_not_stored() {
+        let mut manager = ConversationManager::new().expect("Failed to create manager");
+        let client = MockLlmClient::new();
+
+        manager
+            .send_message("Throwaway".to_string(), true, &client)
+            .await
+            .expect("Failed to send message");
+
+        assert!(manager.get_messages().is_empty());
+        assert_eq!(client.call_count(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_duplicate_detection_respects_window_and_content() {
+        let mut manager = ConversationManager::new().expect("Failed to create manager");
+        let client = MockLlmClient::new();
+        assert!(!manager.is_duplicate("Hello", Utc::now()));
+
+        manager
+            .send_message("Hello".to_string(), false, &client)
+            .await
+            .expect("Failed to send message");
+        let sent_at = manager.get_messages()[0].timestamp;
+
+        let soon = sent_at + chrono::Duration::seconds(3);
+        assert!(manager.is_duplicate("Hello", soon));
+        assert!(manager.is_duplicate("  Hello\n", soon));
+        assert!(!manager.is_duplicate("Hello again", soon));
+
+        let late = sent_at + chrono::Duration::seconds(30);
+        assert!(!manager.is_duplicate("Hello", late));
+    }
+}
@@ -0,0 +1,154 @@
+// Line-based unified diffs for comparing files from the sources
+
+// Unchanged lines shown around each change
+const CONTEXT_LINES: usize = 3;
+
+// Largest LCS table built for the changed region; past it the region is shown
+// as removed and re-added, which is still a correct if noisy diff
+const MAX_DIFF_CELLS: usize = 4_000_000;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Op {
+    Equal,
+    Delete,
+    Insert,
+}
+
+/// Formats the changes from `old` to `new` as a unified diff with the given
+/// file labels. Returns an empty string when the contents have the same lines.
+pub fn unified_diff(old_label: &str, old: &str, new_label: &str, new: &str) -> String {
+    let old_lines: Vec<&str> = old.lines().collect();
+    let new_lines: Vec<&str> = new.lines().collect();
+    let ops = diff_lines(&old_lines, &new_lines);
+    if ops.iter().all(|(op, _)| *op == Op::Equal) {
+        return String::new();
+    }
+
+    let mut out = format!("--- {}\n+++ {}\n", old_label, new_label);
+    for (start, end) in hunk_ranges(&ops) {
+        // Line numbers are 1-based; an empty side points at the line before it
+        let old_before = ops[..start].iter().filter(|(op, _)| *op != Op::Insert).count();
+        let new_before = ops[..start].iter().filter(|(op, _)| *op != Op::Delete).count();
+        let hunk = &ops[start..end];
+        let old_count = hunk.iter().filter(|(op, _)| *op != Op::Insert).count();
+        let new_count = hunk.iter().filter(|(op, _)| *op != Op::Delete).count();
+        out.push_str(&format!(
+            "@@ -{},{} +{},{} @@\n",
+            old_before + usize::from(old_count > 0),
+            old_count,
+            new_before + usize::from(new_count > 0),
+            new_count
+        ));
+        for (op, line) in hunk {
+            let marker = match op {
+                Op::Equal => ' ',
+                Op::Delete => '-',
+                Op::Insert => '+',
+            };
+            out.push(marker);
+            out.push_str(line);
+            out.push('\n');
+        }
+    }
+    out
+}
+
+// Edit script turning `old` into `new`, keeping a longest common subsequence
+fn diff_lines<'a>(old: &[&'a str], new: &[&'a str]) -> Vec<(Op, &'a str)> {
+    let prefix = old.iter().zip(new).take_while(|(a, b)| a == b).count();
+    let suffix = old[prefix..]
+        .iter()
+        .rev()
+        .zip(new[prefix..].iter().rev())
+        .take_while(|(a, b)| a == b)
+        .count();
+    let old_mid = &old[prefix..old.len() - suffix];
+    let new_mid = &new[prefix..new.len() - suffix];
+
+    let mut ops: Vec<(Op, &str)> = old[..prefix].iter().map(|line| (Op::Equal, *line)).collect();
+    if (old_mid.len() + 1) * (new_mid.len() + 1) > MAX_DIFF_CELLS {
+        ops.extend(old_mid.iter().map(|line| (Op::Delete, *line)));
+        ops.extend(new_mid.iter().map(|line| (Op::Insert, *line)));
+    } else {
+        // lcs[i][j] is the LCS length of old_mid[i..] and new_mid[j..]
+        let width = new_mid.len() + 1;
+        let mut lcs = vec![0u32; (old_mid.len() + 1) * width];
+        for i in (0..old_mid.len()).rev() {
+            for j in (0..new_mid.len()).rev() {
+                lcs[i * width + j] = if old_mid[i] == new_mid[j] {
+                    lcs[(i + 1) * width + j + 1] + 1
+                } else {
+                    lcs[(i + 1) * width + j].max(lcs[i * width + j + 1])
+                };
+            }
+        }
+
+        let (mut i, mut j) = (0, 0);
+        while i < old_mid.len() && j < new_mid.len() {
+            if old_mid[i] == new_mid[j] {
+                ops.push((Op::Equal, old_mid[i]));
+                i += 1;
+                j += 1;
+            } else if lcs[(i + 1) * width + j] >= lcs[i * width + j + 1] {
+                ops.push((Op::Delete, old_mid[i]));
+                i += 1;
+            } else {
+                ops.push((Op::Insert, new_mid[j]));
+                j += 1;
+            }
+        }
+        ops.extend(old_mid[i..].iter().map(|line| (Op::Delete, *line)));
+        ops.extend(new_mid[j..].iter().map(|line| (Op::Insert, *line)));
+    }
+    ops.extend(old[old.len() - suffix..].iter().map(|line| (Op::Equal, *line)));
+    ops
+}
+
+// Half-open op ranges of each hunk: changes plus their context, merging
+// changes whose context would overlap
+fn hunk_ranges(ops: &[(Op, &str)]) -> Vec<(usize, usize)> {
+    let mut ranges: Vec<(usize, usize)> = Vec::new();
+    for (index, _) in ops.iter().enumerate().filter(|(_, (op, _))| *op != Op::Equal) {
+        let start = index.saturating_sub(CONTEXT_LINES);
+        let end = (index + 1 + CONTEXT_LINES).min(ops.len());
+        match ranges.last_mut() {
+            Some(last) if start <= last.1 => last.1 = end,
+            _ => ranges.push((start, end)),
+        }
+    }
+    ranges
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_unified_diff_marks_added_and_removed_lines() {
+        let old = "one\ntwo\nthree\nfour\n";
+        let new = "one\n2\nthree\nfour\nfive\n";
+
+        let diff = unified_diff("a.txt", old, "b.txt", new);
+        assert_eq!(
+            diff,
+            "--- a.txt\n+++ b.txt\n@@ -1,4 +1,5 @@\n one\n-two\n+2\n three\n four\n+five\n"
+        );
+    }
+
+    #[test]
+    fn test_distant_changes_get_separate_hunks() {
+        let old: Vec<String> = (1..=20).map(|n| n.to_string()).collect();
+        let mut new = old.clone();
+        new[1] = "changed".to_string();
+        new.remove(17);
+
+        let diff = unified_diff("a", &old.join("\n"), "b", &new.join("\n"));
+        assert!(diff.contains("@@ -1,5 +1,5 @@\n 1\n-2\n+changed\n 3\n"));
+        assert!(diff.contains("@@ -15,6 +15,5 @@\n 15\n 16\n 17\n-18\n 19\n 20\n"));
+    }
+
+    #[test]
+    fn test_identical_content_has_no_diff() {
+        assert!(unified_diff("a", "same\n", "b", "same").is_empty());
+    }
+}
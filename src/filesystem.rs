@@ -1,15 +1,202 @@
 use crate::types::*;
-use chrono::Utc;
+use chrono::{DateTime, Utc};
 use regex::Regex;
 use std::collections::HashMap;
-use std::path::PathBuf;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, PoisonError, RwLock};
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+use tracing::warn;
+use walkdir::WalkDir;
+
+pub const DEFAULT_MAX_FILE_SIZE_BYTES: u64 = 1024 * 1024;
+
+// Hit count at which a file's keyword density saturates in the relevance score
+const SEARCH_DENSITY_CAP: usize = 10;
+
+// Number of separate regions included in a search result snippet
+const SNIPPET_REGIONS: usize = 3;
+
+// Matching lines at most this many lines apart share a snippet region
+const SNIPPET_MERGE_GAP: usize = 3;
+
+// Longest a single snippet region grows before a new one is started
+const SNIPPET_REGION_LINES: usize = 10;
+
+// How many leading bytes are inspected when sniffing for binary content
+const BINARY_SNIFF_BYTES: usize = 8192;
+
+// Largest amount of a file shown in a preview, further capped by the max file size
+pub const PREVIEW_MAX_BYTES: u64 = 64 * 1024;
+
+// Where /export-sources writes when no path is given
+pub const DEFAULT_SOURCES_EXPORT: &str = "indexed-files.json";
+
+// Most of the log file read for the log viewer
+pub const LOG_TAIL_BYTES: u64 = 64 * 1024;
+
+// Largest image that can be attached to a message; OpenAI refuses bigger ones
+pub const MAX_IMAGE_BYTES: u64 = 20 * 1024 * 1024;
+
+// Progress of an indexing run, reported once per processed file
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IndexProgress {
+    pub processed: usize,
+    pub total: usize,
+}
+
+// Summary of an indexing run
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct IndexReport {
+    pub indexed: usize,
+    pub added: usize,
+    pub updated: usize,
+    pub removed: usize,
+    pub cancelled: bool,
+}
+
+// Aggregate view of the current file index
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct IndexSummary {
+    pub file_count: usize,
+    pub indexable_count: usize,
+    pub total_bytes: u64,
+    pub by_type: HashMap<FileType, usize>,
+}
+
+// Content of a file prepared for display in the preview popup
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FilePreview {
+    pub path: PathBuf,
+    pub content: String,
+    pub size: u64,
+    pub truncated: bool,
+    pub binary: bool,
+    // Content is a unified diff, shown with added and removed lines coloured
+    pub diff: bool,
+    // Content is the end of a growing file; the view is anchored to the bottom
+    pub tail: bool,
+}
+
+impl FilePreview {
+    // Builds a preview from the leading bytes of a file of `size` bytes,
+    // truncating to `limit` bytes at the last complete line
+    pub fn from_bytes(path: PathBuf, bytes: &[u8], size: u64, limit: u64) -> Self {
+        let sniff = &bytes[..bytes.len().min(BINARY_SNIFF_BYTES)];
+        if sniff.contains(&0) {
+            return Self {
+                path,
+                content: String::new(),
+                size,
+                truncated: false,
+                binary: true,
+                diff: false,
+                tail: false,
+            };
+        }
+
+        let truncated = size > limit;
+        let mut shown = &bytes[..bytes.len().min(limit as usize)];
+        if truncated {
+            if let Some(newline) = shown.iter().rposition(|b| *b == b'\n') {
+                shown = &shown[..newline];
+            }
+        }
+        let content = String::from_utf8_lossy(shown).into_owned();
+        Self { path, content, size, truncated, binary: false, diff: false, tail: false }
+    }
+
+    // Text produced in the app rather than read from disk, shown under `label`
+    pub fn from_text(label: impl Into<PathBuf>, content: String) -> Self {
+        let size = content.len() as u64;
+        Self {
+            path: label.into(),
+            content,
+            size,
+            truncated: false,
+            binary: false,
+            diff: false,
+            tail: false,
+        }
+    }
+
+    pub fn from_diff(label: impl Into<PathBuf>, diff: String) -> Self {
+        Self { diff: true, ..Self::from_text(label, diff) }
+    }
+
+    pub fn from_tail(path: PathBuf, content: String) -> Self {
+        Self { tail: true, ..Self::from_text(path, content) }
+    }
+
+    pub fn lines(&self) -> Vec<String> {
+        if self.binary {
+            return vec!["binary file, not previewable".to_string()];
+        }
+        let mut lines: Vec<String> = self.content.lines().map(str::to_string).collect();
+        if self.truncated {
+            lines.push(String::new());
+            lines.push(format!(
+                "[truncated: showing {} of {} bytes]",
+                self.content.len(),
+                self.size
+            ));
+        }
+        lines
+    }
+}
+
+// Shared flag used to abort a running indexing pass between files
+#[derive(Debug, Clone, Default)]
+pub struct CancellationToken(Arc<AtomicBool>);
+
+impl CancellationToken {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+// Message from a background indexing task
+#[derive(Debug)]
+pub enum IndexEvent {
+    Progress(IndexProgress),
+    Finished(Result<IndexReport, FileSystemError>),
+}
 
 // Manages file system operations, indexing, and searching
+#[derive(Clone)]
 pub struct FileSystemManager {
     indexed_sources: Vec<DataSource>,
     file_index: HashMap<PathBuf, FileInfo>,
     include_patterns: Vec<Regex>,
     exclude_patterns: Vec<Regex>,
+    // Compiled per-source overrides, keyed by source path
+    source_patterns: HashMap<PathBuf, SourcePatterns>,
+    max_file_size_bytes: u64,
+    // Whether a single-file source over the size limit is refused or only warned about
+    reject_oversized_files: bool,
+    allowed_roots: Vec<PathBuf>,
+}
+
+#[derive(Debug, Clone, Default)]
+struct SourcePatterns {
+    include: Option<Vec<Regex>>,
+    exclude: Option<Vec<Regex>>,
+}
+
+impl Default for FileSystemManager {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl FileSystemManager {
@@ -19,10 +206,55 @@ impl FileSystemManager {
             file_index: HashMap::new(),
             include_patterns: Vec::new(),
             exclude_patterns: Vec::new(),
+            source_patterns: HashMap::new(),
+            max_file_size_bytes: DEFAULT_MAX_FILE_SIZE_BYTES,
+            reject_oversized_files: true,
+            allowed_roots: Vec::new(),
         }
     }
 
+    pub fn set_max_file_size_bytes(&mut self, max_file_size_bytes: u64) {
+        self.max_file_size_bytes = max_file_size_bytes;
+    }
+
+    pub fn set_reject_oversized_files(&mut self, reject: bool) {
+        self.reject_oversized_files = reject;
+    }
+
+    /// Size of `path` when it is a single file over the size limit. Files in
+    /// directory sources are checked one by one while indexing instead.
+    pub fn oversized_file(&self, path: &Path) -> Option<u64> {
+        let metadata = std::fs::metadata(path).ok()?;
+        (metadata.is_file() && metadata.len() > self.max_file_size_bytes).then_some(metadata.len())
+    }
+
+    // Restricts sources to these directories; an empty list allows any path.
+    // Roots are canonicalized so symlinks and `..` can't be used to escape them.
+    pub fn set_allowed_roots(&mut self, roots: Vec<PathBuf>) {
+        self.allowed_roots = roots
+            .into_iter()
+            .map(|root| root.canonicalize().unwrap_or(root))
+            .collect();
+    }
+
+    // Whether a canonical path lies under one of the allowed roots
+    fn is_allowed(&self, path: &Path) -> bool {
+        self.allowed_roots.is_empty() || self.allowed_roots.iter().any(|root| path.starts_with(root))
+    }
+
     pub fn add_source(&mut self, path: PathBuf) -> Result<(), FileSystemError> {
+        self.add_source_with_patterns(path, None, None)
+    }
+
+    /// Adds a source whose own include/exclude patterns, where given, are used
+    /// instead of the global ones when indexing it. Re-adding an existing
+    /// source replaces its patterns.
+    pub fn add_source_with_patterns(
+        &mut self,
+        path: PathBuf,
+        include_patterns: Option<Vec<String>>,
+        exclude_patterns: Option<Vec<String>>,
+    ) -> Result<(), FileSystemError> {
         if !path.exists() {
             return Err(FileSystemError::FileAccess(format!(
                 "Path does not exist: {:?}",
@@ -30,6 +262,42 @@ impl FileSystemManager {
             )));
         }
 
+        // Sources are stored canonically so different spellings of a path dedup
+        let path = path.canonicalize().map_err(|e| {
+            FileSystemError::FileAccess(format!("Failed to resolve {:?}: {}", path, e))
+        })?;
+        if !self.is_allowed(&path) {
+            return Err(FileSystemError::PermissionDenied(format!(
+                "{:?} is outside the allowed roots",
+                path
+            )));
+        }
+        if let Some(size) = self.oversized_file(&path) {
+            let message = format!(
+                "{:?} is {} bytes, over the {} byte file size limit",
+                path, size, self.max_file_size_bytes
+            );
+            if self.reject_oversized_files {
+                return Err(FileSystemError::FileAccess(message));
+            }
+            warn!("{}; adding it without indexing its content", message);
+        }
+        let patterns = SourcePatterns {
+            include: include_patterns.as_deref().map(compile_patterns).transpose()?,
+            exclude: exclude_patterns.as_deref().map(compile_patterns).transpose()?,
+        };
+        if patterns.include.is_some() || patterns.exclude.is_some() {
+            self.source_patterns.insert(path.clone(), patterns);
+        } else {
+            self.source_patterns.remove(&path);
+        }
+
+        if let Some(source) = self.indexed_sources.iter_mut().find(|source| source.path == path) {
+            source.include_patterns = include_patterns;
+            source.exclude_patterns = exclude_patterns;
+            return Ok(());
+        }
+
         let source_type = if path.is_file() {
             SourceType::File
         } else {
@@ -40,18 +308,22 @@ impl FileSystemManager {
             path,
             source_type,
             last_indexed: Utc::now(),
+            include_patterns,
+            exclude_patterns,
         };
 
         self.indexed_sources.push(data_source);
         Ok(())
     }
 
-    pub fn remove_source(&mut self, path: &PathBuf) -> Result<(), FileSystemError> {
-        self.indexed_sources.retain(|source| &source.path != path);
+    pub fn remove_source(&mut self, path: &Path) -> Result<(), FileSystemError> {
+        let path = canonical_path(path);
+        self.indexed_sources.retain(|source| source.path != path);
+        self.source_patterns.remove(&path);
         
         // Remove files from index that belong to this source
         self.file_index.retain(|file_path, _| {
-            !file_path.starts_with(path)
+            !file_path.starts_with(&path)
         });
         
         Ok(())
@@ -61,19 +333,293 @@ impl FileSystemManager {
         &self.indexed_sources
     }
 
-    pub fn index_sources(&mut self) -> Result<(), FileSystemError> {
-        // TODO: Implement file indexing logic
-        // - Walk through all data sources
-        // - Apply include/exclude patterns
-        // - Determine file types
-        // - Check file sizes for LLM context suitability
-        Ok(())
+    pub fn index_sources(&mut self) -> Result<IndexReport, FileSystemError> {
+        self.index_sources_with_progress(|_| {}, &CancellationToken::new())
+    }
+
+    /// Rebuilds the file index from all sources, calling `on_progress` after
+    /// each file. A first pass collects the candidate paths so the total is
+    /// known up front; the second pass reads metadata for each of them.
+    ///
+    /// `cancel` is checked between files. A cancelled run keeps whatever was
+    /// indexed so far and reports `cancelled: true`.
+    pub fn index_sources_with_progress<F>(
+        &mut self,
+        on_progress: F,
+        cancel: &CancellationToken,
+    ) -> Result<IndexReport, FileSystemError>
+    where
+        F: FnMut(IndexProgress),
+    {
+        self.index_matching(None, on_progress, cancel)
+    }
+
+    // Takes the index built on a copy of this manager, source by source.
+    // Sources added or re-indexed while it was being built keep their newer
+    // entries, and sources removed meanwhile are not brought back.
+    fn adopt_index(&mut self, indexed: FileSystemManager) {
+        for source in &mut self.indexed_sources {
+            let Some(done) = indexed.indexed_sources.iter().find(|done| done.path == source.path) else {
+                continue;
+            };
+            if source.last_indexed > done.last_indexed {
+                continue;
+            }
+            self.file_index.retain(|path, _| !path.starts_with(&source.path));
+            self.file_index.extend(
+                indexed
+                    .file_index
+                    .iter()
+                    .filter(|(path, _)| path.starts_with(&source.path))
+                    .map(|(path, info)| (path.clone(), info.clone())),
+            );
+            source.last_indexed = done.last_indexed;
+        }
+        let sources = &self.indexed_sources;
+        self.file_index.retain(|path, _| sources.iter().any(|source| path.starts_with(&source.path)));
+    }
+
+    /// Re-indexes a single configured source, leaving other sources untouched
+    pub fn reindex_source(&mut self, path: &Path) -> Result<IndexReport, FileSystemError> {
+        let path = canonical_path(path);
+        if !self.indexed_sources.iter().any(|source| source.path == path) {
+            return Err(FileSystemError::FileAccess(format!(
+                "Not a configured source: {:?}",
+                path
+            )));
+        }
+        self.index_matching(Some(&path), |_| {}, &CancellationToken::new())
+    }
+
+    fn index_matching<F>(
+        &mut self,
+        only_source: Option<&Path>,
+        mut on_progress: F,
+        cancel: &CancellationToken,
+    ) -> Result<IndexReport, FileSystemError>
+    where
+        F: FnMut(IndexProgress),
+    {
+        let in_scope = |path: &Path| only_source.is_none_or(|source| path.starts_with(source));
+
+        let mut candidates = Vec::new();
+        let sources = self
+            .indexed_sources
+            .iter()
+            .filter(|source| only_source.is_none_or(|path| source.path == path));
+        'sources: for source in sources {
+            match source.source_type {
+                // Explicitly added files are indexed regardless of patterns
+                SourceType::File => candidates.push(source.path.clone()),
+                SourceType::Directory => {
+                    for entry in WalkDir::new(&source.path).follow_links(true) {
+                        if cancel.is_cancelled() {
+                            break 'sources;
+                        }
+                        // One unreadable entry, e.g. a dangling link, shouldn't cost the whole source
+                        let entry = match entry {
+                            Ok(entry) => entry,
+                            Err(e) => {
+                                warn!("Skipping an entry while walking {:?}: {}", source.path, e);
+                                continue;
+                            }
+                        };
+                        if !entry.file_type().is_file()
+                            || !self.matches_patterns(entry.path(), &source.path)
+                        {
+                            continue;
+                        }
+                        // Links are followed, so check where each file really lives
+                        let resolved = entry.path().canonicalize().is_ok_and(|real| self.is_allowed(&real));
+                        if resolved {
+                            candidates.push(entry.into_path());
+                        } else {
+                            warn!("Skipping {:?}: it resolves outside the allowed roots", entry.path());
+                        }
+                    }
+                }
+            }
+        }
+
+        let total = candidates.len();
+        let mut file_index = HashMap::with_capacity(total);
+        let mut cancelled = false;
+        for (processed, path) in candidates.into_iter().enumerate() {
+            if cancel.is_cancelled() {
+                cancelled = true;
+                break;
+            }
+            // A file can go away or become unreadable after the walk found it
+            match self.build_file_info(&path) {
+                Ok(info) => {
+                    file_index.insert(path, info);
+                }
+                Err(e) => warn!("Skipping {:?}: {}", path, e),
+            }
+            on_progress(IndexProgress {
+                processed: processed + 1,
+                total,
+            });
+        }
+
+        let mut report = IndexReport {
+            indexed: file_index.len(),
+            cancelled: cancelled || cancel.is_cancelled(),
+            ..IndexReport::default()
+        };
+        for (path, info) in &file_index {
+            match self.file_index.get(path) {
+                None => report.added += 1,
+                Some(old) if old.modified != info.modified || old.size != info.size => {
+                    report.updated += 1
+                }
+                Some(_) => {}
+            }
+        }
+        report.removed = self
+            .file_index
+            .keys()
+            .filter(|path| in_scope(path) && !file_index.contains_key(*path))
+            .count();
+
+        self.file_index.retain(|path, _| !in_scope(path));
+        self.file_index.extend(file_index);
+        if !report.cancelled {
+            let now = Utc::now();
+            for source in &mut self.indexed_sources {
+                if in_scope(&source.path) {
+                    source.last_indexed = now;
+                }
+            }
+        }
+        Ok(report)
+    }
+
+    // Checks `path` against the patterns of `source`, falling back to the
+    // global ones for whichever list the source does not override
+    fn matches_patterns(&self, path: &Path, source: &Path) -> bool {
+        let overrides = self.source_patterns.get(source);
+        let include = overrides
+            .and_then(|patterns| patterns.include.as_ref())
+            .unwrap_or(&self.include_patterns);
+        let exclude = overrides
+            .and_then(|patterns| patterns.exclude.as_ref())
+            .unwrap_or(&self.exclude_patterns);
+
+        let path = path.to_string_lossy();
+        if exclude.iter().any(|pattern| pattern.is_match(&path)) {
+            return false;
+        }
+        include.is_empty() || include.iter().any(|pattern| pattern.is_match(&path))
     }
 
+    fn build_file_info(&self, path: &Path) -> Result<FileInfo, FileSystemError> {
+        let metadata = std::fs::metadata(path).map_err(|e| {
+            FileSystemError::FileAccess(format!("Failed to read metadata for {:?}: {}", path, e))
+        })?;
+        let modified = metadata
+            .modified()
+            .map(DateTime::<Utc>::from)
+            .unwrap_or_else(|_| Utc::now());
+
+        let file_type = detect_file_type(path);
+        let indexable = !matches!(file_type, FileType::Binary) && metadata.len() <= self.max_file_size_bytes;
+        let content_hash = if indexable {
+            std::fs::read(path).ok().map(|content| content_hash(&content))
+        } else {
+            None
+        };
+
+        Ok(FileInfo {
+            path: path.to_path_buf(),
+            size: metadata.len(),
+            modified,
+            file_type,
+            indexable,
+            content_hash,
+        })
+    }
+
+    /// Case-insensitive keyword search over indexable files, best matches first.
+    /// The score blends keyword coverage (how many distinct keywords matched)
+    /// with hit density, and falls in the range 0.0..=1.0. Files with identical
+    /// content are searched once, under the first path, listing the others.
     pub fn search_files(&self, keywords: &[String]) -> Result<Vec<SearchResult>, FileSystemError> {
-        // TODO: Implement keyword search across indexed files
-        let _keywords = keywords; // Suppress unused warning
-        Ok(Vec::new())
+        let keywords: Vec<String> = keywords
+            .iter()
+            .map(|keyword| keyword.trim().to_lowercase())
+            .filter(|keyword| !keyword.is_empty())
+            .collect();
+        if keywords.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        let mut files: Vec<&FileInfo> = self.file_index.values().filter(|info| info.indexable).collect();
+        files.sort_by(|a, b| a.path.cmp(&b.path));
+        let mut duplicates: HashMap<u64, Vec<PathBuf>> = HashMap::new();
+        let mut unique = Vec::with_capacity(files.len());
+        for info in files {
+            match info.content_hash {
+                Some(hash) if duplicates.contains_key(&hash) => {
+                    duplicates.entry(hash).or_default().push(info.path.clone())
+                }
+                Some(hash) => {
+                    duplicates.insert(hash, Vec::new());
+                    unique.push(info);
+                }
+                None => unique.push(info),
+            }
+        }
+
+        let mut results = Vec::new();
+        for info in unique {
+            // Files can disappear between indexing and searching; skip them
+            let Ok(content) = self.read_file_content(&info.path) else {
+                continue;
+            };
+
+            let mut matched = vec![false; keywords.len()];
+            let mut hits = 0;
+            let mut matching_lines = Vec::new();
+            let lines: Vec<&str> = content.lines().collect();
+            for (line_number, line) in lines.iter().enumerate() {
+                let lowered = line.to_lowercase();
+                let mut line_matched = false;
+                for (i, keyword) in keywords.iter().enumerate() {
+                    let count = lowered.matches(keyword.as_str()).count();
+                    if count > 0 {
+                        matched[i] = true;
+                        hits += count;
+                        line_matched = true;
+                    }
+                }
+                if line_matched {
+                    matching_lines.push((line_number + 1, line.to_string()));
+                }
+            }
+
+            if matching_lines.is_empty() {
+                continue;
+            }
+
+            let coverage = matched.iter().filter(|m| **m).count() as f32 / keywords.len() as f32;
+            let density = hits.min(SEARCH_DENSITY_CAP) as f32 / SEARCH_DENSITY_CAP as f32;
+            let snippet = build_snippet(&lines, &matching_lines);
+
+            results.push(SearchResult {
+                file_path: info.path.clone(),
+                relevance_score: 0.7 * coverage + 0.3 * density,
+                matching_lines,
+                snippet,
+                duplicates: info
+                    .content_hash
+                    .and_then(|hash| duplicates.get(&hash).cloned())
+                    .unwrap_or_default(),
+            });
+        }
+
+        results.sort_by(|a, b| b.relevance_score.total_cmp(&a.relevance_score));
+        Ok(results)
     }
 
     pub fn read_file_content(&self, path: &PathBuf) -> Result<String, FileSystemError> {
@@ -82,31 +628,877 @@ impl FileSystemManager {
         })
     }
 
-    pub fn set_include_patterns(&mut self, patterns: Vec<String>) -> Result<(), FileSystemError> {
-        let mut compiled_patterns = Vec::new();
-        for pattern in patterns {
-            let regex = Regex::new(&pattern).map_err(|e| {
-                FileSystemError::Indexing(format!("Invalid regex pattern '{}': {}", pattern, e))
-            })?;
-            compiled_patterns.push(regex);
+    /// Reads a file on the model's behalf. Only files inside a configured
+    /// source qualify, and the size limit and binary check still apply.
+    pub fn read_source_file(&self, path: &Path) -> Result<String, FileSystemError> {
+        let canonical = path.canonicalize().map_err(|e| {
+            FileSystemError::FileAccess(format!("Failed to resolve {:?}: {}", path, e))
+        })?;
+        if !self
+            .indexed_sources
+            .iter()
+            .any(|source| canonical.starts_with(&source.path))
+        {
+            return Err(FileSystemError::PermissionDenied(format!(
+                "{:?} is not inside a configured source",
+                path
+            )));
+        }
+
+        let info = self.build_file_info(&canonical)?;
+        if matches!(info.file_type, FileType::Binary) {
+            return Err(FileSystemError::FileAccess(format!("{:?} is a binary file", path)));
+        }
+        if info.size > self.max_file_size_bytes {
+            return Err(FileSystemError::FileAccess(format!(
+                "{:?} is larger than the {} byte limit",
+                path, self.max_file_size_bytes
+            )));
+        }
+        self.read_file_content(&canonical)
+    }
+
+    pub fn preview_file(&self, path: &Path) -> Result<FilePreview, FileSystemError> {
+        let to_error =
+            |e: std::io::Error| FileSystemError::FileAccess(format!("Failed to read file {:?}: {}", path, e));
+        let file = std::fs::File::open(path).map_err(to_error)?;
+        let size = file.metadata().map_err(to_error)?.len();
+        let limit = PREVIEW_MAX_BYTES.min(self.max_file_size_bytes);
+
+        let mut bytes = Vec::new();
+        file.take(limit.max(BINARY_SNIFF_BYTES as u64))
+            .read_to_end(&mut bytes)
+            .map_err(to_error)?;
+        Ok(FilePreview::from_bytes(path.to_path_buf(), &bytes, size, limit))
+    }
+
+    // Reads lines start_line..=end_line (1-based), clamping the span to the file;
+    // a span that starts past the end of the file yields an empty string
+    pub fn read_file_range(
+        &self,
+        path: &PathBuf,
+        start_line: usize,
+        end_line: usize,
+    ) -> Result<String, FileSystemError> {
+        let content = self.read_file_content(path)?;
+        let start = start_line.max(1);
+        if end_line < start {
+            return Ok(String::new());
         }
-        self.include_patterns = compiled_patterns;
+        let lines: Vec<&str> = content
+            .lines()
+            .skip(start - 1)
+            .take(end_line - start + 1)
+            .collect();
+        Ok(lines.join("\n"))
+    }
+
+    pub fn set_include_patterns(&mut self, patterns: Vec<String>) -> Result<(), FileSystemError> {
+        self.include_patterns = compile_patterns(&patterns)?;
         Ok(())
     }
 
     pub fn set_exclude_patterns(&mut self, patterns: Vec<String>) -> Result<(), FileSystemError> {
-        let mut compiled_patterns = Vec::new();
-        for pattern in patterns {
-            let regex = Regex::new(&pattern).map_err(|e| {
-                FileSystemError::Indexing(format!("Invalid regex pattern '{}': {}", pattern, e))
-            })?;
-            compiled_patterns.push(regex);
-        }
-        self.exclude_patterns = compiled_patterns;
+        self.exclude_patterns = compile_patterns(&patterns)?;
         Ok(())
     }
 
+    /// Every indexed file, sorted by path so prompts and listings built from
+    /// it don't depend on hash order
     pub fn get_indexed_files(&self) -> Vec<&FileInfo> {
-        self.file_index.values().collect()
+        let mut files: Vec<&FileInfo> = self.file_index.values().collect();
+        files.sort_by(|a, b| a.path.cmp(&b.path));
+        files
+    }
+
+    pub fn index_summary(&self) -> IndexSummary {
+        let mut summary = IndexSummary::default();
+        for info in self.file_index.values() {
+            summary.file_count += 1;
+            summary.total_bytes += info.size;
+            if info.indexable {
+                summary.indexable_count += 1;
+            }
+            *summary.by_type.entry(info.file_type.clone()).or_default() += 1;
+        }
+        summary
+    }
+
+    /// Writes the metadata of every indexed file to `path`, sorted by path:
+    /// CSV if the file name ends in `.csv`, JSON otherwise. Returns the entry count.
+    pub fn export_index(&self, path: &Path) -> Result<usize, FileSystemError> {
+        let files = self.get_indexed_files();
+        let is_csv = path
+            .extension()
+            .is_some_and(|extension| extension.eq_ignore_ascii_case("csv"));
+        let content = if is_csv {
+            let mut csv = String::from("path,size,type,indexable,modified\n");
+            for info in &files {
+                csv.push_str(&format!(
+                    "{},{},{},{},{}\n",
+                    csv_field(&info.path.display().to_string()),
+                    info.size,
+                    csv_field(info.file_type.label()),
+                    info.indexable,
+                    info.modified.to_rfc3339()
+                ));
+            }
+            csv
+        } else {
+            serde_json::to_string_pretty(&files).map_err(|e| {
+                FileSystemError::FileAccess(format!("Failed to serialize index: {}", e))
+            })?
+        };
+
+        std::fs::write(path, content).map_err(|e| {
+            FileSystemError::FileAccess(format!("Failed to write {:?}: {}", path, e))
+        })?;
+        Ok(files.len())
+    }
+}
+
+// Quotes a CSV field when it contains a separator, quote or line break
+fn csv_field(value: &str) -> String {
+    if value.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
+}
+
+fn compile_patterns(patterns: &[String]) -> Result<Vec<Regex>, FileSystemError> {
+    patterns
+        .iter()
+        .map(|pattern| {
+            Regex::new(pattern).map_err(|e| {
+                FileSystemError::Indexing(format!("Invalid regex pattern '{}': {}", pattern, e))
+            })
+        })
+        .collect()
+}
+
+/// Indexes all sources on a blocking thread, sending progress and then the
+/// result over the returned channel. The walk runs on a copy of the manager,
+/// so the shared one is only locked to take the copy and to store the index.
+pub fn spawn_indexing(
+    manager: Arc<RwLock<FileSystemManager>>,
+    cancel: CancellationToken,
+) -> (JoinHandle<()>, mpsc::UnboundedReceiver<IndexEvent>) {
+    let (sender, receiver) = mpsc::unbounded_channel();
+    let handle = tokio::task::spawn_blocking(move || {
+        let mut snapshot = manager.read().unwrap_or_else(PoisonError::into_inner).clone();
+        let result = snapshot.index_sources_with_progress(
+            |progress| {
+                let _ = sender.send(IndexEvent::Progress(progress));
+            },
+            &cancel,
+        );
+        if result.is_ok() {
+            manager.write().unwrap_or_else(PoisonError::into_inner).adopt_index(snapshot);
+        }
+        let _ = sender.send(IndexEvent::Finished(result));
+    });
+    (handle, receiver)
+}
+
+/// Reads at most the last `max_bytes` of a file. When that starts partway
+/// through, the partial first line is dropped.
+pub fn read_tail(path: &Path, max_bytes: u64) -> Result<String, FileSystemError> {
+    use std::io::{Seek, SeekFrom};
+
+    let access = |e: std::io::Error| FileSystemError::FileAccess(format!("Failed to read {:?}: {}", path, e));
+    let mut file = std::fs::File::open(path).map_err(access)?;
+    let len = file.metadata().map_err(access)?.len();
+    let start = len.saturating_sub(max_bytes);
+    file.seek(SeekFrom::Start(start)).map_err(access)?;
+    let mut bytes = Vec::with_capacity((len - start) as usize);
+    file.read_to_end(&mut bytes).map_err(access)?;
+
+    let mut shown = bytes.as_slice();
+    if start > 0 {
+        if let Some(newline) = shown.iter().position(|b| *b == b'\n') {
+            shown = &shown[newline + 1..];
+        }
+    }
+    Ok(String::from_utf8_lossy(shown).into_owned())
+}
+
+/// Reads a PNG, JPEG, GIF or WebP file and base64-encodes it for attaching
+/// to a message
+pub fn read_image(path: &Path) -> Result<ImageAttachment, FileSystemError> {
+    use base64::Engine;
+
+    let extension = path
+        .extension()
+        .and_then(|ext| ext.to_str())
+        .map(str::to_ascii_lowercase)
+        .unwrap_or_default();
+    let media_type = match extension.as_str() {
+        "png" => "image/png",
+        "jpg" | "jpeg" => "image/jpeg",
+        "gif" => "image/gif",
+        "webp" => "image/webp",
+        _ => {
+            return Err(FileSystemError::FileAccess(format!(
+                "{:?} is not a PNG, JPEG, GIF or WebP image",
+                path
+            )))
+        }
+    };
+    let access = |e: std::io::Error| FileSystemError::FileAccess(format!("Failed to read {:?}: {}", path, e));
+    let size = std::fs::metadata(path).map_err(access)?.len();
+    if size > MAX_IMAGE_BYTES {
+        return Err(FileSystemError::FileAccess(format!(
+            "{:?} is {} bytes, over the {} byte image limit",
+            path, size, MAX_IMAGE_BYTES
+        )));
+    }
+    let bytes = std::fs::read(path).map_err(access)?;
+
+    Ok(ImageAttachment {
+        name: path
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+            .unwrap_or_default(),
+        media_type: media_type.to_string(),
+        data: base64::engine::general_purpose::STANDARD.encode(bytes),
+    })
+}
+
+// FNV-1a over the file content; stable across runs, unlike the std hasher
+fn content_hash(content: &[u8]) -> u64 {
+    content.iter().fold(0xcbf29ce484222325, |hash, byte| {
+        (hash ^ *byte as u64).wrapping_mul(0x100000001b3)
+    })
+}
+
+// Groups matching line numbers into spans, joining matches within
+// SNIPPET_MERGE_GAP lines of each other so nearby hits read as one passage
+fn snippet_regions(matching_lines: &[(usize, String)]) -> Vec<(usize, usize)> {
+    let mut regions: Vec<(usize, usize)> = Vec::new();
+    for (line_number, _) in matching_lines {
+        match regions.last_mut() {
+            Some((start, end))
+                if *line_number <= *end + SNIPPET_MERGE_GAP
+                    && *line_number - *start < SNIPPET_REGION_LINES =>
+            {
+                *end = *line_number
+            }
+            _ => regions.push((*line_number, *line_number)),
+        }
+    }
+    regions.truncate(SNIPPET_REGIONS);
+    regions
+}
+
+// Numbered lines of each snippet region, with an ellipsis between regions
+fn build_snippet(lines: &[&str], matching_lines: &[(usize, String)]) -> String {
+    snippet_regions(matching_lines)
+        .into_iter()
+        .map(|(start, end)| {
+            (start..=end)
+                .filter_map(|line_number| {
+                    let line = lines.get(line_number - 1)?;
+                    Some(format!("{}: {}", line_number, line.trim()))
+                })
+                .collect::<Vec<_>>()
+                .join("\n")
+        })
+        .collect::<Vec<_>>()
+        .join("\n...\n")
+}
+
+// Resolves a path to its canonical form, falling back to the path as given
+// when it can't be resolved (e.g. it no longer exists)
+pub fn canonical_path(path: &Path) -> PathBuf {
+    path.canonicalize().unwrap_or_else(|_| path.to_path_buf())
+}
+
+// Classifies a file by extension, sniffing the content when the extension is unknown
+pub fn detect_file_type(path: &Path) -> FileType {
+    let extension = path
+        .extension()
+        .map(|ext| ext.to_string_lossy().to_lowercase())
+        .unwrap_or_default();
+
+    match extension.as_str() {
+        "txt" => FileType::Text,
+        "md" | "markdown" => FileType::Markdown,
+        "json" => FileType::Json,
+        "toml" | "yaml" | "yml" | "ini" | "cfg" | "conf" => FileType::Config,
+        "log" => FileType::Log,
+        "rs" | "py" | "js" | "ts" | "go" | "c" | "h" | "cpp" | "hpp" | "java" | "rb" | "sh"
+        | "cs" | "kt" | "swift" | "php" | "sql" | "html" | "css" => FileType::Code(extension),
+        _ if is_binary_file(path) => FileType::Binary,
+        _ => FileType::Text,
+    }
+}
+
+// Treats a file as binary if its leading bytes contain a NUL byte
+pub fn is_binary_file(path: &Path) -> bool {
+    let Ok(file) = std::fs::File::open(path) else {
+        return false;
+    };
+
+    let mut buffer = Vec::with_capacity(BINARY_SNIFF_BYTES);
+    if file.take(BINARY_SNIFF_BYTES as u64).read_to_end(&mut buffer).is_err() {
+        return false;
+    }
+    buffer.contains(&0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use tempfile::TempDir;
+
+    fn create_test_tree() -> TempDir {
+        let temp_dir = TempDir::new().expect("Failed to create temp dir");
+        let root = temp_dir.path();
+        fs::create_dir_all(root.join("docs")).unwrap();
+        fs::create_dir_all(root.join(".git")).unwrap();
+        fs::write(root.join("notes.txt"), "plain notes").unwrap();
+        fs::write(root.join("docs").join("guide.md"), "# Guide").unwrap();
+        fs::write(root.join("docs").join("settings.json"), "{}").unwrap();
+        fs::write(root.join(".git").join("HEAD.txt"), "ref").unwrap();
+        fs::write(root.join("image.png"), [0x89, 0x50, 0x00, 0x47]).unwrap();
+        temp_dir
+    }
+
+    fn manager_with_default_patterns() -> FileSystemManager {
+        let config = crate::config::AppConfig::default();
+        let mut manager = FileSystemManager::new();
+        manager.set_include_patterns(config.include_patterns).unwrap();
+        manager.set_exclude_patterns(config.exclude_patterns).unwrap();
+        manager
+    }
+
+    #[tokio::test]
+    async fn test_spawned_indexing_reports_completion_to_shared_manager() {
+        let temp_dir = create_test_tree();
+        let mut manager = manager_with_default_patterns();
+        manager.add_source(temp_dir.path().to_path_buf()).unwrap();
+        let manager = Arc::new(RwLock::new(manager));
+
+        let (handle, mut events) = spawn_indexing(manager.clone(), CancellationToken::new());
+        let mut progress = Vec::new();
+        let report = loop {
+            match events.recv().await.expect("Indexing ended without a result") {
+                IndexEvent::Progress(update) => progress.push(update),
+                IndexEvent::Finished(result) => break result.unwrap(),
+            }
+        };
+        handle.await.unwrap();
+
+        assert_eq!(report.added, progress.len());
+        let last = progress.last().unwrap();
+        assert_eq!((last.processed, last.total), (report.indexed, report.indexed));
+        let manager = manager.read().unwrap();
+        assert_eq!(manager.get_indexed_files().len(), report.indexed);
+        let guide = temp_dir.path().join("docs").join("guide.md").canonicalize().unwrap();
+        assert!(manager.get_indexed_files().iter().any(|info| info.path == guide));
+    }
+
+    #[test]
+    fn test_adopted_index_keeps_sources_added_meanwhile() {
+        let temp_dir = create_test_tree();
+        let other = TempDir::new().unwrap();
+        fs::write(other.path().join("extra.md"), "extra").unwrap();
+        let mut manager = manager_with_default_patterns();
+        manager.add_source(temp_dir.path().to_path_buf()).unwrap();
+        let mut snapshot = manager.clone();
+        snapshot.index_sources().unwrap();
+
+        manager.add_source(other.path().to_path_buf()).unwrap();
+        manager.reindex_source(other.path()).unwrap();
+        manager.adopt_index(snapshot);
+
+        let extra = other.path().join("extra.md").canonicalize().unwrap();
+        let guide = temp_dir.path().join("docs").join("guide.md").canonicalize().unwrap();
+        let files = manager.get_indexed_files();
+        assert!(files.iter().any(|info| info.path == extra));
+        assert!(files.iter().any(|info| info.path == guide));
+    }
+
+    #[test]
+    fn test_read_tail_keeps_last_whole_lines() {
+        let temp_dir = TempDir::new().expect("Failed to create temp dir");
+        let path = temp_dir.path().join("app.log");
+        fs::write(&path, "first line\nsecond line\nthird\n").unwrap();
+
+        // The cut lands inside "second line", so that line is dropped
+        assert_eq!(read_tail(&path, 12).unwrap(), "third\n");
+        // Smaller files are returned whole
+        assert_eq!(read_tail(&path, 1024).unwrap(), "first line\nsecond line\nthird\n");
+        assert!(read_tail(&temp_dir.path().join("missing.log"), 1024).is_err());
+    }
+
+    #[test]
+    fn test_identical_files_collapse_to_one_search_result() {
+        let temp_dir = create_test_tree();
+        let vendor = temp_dir.path().join("vendor");
+        fs::create_dir_all(&vendor).unwrap();
+        fs::write(temp_dir.path().join("docs").join("copy.md"), "# Guide").unwrap();
+        fs::write(vendor.join("guide.md"), "# Guide").unwrap();
+        let mut manager = manager_with_default_patterns();
+        manager.add_source(temp_dir.path().to_path_buf()).unwrap();
+        manager.index_sources().unwrap();
+
+        let results = manager.search_files(&["guide".to_string()]).unwrap();
+        assert_eq!(results.len(), 1);
+        let root = temp_dir.path().canonicalize().unwrap();
+        assert_eq!(results[0].file_path, root.join("docs").join("copy.md"));
+        assert_eq!(
+            results[0].duplicates,
+            vec![root.join("docs").join("guide.md"), root.join("vendor").join("guide.md")]
+        );
+    }
+
+    #[test]
+    fn test_index_sources_applies_patterns() {
+        let temp_dir = create_test_tree();
+        let mut manager = manager_with_default_patterns();
+        manager.add_source(temp_dir.path().to_path_buf()).unwrap();
+
+        manager.index_sources().expect("Failed to index sources");
+
+        let mut names: Vec<String> = manager
+            .get_indexed_files()
+            .iter()
+            .map(|info| info.path.file_name().unwrap().to_string_lossy().to_string())
+            .collect();
+        names.sort();
+        assert_eq!(names, vec!["guide.md", "notes.txt", "settings.json"]);
+    }
+
+    #[test]
+    fn test_indexed_files_are_sorted_by_path() {
+        let temp_dir = TempDir::new().expect("Failed to create temp dir");
+        let names = ["zeta.md", "alpha.md", "mid.md", "beta.md"];
+        for name in names.iter().rev() {
+            std::fs::write(temp_dir.path().join(name), "text").unwrap();
+        }
+        let mut manager = FileSystemManager::new();
+        manager.add_source(temp_dir.path().to_path_buf()).unwrap();
+        manager.index_sources().unwrap();
+        // Files added singly in a different order end up in the same list
+        let mut singly = FileSystemManager::new();
+        for name in names {
+            singly.add_source(temp_dir.path().join(name)).unwrap();
+        }
+        singly.index_sources().unwrap();
+
+        let paths: Vec<PathBuf> = manager.get_indexed_files().iter().map(|info| info.path.clone()).collect();
+        let mut sorted = paths.clone();
+        sorted.sort();
+        assert_eq!(paths.len(), 4);
+        assert_eq!(paths, sorted);
+        let singly: Vec<PathBuf> = singly.get_indexed_files().iter().map(|info| info.path.clone()).collect();
+        assert_eq!(singly, paths);
+    }
+
+    #[test]
+    fn test_source_patterns_override_globals() {
+        let temp_dir = create_test_tree();
+        let mut manager = manager_with_default_patterns();
+        manager
+            .add_source_with_patterns(
+                temp_dir.path().to_path_buf(),
+                Some(vec![r"\.md$".to_string()]),
+                None,
+            )
+            .unwrap();
+
+        let report = manager.index_sources().expect("Failed to index sources");
+        // The global defaults would pick up three files here
+        assert_eq!(report.indexed, 1);
+        assert!(manager.get_indexed_files()[0].path.ends_with("guide.md"));
+
+        // Re-adding without patterns restores the globals
+        manager.add_source(temp_dir.path().to_path_buf()).unwrap();
+        assert_eq!(manager.index_sources().unwrap().indexed, 3);
+        assert!(manager.list_sources()[0].include_patterns.is_none());
+    }
+
+    #[test]
+    fn test_index_marks_oversized_files_unindexable() {
+        let temp_dir = create_test_tree();
+        let mut manager = manager_with_default_patterns();
+        manager.set_max_file_size_bytes(8);
+        manager.add_source(temp_dir.path().to_path_buf()).unwrap();
+
+        manager.index_sources().expect("Failed to index sources");
+
+        for info in manager.get_indexed_files() {
+            assert_eq!(info.indexable, info.size <= 8, "{:?}", info.path);
+        }
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_index_progress_is_monotonic() {
+        let temp_dir = create_test_tree();
+        let mut manager = manager_with_default_patterns();
+        manager.add_source(temp_dir.path().to_path_buf()).unwrap();
+
+        let mut updates = Vec::new();
+        manager
+            .index_sources_with_progress(|progress| updates.push(progress), &CancellationToken::new())
+            .expect("Failed to index sources");
+
+        assert_eq!(updates.len(), 3);
+        for (i, progress) in updates.iter().enumerate() {
+            assert_eq!(progress.processed, i + 1);
+            assert_eq!(progress.total, 3);
+        }
+    }
+
+    #[test]
+    fn test_cancel_after_first_file_keeps_partial_index() {
+        let temp_dir = create_test_tree();
+        let mut manager = manager_with_default_patterns();
+        manager.add_source(temp_dir.path().to_path_buf()).unwrap();
+
+        let cancel = CancellationToken::new();
+        let report = manager
+            .index_sources_with_progress(|_| cancel.cancel(), &cancel)
+            .expect("Failed to index sources");
+
+        assert!(report.cancelled);
+        assert_eq!(report.indexed, 1);
+        assert_eq!(manager.get_indexed_files().len(), 1);
+    }
+
+    #[test]
+    fn test_reindex_reports_delta() {
+        let temp_dir = create_test_tree();
+        let mut manager = manager_with_default_patterns();
+        manager.add_source(temp_dir.path().to_path_buf()).unwrap();
+
+        let first = manager.index_sources().expect("Failed to index sources");
+        assert_eq!(first.added, 3);
+
+        fs::write(temp_dir.path().join("new.md"), "fresh").unwrap();
+        fs::remove_file(temp_dir.path().join("notes.txt")).unwrap();
+
+        let report = manager
+            .reindex_source(temp_dir.path())
+            .expect("Failed to reindex source");
+        assert_eq!(report.indexed, 3);
+        assert_eq!(report.added, 1);
+        assert_eq!(report.removed, 1);
+        assert!(manager
+            .get_indexed_files()
+            .iter()
+            .any(|info| info.path.ends_with("new.md")));
+    }
+
+    #[test]
+    fn test_add_source_respects_allowed_roots() {
+        let temp_dir = create_test_tree();
+        let allowed = temp_dir.path().join("docs");
+        let mut manager = FileSystemManager::new();
+        manager.set_allowed_roots(vec![allowed.clone()]);
+
+        manager.add_source(allowed.clone()).expect("Allowed root should be accepted");
+        manager
+            .add_source(allowed.join("guide.md"))
+            .expect("Files inside the root should be accepted");
+
+        let outside = manager.add_source(temp_dir.path().to_path_buf());
+        assert!(matches!(outside, Err(FileSystemError::PermissionDenied(_))));
+        let traversal = manager.add_source(allowed.join("..").join("notes.txt"));
+        assert!(matches!(traversal, Err(FileSystemError::PermissionDenied(_))));
+        assert_eq!(manager.list_sources().len(), 2);
+    }
+
+    #[test]
+    fn test_source_spellings_dedup_and_remove_canonically() {
+        let temp_dir = create_test_tree();
+        let docs = temp_dir.path().join("docs");
+        let mut manager = FileSystemManager::new();
+
+        manager.add_source(docs.clone()).unwrap();
+        manager.add_source(temp_dir.path().join("docs/")).unwrap();
+        manager.add_source(temp_dir.path().join(".git/../docs/.")).unwrap();
+        assert_eq!(manager.list_sources().len(), 1);
+
+        manager.index_sources().unwrap();
+        assert!(!manager.get_indexed_files().is_empty());
+        manager
+            .remove_source(&temp_dir.path().join("docs/../docs"))
+            .unwrap();
+        assert!(manager.list_sources().is_empty());
+        assert!(manager.get_indexed_files().is_empty());
+    }
+
+    #[test]
+    fn test_oversized_single_file_source_is_rejected_or_flagged() {
+        let temp_dir = TempDir::new().expect("Failed to create temp dir");
+        let big = temp_dir.path().join("big.log");
+        fs::write(&big, "x".repeat(100)).unwrap();
+        let mut manager = FileSystemManager::new();
+        manager.set_max_file_size_bytes(50);
+
+        let err = manager.add_source(big.clone()).unwrap_err();
+        assert!(err.to_string().contains("100 bytes, over the 50 byte file size limit"));
+        assert!(manager.list_sources().is_empty());
+        // The limit applies per file inside directories, not to the directory
+        manager.add_source(temp_dir.path().to_path_buf()).unwrap();
+
+        manager.set_reject_oversized_files(false);
+        manager.add_source(big.clone()).unwrap();
+        assert_eq!(manager.oversized_file(&big), Some(100));
+        assert_eq!(manager.list_sources().len(), 2);
+    }
+
+    #[test]
+    fn test_read_source_file_is_limited_to_sources() {
+        let temp_dir = create_test_tree();
+        let mut manager = FileSystemManager::new();
+        manager.set_max_file_size_bytes(8);
+        manager.add_source(temp_dir.path().join("docs")).unwrap();
+
+        let guide = temp_dir.path().join("docs").join("guide.md");
+        assert_eq!(manager.read_source_file(&guide).unwrap(), "# Guide");
+        let outside = manager.read_source_file(&temp_dir.path().join("notes.txt"));
+        assert!(matches!(outside, Err(FileSystemError::PermissionDenied(_))));
+        let escape = manager.read_source_file(&temp_dir.path().join("docs/../notes.txt"));
+        assert!(matches!(escape, Err(FileSystemError::PermissionDenied(_))));
+
+        fs::write(temp_dir.path().join("docs").join("big.md"), "123456789").unwrap();
+        assert!(manager.read_source_file(&temp_dir.path().join("docs/big.md")).is_err());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_symlinks_out_of_allowed_roots_are_not_indexed() {
+        let temp_dir = TempDir::new().expect("Failed to create temp dir");
+        let root = temp_dir.path().join("root");
+        let outside = temp_dir.path().join("outside");
+        fs::create_dir_all(root.join("docs")).unwrap();
+        fs::create_dir_all(&outside).unwrap();
+        fs::write(root.join("docs/inside.md"), "inside").unwrap();
+        fs::write(outside.join("secret.md"), "secret").unwrap();
+        std::os::unix::fs::symlink(outside.join("secret.md"), root.join("docs/linked.md")).unwrap();
+        std::os::unix::fs::symlink(&outside, root.join("docs/linked_dir")).unwrap();
+        let mut manager = manager_with_default_patterns();
+        manager.set_allowed_roots(vec![root.clone()]);
+
+        manager.add_source(root.join("docs")).unwrap();
+        manager.index_sources().unwrap();
+
+        let indexed: Vec<&PathBuf> = manager.get_indexed_files().into_iter().map(|info| &info.path).collect();
+        assert_eq!(indexed.len(), 1);
+        assert!(indexed[0].ends_with("inside.md"));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_bad_walk_entry_is_skipped() {
+        let temp_dir = create_test_tree();
+        std::os::unix::fs::symlink(temp_dir.path().join("gone.md"), temp_dir.path().join("docs/dangling.md"))
+            .unwrap();
+        let mut manager = manager_with_default_patterns();
+        manager.add_source(temp_dir.path().to_path_buf()).unwrap();
+
+        let report = manager.index_sources().expect("A dangling link should not fail the source");
+        assert!(report.indexed > 0);
+        let guide = temp_dir.path().join("docs").join("guide.md").canonicalize().unwrap();
+        assert!(manager.get_indexed_files().iter().any(|info| info.path == guide));
+    }
+
+    #[test]
+    fn test_file_gone_after_walk_is_skipped() {
+        let temp_dir = create_test_tree();
+        let mut manager = manager_with_default_patterns();
+        manager.add_source(temp_dir.path().to_path_buf()).unwrap();
+        let docs = temp_dir.path().join("docs");
+        let files = [temp_dir.path().join("notes.txt"), docs.join("guide.md"), docs.join("settings.json")];
+
+        // Whichever file comes first is read, then the others disappear
+        let mut last = None;
+        let report = manager
+            .index_sources_with_progress(
+                |progress| {
+                    for path in &files {
+                        let _ = fs::remove_file(path);
+                    }
+                    last = Some(progress);
+                },
+                &CancellationToken::new(),
+            )
+            .expect("A vanished file should not fail the index");
+        assert_eq!(report.indexed, 1);
+        let last = last.unwrap();
+        assert_eq!(last.processed, last.total);
+    }
+
+    #[test]
+    fn test_empty_allowed_roots_is_unrestricted() {
+        let temp_dir = create_test_tree();
+        let mut manager = FileSystemManager::new();
+        manager.set_allowed_roots(Vec::new());
+
+        assert!(manager.add_source(temp_dir.path().to_path_buf()).is_ok());
+    }
+
+    #[test]
+    fn test_index_summary_counts_files_and_types() {
+        let temp_dir = create_test_tree();
+        fs::write(temp_dir.path().join("docs").join("extra.md"), "# More").unwrap();
+        let mut manager = FileSystemManager::new();
+        manager.set_max_file_size_bytes(7);
+        manager.add_source(temp_dir.path().to_path_buf()).unwrap();
+        manager.index_sources().unwrap();
+
+        let summary = manager.index_summary();
+        // notes.txt, guide.md, extra.md, settings.json, .git/HEAD.txt, image.png
+        assert_eq!(summary.file_count, 6);
+        // notes.txt is over the size limit and image.png is binary
+        assert_eq!(summary.indexable_count, 4);
+        assert_eq!(summary.total_bytes, 11 + 7 + 6 + 2 + 3 + 4);
+        assert_eq!(summary.by_type[&FileType::Markdown], 2);
+        assert_eq!(summary.by_type[&FileType::Text], 2);
+        assert_eq!(summary.by_type[&FileType::Json], 1);
+        assert_eq!(summary.by_type[&FileType::Binary], 1);
+    }
+
+    #[test]
+    fn test_export_index_writes_json_and_csv() {
+        let temp_dir = create_test_tree();
+        let mut manager = FileSystemManager::new();
+        manager.add_source(temp_dir.path().to_path_buf()).unwrap();
+        manager.index_sources().unwrap();
+        let out_dir = TempDir::new().unwrap();
+
+        let json_path = out_dir.path().join("index.json");
+        assert_eq!(manager.export_index(&json_path).unwrap(), 5);
+        let entries: Vec<FileInfo> =
+            serde_json::from_str(&fs::read_to_string(&json_path).unwrap()).unwrap();
+        let guide = entries
+            .iter()
+            .find(|info| info.path.ends_with("docs/guide.md"))
+            .expect("guide.md missing from export");
+        assert_eq!(guide.size, 7);
+        assert_eq!(guide.file_type, FileType::Markdown);
+        assert!(guide.indexable);
+        let image = entries.iter().find(|info| info.path.ends_with("image.png")).unwrap();
+        assert!(!image.indexable);
+
+        let csv_path = out_dir.path().join("index.csv");
+        assert_eq!(manager.export_index(&csv_path).unwrap(), 5);
+        let csv = fs::read_to_string(&csv_path).unwrap();
+        let lines: Vec<&str> = csv.lines().collect();
+        assert_eq!(lines[0], "path,size,type,indexable,modified");
+        assert_eq!(lines.len(), 6);
+        let settings = lines.iter().find(|line| line.contains("settings.json")).unwrap();
+        assert!(settings.contains(",2,json,true,"));
+    }
+
+    #[test]
+    fn test_csv_field_quotes_when_needed() {
+        assert_eq!(csv_field("plain.md"), "plain.md");
+        assert_eq!(csv_field("a,b.md"), "\"a,b.md\"");
+        assert_eq!(csv_field("say \"hi\".md"), "\"say \"\"hi\"\".md\"");
+    }
+
+    #[test]
+    fn test_reindex_unknown_source_fails() {
+        let mut manager = FileSystemManager::new();
+        assert!(manager.reindex_source(Path::new("/not/a/source")).is_err());
+    }
+
+    #[test]
+    fn test_search_files_ranks_by_relevance() {
+        let temp_dir = TempDir::new().expect("Failed to create temp dir");
+        fs::write(temp_dir.path().join("strong.md"), "alpha beta\nbeta gamma\nALPHA").unwrap();
+        fs::write(temp_dir.path().join("weak.md"), "just alpha here").unwrap();
+        fs::write(temp_dir.path().join("none.md"), "nothing relevant").unwrap();
+        let mut manager = manager_with_default_patterns();
+        manager.add_source(temp_dir.path().to_path_buf()).unwrap();
+        manager.index_sources().unwrap();
+
+        let results = manager
+            .search_files(&["alpha".to_string(), "Beta".to_string()])
+            .expect("Search failed");
+
+        assert_eq!(results.len(), 2);
+        assert!(results[0].file_path.ends_with("strong.md"));
+        assert!(results[1].file_path.ends_with("weak.md"));
+        assert!(results[0].relevance_score > results[1].relevance_score);
+        assert_eq!(results[0].matching_lines.len(), 3);
+        assert!(results[1].snippet.contains("1: just alpha here"));
+    }
+
+    #[test]
+    fn test_snippet_merges_nearby_matches() {
+        let lines: Vec<String> = (1..=60).map(|n| format!("line {}", n)).collect();
+        let lines: Vec<&str> = lines.iter().map(String::as_str).collect();
+        let matching_lines: Vec<(usize, String)> =
+            [10, 11, 50].iter().map(|n| (*n, lines[n - 1].to_string())).collect();
+
+        assert_eq!(snippet_regions(&matching_lines), vec![(10, 11), (50, 50)]);
+        assert_eq!(build_snippet(&lines, &matching_lines), "10: line 10\n11: line 11\n...\n50: line 50");
+        // Gap lines between merged matches are kept so the region reads contiguously
+        let matching_lines = vec![(3, String::new()), (6, String::new())];
+        assert_eq!(build_snippet(&lines, &matching_lines), "3: line 3\n4: line 4\n5: line 5\n6: line 6");
+    }
+
+    #[test]
+    fn test_read_file_range_clamps_to_file() {
+        let temp_dir = TempDir::new().expect("Failed to create temp dir");
+        let path = temp_dir.path().join("lines.txt");
+        fs::write(&path, "one\ntwo\nthree\nfour").unwrap();
+        let manager = FileSystemManager::new();
+
+        assert_eq!(manager.read_file_range(&path, 2, 3).unwrap(), "two\nthree");
+        assert_eq!(manager.read_file_range(&path, 0, 1).unwrap(), "one");
+        assert_eq!(manager.read_file_range(&path, 3, 100).unwrap(), "three\nfour");
+        assert_eq!(manager.read_file_range(&path, 10, 20).unwrap(), "");
+        assert_eq!(manager.read_file_range(&path, 3, 2).unwrap(), "");
+        assert!(manager.read_file_range(&temp_dir.path().join("missing.txt"), 1, 2).is_err());
+    }
+
+    #[test]
+    fn test_preview_truncates_large_files_and_rejects_binary() {
+        let path = PathBuf::from("notes.txt");
+
+        let small = FilePreview::from_bytes(path.clone(), b"one\ntwo", 7, 64);
+        assert!(!small.truncated && !small.binary);
+        assert_eq!(small.lines(), vec!["one", "two"]);
+
+        let large = FilePreview::from_bytes(path.clone(), b"first line\nsecond line\n", 100, 16);
+        assert!(large.truncated);
+        assert_eq!(large.content, "first line");
+        assert_eq!(large.lines().last().unwrap(), "[truncated: showing 10 of 100 bytes]");
+
+        let binary = FilePreview::from_bytes(path, &[0x89, 0x50, 0x00, 0x47], 4, 64);
+        assert!(binary.binary);
+        assert_eq!(binary.lines(), vec!["binary file, not previewable"]);
+    }
+
+    #[test]
+    fn test_preview_file_respects_max_file_size() {
+        let temp_dir = TempDir::new().expect("Failed to create temp dir");
+        let path = temp_dir.path().join("big.txt");
+        fs::write(&path, "0123456789\n".repeat(10)).unwrap();
+        let mut manager = FileSystemManager::new();
+        manager.set_max_file_size_bytes(25);
+
+        let preview = manager.preview_file(&path).expect("Failed to preview");
+        assert!(preview.truncated);
+        assert_eq!(preview.content, "0123456789\n0123456789");
+        assert_eq!(preview.size, 110);
+    }
+
+    #[test]
+    fn test_detect_file_type() {
+        let temp_dir = create_test_tree();
+
+        assert!(matches!(detect_file_type(Path::new("a.md")), FileType::Markdown));
+        assert!(matches!(detect_file_type(Path::new("a.yml")), FileType::Config));
+        assert!(matches!(detect_file_type(Path::new("main.rs")), FileType::Code(ext) if ext == "rs"));
+        assert!(matches!(detect_file_type(&temp_dir.path().join("image.png")), FileType::Binary));
+    }
+}
@@ -1,9 +1,13 @@
 pub mod app;
 pub mod config;
 pub mod conversation;
+pub mod diff;
 pub mod filesystem;
 pub mod llm;
 pub mod rag;
+#[cfg(feature = "serve")]
+pub mod server;
+pub mod tokenizer;
 pub mod ui;
 
 pub use types::*;
@@ -11,7 +15,7 @@ pub use types::*;
 pub mod types {
     use chrono::{DateTime, Utc};
     use serde::{Deserialize, Serialize};
-    use std::collections::HashMap;
+    use std::collections::BTreeMap;
     use std::path::PathBuf;
 
     // Core message structure for conversations
@@ -22,6 +26,28 @@ pub mod types {
         pub timestamp: DateTime<Utc>,
         pub provisional: bool,
         pub context_files: Vec<PathBuf>,
+        // On an assistant message, the tool it asked to run; on a tool message,
+        // the call it answers
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        pub tool_call: Option<ToolCall>,
+        // Images sent along with the text, for models that accept them
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        pub images: Vec<ImageAttachment>,
+    }
+
+    #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+    pub struct ImageAttachment {
+        pub name: String, // File name, shown in the conversation
+        pub media_type: String,
+        pub data: String, // Base64-encoded file content
+    }
+
+    #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+    pub struct ToolCall {
+        pub id: String,
+        pub name: String,
+        #[serde(default)]
+        pub arguments: String, // JSON-encoded arguments, as sent by the model
     }
 
     #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -29,23 +55,72 @@ pub mod types {
         User,
         Assistant,
         System,
+        Tool,
+    }
+
+    impl MessageRole {
+        pub fn as_str(&self) -> &'static str {
+            match self {
+                MessageRole::User => "user",
+                MessageRole::Assistant => "assistant",
+                MessageRole::System => "system",
+                MessageRole::Tool => "tool",
+            }
+        }
     }
 
     // User input and actions
     #[derive(Debug, Clone)]
     pub enum UserInput {
         Message(String),
+        // A message sent as provisional (or persistent) regardless of the global mode
+        MessageAs { content: String, provisional: bool },
+        // Answer to the confirmation dialog the controller last asked for
+        Confirm(bool),
         Command(Command),
         KeyAction(KeyAction),
     }
 
+    // Which of the global pattern lists an edit applies to
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub enum PatternKind {
+        Include,
+        Exclude,
+    }
+
+    impl PatternKind {
+        pub fn as_str(&self) -> &'static str {
+            match self {
+                PatternKind::Include => "include",
+                PatternKind::Exclude => "exclude",
+            }
+        }
+    }
+
+    #[derive(Debug, Clone, PartialEq)]
+    pub enum PatternEdit {
+        Add(PatternKind, String),
+        Remove(PatternKind, String),
+        // Moves a pattern one place up (true) or down its list
+        Move(PatternKind, String, bool),
+    }
+
     #[derive(Debug, Clone)]
     pub enum UserAction {
-        SendMessage,
+        // `provisional` overrides the global mode for this one message
+        SendMessage { content: String, provisional: Option<bool> },
+        Confirm(bool),
+        // Provider completed in the setup wizard, to validate and save
+        SaveProvider(LlmProvider),
+        // Change made in the pattern editor, to save and apply
+        EditPatterns(PatternEdit),
+        // No input for the idle timeout: save, then exit if `exit` is set
+        IdleTimeout { exit: bool },
         ExecuteCommand(Command),
         ToggleMode,
         ScrollUp,
         ScrollDown,
+        Redraw,
         Exit,
     }
 
@@ -69,11 +144,50 @@ pub mod types {
         Help,
         Config,
         Clear,
+        // Clears the conversation and returns session toggles to the config defaults
+        Reset,
         ToggleRag,
         ToggleProvisional,
+        ToggleAutoScroll,
+        ToggleScratch,
+        // Image sent with the next message, for models that accept images
+        AttachImage(PathBuf),
+        ToggleView,
         AddSource(PathBuf),
         RemoveSource(PathBuf),
         ListSources,
+        ClearCache,
+        ReloadPrompt,
+        // Sets or clears the global system prompt in the saved config
+        SetSystem(String),
+        ClearSystem,
+        Reindex(Option<PathBuf>),
+        Open(PathBuf),
+        Diff(PathBuf, PathBuf),
+        // 1-based index of a code block in the last reply, and where to write it
+        SaveBlock(usize, PathBuf),
+        Conversations(Option<String>),
+        Tag(String),
+        Untag(String),
+        LoadConversation(String),
+        IndexStatus,
+        RagStats,
+        Patterns,
+        ExportSources(Option<PathBuf>),
+        Raw(String),
+        ShowPrompt(String),
+        Prefill(String),
+        Temperature(f32),
+        MaxTokens(u32),
+        // Sends a message with a max_tokens that applies to that turn only
+        OnceMax(u32, String),
+        Redo(Option<String>),
+        LastRaw,
+        Compare(String),
+        Setup,
+        CopyConversation,
+        Ping,
+        Logs,
         Exit,
     }
 
@@ -84,6 +198,8 @@ pub mod types {
         pub relevance_score: f32,
         pub matching_lines: Vec<(usize, String)>,
         pub snippet: String,
+        // Other indexed files with the same content, folded into this result
+        pub duplicates: Vec<PathBuf>,
     }
 
     #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -93,9 +209,12 @@ pub mod types {
         pub modified: DateTime<Utc>,
         pub file_type: FileType,
         pub indexable: bool,
+        // Hash of the content of indexable files, used to spot copies
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        pub content_hash: Option<u64>,
     }
 
-    #[derive(Debug, Clone, Serialize, Deserialize)]
+    #[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
     pub enum FileType {
         Text,
         Markdown,
@@ -106,11 +225,31 @@ pub mod types {
         Binary, // Not indexable
     }
 
+    impl FileType {
+        // Short name for listings; code files are named by their extension
+        pub fn label(&self) -> &str {
+            match self {
+                FileType::Text => "text",
+                FileType::Markdown => "markdown",
+                FileType::Json => "json",
+                FileType::Config => "config",
+                FileType::Code(extension) => extension,
+                FileType::Log => "log",
+                FileType::Binary => "binary",
+            }
+        }
+    }
+
     #[derive(Debug, Clone, Serialize, Deserialize)]
     pub struct DataSource {
         pub path: PathBuf,
         pub source_type: SourceType,
         pub last_indexed: DateTime<Utc>,
+        // Replace the global include/exclude patterns while indexing this source
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        pub include_patterns: Option<Vec<String>>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        pub exclude_patterns: Option<Vec<String>>,
     }
 
     #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -127,11 +266,16 @@ pub mod types {
         pub keywords: Vec<String>,
         pub search_results: Vec<SearchResult>,
         pub selected_files: Vec<PathBuf>,
-        pub file_contents: HashMap<PathBuf, String>,
+        // Keyed in path order so anything built from it is stable across runs
+        pub file_contents: BTreeMap<PathBuf, String>,
+        // Selected files whose content looks like an attempt to instruct the model
+        pub flagged_files: Vec<PathBuf>,
+        // Selected files read as `[lines a-b]` excerpts rather than whole
+        pub excerpted_files: Vec<PathBuf>,
     }
 
     // Configuration types
-    #[derive(Debug, Clone, Serialize, Deserialize)]
+    #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
     pub struct LlmProvider {
         pub provider_type: ProviderType,
         pub api_key: String,
@@ -139,13 +283,41 @@ pub mod types {
         pub base_url: Option<String>,
         pub max_tokens: Option<u32>,
         pub temperature: Option<f32>,
+        // Sequences that end generation when produced; empty sends none
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        pub stop: Vec<String>,
+        // OpenAI reasoning models: "minimal", "low", "medium" or "high"
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        pub reasoning_effort: Option<String>,
+        // Anthropic extended thinking: tokens the model may spend thinking
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        pub thinking_budget_tokens: Option<u32>,
+        // Echo dry-run: text put before each echoed message
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        pub echo_prefix: Option<String>,
     }
 
-    #[derive(Debug, Clone, Serialize, Deserialize)]
+    // A named provider setup that /compare sends to alongside the others
+    #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+    pub struct ProviderProfile {
+        pub name: String,
+        #[serde(flatten)]
+        pub provider: LlmProvider,
+    }
+
+    // One profile's answer in a /compare run; a failure is kept as its message
+    #[derive(Debug, Clone, PartialEq)]
+    pub struct ComparisonReply {
+        pub profile: String,
+        pub response: Result<String, String>,
+    }
+
+    #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
     pub enum ProviderType {
         OpenAi,
         Anthropic,
         Local, // For future local model support
+        Echo,  // Offline dry-run provider that echoes the prompt back
     }
 
     // Error types
@@ -168,6 +340,9 @@ pub mod types {
         
         #[error("Conversation error: {0}")]
         Conversation(#[from] ConversationError),
+
+        #[error("Command error: {0}")]
+        Command(#[from] CommandError),
     }
 
     #[derive(Debug, thiserror::Error)]
@@ -189,15 +364,31 @@ pub mod types {
         
         #[error("API error: {0}")]
         Api(String),
+
+        // An error response from the provider, with the machine-readable code
+        // from its error body when it sent one
+        #[error("API error: HTTP {status}: {message}")]
+        Provider {
+            status: u16,
+            code: Option<String>,
+            message: String,
+        },
         
         #[error("Authentication error")]
         Authentication,
         
         #[error("Rate limit exceeded")]
         RateLimit,
+
+        // A 5xx response, usually transient on the provider's side
+        #[error("Server error: HTTP {0}: {1}")]
+        Server(u16, String),
         
         #[error("Context window exceeded")]
         ContextWindowExceeded,
+
+        #[error("Stream ended before the response completed")]
+        StreamInterrupted,
     }
 
     #[derive(Debug, thiserror::Error)]
@@ -210,6 +401,9 @@ pub mod types {
         
         #[error("Context preparation error: {0}")]
         ContextPreparation(String),
+
+        #[error("LLM error: {0}")]
+        Llm(#[from] LlmError),
     }
 
     #[derive(Debug, thiserror::Error)]
@@ -246,6 +440,9 @@ pub mod types {
         
         #[error("History error: {0}")]
         History(String),
+
+        #[error("LLM error: {0}")]
+        Llm(#[from] LlmError),
     }
 
     #[derive(Debug, thiserror::Error)]
@@ -1,5 +1,16 @@
 use crate::types::*;
 use async_trait::async_trait;
+use chrono::Utc;
+use futures::StreamExt;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::io::Write;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tracing::warn;
 
 // Response stream for handling streaming LLM responses
 pub type ResponseStream = Box<dyn futures::Stream<Item = Result<String, LlmError>> + Unpin + Send>;
@@ -9,6 +20,557 @@ pub type ResponseStream = Box<dyn futures::Stream<Item = Result<String, LlmError
 pub trait LlmClient: Send + Sync {
     async fn send_message(&self, messages: &[Message]) -> Result<String, LlmError>;
     async fn stream_message(&self, messages: &[Message]) -> Result<ResponseStream, LlmError>;
+
+    /// Sends a request offering `tools` to the model. Providers without tool
+    /// support ignore the tools and always answer with text.
+    async fn send_with_tools(
+        &self,
+        messages: &[Message],
+        _tools: &[ToolSpec],
+    ) -> Result<LlmReply, LlmError> {
+        self.send_message(messages).await.map(LlmReply::Text)
+    }
+
+    /// Sends a request asking for a JSON reply that follows `schema`.
+    /// Providers without structured output answer as usual, so the reply
+    /// still needs lenient parsing.
+    async fn send_structured(
+        &self,
+        messages: &[Message],
+        _schema: &OutputSchema,
+    ) -> Result<String, LlmError> {
+        self.send_message(messages).await
+    }
+
+    /// Whether a request ending in a partial assistant message is continued
+    /// natively from where that message stops. Clients without native support
+    /// still honour such a prefill as best they can; either way the reply
+    /// holds only the text that follows it.
+    fn supports_continuation(&self) -> bool {
+        false
+    }
+
+    /// Makes a minimal request to confirm the endpoint and credentials work
+    async fn health_check(&self) -> Result<(), LlmError> {
+        self.send_message(&ping_messages()).await.map(|_| ())
+    }
+}
+
+// Smallest useful prompt for a connectivity check
+fn ping_messages() -> Vec<Message> {
+    vec![Message {
+        role: MessageRole::User,
+        content: "ping".to_string(),
+        timestamp: Utc::now(),
+        provisional: false,
+        context_files: Vec::new(),
+        tool_call: None,
+        images: Vec::new(),
+    }]
+}
+
+// A function the model may call, described by a JSON schema for its arguments
+#[derive(Debug, Clone)]
+pub struct ToolSpec {
+    pub name: &'static str,
+    pub description: &'static str,
+    pub parameters: Value,
+}
+
+// JSON schema a structured reply must follow
+#[derive(Debug, Clone)]
+pub struct OutputSchema {
+    pub name: &'static str,
+    pub schema: Value,
+}
+
+// Completion that either answers or asks for a tool to be run first
+#[derive(Debug, Clone, PartialEq)]
+pub enum LlmReply {
+    Text(String),
+    ToolCall(ToolCall),
+}
+
+pub const READ_FILE_TOOL: &str = "read_file";
+
+pub fn read_file_tool() -> ToolSpec {
+    ToolSpec {
+        name: READ_FILE_TOOL,
+        description: "Read a text file from the user's configured data sources",
+        parameters: json!({
+            "type": "object",
+            "properties": {
+                "path": { "type": "string", "description": "Path of the file to read" }
+            },
+            "required": ["path"],
+        }),
+    }
+}
+
+const OPENAI_BASE_URL: &str = "https://api.openai.com/v1";
+const ANTHROPIC_BASE_URL: &str = "https://api.anthropic.com/v1";
+const ANTHROPIC_VERSION: &str = "2023-06-01";
+const ANTHROPIC_DEFAULT_MAX_TOKENS: u32 = 4096;
+// Smallest extended thinking budget Anthropic accepts
+pub const ANTHROPIC_MIN_THINKING_BUDGET: u32 = 1024;
+pub const REASONING_EFFORT_LEVELS: &[&str] = &["minimal", "low", "medium", "high"];
+const ECHO_TOKEN_DELAY: Duration = Duration::from_millis(30);
+const OPENAI_DEFAULT_MODEL: &str = "gpt-4o-mini";
+const OPENAI_MAX_STOP_SEQUENCES: usize = 4;
+const ANTHROPIC_DEFAULT_MODEL: &str = "claude-3-5-sonnet-latest";
+
+// Model name prefixes that identify each hosted provider's models
+const OPENAI_MODEL_PREFIXES: &[&str] = &["gpt-", "chatgpt-", "o1", "o3", "o4"];
+const ANTHROPIC_MODEL_PREFIXES: &[&str] = &["claude-"];
+
+// OpenAI models that accept image input
+const OPENAI_VISION_MODEL_PREFIXES: &[&str] =
+    &["gpt-4o", "gpt-4-turbo", "gpt-4.1", "gpt-4.5", "gpt-5", "chatgpt-4o", "o3", "o4"];
+
+/// Model used when the config leaves the model empty
+pub fn default_model(provider_type: &ProviderType) -> Option<&'static str> {
+    match provider_type {
+        ProviderType::OpenAi => Some(OPENAI_DEFAULT_MODEL),
+        ProviderType::Anthropic => Some(ANTHROPIC_DEFAULT_MODEL),
+        ProviderType::Echo => Some("echo"),
+        ProviderType::Local => None,
+    }
+}
+
+/// Most stop sequences the provider accepts in one request, where it has a limit
+pub fn max_stop_sequences(provider_type: &ProviderType) -> Option<usize> {
+    match provider_type {
+        ProviderType::OpenAi => Some(OPENAI_MAX_STOP_SEQUENCES),
+        ProviderType::Anthropic | ProviderType::Echo | ProviderType::Local => None,
+    }
+}
+
+/// The configured model, or the provider's default when none is set
+pub fn resolved_model(provider: &LlmProvider) -> String {
+    let model = provider.model.trim();
+    if model.is_empty() {
+        default_model(&provider.provider_type).unwrap_or_default().to_string()
+    } else {
+        model.to_string()
+    }
+}
+
+/// Describes a model name that doesn't look like it belongs to the selected
+/// provider. OpenAI-compatible endpoints set via `base_url` serve arbitrary
+/// model names, so those are not checked.
+pub fn model_mismatch_warning(provider: &LlmProvider) -> Option<String> {
+    let prefixes = match provider.provider_type {
+        ProviderType::OpenAi if provider.base_url.is_none() => OPENAI_MODEL_PREFIXES,
+        ProviderType::Anthropic => ANTHROPIC_MODEL_PREFIXES,
+        _ => return None,
+    };
+
+    let model = resolved_model(provider);
+    if prefixes.iter().any(|prefix| model.starts_with(prefix)) {
+        return None;
+    }
+    Some(format!(
+        "Model '{}' doesn't look like a {:?} model (expected a name starting with {}); requests may fail",
+        model,
+        provider.provider_type,
+        prefixes.join(", ")
+    ))
+}
+
+/// Whether images attached to a message reach the provider's model. Only the
+/// OpenAI client sends images; endpoints set via `base_url` are trusted to
+/// serve a model that accepts them.
+pub fn supports_images(provider: &LlmProvider) -> bool {
+    match provider.provider_type {
+        ProviderType::OpenAi if provider.base_url.is_some() => true,
+        ProviderType::OpenAi => {
+            let model = resolved_model(provider);
+            OPENAI_VISION_MODEL_PREFIXES.iter().any(|prefix| model.starts_with(prefix))
+        }
+        _ => false,
+    }
+}
+
+// Cross-cutting options applied to every client built by the factory
+#[derive(Clone, Default)]
+pub struct ClientOptions {
+    pub transcript: Option<Arc<DebugTranscript>>,
+    pub last_response: Option<Arc<LastResponse>>,
+}
+
+// Opt-in copy of the most recent provider response body, for /last-raw.
+// Streams keep the reassembled text rather than the individual events.
+#[derive(Default)]
+pub struct LastResponse {
+    body: Mutex<Option<String>>,
+}
+
+impl LastResponse {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn record(&self, status: u16, body: &str, api_key: &str) {
+        let entry = format!("HTTP {}\n{}", status, redact(body, api_key));
+        *self.body.lock().unwrap() = Some(entry);
+    }
+
+    pub fn get(&self) -> Option<String> {
+        self.body.lock().unwrap().clone()
+    }
+}
+
+// Size in KB a transcript entry's body is cut to unless configured otherwise
+pub const DEFAULT_TRANSCRIPT_MAX_KB: u64 = 64;
+
+// Which bodies the debug transcript records
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TranscriptBodies {
+    Requests,
+    Responses,
+    #[default]
+    Both,
+}
+
+// Opt-in append-only log of raw provider traffic, separate from tracing output
+pub struct DebugTranscript {
+    path: PathBuf,
+    write_lock: Mutex<()>,
+    bodies: TranscriptBodies,
+    // Bodies longer than this are cut and marked; None logs them whole
+    max_body_bytes: Option<usize>,
+}
+
+impl DebugTranscript {
+    pub fn new(path: PathBuf) -> Self {
+        Self {
+            path,
+            write_lock: Mutex::new(()),
+            bodies: TranscriptBodies::Both,
+            max_body_bytes: None,
+        }
+    }
+
+    /// Limits what is logged to `bodies`, each cut to `max_body_bytes`
+    pub fn with_limits(mut self, bodies: TranscriptBodies, max_body_bytes: Option<usize>) -> Self {
+        self.bodies = bodies;
+        self.max_body_bytes = max_body_bytes;
+        self
+    }
+
+    pub fn log_request(&self, provider: &str, url: &str, body: &Value, api_key: &str) {
+        if self.bodies == TranscriptBodies::Responses {
+            return;
+        }
+        let body = serde_json::to_string_pretty(body).unwrap_or_else(|_| body.to_string());
+        self.append(&format!(
+            "=== {} {} request POST {} ===\napi_key: [REDACTED]\n{}\n\n",
+            Utc::now().to_rfc3339(),
+            provider,
+            url,
+            self.truncate(redact(&body, api_key))
+        ));
+    }
+
+    pub fn log_response(&self, provider: &str, status: u16, body: &str, api_key: &str) {
+        if self.bodies == TranscriptBodies::Requests {
+            return;
+        }
+        self.append(&format!(
+            "=== {} {} response {} ===\n{}\n\n",
+            Utc::now().to_rfc3339(),
+            provider,
+            status,
+            self.truncate(redact(body, api_key))
+        ));
+    }
+
+    // Cuts a body to the size limit on a character boundary, noting how much was dropped
+    fn truncate(&self, mut body: String) -> String {
+        let Some(limit) = self.max_body_bytes.filter(|limit| body.len() > *limit) else {
+            return body;
+        };
+        let total = body.len();
+        let mut end = limit;
+        while !body.is_char_boundary(end) {
+            end -= 1;
+        }
+        body.truncate(end);
+        body.push_str(&format!("\n[truncated: {} of {} bytes logged]", end, total));
+        body
+    }
+
+    fn append(&self, entry: &str) {
+        let _guard = self.write_lock.lock().unwrap();
+
+        let result = self
+            .path
+            .parent()
+            .filter(|parent| !parent.as_os_str().is_empty())
+            .map_or(Ok(()), std::fs::create_dir_all)
+            .and_then(|_| {
+                std::fs::OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(&self.path)
+            })
+            .and_then(|mut file| file.write_all(entry.as_bytes()));
+
+        if let Err(e) = result {
+            warn!("Failed to write debug transcript {:?}: {}", self.path, e);
+        }
+    }
+}
+
+fn redact(text: &str, secret: &str) -> String {
+    if secret.is_empty() {
+        text.to_string()
+    } else {
+        text.replace(secret, "[REDACTED]")
+    }
+}
+
+fn error_for_status(status: u16, body: &str) -> LlmError {
+    match status {
+        401 | 403 => LlmError::Authentication,
+        429 => LlmError::RateLimit,
+        500..=599 => LlmError::Server(status, body.to_string()),
+        _ => {
+            let (code, message) = parse_error_body(body);
+            let context_overflow = code.as_deref() == Some("context_length_exceeded")
+                || message.contains("prompt is too long");
+            if context_overflow {
+                LlmError::ContextWindowExceeded
+            } else {
+                LlmError::Provider { status, code, message }
+            }
+        }
+    }
+}
+
+// Pulls the code and message out of a provider error body. OpenAI sends
+// `{"error": {"message", "type", "code"}}` and Anthropic `{"type": "error",
+// "error": {"type", "message"}}`; the specific `code` wins over the broader
+// `type`. A body that is not such an envelope becomes the message as is.
+fn parse_error_body(body: &str) -> (Option<String>, String) {
+    let envelope = serde_json::from_str::<Value>(body).ok();
+    let Some(error) = envelope.as_ref().map(|value| &value["error"]).filter(|e| e.is_object())
+    else {
+        return (None, body.to_string());
+    };
+    let code = error["code"]
+        .as_str()
+        .or_else(|| error["type"].as_str())
+        .map(str::to_string);
+    let message = error["message"].as_str().unwrap_or(body).to_string();
+    (code, message)
+}
+
+// Incrementally splits a server-sent events byte stream into `data:` payloads
+#[derive(Default)]
+struct SseDecoder {
+    buffer: String,
+    // Leading bytes of a character split across chunks, held for the next chunk
+    partial: Vec<u8>,
+}
+
+impl SseDecoder {
+    fn push(&mut self, chunk: &[u8]) -> Vec<String> {
+        self.partial.extend_from_slice(chunk);
+        let complete = complete_utf8_len(&self.partial);
+        let rest = self.partial.split_off(complete);
+        self.buffer.push_str(&String::from_utf8_lossy(&self.partial));
+        self.partial = rest;
+
+        let mut payloads = Vec::new();
+        while let Some(newline) = self.buffer.find('\n') {
+            let line: String = self.buffer.drain(..=newline).collect();
+            let line = line.trim_end_matches(['\r', '\n']);
+            if let Some(data) = line.strip_prefix("data:") {
+                payloads.push(data.trim_start().to_string());
+            }
+        }
+        payloads
+    }
+
+    // A final line the server sent without a trailing newline
+    fn finish(&mut self) -> Option<String> {
+        self.buffer.push_str(&String::from_utf8_lossy(&std::mem::take(&mut self.partial)));
+        let line = std::mem::take(&mut self.buffer);
+        line.trim_end_matches(['\r', '\n'])
+            .strip_prefix("data:")
+            .map(|data| data.trim_start().to_string())
+    }
+}
+
+// Length of `bytes` without a multibyte UTF-8 sequence cut off at its end.
+// Invalid bytes count as complete and are replaced when decoded.
+fn complete_utf8_len(bytes: &[u8]) -> usize {
+    for back in 1..=bytes.len().min(3) {
+        let byte = bytes[bytes.len() - back];
+        // Walk back over continuation bytes to the byte that starts the sequence
+        if byte & 0xC0 == 0x80 {
+            continue;
+        }
+        let needed = match byte {
+            0xC0..=0xDF => 2,
+            0xE0..=0xEF => 3,
+            0xF0..=0xF7 => 4,
+            _ => 1,
+        };
+        return if needed > back { bytes.len() - back } else { bytes.len() };
+    }
+    bytes.len()
+}
+
+// The partial assistant message a request ends with, if any, which the reply
+// should continue from
+fn prefill_of(messages: &[Message]) -> Option<&str> {
+    messages
+        .last()
+        .filter(|message| matches!(message.role, MessageRole::Assistant) && message.tool_call.is_none())
+        .map(|message| message.content.as_str())
+}
+
+// Stands in for a prefill the provider can't continue natively; the echoed
+// text is stripped off the reply with `strip_prefill`
+fn prefill_instruction(prefill: &str) -> String {
+    format!("Begin your reply with exactly the following text, then continue it:\n{}", prefill)
+}
+
+// Removes an echoed prefill from the start of a reply
+fn strip_prefill(response: &str, prefill: &str) -> String {
+    response
+        .strip_prefix(prefill)
+        .or_else(|| response.trim_start().strip_prefix(prefill.trim_start()))
+        .unwrap_or(response)
+        .to_string()
+}
+
+// Streaming counterpart of `strip_prefill`: holds tokens back until enough
+// text has arrived to tell whether the reply starts with the prefill
+fn strip_prefill_stream(stream: ResponseStream, prefill: String) -> ResponseStream {
+    let pending = Arc::new(Mutex::new(Some(String::new())));
+    let tail = pending.clone();
+    let tail_prefill = prefill.clone();
+    let tokens = stream.filter_map(move |item| {
+        let mut pending = pending.lock().unwrap();
+        let output = match (item, pending.as_mut()) {
+            (Ok(token), Some(buffer)) => {
+                buffer.push_str(&token);
+                let undecided = buffer.len() < prefill.len()
+                    && prefill.trim_start().starts_with(buffer.trim_start());
+                if undecided {
+                    None
+                } else {
+                    let text = strip_prefill(buffer, &prefill);
+                    *pending = None;
+                    (!text.is_empty()).then_some(Ok(text))
+                }
+            }
+            (item, _) => Some(item),
+        };
+        futures::future::ready(output)
+    });
+    // A reply shorter than the prefill is flushed when the stream ends
+    let rest = futures::stream::once(futures::future::lazy(move |_| {
+        tail.lock()
+            .unwrap()
+            .take()
+            .map(|buffer| strip_prefill(&buffer, &tail_prefill))
+            .filter(|text| !text.is_empty())
+            .map(Ok)
+    }))
+    .filter_map(futures::future::ready);
+    Box::new(tokens.chain(rest))
+}
+
+// A decoded streaming event, independent of the provider's wire format
+enum StreamEvent {
+    Token(String),
+    Ignored,
+    Done,
+}
+
+fn sse_payloads(
+    response: reqwest::Response,
+) -> impl futures::Stream<Item = Result<String, LlmError>> + Send {
+    let decoder = Arc::new(Mutex::new(SseDecoder::default()));
+    let tail = decoder.clone();
+    let payloads = response.bytes_stream().flat_map(move |chunk| {
+        let payloads: Vec<Result<String, LlmError>> = match chunk {
+            Ok(bytes) => decoder.lock().unwrap().push(&bytes).into_iter().map(Ok).collect(),
+            Err(e) => vec![Err(LlmError::Network(e.to_string()))],
+        };
+        futures::stream::iter(payloads)
+    });
+    let last = futures::stream::once(futures::future::lazy(move |_| {
+        tail.lock().unwrap().finish().map(Ok)
+    }))
+    .filter_map(futures::future::ready);
+    payloads.chain(last)
+}
+
+// Yields the tokens of a streamed response. A connection that closes before
+// the provider's end-of-stream event ends with `StreamInterrupted`, so a
+// truncated answer is never mistaken for a complete one.
+fn token_stream(
+    response: reqwest::Response,
+    parse_event: fn(&str) -> Result<StreamEvent, LlmError>,
+) -> ResponseStream {
+    let settled = Arc::new(AtomicBool::new(false));
+    let (on_done, on_error) = (settled.clone(), settled.clone());
+    let tokens = sse_payloads(response)
+        .map(move |payload| payload.and_then(|data| parse_event(&data)))
+        .take_while(move |event| {
+            let done = matches!(event, Ok(StreamEvent::Done));
+            if done {
+                on_done.store(true, Ordering::SeqCst);
+            }
+            futures::future::ready(!done)
+        })
+        .filter_map(move |event| {
+            futures::future::ready(match event {
+                Ok(StreamEvent::Token(token)) => Some(Ok(token)),
+                Ok(_) => None,
+                Err(e) => {
+                    on_error.store(true, Ordering::SeqCst);
+                    Some(Err(e))
+                }
+            })
+        });
+    let interrupted = futures::stream::once(futures::future::lazy(move |_| {
+        (!settled.load(Ordering::SeqCst)).then_some(Err(LlmError::StreamInterrupted))
+    }))
+    .filter_map(futures::future::ready);
+    Box::new(Box::pin(tokens.chain(interrupted)))
+}
+
+// Calls `on_complete` with the reassembled text once the stream ends, or with
+// the first error message if the stream fails
+fn tap_completed<F>(stream: ResponseStream, on_complete: F) -> ResponseStream
+where
+    F: FnOnce(Result<String, String>) + Send + 'static,
+{
+    let collected = Arc::new(Mutex::new(Ok(String::new())));
+    let sink = collected.clone();
+    let tokens = stream.inspect(move |item| {
+        let mut buffer = sink.lock().unwrap();
+        match (item, buffer.as_mut()) {
+            (Ok(token), Ok(text)) => text.push_str(token),
+            (Err(e), Ok(_)) => *buffer = Err(e.to_string()),
+            _ => {}
+        }
+    });
+
+    let finish = futures::stream::once(futures::future::lazy(move |_| {
+        let result = std::mem::replace(&mut *collected.lock().unwrap(), Ok(String::new()));
+        on_complete(result);
+    }))
+    .filter_map(|_| futures::future::ready(None));
+
+    Box::new(tokens.chain(finish))
 }
 
 // OpenAI client implementation
@@ -16,6 +578,15 @@ pub struct OpenAiClient {
     api_key: String,
     model: String,
     base_url: String,
+    max_tokens: Option<u32>,
+    temperature: Option<f32>,
+    stop: Vec<String>,
+    reasoning_effort: Option<String>,
+    transcript: Option<Arc<DebugTranscript>>,
+    last_response: Option<Arc<LastResponse>>,
+    // Cleared once the server rejects `response_format`, so later structured
+    // requests go straight to the prompt-only contract
+    structured_output: AtomicBool,
     client: reqwest::Client,
 }
 
@@ -24,7 +595,14 @@ impl OpenAiClient {
         Self {
             api_key,
             model,
-            base_url: "https://api.openai.com/v1".to_string(),
+            base_url: OPENAI_BASE_URL.to_string(),
+            max_tokens: None,
+            temperature: None,
+            stop: Vec::new(),
+            reasoning_effort: None,
+            transcript: None,
+            last_response: None,
+            structured_output: AtomicBool::new(true),
             client: reqwest::Client::new(),
         }
     }
@@ -33,18 +611,275 @@ impl OpenAiClient {
         self.base_url = base_url;
         self
     }
+
+    pub fn with_max_tokens(mut self, max_tokens: Option<u32>) -> Self {
+        self.max_tokens = max_tokens;
+        self
+    }
+
+    pub fn with_temperature(mut self, temperature: Option<f32>) -> Self {
+        self.temperature = temperature;
+        self
+    }
+
+    pub fn with_stop(mut self, stop: Vec<String>) -> Self {
+        self.stop = stop;
+        self
+    }
+
+    pub fn with_reasoning_effort(mut self, reasoning_effort: Option<String>) -> Self {
+        self.reasoning_effort = reasoning_effort;
+        self
+    }
+
+    pub fn with_transcript(mut self, transcript: Option<Arc<DebugTranscript>>) -> Self {
+        self.transcript = transcript;
+        self
+    }
+
+    pub fn with_last_response(mut self, last_response: Option<Arc<LastResponse>>) -> Self {
+        self.last_response = last_response;
+        self
+    }
+
+    fn request_body(&self, messages: &[Message], stream: bool) -> Value {
+        // OpenAI has no native prefill, so it becomes an instruction to open
+        // the reply with that text; the echoed text is stripped off the reply
+        let messages: Vec<Value> = match prefill_of(messages) {
+            Some(prefill) => {
+                let mut converted: Vec<Value> =
+                    messages[..messages.len() - 1].iter().map(Self::message_json).collect();
+                converted.push(json!({ "role": "system", "content": prefill_instruction(prefill) }));
+                converted
+            }
+            None => messages.iter().map(Self::message_json).collect(),
+        };
+
+        let mut body = json!({
+            "model": self.model,
+            "messages": messages,
+            "stream": stream,
+        });
+        if let Some(max_tokens) = self.max_tokens {
+            body["max_tokens"] = json!(max_tokens);
+        }
+        if let Some(temperature) = self.temperature {
+            body["temperature"] = json!(temperature);
+        }
+        if !self.stop.is_empty() {
+            body["stop"] = json!(self.stop);
+        }
+        if let Some(effort) = &self.reasoning_effort {
+            body["reasoning_effort"] = json!(effort);
+        }
+        body
+    }
+
+    fn message_json(message: &Message) -> Value {
+        match (&message.role, &message.tool_call) {
+            (MessageRole::Tool, Some(call)) => json!({
+                "role": "tool",
+                "tool_call_id": call.id,
+                "content": message.content,
+            }),
+            (MessageRole::Assistant, Some(call)) => json!({
+                "role": "assistant",
+                "content": if message.content.is_empty() { Value::Null } else { json!(message.content) },
+                "tool_calls": [{
+                    "id": call.id,
+                    "type": "function",
+                    "function": { "name": call.name, "arguments": call.arguments },
+                }],
+            }),
+            // Attached images go in a content array after the text part
+            (MessageRole::User, None) if !message.images.is_empty() => {
+                let mut parts = vec![json!({ "type": "text", "text": message.content })];
+                parts.extend(message.images.iter().map(|image| {
+                    json!({
+                        "type": "image_url",
+                        "image_url": { "url": format!("data:{};base64,{}", image.media_type, image.data) },
+                    })
+                }));
+                json!({ "role": "user", "content": parts })
+            }
+            _ => json!({ "role": message.role.as_str(), "content": message.content }),
+        }
+    }
+
+    // Posts a non-streaming request and returns the first choice's message
+    async fn complete(&self, body: &Value) -> Result<Value, LlmError> {
+        let response = self.post(body).await?;
+        let status = response.status().as_u16();
+        let text = response
+            .text()
+            .await
+            .map_err(|e| LlmError::Network(e.to_string()))?;
+        self.log_response(status, &text);
+
+        let mut body: Value = serde_json::from_str(&text)
+            .map_err(|e| LlmError::Api(format!("Invalid response body: {}", e)))?;
+        Ok(body["choices"][0]["message"].take())
+    }
+
+    async fn post(&self, body: &Value) -> Result<reqwest::Response, LlmError> {
+        let url = format!("{}/chat/completions", self.base_url);
+        if let Some(transcript) = &self.transcript {
+            transcript.log_request("openai", &url, body, &self.api_key);
+        }
+
+        let request = self.client.post(&url).bearer_auth(&self.api_key).json(body);
+        let response = request
+            .send()
+            .await
+            .map_err(|e| LlmError::Network(e.to_string()))?;
+        if response.status().is_success() {
+            return Ok(response);
+        }
+
+        let status = response.status().as_u16();
+        let text = response.text().await.unwrap_or_default();
+        self.log_response(status, &text);
+        Err(error_for_status(status, &text))
+    }
+
+    fn log_response(&self, status: u16, body: &str) {
+        if let Some(transcript) = &self.transcript {
+            transcript.log_response("openai", status, body, &self.api_key);
+        }
+        if let Some(last_response) = &self.last_response {
+            last_response.record(status, body, &self.api_key);
+        }
+    }
+
+    fn parse_event(data: &str) -> Result<StreamEvent, LlmError> {
+        if data == "[DONE]" {
+            return Ok(StreamEvent::Done);
+        }
+
+        let event: Value = serde_json::from_str(data)
+            .map_err(|e| LlmError::Api(format!("Invalid stream event: {}", e)))?;
+        Ok(match event["choices"][0]["delta"]["content"].as_str() {
+            Some(token) if !token.is_empty() => StreamEvent::Token(token.to_string()),
+            _ => StreamEvent::Ignored,
+        })
+    }
 }
 
 #[async_trait]
 impl LlmClient for OpenAiClient {
-    async fn send_message(&self, _messages: &[Message]) -> Result<String, LlmError> {
-        // TODO: Implement OpenAI API call
-        Ok("OpenAI response placeholder".to_string())
+    async fn send_message(&self, messages: &[Message]) -> Result<String, LlmError> {
+        let message = self.complete(&self.request_body(messages, false)).await?;
+        let content = message["content"]
+            .as_str()
+            .ok_or_else(|| LlmError::Api("Response missing message content".to_string()))?;
+        Ok(match prefill_of(messages) {
+            Some(prefill) => strip_prefill(content, prefill),
+            None => content.to_string(),
+        })
+    }
+
+    async fn send_with_tools(
+        &self,
+        messages: &[Message],
+        tools: &[ToolSpec],
+    ) -> Result<LlmReply, LlmError> {
+        let mut body = self.request_body(messages, false);
+        if !tools.is_empty() {
+            let tools: Vec<Value> = tools
+                .iter()
+                .map(|tool| {
+                    json!({
+                        "type": "function",
+                        "function": {
+                            "name": tool.name,
+                            "description": tool.description,
+                            "parameters": tool.parameters,
+                        },
+                    })
+                })
+                .collect();
+            body["tools"] = json!(tools);
+        }
+
+        let message = self.complete(&body).await?;
+        // Only the first call is honoured; the model is re-invoked after it runs
+        if let Some(call) = message["tool_calls"].get(0) {
+            return Ok(LlmReply::ToolCall(ToolCall {
+                id: call["id"].as_str().unwrap_or_default().to_string(),
+                name: call["function"]["name"].as_str().unwrap_or_default().to_string(),
+                arguments: call["function"]["arguments"].as_str().unwrap_or("{}").to_string(),
+            }));
+        }
+        let content = message["content"]
+            .as_str()
+            .ok_or_else(|| LlmError::Api("Response missing message content".to_string()))?;
+        Ok(LlmReply::Text(match prefill_of(messages) {
+            Some(prefill) => strip_prefill(content, prefill),
+            None => content.to_string(),
+        }))
+    }
+
+    async fn send_structured(
+        &self,
+        messages: &[Message],
+        schema: &OutputSchema,
+    ) -> Result<String, LlmError> {
+        if !self.structured_output.load(Ordering::Relaxed) {
+            return self.send_message(messages).await;
+        }
+        let mut body = self.request_body(messages, false);
+        body["response_format"] = json!({
+            "type": "json_schema",
+            "json_schema": { "name": schema.name, "strict": true, "schema": schema.schema },
+        });
+        // Models and compatible servers without structured output reject the
+        // request outright; the prompt already asks for the same format
+        let message = match self.complete(&body).await {
+            Err(LlmError::Provider { status: 400 | 422, message, .. }) => {
+                warn!("Structured output rejected ({}); falling back to a plain request", message);
+                self.structured_output.store(false, Ordering::Relaxed);
+                return self.send_message(messages).await;
+            }
+            result => result?,
+        };
+        message["content"]
+            .as_str()
+            .map(str::to_string)
+            .ok_or_else(|| LlmError::Api("Response missing message content".to_string()))
+    }
+
+    // A one-token completion is enough to exercise the key, endpoint and model
+    async fn health_check(&self) -> Result<(), LlmError> {
+        let mut body = self.request_body(&ping_messages(), false);
+        body["max_tokens"] = json!(1);
+        self.post(&body).await.map(|_| ())
     }
 
-    async fn stream_message(&self, _messages: &[Message]) -> Result<ResponseStream, LlmError> {
-        // TODO: Implement OpenAI streaming API call
-        Err(LlmError::Api("Streaming not yet implemented".to_string()))
+    async fn stream_message(&self, messages: &[Message]) -> Result<ResponseStream, LlmError> {
+        let response = self.post(&self.request_body(messages, true)).await?;
+        let status = response.status().as_u16();
+        let stream = token_stream(response, Self::parse_event);
+
+        let stream = if self.transcript.is_some() || self.last_response.is_some() {
+            let transcript = self.transcript.clone();
+            let last_response = self.last_response.clone();
+            let api_key = self.api_key.clone();
+            tap_completed(stream, move |result| {
+                let body = result.unwrap_or_else(|e| format!("[stream error] {}", e));
+                if let Some(transcript) = transcript {
+                    transcript.log_response("openai", status, &body, &api_key);
+                }
+                if let Some(last_response) = last_response {
+                    last_response.record(status, &body, &api_key);
+                }
+            })
+        } else {
+            stream
+        };
+        Ok(match prefill_of(messages) {
+            Some(prefill) => strip_prefill_stream(stream, prefill.to_string()),
+            None => stream,
+        })
     }
 }
 
@@ -52,6 +887,13 @@ impl LlmClient for OpenAiClient {
 pub struct AnthropicClient {
     api_key: String,
     model: String,
+    base_url: String,
+    max_tokens: Option<u32>,
+    temperature: Option<f32>,
+    stop: Vec<String>,
+    thinking_budget_tokens: Option<u32>,
+    transcript: Option<Arc<DebugTranscript>>,
+    last_response: Option<Arc<LastResponse>>,
     client: reqwest::Client,
 }
 
@@ -60,41 +902,1691 @@ impl AnthropicClient {
         Self {
             api_key,
             model,
+            base_url: ANTHROPIC_BASE_URL.to_string(),
+            max_tokens: None,
+            temperature: None,
+            stop: Vec::new(),
+            thinking_budget_tokens: None,
+            transcript: None,
+            last_response: None,
             client: reqwest::Client::new(),
         }
     }
+
+    pub fn with_base_url(mut self, base_url: String) -> Self {
+        self.base_url = base_url;
+        self
+    }
+
+    pub fn with_max_tokens(mut self, max_tokens: Option<u32>) -> Self {
+        self.max_tokens = max_tokens;
+        self
+    }
+
+    pub fn with_temperature(mut self, temperature: Option<f32>) -> Self {
+        self.temperature = temperature;
+        self
+    }
+
+    pub fn with_stop(mut self, stop: Vec<String>) -> Self {
+        self.stop = stop;
+        self
+    }
+
+    pub fn with_thinking_budget(mut self, thinking_budget_tokens: Option<u32>) -> Self {
+        self.thinking_budget_tokens = thinking_budget_tokens;
+        self
+    }
+
+    pub fn with_transcript(mut self, transcript: Option<Arc<DebugTranscript>>) -> Self {
+        self.transcript = transcript;
+        self
+    }
+
+    pub fn with_last_response(mut self, last_response: Option<Arc<LastResponse>>) -> Self {
+        self.last_response = last_response;
+        self
+    }
+
+    fn request_body(&self, messages: &[Message], stream: bool) -> Value {
+        let mut prefill = prefill_of(messages);
+        // Extended thinking rejects a trailing assistant message, so there the
+        // prefill becomes an instruction, as it does for OpenAI
+        let instruction = match prefill {
+            Some(text) if !self.supports_continuation() => {
+                prefill = None;
+                Some(prefill_instruction(text))
+            }
+            _ => None,
+        };
+        let messages = match instruction {
+            Some(_) => &messages[..messages.len() - 1],
+            None => messages,
+        };
+        // Anthropic takes the system prompt as a top-level field, not a message
+        let system: Vec<&str> = messages
+            .iter()
+            .filter(|message| matches!(message.role, MessageRole::System))
+            .map(|message| message.content.as_str())
+            .chain(instruction.as_deref())
+            .collect();
+        let mut messages: Vec<Value> = messages
+            .iter()
+            .filter(|message| !matches!(message.role, MessageRole::System))
+            .map(|message| match message.role {
+                // Tool calls aren't sent to Anthropic; their results go in as plain user text
+                MessageRole::Tool => json!({
+                    "role": "user",
+                    "content": format!(
+                        "Result of {}:\n{}",
+                        message.tool_call.as_ref().map_or("tool", |call| call.name.as_str()),
+                        message.content
+                    ),
+                }),
+                _ => json!({ "role": message.role.as_str(), "content": message.content }),
+            })
+            .collect();
+        // A prefill is sent as-is and continued natively, but the API rejects
+        // one that ends in whitespace
+        if let (Some(prefill), Some(last)) = (prefill, messages.last_mut()) {
+            last["content"] = json!(prefill.trim_end());
+        }
+
+        // The thinking budget counts toward max_tokens, so without a set limit
+        // the default reply allowance comes on top of it
+        let max_tokens = match (self.max_tokens, self.thinking_budget_tokens) {
+            (Some(max_tokens), _) => max_tokens,
+            (None, Some(budget)) => budget + ANTHROPIC_DEFAULT_MAX_TOKENS,
+            (None, None) => ANTHROPIC_DEFAULT_MAX_TOKENS,
+        };
+        let mut body = json!({
+            "model": self.model,
+            "max_tokens": max_tokens,
+            "messages": messages,
+            "stream": stream,
+        });
+        if !system.is_empty() {
+            body["system"] = json!(system.join("\n\n"));
+        }
+        match self.thinking_budget_tokens {
+            // Extended thinking doesn't accept a custom temperature
+            Some(budget) => body["thinking"] = json!({ "type": "enabled", "budget_tokens": budget }),
+            None => {
+                if let Some(temperature) = self.temperature {
+                    body["temperature"] = json!(temperature);
+                }
+            }
+        }
+        if !self.stop.is_empty() {
+            body["stop_sequences"] = json!(self.stop);
+        }
+        body
+    }
+
+    async fn post(&self, body: &Value) -> Result<reqwest::Response, LlmError> {
+        let url = format!("{}/messages", self.base_url);
+        if let Some(transcript) = &self.transcript {
+            transcript.log_request("anthropic", &url, body, &self.api_key);
+        }
+
+        let request = self
+            .client
+            .post(&url)
+            .header("x-api-key", &self.api_key)
+            .header("anthropic-version", ANTHROPIC_VERSION)
+            .json(body);
+        let response = request
+            .send()
+            .await
+            .map_err(|e| LlmError::Network(e.to_string()))?;
+        if response.status().is_success() {
+            return Ok(response);
+        }
+
+        let status = response.status().as_u16();
+        let text = response.text().await.unwrap_or_default();
+        self.log_response(status, &text);
+        Err(error_for_status(status, &text))
+    }
+
+    fn log_response(&self, status: u16, body: &str) {
+        if let Some(transcript) = &self.transcript {
+            transcript.log_response("anthropic", status, body, &self.api_key);
+        }
+        if let Some(last_response) = &self.last_response {
+            last_response.record(status, body, &self.api_key);
+        }
+    }
+
+    fn parse_event(data: &str) -> Result<StreamEvent, LlmError> {
+        let event: Value = serde_json::from_str(data)
+            .map_err(|e| LlmError::Api(format!("Invalid stream event: {}", e)))?;
+        match event["type"].as_str() {
+            Some("content_block_delta") => Ok(event["delta"]["text"]
+                .as_str()
+                .map(|token| StreamEvent::Token(token.to_string()))
+                .unwrap_or(StreamEvent::Ignored)),
+            Some("message_stop") => Ok(StreamEvent::Done),
+            Some("error") => Err(LlmError::Api(event["error"]["message"]
+                .as_str()
+                .unwrap_or("Unknown streaming error")
+                .to_string())),
+            _ => Ok(StreamEvent::Ignored),
+        }
+    }
 }
 
 #[async_trait]
 impl LlmClient for AnthropicClient {
-    async fn send_message(&self, _messages: &[Message]) -> Result<String, LlmError> {
-        // TODO: Implement Anthropic API call
-        Ok("Anthropic response placeholder".to_string())
+    async fn send_message(&self, messages: &[Message]) -> Result<String, LlmError> {
+        let response = self.post(&self.request_body(messages, false)).await?;
+        let status = response.status().as_u16();
+        let text = response
+            .text()
+            .await
+            .map_err(|e| LlmError::Network(e.to_string()))?;
+        self.log_response(status, &text);
+
+        let body: Value = serde_json::from_str(&text)
+            .map_err(|e| LlmError::Api(format!("Invalid response body: {}", e)))?;
+        let blocks = body["content"]
+            .as_array()
+            .ok_or_else(|| LlmError::Api("Response missing content".to_string()))?;
+        let content = blocks
+            .iter()
+            .filter_map(|block| block["text"].as_str())
+            .collect::<Vec<_>>()
+            .concat();
+        Ok(match prefill_of(messages).filter(|_| !self.supports_continuation()) {
+            Some(prefill) => strip_prefill(&content, prefill),
+            None => content,
+        })
+    }
+
+    // A trailing assistant message is treated as a prefix of the reply,
+    // except with extended thinking, which doesn't accept one
+    fn supports_continuation(&self) -> bool {
+        self.thinking_budget_tokens.is_none()
+    }
+
+    async fn health_check(&self) -> Result<(), LlmError> {
+        let mut body = self.request_body(&ping_messages(), false);
+        body["max_tokens"] = json!(1);
+        self.post(&body).await.map(|_| ())
     }
 
-    async fn stream_message(&self, _messages: &[Message]) -> Result<ResponseStream, LlmError> {
-        // TODO: Implement Anthropic streaming API call
-        Err(LlmError::Api("Streaming not yet implemented".to_string()))
+    async fn stream_message(&self, messages: &[Message]) -> Result<ResponseStream, LlmError> {
+        let response = self.post(&self.request_body(messages, true)).await?;
+        let status = response.status().as_u16();
+        let stream = token_stream(response, Self::parse_event);
+
+        let stream = if self.transcript.is_some() || self.last_response.is_some() {
+            let transcript = self.transcript.clone();
+            let last_response = self.last_response.clone();
+            let api_key = self.api_key.clone();
+            tap_completed(stream, move |result| {
+                let body = result.unwrap_or_else(|e| format!("[stream error] {}", e));
+                if let Some(transcript) = transcript {
+                    transcript.log_response("anthropic", status, &body, &api_key);
+                }
+                if let Some(last_response) = last_response {
+                    last_response.record(status, &body, &api_key);
+                }
+            })
+        } else {
+            stream
+        };
+        Ok(match prefill_of(messages).filter(|_| !self.supports_continuation()) {
+            Some(prefill) => strip_prefill_stream(stream, prefill.to_string()),
+            None => stream,
+        })
     }
 }
 
-// Factory function to create LLM clients based on provider configuration
-pub fn create_llm_client(provider: &LlmProvider) -> Result<Box<dyn LlmClient>, LlmError> {
-    match provider.provider_type {
-        ProviderType::OpenAi => {
-            let mut client = OpenAiClient::new(provider.api_key.clone(), provider.model.clone());
-            if let Some(base_url) = &provider.base_url {
-                client = client.with_base_url(base_url.clone());
-            }
-            Ok(Box::new(client))
-        }
-        ProviderType::Anthropic => {
-            let client = AnthropicClient::new(provider.api_key.clone(), provider.model.clone());
-            Ok(Box::new(client))
-        }
-        ProviderType::Local => {
-            // TODO: Implement local model support
-            Err(LlmError::Api("Local models not yet supported".to_string()))
+// Dry-run client that answers with the last user message, so the UI can be
+// exercised offline without keys
+pub struct EchoClient {
+    prefix: String,
+    token_delay: Duration,
+}
+
+impl Default for EchoClient {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl EchoClient {
+    pub fn new() -> Self {
+        Self {
+            prefix: String::new(),
+            token_delay: Duration::ZERO,
         }
     }
-}
\ No newline at end of file
+
+    pub fn with_prefix(mut self, prefix: String) -> Self {
+        self.prefix = prefix;
+        self
+    }
+
+    // Pause between streamed tokens to mimic a real provider
+    pub fn with_token_delay(mut self, token_delay: Duration) -> Self {
+        self.token_delay = token_delay;
+        self
+    }
+
+    fn echo(&self, messages: &[Message]) -> String {
+        let last_user = messages
+            .iter()
+            .rev()
+            .find(|message| matches!(message.role, MessageRole::User))
+            .map(|message| message.content.as_str())
+            .unwrap_or_default();
+        format!("{}{}", self.prefix, last_user)
+    }
+}
+
+#[async_trait]
+impl LlmClient for EchoClient {
+    async fn send_message(&self, messages: &[Message]) -> Result<String, LlmError> {
+        Ok(self.echo(messages))
+    }
+
+    async fn stream_message(&self, messages: &[Message]) -> Result<ResponseStream, LlmError> {
+        let tokens: Vec<String> = self
+            .echo(messages)
+            .split_inclusive(char::is_whitespace)
+            .map(str::to_string)
+            .collect();
+        let delay = self.token_delay;
+
+        let stream = futures::stream::iter(tokens).then(move |token| async move {
+            if !delay.is_zero() {
+                tokio::time::sleep(delay).await;
+            }
+            Ok(token)
+        });
+        Ok(Box::new(Box::pin(stream)))
+    }
+}
+
+// Factory function to create LLM clients based on provider configuration
+pub fn create_llm_client(
+    provider: &LlmProvider,
+    options: &ClientOptions,
+) -> Result<Box<dyn LlmClient>, LlmError> {
+    match provider.provider_type {
+        ProviderType::OpenAi => {
+            let mut client = OpenAiClient::new(provider.api_key.clone(), resolved_model(provider))
+                .with_max_tokens(provider.max_tokens)
+                .with_temperature(provider.temperature)
+                .with_stop(provider.stop.clone())
+                .with_reasoning_effort(provider.reasoning_effort.clone())
+                .with_transcript(options.transcript.clone())
+                .with_last_response(options.last_response.clone());
+            if let Some(base_url) = &provider.base_url {
+                client = client.with_base_url(base_url.clone());
+            }
+            Ok(Box::new(client))
+        }
+        ProviderType::Anthropic => {
+            let mut client = AnthropicClient::new(provider.api_key.clone(), resolved_model(provider))
+                .with_max_tokens(provider.max_tokens)
+                .with_temperature(provider.temperature)
+                .with_stop(provider.stop.clone())
+                .with_thinking_budget(provider.thinking_budget_tokens)
+                .with_transcript(options.transcript.clone())
+                .with_last_response(options.last_response.clone());
+            if let Some(base_url) = &provider.base_url {
+                client = client.with_base_url(base_url.clone());
+            }
+            Ok(Box::new(client))
+        }
+        ProviderType::Echo => Ok(Box::new(
+            EchoClient::new()
+                .with_prefix(provider.echo_prefix.clone().unwrap_or_default())
+                .with_token_delay(ECHO_TOKEN_DELAY),
+        )),
+        ProviderType::Local => {
+            // TODO: Implement local model support
+            Err(LlmError::Api("Local models not yet supported".to_string()))
+        }
+    }
+}
+
+// Stores completions keyed by a hash of the request, optionally mirrored to disk
+pub struct ResponseCache {
+    entries: Mutex<HashMap<u64, String>>,
+    path: Option<PathBuf>,
+}
+
+impl Default for ResponseCache {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ResponseCache {
+    pub fn new() -> Self {
+        Self {
+            entries: Mutex::new(HashMap::new()),
+            path: None,
+        }
+    }
+
+    /// Creates a disk-backed cache, loading any entries already stored at `path`
+    pub fn with_path(path: PathBuf) -> Self {
+        let entries = std::fs::read_to_string(&path)
+            .ok()
+            .and_then(|content| serde_json::from_str::<HashMap<String, String>>(&content).ok())
+            .map(|stored| {
+                stored
+                    .into_iter()
+                    .filter_map(|(key, value)| u64::from_str_radix(&key, 16).ok().map(|k| (k, value)))
+                    .collect()
+            })
+            .unwrap_or_default();
+
+        Self {
+            entries: Mutex::new(entries),
+            path: Some(path),
+        }
+    }
+
+    pub fn get(&self, key: u64) -> Option<String> {
+        self.entries.lock().unwrap().get(&key).cloned()
+    }
+
+    pub fn insert(&self, key: u64, response: String) {
+        let mut entries = self.entries.lock().unwrap();
+        entries.insert(key, response);
+        self.persist(&entries);
+    }
+
+    pub fn clear(&self) {
+        let mut entries = self.entries.lock().unwrap();
+        entries.clear();
+        self.persist(&entries);
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn persist(&self, entries: &HashMap<u64, String>) {
+        let Some(path) = &self.path else {
+            return;
+        };
+
+        let stored: HashMap<String, &String> = entries
+            .iter()
+            .map(|(key, value)| (format!("{:016x}", key), value))
+            .collect();
+
+        let result = serde_json::to_string(&stored)
+            .map_err(|e| e.to_string())
+            .and_then(|content| {
+                if let Some(parent) = path.parent() {
+                    std::fs::create_dir_all(parent).map_err(|e| e.to_string())?;
+                }
+                std::fs::write(path, content).map_err(|e| e.to_string())
+            });
+
+        if let Err(e) = result {
+            warn!("Failed to persist response cache to {:?}: {}", path, e);
+        }
+    }
+}
+
+/// Computes a stable cache key for a request from everything that shapes the
+/// request body or where it is sent; only the API key is left out. FNV-1a is
+/// used rather than the std hasher so keys stay valid across builds for the
+/// on-disk cache.
+pub fn cache_key(messages: &[Message], provider: &LlmProvider) -> u64 {
+    const FNV_OFFSET: u64 = 0xcbf29ce484222325;
+    const FNV_PRIME: u64 = 0x100000001b3;
+
+    let mut hash = FNV_OFFSET;
+    let mut feed = |bytes: &[u8]| {
+        for byte in bytes {
+            hash ^= *byte as u64;
+            hash = hash.wrapping_mul(FNV_PRIME);
+        }
+        // Field separator so ("ab", "c") and ("a", "bc") hash differently
+        hash ^= 0xff;
+        hash = hash.wrapping_mul(FNV_PRIME);
+    };
+
+    feed(format!("{:?}", provider.provider_type).as_bytes());
+    feed(provider.base_url.as_deref().unwrap_or_default().as_bytes());
+    feed(provider.model.as_bytes());
+    feed(&provider.temperature.map(f32::to_bits).unwrap_or(u32::MAX).to_le_bytes());
+    feed(&provider.max_tokens.unwrap_or(u32::MAX).to_le_bytes());
+    feed(&provider.stop.len().to_le_bytes());
+    for stop in &provider.stop {
+        feed(stop.as_bytes());
+    }
+    feed(provider.reasoning_effort.as_deref().unwrap_or_default().as_bytes());
+    feed(&provider.thinking_budget_tokens.unwrap_or(u32::MAX).to_le_bytes());
+    feed(provider.echo_prefix.as_deref().unwrap_or_default().as_bytes());
+    for message in messages {
+        feed(message.role.as_str().as_bytes());
+        feed(message.content.as_bytes());
+        if let Some(call) = &message.tool_call {
+            feed(call.id.as_bytes());
+            feed(call.name.as_bytes());
+            feed(call.arguments.as_bytes());
+        }
+        for image in &message.images {
+            feed(image.media_type.as_bytes());
+            feed(image.data.as_bytes());
+        }
+    }
+
+    hash
+}
+
+// Decorator that serves repeated requests from a ResponseCache
+pub struct CachingClient {
+    inner: Box<dyn LlmClient>,
+    cache: Arc<ResponseCache>,
+    // The provider settings the inner client sends with, for the cache key
+    provider: LlmProvider,
+}
+
+impl CachingClient {
+    /// `provider` should carry the resolved model name; its API key is not kept
+    pub fn new(inner: Box<dyn LlmClient>, cache: Arc<ResponseCache>, provider: &LlmProvider) -> Self {
+        Self {
+            inner,
+            cache,
+            provider: LlmProvider {
+                api_key: String::new(),
+                ..provider.clone()
+            },
+        }
+    }
+
+    // Sampling with a positive temperature is non-deterministic, so a cached
+    // completion would not be a faithful answer to the request
+    fn is_cacheable(&self) -> bool {
+        self.provider.temperature.is_none_or(|t| t <= 0.0)
+    }
+}
+
+#[async_trait]
+impl LlmClient for CachingClient {
+    async fn send_message(&self, messages: &[Message]) -> Result<String, LlmError> {
+        if !self.is_cacheable() {
+            return self.inner.send_message(messages).await;
+        }
+
+        let key = cache_key(messages, &self.provider);
+        if let Some(cached) = self.cache.get(key) {
+            return Ok(cached);
+        }
+
+        let response = self.inner.send_message(messages).await?;
+        self.cache.insert(key, response.clone());
+        Ok(response)
+    }
+
+    // Tool exchanges depend on local state (file contents), so they bypass the cache
+    async fn send_with_tools(
+        &self,
+        messages: &[Message],
+        tools: &[ToolSpec],
+    ) -> Result<LlmReply, LlmError> {
+        self.inner.send_with_tools(messages, tools).await
+    }
+
+    // The cache key leaves out the schema, so structured requests bypass it
+    async fn send_structured(
+        &self,
+        messages: &[Message],
+        schema: &OutputSchema,
+    ) -> Result<String, LlmError> {
+        self.inner.send_structured(messages, schema).await
+    }
+
+    fn supports_continuation(&self) -> bool {
+        self.inner.supports_continuation()
+    }
+
+    // A cached answer would say nothing about whether the provider is reachable
+    async fn health_check(&self) -> Result<(), LlmError> {
+        self.inner.health_check().await
+    }
+
+    async fn stream_message(&self, messages: &[Message]) -> Result<ResponseStream, LlmError> {
+        if !self.is_cacheable() {
+            return self.inner.stream_message(messages).await;
+        }
+
+        let key = cache_key(messages, &self.provider);
+        if let Some(cached) = self.cache.get(key) {
+            return Ok(Box::new(futures::stream::iter(vec![Ok(cached)])));
+        }
+
+        let stream = self.inner.stream_message(messages).await?;
+
+        // Cache the reassembled text only if the stream completes cleanly
+        let cache = self.cache.clone();
+        Ok(tap_completed(stream, move |result| {
+            if let Ok(text) = result {
+                cache.insert(key, text);
+            }
+        }))
+    }
+}
+
+// Wait before the first retry of a server error, doubled for each retry after
+pub const DEFAULT_SERVER_RETRY_DELAY: Duration = Duration::from_millis(500);
+
+// Decorator that retries requests failing with a 5xx server error. Rate limits
+// and other client errors are returned unchanged, since repeating the same
+// request straight away would not help.
+pub struct RetryingClient {
+    inner: Box<dyn LlmClient>,
+    attempts: u32,
+    base_delay: Duration,
+}
+
+impl RetryingClient {
+    pub fn new(inner: Box<dyn LlmClient>, attempts: u32, base_delay: Duration) -> Self {
+        Self {
+            inner,
+            attempts,
+            base_delay,
+        }
+    }
+
+    async fn with_retries<'a, T, F>(&'a self, mut call: F) -> Result<T, LlmError>
+    where
+        F: FnMut() -> futures::future::BoxFuture<'a, Result<T, LlmError>>,
+    {
+        let mut attempt = 0;
+        loop {
+            match call().await {
+                Err(LlmError::Server(status, body)) if attempt < self.attempts => {
+                    let delay = self.base_delay * 2u32.saturating_pow(attempt);
+                    warn!(
+                        "Provider returned HTTP {}, retrying in {:?}: {}",
+                        status, delay, body
+                    );
+                    tokio::time::sleep(delay).await;
+                    attempt += 1;
+                }
+                result => return result,
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl LlmClient for RetryingClient {
+    async fn send_message(&self, messages: &[Message]) -> Result<String, LlmError> {
+        self.with_retries(|| self.inner.send_message(messages)).await
+    }
+
+    async fn send_with_tools(
+        &self,
+        messages: &[Message],
+        tools: &[ToolSpec],
+    ) -> Result<LlmReply, LlmError> {
+        self.with_retries(|| self.inner.send_with_tools(messages, tools)).await
+    }
+
+    async fn send_structured(
+        &self,
+        messages: &[Message],
+        schema: &OutputSchema,
+    ) -> Result<String, LlmError> {
+        self.with_retries(|| self.inner.send_structured(messages, schema)).await
+    }
+
+    fn supports_continuation(&self) -> bool {
+        self.inner.supports_continuation()
+    }
+
+    // A health check should report the server error rather than hide it
+    async fn health_check(&self) -> Result<(), LlmError> {
+        self.inner.health_check().await
+    }
+
+    // Only opening the stream is retried; a stream that fails partway is
+    // left to the conversation's reconnect handling
+    async fn stream_message(&self, messages: &[Message]) -> Result<ResponseStream, LlmError> {
+        self.with_retries(|| self.inner.stream_message(messages)).await
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod mock {
+    use super::*;
+    use std::collections::VecDeque;
+
+    // Scripted client for tests: replays queued responses and records every request
+    #[derive(Default)]
+    pub struct MockLlmClient {
+        responses: Mutex<VecDeque<Result<String, LlmError>>>,
+        pub requests: Arc<Mutex<Vec<Vec<Message>>>>,
+        // "send" or "stream" for each request, in order
+        pub methods: Mutex<Vec<&'static str>>,
+        tool_calls: Mutex<VecDeque<ToolCall>>,
+        streams: Mutex<VecDeque<Vec<Result<String, LlmError>>>>,
+        continuation: bool,
+    }
+
+    impl MockLlmClient {
+        pub fn new() -> Self {
+            Self::default()
+        }
+
+        pub fn with_responses(responses: Vec<&str>) -> Self {
+            let client = Self::new();
+            for response in responses {
+                client.push_response(Ok(response.to_string()));
+            }
+            client
+        }
+
+        // Reports continuation support, like a provider that resumes partial answers
+        pub fn with_continuation(mut self) -> Self {
+            self.continuation = true;
+            self
+        }
+
+        pub fn push_response(&self, response: Result<String, LlmError>) {
+            self.responses.lock().unwrap().push_back(response);
+        }
+
+        // Queues a tool call, returned by the next `send_with_tools` ahead of any text
+        pub fn push_tool_call(&self, call: ToolCall) {
+            self.tool_calls.lock().unwrap().push_back(call);
+        }
+
+        // Queues the exact items the next `stream_message` yields, e.g. to end in an error
+        pub fn push_stream(&self, items: Vec<Result<String, LlmError>>) {
+            self.streams.lock().unwrap().push_back(items);
+        }
+
+        pub fn call_count(&self) -> usize {
+            self.requests.lock().unwrap().len()
+        }
+
+        fn next_response(&self, messages: &[Message]) -> Result<String, LlmError> {
+            self.requests.lock().unwrap().push(messages.to_vec());
+            self.responses
+                .lock()
+                .unwrap()
+                .pop_front()
+                .unwrap_or_else(|| Ok("mock response".to_string()))
+        }
+    }
+
+    #[async_trait]
+    impl LlmClient for MockLlmClient {
+        async fn send_message(&self, messages: &[Message]) -> Result<String, LlmError> {
+            self.methods.lock().unwrap().push("send");
+            self.next_response(messages)
+        }
+
+        async fn send_with_tools(
+            &self,
+            messages: &[Message],
+            _tools: &[ToolSpec],
+        ) -> Result<LlmReply, LlmError> {
+            let call = self.tool_calls.lock().unwrap().pop_front();
+            match call {
+                Some(call) => {
+                    self.methods.lock().unwrap().push("tool");
+                    self.requests.lock().unwrap().push(messages.to_vec());
+                    Ok(LlmReply::ToolCall(call))
+                }
+                None => self.send_message(messages).await.map(LlmReply::Text),
+            }
+        }
+
+        async fn send_structured(
+            &self,
+            messages: &[Message],
+            _schema: &OutputSchema,
+        ) -> Result<String, LlmError> {
+            self.methods.lock().unwrap().push("structured");
+            self.next_response(messages)
+        }
+
+        async fn stream_message(&self, messages: &[Message]) -> Result<ResponseStream, LlmError> {
+            self.methods.lock().unwrap().push("stream");
+            if let Some(items) = self.streams.lock().unwrap().pop_front() {
+                self.requests.lock().unwrap().push(messages.to_vec());
+                return Ok(Box::new(futures::stream::iter(items)));
+            }
+            let response = self.next_response(messages)?;
+            let tokens: Vec<Result<String, LlmError>> = response
+                .split_inclusive(' ')
+                .map(|token| Ok(token.to_string()))
+                .collect();
+            Ok(Box::new(futures::stream::iter(tokens)))
+        }
+
+        fn supports_continuation(&self) -> bool {
+            self.continuation
+        }
+    }
+
+    // Minimal HTTP server that answers each connection with the next scripted
+    // (status, body) pair and records the raw requests it receives
+    pub struct MockServer {
+        pub base_url: String,
+        pub requests: Arc<Mutex<Vec<String>>>,
+    }
+
+    impl MockServer {
+        pub async fn start(responses: Vec<(u16, String)>) -> Self {
+            use tokio::io::AsyncWriteExt;
+
+            let listener = tokio::net::TcpListener::bind("127.0.0.1:0")
+                .await
+                .expect("Failed to bind mock server");
+            let base_url = format!("http://{}", listener.local_addr().unwrap());
+            let requests = Arc::new(Mutex::new(Vec::new()));
+            let captured = requests.clone();
+
+            tokio::spawn(async move {
+                for (status, body) in responses {
+                    let Ok((mut socket, _)) = listener.accept().await else {
+                        return;
+                    };
+                    let request = read_request(&mut socket).await;
+                    captured.lock().unwrap().push(request);
+
+                    let response = format!(
+                        "HTTP/1.1 {} Mock\r\ncontent-type: application/json\r\ncontent-length: {}\r\nconnection: close\r\n\r\n{}",
+                        status,
+                        body.len(),
+                        body
+                    );
+                    let _ = socket.write_all(response.as_bytes()).await;
+                    let _ = socket.shutdown().await;
+                }
+            });
+
+            Self { base_url, requests }
+        }
+
+        pub fn request_bodies(&self) -> Vec<Value> {
+            self.requests
+                .lock()
+                .unwrap()
+                .iter()
+                .filter_map(|request| request.split("\r\n\r\n").nth(1))
+                .filter_map(|body| serde_json::from_str(body).ok())
+                .collect()
+        }
+    }
+
+    async fn read_request(socket: &mut tokio::net::TcpStream) -> String {
+        use tokio::io::AsyncReadExt;
+
+        let mut data = Vec::new();
+        let mut buffer = [0u8; 4096];
+        while let Ok(read) = socket.read(&mut buffer).await {
+            if read == 0 {
+                break;
+            }
+            data.extend_from_slice(&buffer[..read]);
+
+            let text = String::from_utf8_lossy(&data);
+            if let Some(header_end) = text.find("\r\n\r\n") {
+                let content_length = text[..header_end]
+                    .lines()
+                    .find_map(|line| {
+                        let (name, value) = line.split_once(':')?;
+                        name.eq_ignore_ascii_case("content-length")
+                            .then(|| value.trim().parse::<usize>().ok())
+                            .flatten()
+                    })
+                    .unwrap_or(0);
+                if data.len() >= header_end + 4 + content_length {
+                    break;
+                }
+            }
+        }
+        String::from_utf8_lossy(&data).to_string()
+    }
+
+    // Lets tests keep a handle on the mock after boxing it as a client
+    #[async_trait]
+    impl LlmClient for Arc<MockLlmClient> {
+        async fn send_message(&self, messages: &[Message]) -> Result<String, LlmError> {
+            self.as_ref().send_message(messages).await
+        }
+
+        async fn stream_message(&self, messages: &[Message]) -> Result<ResponseStream, LlmError> {
+            self.as_ref().stream_message(messages).await
+        }
+
+        async fn send_with_tools(
+            &self,
+            messages: &[Message],
+            tools: &[ToolSpec],
+        ) -> Result<LlmReply, LlmError> {
+            self.as_ref().send_with_tools(messages, tools).await
+        }
+
+        async fn send_structured(
+            &self,
+            messages: &[Message],
+            schema: &OutputSchema,
+        ) -> Result<String, LlmError> {
+            self.as_ref().send_structured(messages, schema).await
+        }
+
+        fn supports_continuation(&self) -> bool {
+            self.as_ref().supports_continuation()
+        }
+
+        async fn health_check(&self) -> Result<(), LlmError> {
+            self.as_ref().health_check().await
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::mock::{MockLlmClient, MockServer};
+    use super::*;
+    use chrono::Utc;
+    use tempfile::TempDir;
+
+    fn user_message(content: &str) -> Message {
+        Message {
+            role: MessageRole::User,
+            content: content.to_string(),
+            timestamp: Utc::now(),
+            provisional: false,
+            context_files: vec![],
+            tool_call: None,
+            images: Vec::new(),
+        }
+    }
+
+    fn system_message(content: &str) -> Message {
+        Message {
+            role: MessageRole::System,
+            ..user_message(content)
+        }
+    }
+
+    #[test]
+    fn test_openai_request_body() {
+        let client = OpenAiClient::new("key".to_string(), "gpt-4".to_string())
+            .with_max_tokens(Some(100))
+            .with_temperature(Some(0.5));
+        let body = client.request_body(&[system_message("Be brief"), user_message("Hi")], true);
+
+        assert_eq!(body["model"], "gpt-4");
+        assert_eq!(body["stream"], true);
+        assert_eq!(body["max_tokens"], 100);
+        assert_eq!(body["temperature"], 0.5);
+        assert_eq!(body["messages"][0]["role"], "system");
+        assert_eq!(body["messages"][1]["content"], "Hi");
+    }
+
+    #[test]
+    fn test_openai_sends_attached_image_as_content_part() {
+        let client = OpenAiClient::new("key".to_string(), "gpt-4o".to_string());
+        let message = Message {
+            images: vec![ImageAttachment {
+                name: "chart.png".to_string(),
+                media_type: "image/png".to_string(),
+                data: "iVBORw0K".to_string(),
+            }],
+            ..user_message("What does this show?")
+        };
+        let body = client.request_body(&[system_message("Be brief"), message], false);
+
+        assert_eq!(body["messages"][0]["content"], "Be brief");
+        assert_eq!(
+            body["messages"][1],
+            json!({
+                "role": "user",
+                "content": [
+                    { "type": "text", "text": "What does this show?" },
+                    { "type": "image_url", "image_url": { "url": "data:image/png;base64,iVBORw0K" } },
+                ],
+            })
+        );
+    }
+
+    #[test]
+    fn test_supports_images_only_for_vision_models() {
+        let mut provider = LlmProvider {
+            provider_type: ProviderType::OpenAi,
+            api_key: "key".to_string(),
+            model: "gpt-4o-mini".to_string(),
+            base_url: None,
+            max_tokens: None,
+            temperature: None,
+            stop: Vec::new(),
+            reasoning_effort: None,
+            thinking_budget_tokens: None,
+            echo_prefix: None,
+        };
+        assert!(supports_images(&provider));
+        provider.model = "gpt-3.5-turbo".to_string();
+        assert!(!supports_images(&provider));
+        provider.base_url = Some("http://localhost:8080".to_string());
+        assert!(supports_images(&provider));
+        provider.provider_type = ProviderType::Anthropic;
+        assert!(!supports_images(&provider));
+    }
+
+    #[test]
+    fn test_stop_sequences_are_sent_only_when_set() {
+        let stop = vec!["\n\n".to_string(), "END".to_string()];
+        let messages = [user_message("Hi")];
+
+        let openai = OpenAiClient::new("key".to_string(), "gpt-4".to_string());
+        assert!(openai.request_body(&messages, false).get("stop").is_none());
+        let body = openai.with_stop(stop.clone()).request_body(&messages, false);
+        assert_eq!(body["stop"], json!(["\n\n", "END"]));
+
+        let anthropic = AnthropicClient::new("key".to_string(), "claude-3-haiku".to_string());
+        assert!(anthropic.request_body(&messages, false).get("stop_sequences").is_none());
+        let body = anthropic.with_stop(stop).request_body(&messages, false);
+        assert_eq!(body["stop_sequences"], json!(["\n\n", "END"]));
+        assert!(body.get("stop").is_none());
+    }
+
+    #[test]
+    fn test_reasoning_parameters_go_only_to_their_provider() {
+        let messages = [user_message("Hi")];
+        let openai = OpenAiClient::new("key".to_string(), "o3-mini".to_string());
+        assert!(openai.request_body(&messages, false).get("reasoning_effort").is_none());
+        let body = openai.with_reasoning_effort(Some("high".to_string())).request_body(&messages, false);
+        assert_eq!(body["reasoning_effort"], "high");
+        assert!(body.get("thinking").is_none());
+
+        let anthropic = AnthropicClient::new("key".to_string(), "claude-3-7-sonnet".to_string())
+            .with_temperature(Some(0.5));
+        let body = anthropic.request_body(&messages, false);
+        assert!(body.get("thinking").is_none());
+        assert_eq!(body["temperature"], 0.5);
+        let body = anthropic.with_thinking_budget(Some(2048)).request_body(&messages, false);
+        assert_eq!(body["thinking"], json!({ "type": "enabled", "budget_tokens": 2048 }));
+        assert_eq!(body["max_tokens"], 2048 + ANTHROPIC_DEFAULT_MAX_TOKENS);
+        assert!(body.get("temperature").is_none());
+        assert!(body.get("reasoning_effort").is_none());
+    }
+
+    #[test]
+    fn test_openai_request_body_tool_messages() {
+        let call = ToolCall {
+            id: "call_1".to_string(),
+            name: "read_file".to_string(),
+            arguments: r#"{"path":"a.md"}"#.to_string(),
+        };
+        let request = Message {
+            role: MessageRole::Assistant,
+            content: String::new(),
+            tool_call: Some(call.clone()),
+            images: Vec::new(),
+            ..user_message("")
+        };
+        let result = Message {
+            role: MessageRole::Tool,
+            tool_call: Some(call),
+            images: Vec::new(),
+            ..user_message("file contents")
+        };
+        let client = OpenAiClient::new("key".to_string(), "gpt-4".to_string());
+        let body = client.request_body(&[request, result], false);
+
+        assert!(body["messages"][0]["content"].is_null());
+        assert_eq!(body["messages"][0]["tool_calls"][0]["id"], "call_1");
+        assert_eq!(body["messages"][0]["tool_calls"][0]["function"]["name"], "read_file");
+        assert_eq!(body["messages"][1]["role"], "tool");
+        assert_eq!(body["messages"][1]["tool_call_id"], "call_1");
+        assert_eq!(body["messages"][1]["content"], "file contents");
+    }
+
+    #[test]
+    fn test_anthropic_request_body_extracts_system_prompt() {
+        let client = AnthropicClient::new("key".to_string(), "claude-3-haiku".to_string());
+        let body = client.request_body(&[system_message("Be brief"), user_message("Hi")], false);
+
+        assert_eq!(body["system"], "Be brief");
+        assert_eq!(body["max_tokens"], ANTHROPIC_DEFAULT_MAX_TOKENS);
+        assert_eq!(body["messages"].as_array().unwrap().len(), 1);
+        assert_eq!(body["messages"][0]["role"], "user");
+        assert!(body.get("temperature").is_none());
+    }
+
+    #[test]
+    fn test_sse_decoder_handles_split_lines() {
+        let mut decoder = SseDecoder::default();
+
+        assert!(decoder.push(b"event: ping\ndata: {\"a\"").is_empty());
+        assert_eq!(decoder.push(b":1}\r\n\ndata: [DONE]\n"), vec!["{\"a\":1}", "[DONE]"]);
+    }
+
+    #[test]
+    fn test_sse_decoder_joins_character_split_across_chunks() {
+        let line = "data: {\"text\":\"caf\u{e9} \u{1f600}\"}\n".as_bytes();
+        // Split inside the two-byte "é", then inside the four-byte emoji
+        let e_acute = line.iter().position(|byte| *byte == 0xC3).unwrap();
+        let emoji = line.iter().position(|byte| *byte == 0xF0).unwrap();
+        let mut decoder = SseDecoder::default();
+
+        assert!(decoder.push(&line[..e_acute + 1]).is_empty());
+        assert!(decoder.push(&line[e_acute + 1..emoji + 2]).is_empty());
+        assert_eq!(decoder.push(&line[emoji + 2..]), vec!["{\"text\":\"caf\u{e9} \u{1f600}\"}"]);
+
+        let payload = decoder.push(b"data: \xC3").into_iter().chain(decoder.finish()).collect::<Vec<_>>();
+        assert_eq!(payload, vec!["\u{fffd}"]);
+    }
+
+    #[test]
+    fn test_error_for_status() {
+        assert!(matches!(error_for_status(401, ""), LlmError::Authentication));
+        assert!(matches!(error_for_status(429, ""), LlmError::RateLimit));
+        assert!(matches!(error_for_status(503, ""), LlmError::Server(503, _)));
+        assert!(error_for_status(500, "boom").to_string().contains("boom"));
+        assert!(matches!(error_for_status(400, "bad"), LlmError::Provider { status: 400, .. }));
+    }
+
+    #[test]
+    fn test_openai_context_length_error_maps_to_context_window() {
+        let body = r#"{"error":{"message":"This model's maximum context length is 8192 tokens.","type":"invalid_request_error","param":"messages","code":"context_length_exceeded"}}"#;
+        assert!(matches!(error_for_status(400, body), LlmError::ContextWindowExceeded));
+
+        let body = r#"{"type":"error","error":{"type":"invalid_request_error","message":"prompt is too long: 210000 tokens > 200000 maximum"}}"#;
+        assert!(matches!(error_for_status(400, body), LlmError::ContextWindowExceeded));
+    }
+
+    #[test]
+    fn test_provider_error_keeps_status_and_code() {
+        let body = r#"{"error":{"message":"You exceeded your current quota.","type":"insufficient_quota","code":"insufficient_quota"}}"#;
+        match error_for_status(402, body) {
+            LlmError::Provider { status, code, message } => {
+                assert_eq!(status, 402);
+                assert_eq!(code.as_deref(), Some("insufficient_quota"));
+                assert_eq!(message, "You exceeded your current quota.");
+            }
+            other => panic!("Expected a provider error, got {:?}", other),
+        }
+
+        // Anthropic has no code, so the error type is used
+        let body = r#"{"type":"error","error":{"type":"not_found_error","message":"model: claude-x"}}"#;
+        assert!(matches!(
+            error_for_status(404, body),
+            LlmError::Provider { status: 404, code: Some(code), .. } if code == "not_found_error"
+        ));
+
+        // A body that is not an error envelope is kept whole
+        assert!(matches!(
+            error_for_status(418, "teapot"),
+            LlmError::Provider { code: None, message, .. } if message == "teapot"
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_openai_send_message() {
+        let server = MockServer::start(vec![(
+            200,
+            r#"{"choices":[{"message":{"role":"assistant","content":"Hello!"}}]}"#.to_string(),
+        )])
+        .await;
+        let client = OpenAiClient::new("key".to_string(), "gpt-4".to_string())
+            .with_base_url(server.base_url.clone());
+
+        let response = client.send_message(&[user_message("Hi")]).await.unwrap();
+        assert_eq!(response, "Hello!");
+
+        let request = server.requests.lock().unwrap()[0].clone();
+        assert!(request.starts_with("POST /chat/completions"));
+        assert!(request.to_lowercase().contains("authorization: bearer key"));
+    }
+
+    #[tokio::test]
+    async fn test_openai_structured_request_sends_json_schema() {
+        let server = MockServer::start(vec![(
+            200,
+            r#"{"choices":[{"message":{"role":"assistant","content":"{\"files\":[]}"}}]}"#.to_string(),
+        )])
+        .await;
+        let client = OpenAiClient::new("key".to_string(), "gpt-4o".to_string())
+            .with_base_url(server.base_url.clone());
+        let schema = OutputSchema { name: "pick", schema: json!({ "type": "object" }) };
+
+        let reply = client.send_structured(&[user_message("Pick")], &schema).await.unwrap();
+        assert_eq!(reply, r#"{"files":[]}"#);
+        assert_eq!(
+            server.request_bodies()[0]["response_format"],
+            json!({
+                "type": "json_schema",
+                "json_schema": { "name": "pick", "strict": true, "schema": { "type": "object" } },
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn test_openai_structured_falls_back_when_format_is_rejected() {
+        let reply = |content: &str| {
+            json!({ "choices": [{ "message": { "role": "assistant", "content": content } }] }).to_string()
+        };
+        let server = MockServer::start(vec![
+            (400, r#"{"error":{"message":"response_format is not supported"}}"#.to_string()),
+            (200, reply("notes.md")),
+            (200, reply("other.md")),
+        ])
+        .await;
+        let client = OpenAiClient::new("key".to_string(), "local-model".to_string())
+            .with_base_url(server.base_url.clone());
+        let schema = OutputSchema { name: "pick", schema: json!({ "type": "object" }) };
+
+        assert_eq!(client.send_structured(&[user_message("Pick")], &schema).await.unwrap(), "notes.md");
+        assert_eq!(client.send_structured(&[user_message("Again")], &schema).await.unwrap(), "other.md");
+        let bodies = server.request_bodies();
+        assert_eq!(bodies.len(), 3);
+        assert!(bodies[0].get("response_format").is_some());
+        assert!(bodies[1].get("response_format").is_none());
+        assert!(bodies[2].get("response_format").is_none());
+    }
+
+    #[tokio::test]
+    async fn test_openai_503_is_retried_and_400_is_not() {
+        let server = MockServer::start(vec![
+            (503, "overloaded".to_string()),
+            (
+                200,
+                r#"{"choices":[{"message":{"role":"assistant","content":"Hello!"}}]}"#.to_string(),
+            ),
+            (400, "bad request".to_string()),
+        ])
+        .await;
+        let openai = OpenAiClient::new("key".to_string(), "gpt-4".to_string())
+            .with_base_url(server.base_url.clone());
+        let client = RetryingClient::new(Box::new(openai), 2, Duration::ZERO);
+
+        let response = client.send_message(&[user_message("Hi")]).await.unwrap();
+        assert_eq!(response, "Hello!");
+        assert_eq!(server.requests.lock().unwrap().len(), 2);
+
+        let err = client.send_message(&[user_message("Hi")]).await.unwrap_err();
+        assert!(err.to_string().contains("HTTP 400"));
+        assert_eq!(server.requests.lock().unwrap().len(), 3);
+    }
+
+    #[tokio::test]
+    async fn test_openai_send_with_tools_parses_tool_call() {
+        let server = MockServer::start(vec![(
+            200,
+            r#"{"choices":[{"message":{"role":"assistant","content":null,"tool_calls":[{"id":"call_1","type":"function","function":{"name":"read_file","arguments":"{\"path\":\"a.md\"}"}}]}}]}"#.to_string(),
+        )])
+        .await;
+        let client = OpenAiClient::new("key".to_string(), "gpt-4".to_string())
+            .with_base_url(server.base_url.clone());
+
+        let reply = client
+            .send_with_tools(&[user_message("Hi")], &[read_file_tool()])
+            .await
+            .unwrap();
+        assert_eq!(
+            reply,
+            LlmReply::ToolCall(ToolCall {
+                id: "call_1".to_string(),
+                name: "read_file".to_string(),
+                arguments: r#"{"path":"a.md"}"#.to_string(),
+            })
+        );
+
+        let body = &server.request_bodies()[0];
+        assert_eq!(body["tools"][0]["function"]["name"], "read_file");
+    }
+
+    #[tokio::test]
+    async fn test_openai_auth_failure() {
+        let server = MockServer::start(vec![(401, r#"{"error":{}}"#.to_string())]).await;
+        let client = OpenAiClient::new("bad".to_string(), "gpt-4".to_string())
+            .with_base_url(server.base_url.clone());
+
+        let result = client.send_message(&[user_message("Hi")]).await;
+        assert!(matches!(result, Err(LlmError::Authentication)));
+    }
+
+    #[tokio::test]
+    async fn test_anthropic_stream_message() {
+        let events = [
+            r#"{"type":"message_start"}"#,
+            r#"{"type":"content_block_delta","delta":{"type":"text_delta","text":"Hel"}}"#,
+            r#"{"type":"content_block_delta","delta":{"type":"text_delta","text":"lo"}}"#,
+            r#"{"type":"message_stop"}"#,
+        ];
+        let body: String = events
+            .iter()
+            .map(|event| format!("event: x\ndata: {}\n\n", event))
+            .collect();
+        let server = MockServer::start(vec![(200, body)]).await;
+        let client = AnthropicClient::new("key".to_string(), "claude-3-haiku".to_string())
+            .with_base_url(server.base_url.clone());
+
+        let tokens: Vec<String> = client
+            .stream_message(&[user_message("Hi")])
+            .await
+            .unwrap()
+            .map(|token| token.unwrap())
+            .collect()
+            .await;
+        assert_eq!(tokens, vec!["Hel", "lo"]);
+        assert_eq!(server.request_bodies()[0]["stream"], true);
+    }
+
+    #[tokio::test]
+    async fn test_debug_transcript_logs_redacted_request() {
+        let temp_dir = TempDir::new().expect("Failed to create temp dir");
+        let path = temp_dir.path().join("transcript.log");
+        let server = MockServer::start(vec![(
+            200,
+            r#"{"choices":[{"message":{"content":"Hello!"}}]}"#.to_string(),
+        )])
+        .await;
+        let client = OpenAiClient::new("sk-secret".to_string(), "gpt-4".to_string())
+            .with_base_url(server.base_url.clone())
+            .with_transcript(Some(Arc::new(DebugTranscript::new(path.clone()))));
+
+        client
+            .send_message(&[user_message("Echo sk-secret please")])
+            .await
+            .unwrap();
+
+        let transcript = std::fs::read_to_string(&path).expect("Transcript was not written");
+        assert!(transcript.contains("openai request POST"));
+        assert!(transcript.contains("api_key: [REDACTED]"));
+        assert!(transcript.contains("Echo [REDACTED] please"));
+        assert!(transcript.contains("openai response 200"));
+        assert!(transcript.contains("Hello!"));
+        assert!(!transcript.contains("sk-secret"));
+    }
+
+    #[tokio::test]
+    async fn test_debug_transcript_truncates_bodies_and_skips_requests() {
+        let temp_dir = TempDir::new().expect("Failed to create temp dir");
+        let path = temp_dir.path().join("transcript.log");
+        let reply = format!(r#"{{"choices":[{{"message":{{"content":"{}"}}}}]}}"#, "é".repeat(100));
+        let server = MockServer::start(vec![(200, reply.clone())]).await;
+        let transcript = DebugTranscript::new(path.clone()).with_limits(TranscriptBodies::Responses, Some(52));
+        let client = OpenAiClient::new("sk-secret".to_string(), "gpt-4".to_string())
+            .with_base_url(server.base_url.clone())
+            .with_transcript(Some(Arc::new(transcript)));
+
+        client.send_message(&[user_message("Hi")]).await.unwrap();
+
+        let transcript = std::fs::read_to_string(&path).expect("Transcript was not written");
+        assert!(!transcript.contains("openai request POST"));
+        assert!(transcript.contains("openai response 200"));
+        // 52 bytes would split a two-byte character, so 51 are kept
+        assert!(transcript.contains(&format!("\n{}\n[truncated: 51 of {} bytes logged]", &reply[..51], reply.len())));
+    }
+
+    fn assistant_message(content: &str) -> Message {
+        Message {
+            role: MessageRole::Assistant,
+            ..user_message(content)
+        }
+    }
+
+    #[test]
+    fn test_anthropic_sends_prefill_natively() {
+        let client = AnthropicClient::new("key".to_string(), "claude-3-haiku".to_string());
+        let body = client.request_body(&[user_message("JSON?"), assistant_message("{ ")], false);
+
+        let messages = body["messages"].as_array().unwrap();
+        assert_eq!(messages.len(), 2);
+        assert_eq!(messages[1]["role"], "assistant");
+        assert_eq!(messages[1]["content"], "{");
+        assert!(client.supports_continuation());
+        assert!(!client.with_thinking_budget(Some(2048)).supports_continuation());
+    }
+
+    #[test]
+    fn test_anthropic_prefill_with_thinking_is_instructed() {
+        let client = AnthropicClient::new("key".to_string(), "claude-3-haiku".to_string())
+            .with_thinking_budget(Some(2048));
+        let body = client.request_body(
+            &[system_message("Be terse."), user_message("JSON?"), assistant_message("{ ")],
+            false,
+        );
+
+        let messages = body["messages"].as_array().unwrap();
+        assert_eq!(messages.len(), 1);
+        assert_eq!(messages[0]["role"], "user");
+        let system = body["system"].as_str().unwrap();
+        assert!(system.starts_with("Be terse.\n\n"));
+        assert!(system.ends_with("continue it:\n{ "));
+        assert_eq!(body["thinking"]["budget_tokens"], 2048);
+    }
+
+    #[tokio::test]
+    async fn test_openai_prefill_is_instructed_and_stripped() {
+        let server = MockServer::start(vec![
+            (
+                200,
+                r#"{"choices":[{"message":{"role":"assistant","content":"{\"a\": 1}"}}]}"#.to_string(),
+            ),
+            (
+                200,
+                [
+                    r#"data: {"choices":[{"delta":{"content":"{"}}]}"#,
+                    r#"data: {"choices":[{"delta":{"content":"\"b\""}}]}"#,
+                    r#"data: {"choices":[{"delta":{"content":": 2}"}}]}"#,
+                    "data: [DONE]",
+                ]
+                .join("\n\n"),
+            ),
+        ])
+        .await;
+        let client = OpenAiClient::new("key".to_string(), "gpt-4".to_string())
+            .with_base_url(server.base_url.clone());
+        let messages = [user_message("JSON?"), assistant_message("{")];
+
+        let reply = client.send_message(&messages).await.unwrap();
+        assert_eq!(reply, "\"a\": 1}");
+        let body = &server.request_bodies()[0];
+        let sent = body["messages"].as_array().unwrap();
+        assert_eq!(sent.len(), 2);
+        assert_eq!(sent[1]["role"], "system");
+        assert!(sent[1]["content"].as_str().unwrap().ends_with("continue it:\n{"));
+
+        let tokens: Vec<String> = client
+            .stream_message(&messages)
+            .await
+            .unwrap()
+            .map(|token| token.unwrap())
+            .collect()
+            .await;
+        assert_eq!(tokens.concat(), "\"b\": 2}");
+    }
+
+    #[test]
+    fn test_strip_prefill_keeps_unechoed_reply() {
+        assert_eq!(strip_prefill("{\"a\": 1}", "{"), "\"a\": 1}");
+        assert_eq!(strip_prefill("  Sure: yes", "Sure:"), " yes");
+        assert_eq!(strip_prefill("\"a\": 1}", "{"), "\"a\": 1}");
+    }
+
+    #[tokio::test]
+    async fn test_stream_without_end_event_is_interrupted() {
+        let body = r#"data: {"choices":[{"delta":{"content":"Hel"}}]}"#.to_string() + "\n\n";
+        let server = MockServer::start(vec![(200, body)]).await;
+        let client = OpenAiClient::new("key".to_string(), "gpt-4".to_string())
+            .with_base_url(server.base_url.clone());
+
+        let items: Vec<Result<String, LlmError>> = client
+            .stream_message(&[user_message("Hi")])
+            .await
+            .unwrap()
+            .collect()
+            .await;
+        assert_eq!(items.len(), 2);
+        assert_eq!(items[0].as_ref().unwrap(), "Hel");
+        assert!(matches!(items[1], Err(LlmError::StreamInterrupted)));
+    }
+
+    #[tokio::test]
+    async fn test_debug_transcript_logs_reassembled_stream() {
+        let temp_dir = TempDir::new().expect("Failed to create temp dir");
+        let path = temp_dir.path().join("transcript.log");
+        let body = [
+            r#"data: {"choices":[{"delta":{"content":"Hel"}}]}"#,
+            r#"data: {"choices":[{"delta":{"content":"lo"}}]}"#,
+            "data: [DONE]",
+        ]
+        .join("\n\n");
+        let server = MockServer::start(vec![(200, body)]).await;
+        let client = OpenAiClient::new("key".to_string(), "gpt-4".to_string())
+            .with_base_url(server.base_url.clone())
+            .with_transcript(Some(Arc::new(DebugTranscript::new(path.clone()))));
+
+        let stream = client.stream_message(&[user_message("Hi")]).await.unwrap();
+        let _: Vec<_> = stream.collect().await;
+
+        let transcript = std::fs::read_to_string(&path).expect("Transcript was not written");
+        assert!(transcript.contains("openai response 200 ===\nHello\n"));
+    }
+
+    #[tokio::test]
+    async fn test_echo_client_returns_last_user_message() {
+        let client = EchoClient::new();
+        let messages = vec![
+            system_message("Be brief"),
+            user_message("first question"),
+            Message {
+                role: MessageRole::Assistant,
+                ..user_message("an answer")
+            },
+            user_message("second question"),
+        ];
+
+        assert_eq!(client.send_message(&messages).await.unwrap(), "second question");
+
+        let prefixed = EchoClient::new().with_prefix("Echo: ".to_string());
+        assert_eq!(prefixed.send_message(&messages).await.unwrap(), "Echo: second question");
+    }
+
+    #[tokio::test]
+    async fn test_echo_client_streams_tokens() {
+        let client = EchoClient::new();
+        let tokens: Vec<String> = client
+            .stream_message(&[user_message("one two three")])
+            .await
+            .unwrap()
+            .map(|token| token.unwrap())
+            .collect()
+            .await;
+
+        assert_eq!(tokens, vec!["one ", "two ", "three"]);
+    }
+
+    #[tokio::test]
+    async fn test_create_echo_client() {
+        let provider = LlmProvider {
+            provider_type: ProviderType::Echo,
+            api_key: String::new(),
+            model: "echo".to_string(),
+            base_url: None,
+            max_tokens: None,
+            temperature: None,
+            stop: Vec::new(),
+            reasoning_effort: None,
+            thinking_budget_tokens: None,
+            echo_prefix: Some("Echo: ".to_string()),
+        };
+
+        let client = create_llm_client(&provider, &ClientOptions::default()).unwrap();
+        assert_eq!(client.send_message(&[user_message("Hi")]).await.unwrap(), "Echo: Hi");
+    }
+
+    fn cache_provider(temperature: Option<f32>) -> LlmProvider {
+        LlmProvider {
+            provider_type: ProviderType::OpenAi,
+            api_key: "sk-test".to_string(),
+            model: "gpt-4".to_string(),
+            base_url: None,
+            max_tokens: None,
+            temperature,
+            stop: Vec::new(),
+            reasoning_effort: None,
+            thinking_budget_tokens: None,
+            echo_prefix: None,
+        }
+    }
+
+    fn caching_client(mock: Arc<MockLlmClient>, temperature: Option<f32>) -> CachingClient {
+        CachingClient::new(Box::new(mock), Arc::new(ResponseCache::new()), &cache_provider(temperature))
+    }
+
+    #[test]
+    fn test_cache_key_depends_on_request() {
+        let messages = vec![user_message("Hello")];
+        let provider = cache_provider(Some(0.0));
+
+        let key = cache_key(&messages, &provider);
+        assert_eq!(key, cache_key(&messages, &provider));
+        assert_eq!(key, cache_key(&messages, &LlmProvider { api_key: "sk-other".to_string(), ..provider.clone() }));
+        assert_ne!(key, cache_key(&[user_message("Hello!")], &provider));
+        let variants = [
+            LlmProvider { model: "gpt-3.5-turbo".to_string(), ..provider.clone() },
+            LlmProvider { temperature: Some(0.5), ..provider.clone() },
+            LlmProvider { provider_type: ProviderType::Local, ..provider.clone() },
+            LlmProvider { base_url: Some("http://localhost:8080".to_string()), ..provider.clone() },
+            LlmProvider { max_tokens: Some(100), ..provider.clone() },
+            LlmProvider { stop: vec!["END".to_string()], ..provider.clone() },
+            LlmProvider { reasoning_effort: Some("low".to_string()), ..provider.clone() },
+            LlmProvider { thinking_budget_tokens: Some(2048), ..provider.clone() },
+        ];
+        for variant in &variants {
+            assert_ne!(key, cache_key(&messages, variant), "{:?}", variant);
+        }
+
+        let mut with_image = user_message("Hello");
+        with_image.images.push(ImageAttachment {
+            name: "a.png".to_string(),
+            media_type: "image/png".to_string(),
+            data: "iVBOR".to_string(),
+        });
+        assert_ne!(key, cache_key(&[with_image], &provider));
+    }
+
+    #[tokio::test]
+    async fn test_caching_client_hit_skips_inner_call() {
+        let mock = Arc::new(MockLlmClient::with_responses(vec!["first", "second"]));
+        let client = caching_client(mock.clone(), Some(0.0));
+        let messages = vec![user_message("Hello")];
+
+        assert_eq!(client.send_message(&messages).await.unwrap(), "first");
+        assert_eq!(client.send_message(&messages).await.unwrap(), "first");
+        assert_eq!(mock.call_count(), 1);
+
+        // A different request misses the cache
+        let other = vec![user_message("Goodbye")];
+        assert_eq!(client.send_message(&other).await.unwrap(), "second");
+        assert_eq!(mock.call_count(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_caching_client_bypasses_positive_temperature() {
+        let mock = Arc::new(MockLlmClient::with_responses(vec!["first", "second"]));
+        let client = caching_client(mock.clone(), Some(0.7));
+        let messages = vec![user_message("Hello")];
+
+        assert_eq!(client.send_message(&messages).await.unwrap(), "first");
+        assert_eq!(client.send_message(&messages).await.unwrap(), "second");
+        assert_eq!(mock.call_count(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_retrying_client_retries_server_errors() {
+        let mock = Arc::new(MockLlmClient::new());
+        mock.push_response(Err(LlmError::Server(503, "unavailable".to_string())));
+        mock.push_response(Ok("recovered".to_string()));
+        let client = RetryingClient::new(Box::new(mock.clone()), 2, Duration::ZERO);
+
+        let response = client.send_message(&[user_message("Hello")]).await.unwrap();
+        assert_eq!(response, "recovered");
+        assert_eq!(mock.call_count(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_retrying_client_gives_up_after_attempts() {
+        let mock = Arc::new(MockLlmClient::new());
+        for _ in 0..3 {
+            mock.push_response(Err(LlmError::Server(502, "bad gateway".to_string())));
+        }
+        let client = RetryingClient::new(Box::new(mock.clone()), 2, Duration::ZERO);
+
+        let result = client.send_message(&[user_message("Hello")]).await;
+        assert!(matches!(result, Err(LlmError::Server(502, _))));
+        assert_eq!(mock.call_count(), 3);
+    }
+
+    #[tokio::test]
+    async fn test_retrying_client_does_not_retry_client_errors() {
+        let mock = Arc::new(MockLlmClient::new());
+        mock.push_response(Err(error_for_status(400, "bad request")));
+        mock.push_response(Err(LlmError::RateLimit));
+        let client = RetryingClient::new(Box::new(mock.clone()), 2, Duration::ZERO);
+
+        assert!(matches!(
+            client.send_message(&[user_message("Hello")]).await,
+            Err(LlmError::Provider { status: 400, .. })
+        ));
+        assert!(matches!(
+            client.send_message(&[user_message("Hello")]).await,
+            Err(LlmError::RateLimit)
+        ));
+        assert_eq!(mock.call_count(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_caching_client_does_not_cache_errors() {
+        let mock = Arc::new(MockLlmClient::new());
+        mock.push_response(Err(LlmError::RateLimit));
+        mock.push_response(Ok("recovered".to_string()));
+        let client = caching_client(mock.clone(), None);
+        let messages = vec![user_message("Hello")];
+
+        assert!(client.send_message(&messages).await.is_err());
+        assert_eq!(client.send_message(&messages).await.unwrap(), "recovered");
+        assert_eq!(mock.call_count(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_caching_client_caches_completed_stream() {
+        let mock = Arc::new(MockLlmClient::with_responses(vec!["streamed answer here"]));
+        let client = caching_client(mock.clone(), Some(0.0));
+        let messages = vec![user_message("Hello")];
+
+        let first: Vec<String> = client
+            .stream_message(&messages)
+            .await
+            .unwrap()
+            .map(|token| token.unwrap())
+            .collect()
+            .await;
+        assert_eq!(first.concat(), "streamed answer here");
+
+        let second: Vec<String> = client
+            .stream_message(&messages)
+            .await
+            .unwrap()
+            .map(|token| token.unwrap())
+            .collect()
+            .await;
+        assert_eq!(second.concat(), "streamed answer here");
+        assert_eq!(mock.call_count(), 1);
+    }
+
+    #[test]
+    fn test_response_cache_persists_to_disk() {
+        let temp_dir = TempDir::new().expect("Failed to create temp dir");
+        let path = temp_dir.path().join("cache").join("responses.json");
+
+        let cache = ResponseCache::with_path(path.clone());
+        cache.insert(42, "cached".to_string());
+        assert!(path.exists());
+
+        let reloaded = ResponseCache::with_path(path.clone());
+        assert_eq!(reloaded.get(42), Some("cached".to_string()));
+
+        reloaded.clear();
+        assert!(ResponseCache::with_path(path).is_empty());
+    }
+}
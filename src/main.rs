@@ -1,17 +1,35 @@
 use llm_tui_assistant::app::AppController;
 use llm_tui_assistant::types::*;
 use llm_tui_assistant::ui::{RatatuiRenderer, TuiRenderer};
+use std::fs::OpenOptions;
+use std::io::Read;
+use std::sync::Mutex;
+use std::time::Duration;
 use tracing::{error, info};
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
-    // Initialize logging
-    tracing_subscriber::fmt::init();
+    // Initialize application controller
+    let app = AppController::new();
+
+    // Initialize logging, to the configured file when there is one
+    let log_file = app.as_ref().ok().and_then(|app| app.config().log_file.clone());
+    match log_file.map(|path| OpenOptions::new().create(true).append(true).open(path)) {
+        Some(Ok(file)) => tracing_subscriber::fmt()
+            .with_writer(Mutex::new(file))
+            .with_ansi(false)
+            .init(),
+        Some(Err(e)) => {
+            tracing_subscriber::fmt().with_writer(std::io::stderr).init();
+            error!("Failed to open log file, logging to stderr: {}", e);
+        }
+        // Stderr, since stdout carries one-shot replies
+        None => tracing_subscriber::fmt().with_writer(std::io::stderr).init(),
+    }
 
     info!("Starting LLM TUI Assistant");
 
-    // Initialize application controller
-    let mut app = match AppController::new() {
+    let mut app = match app {
         Ok(app) => app,
         Err(e) => {
             error!("Failed to initialize application: {}", e);
@@ -19,8 +37,35 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         }
     };
 
+    // One-shot mode: `--once [--stream] [message]` prints the reply to one
+    // message, read from stdin when not given, instead of starting the TUI
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if args.iter().any(|arg| arg == "--once") {
+        let stream = args.iter().any(|arg| arg == "--stream");
+        let mut message = args
+            .iter()
+            .filter(|arg| !arg.starts_with("--"))
+            .cloned()
+            .collect::<Vec<_>>()
+            .join(" ");
+        if message.is_empty() {
+            std::io::stdin().read_to_string(&mut message)?;
+        }
+        app.run_one_shot(message, stream, &mut std::io::stdout()).await?;
+        return Ok(());
+    }
+
+    // Serve mode: `serve [address]` exposes the assistant over HTTP instead
+    // of starting the TUI; needs the `serve` feature
+    #[cfg(feature = "serve")]
+    if args.first().map(String::as_str) == Some("serve") {
+        let addr = args.get(1).map_or(llm_tui_assistant::server::DEFAULT_SERVE_ADDR, String::as_str);
+        llm_tui_assistant::server::serve(app, addr.parse()?).await?;
+        return Ok(());
+    }
+
     // Initialize TUI renderer
-    let mut renderer = match RatatuiRenderer::new() {
+    let mut renderer = match RatatuiRenderer::new(app.config().enable_mouse) {
         Ok(renderer) => renderer,
         Err(e) => {
             error!("Failed to initialize TUI: {}", e);
@@ -28,6 +73,14 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         }
     };
 
+    renderer.set_frame_interval(Duration::from_millis(app.config().frame_interval_ms));
+    renderer.set_keybindings(app.config().keybindings);
+    renderer.set_idle_timeout(
+        app.config().idle_timeout_minutes.map(|minutes| Duration::from_secs(minutes * 60)),
+        app.config().exit_on_idle,
+    );
+    renderer.set_thinking_placeholder(app.config().thinking_placeholder.clone());
+
     info!("Application initialized successfully");
 
     // TODO: Implement main application loop
@@ -0,0 +1,145 @@
+// HTTP API over the assistant for driving it from other tools. Each request
+// to /chat is answered in a fresh conversation, with RAG and the provider
+// taken from the same config as the TUI. API conversations are not saved,
+// so they stay out of the TUI's conversation history.
+
+use crate::app::AppController;
+use crate::types::*;
+use axum::extract::State;
+use axum::http::StatusCode;
+use axum::response::sse::{Event, Sse};
+use axum::response::{IntoResponse, Response};
+use axum::routing::post;
+use axum::{Json, Router};
+use futures::stream::{self, StreamExt};
+use serde::{Deserialize, Serialize};
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tokio::net::TcpListener;
+use tokio::sync::{mpsc, Mutex};
+use tracing::info;
+
+pub const DEFAULT_SERVE_ADDR: &str = "127.0.0.1:3000";
+
+#[derive(Debug, Deserialize)]
+pub struct ChatRequest {
+    pub prompt: String,
+    // Send the reply as server-sent events, one per token, ending with a "done" event
+    #[serde(default)]
+    pub stream: bool,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ChatResponse {
+    pub response: String,
+}
+
+// Requests are handled one at a time, as the controller holds one conversation
+type SharedApp = Arc<Mutex<AppController>>;
+
+pub fn router(mut app: AppController) -> Router {
+    app.set_auto_save(false);
+    Router::new()
+        .route("/chat", post(chat))
+        .with_state(Arc::new(Mutex::new(app)))
+}
+
+/// Serves the API on `addr` until the process is stopped
+pub async fn serve(app: AppController, addr: SocketAddr) -> std::io::Result<()> {
+    let listener = TcpListener::bind(addr).await?;
+    info!("Serving the assistant API on http://{}", listener.local_addr()?);
+    axum::serve(listener, router(app)).await
+}
+
+async fn chat(State(app): State<SharedApp>, Json(request): Json<ChatRequest>) -> Response {
+    if request.stream {
+        return stream_chat(app, request.prompt).await;
+    }
+
+    let mut app = app.lock().await;
+    app.clear_conversation();
+    match app.run_one_shot(request.prompt, false, &mut std::io::sink()).await {
+        Ok(response) => Json(ChatResponse { response }).into_response(),
+        Err(e) => error_response(e),
+    }
+}
+
+// Runs the reply in the background, forwarding tokens as they arrive
+async fn stream_chat(app: SharedApp, prompt: String) -> Response {
+    let (tx, rx) = mpsc::unbounded_channel();
+    tokio::spawn(async move {
+        let mut app = app.lock().await;
+        app.clear_conversation();
+        let tokens = tx.clone();
+        let result = app
+            .process_message_streaming(prompt, move |token: &str| {
+                let _ = tokens.send(Event::default().data(token));
+            })
+            .await;
+        let last = match result {
+            Ok(_) => Event::default().event("done").data(""),
+            Err(e) => Event::default().event("error").data(e.to_string()),
+        };
+        let _ = tx.send(last);
+    });
+
+    let events = stream::unfold(rx, |mut rx| async move { rx.recv().await.map(|event| (event, rx)) });
+    Sse::new(events.map(Ok::<_, Infallible>)).into_response()
+}
+
+fn error_response(error: AppError) -> Response {
+    let status = match error {
+        AppError::Llm(_) => StatusCode::BAD_GATEWAY,
+        _ => StatusCode::INTERNAL_SERVER_ERROR,
+    };
+    (status, Json(serde_json::json!({ "error": error.to_string() }))).into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::ConfigManager;
+    use crate::llm::mock::MockLlmClient;
+    use tempfile::TempDir;
+
+    async fn start(client: Arc<MockLlmClient>, temp_dir: &TempDir) -> String {
+        let mut config_manager = ConfigManager::from_path(temp_dir.path().join("config.toml")).unwrap();
+        config_manager.get_config_mut().conversation_storage_path = temp_dir.path().join("conversations");
+        let app = AppController::from_config(config_manager).unwrap().with_llm_client(Box::new(client));
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/chat", listener.local_addr().unwrap());
+        tokio::spawn(async move { axum::serve(listener, router(app)).await });
+        url
+    }
+
+    #[tokio::test]
+    async fn test_chat_endpoint_returns_reply_as_json() {
+        let temp_dir = TempDir::new().unwrap();
+        let client = Arc::new(MockLlmClient::with_responses(vec!["Hello from the mock"]));
+        client.push_stream(vec![Ok("Str".to_string()), Ok("eamed".to_string())]);
+        let url = start(client.clone(), &temp_dir).await;
+        let http = reqwest::Client::new();
+
+        let response = http.post(&url).json(&serde_json::json!({ "prompt": "Hi" })).send().await.unwrap();
+        assert_eq!(response.status(), 200);
+        let body: ChatResponse = response.json().await.unwrap();
+        assert_eq!(body.response, "Hello from the mock");
+        assert_eq!(client.requests.lock().unwrap()[0].last().unwrap().content, "Hi");
+
+        let events = http
+            .post(&url)
+            .json(&serde_json::json!({ "prompt": "Again", "stream": true }))
+            .send()
+            .await
+            .unwrap()
+            .text()
+            .await
+            .unwrap();
+        assert!(events.contains("data: Str\n\ndata: eamed\n\n"));
+        assert!(events.contains("event: done"));
+        // Each request starts a fresh conversation, and none is stored
+        assert_eq!(client.requests.lock().unwrap()[1].len(), 1);
+        assert!(!temp_dir.path().join("conversations").exists());
+    }
+}
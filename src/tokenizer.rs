@@ -0,0 +1,100 @@
+// Token counting for prompts shown in the UI. No tokenizer vocabularies are
+// bundled, so counts are estimates tuned per model family.
+
+use regex::Regex;
+use std::sync::OnceLock;
+
+// Characters per token when nothing better is known about the model
+pub const DEFAULT_CHARS_PER_TOKEN: f64 = 4.0;
+
+// Claude's tokenizer splits English slightly finer than GPT's
+const CLAUDE_CHARS_PER_TOKEN: f64 = 3.5;
+
+// The cl100k pre-tokenization pattern without its whitespace lookahead, which
+// the regex crate lacks; whitespace runs still come out as the same number of pieces
+const GPT_PIECE_PATTERN: &str = r"'(?i:[sdmt]|ll|ve|re)|[^\r\n\p{L}\p{N}]?\p{L}+|\p{N}{1,3}| ?[^\s\p{L}\p{N}]+[\r\n]*|\s*[\r\n]+|\s+";
+
+// Letters a single BPE token typically covers; longer words count as several
+const GPT_WORD_CHARS: usize = 10;
+
+pub trait Tokenizer: Send + Sync {
+    fn count(&self, text: &str) -> usize;
+}
+
+/// Character-based estimate for models without a known tokenizer
+pub struct ApproximateTokenizer {
+    chars_per_token: f64,
+}
+
+impl ApproximateTokenizer {
+    pub fn new(chars_per_token: f64) -> Self {
+        Self { chars_per_token }
+    }
+}
+
+impl Default for ApproximateTokenizer {
+    fn default() -> Self {
+        Self::new(DEFAULT_CHARS_PER_TOKEN)
+    }
+}
+
+impl Tokenizer for ApproximateTokenizer {
+    fn count(&self, text: &str) -> usize {
+        (text.chars().count() as f64 / self.chars_per_token).ceil() as usize
+    }
+}
+
+/// Splits text the way GPT models' cl100k tokenizer does before applying
+/// BPE, so punctuation, digits and whitespace count exactly; only words
+/// longer than a typical token are estimated
+pub struct GptTokenizer;
+
+impl Tokenizer for GptTokenizer {
+    fn count(&self, text: &str) -> usize {
+        static PIECES: OnceLock<Regex> = OnceLock::new();
+        let pieces = PIECES.get_or_init(|| Regex::new(GPT_PIECE_PATTERN).expect("Invalid piece pattern"));
+        pieces
+            .find_iter(text)
+            .map(|piece| piece.as_str().trim_start().chars().count().div_ceil(GPT_WORD_CHARS).max(1))
+            .sum()
+    }
+}
+
+/// Picks the tokenizer for a model by its name; unknown models get the
+/// approximate one
+pub fn tokenizer_for_model(model: &str) -> Box<dyn Tokenizer> {
+    let model = model.to_lowercase();
+    let gpt = ["gpt-", "chatgpt", "o1", "o3", "o4"].iter().any(|prefix| model.starts_with(prefix));
+    if gpt {
+        Box::new(GptTokenizer)
+    } else if model.starts_with("claude") {
+        Box::new(ApproximateTokenizer::new(CLAUDE_CHARS_PER_TOKEN))
+    } else {
+        Box::new(ApproximateTokenizer::default())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_gpt_tokenizer_matches_known_counts() {
+        let tokenizer = GptTokenizer;
+        // "Hello" "," " world" "!"
+        assert_eq!(tokenizer.count("Hello, world!"), 4);
+        // "The" " year" " " "202" "4" " has" " " "123" "45" " items" "."
+        assert_eq!(tokenizer.count("The year 2024 has 12345 items."), 11);
+        // "I" " don" "'t" " know"
+        assert_eq!(tokenizer.count("I don't know"), 4);
+        assert_eq!(tokenizer.count(""), 0);
+    }
+
+    #[test]
+    fn test_unknown_models_fall_back_to_approximation() {
+        let text = "a".repeat(40);
+        assert_eq!(tokenizer_for_model("llama3.1:8b").count(&text), 10);
+        assert_eq!(tokenizer_for_model("claude-sonnet-4-5").count(&text), 12);
+        assert_eq!(tokenizer_for_model("gpt-4o").count("Hello, world!"), 4);
+    }
+}
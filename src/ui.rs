@@ -1,53 +1,923 @@
 use crate::types::*;
+use crate::config::{ConfigManager, KeyBindings};
+use crate::conversation::{number_code_blocks, ConversationSummary};
+use crate::filesystem::{FilePreview, IndexProgress};
+use crate::llm::default_model;
 use crossterm::{
-    event::{self, DisableMouseCapture, EnableMouseCapture, Event, KeyCode, KeyEventKind},
+    event::{
+        self, DisableMouseCapture, EnableMouseCapture, Event, KeyCode, KeyEvent, KeyEventKind,
+        KeyModifiers,
+    },
     execute,
     terminal::{disable_raw_mode, enable_raw_mode, EnterAlternateScreen, LeaveAlternateScreen},
 };
 use ratatui::{
     backend::CrosstermBackend,
-    layout::{Constraint, Direction, Layout},
+    layout::{Constraint, Direction, Layout, Rect},
     style::{Color, Modifier, Style},
     text::{Line, Span},
-    widgets::{Block, Borders, Clear, List, ListItem, Paragraph, Wrap},
+    widgets::{Block, Borders, Clear, List, ListItem, ListState, Paragraph, Wrap},
     Frame, Terminal,
 };
-use std::io::{self, Stdout};
+use std::io::{self, Stdout, Write};
 use std::time::{Duration, Instant};
-use std::path::PathBuf;
+use tracing::warn;
+use unicode_segmentation::UnicodeSegmentation;
+use unicode_width::UnicodeWidthStr;
 
 // UI state - only display-related information
 #[derive(Debug)]
 pub struct TuiState {
     pub input_buffer: String,
+    // Characters between the cursor and the end of the input, so text set
+    // directly on the buffer keeps the cursor at its end
+    pub cursor_from_end: usize,
     pub scroll_position: usize,
+    // Messages the view has accounted for, and whether any arrived below
+    // the view while auto-scroll was off
+    pub seen_message_count: usize,
+    pub unseen_messages: bool,
     pub command_mode: bool,
     pub status_message: Option<String>,
     pub show_help: bool,
+    pub preview: Option<FilePreview>,
+    pub preview_scroll: usize,
+    pub picker: Option<ConversationPicker>,
+    // Replies from /compare shown side by side
+    pub comparison: Option<Vec<ComparisonReply>>,
+    // Question shown in the yes/no dialog, answered with y/Enter or n/Esc
+    pub confirm: Option<String>,
+    pub setup: Option<SetupWizard>,
+    pub patterns: Option<PatternEditor>,
+    // Input of whichever mode is not active, restored when toggling back
+    pub message_draft: String,
+    pub command_draft: String,
+    pub frame_interval: Duration,
+    pub keybindings: KeyBindings,
+    // Set while a response streams or indexing runs, so the UI refreshes faster
+    pub busy: bool,
+    // Mirrors the app's read-only view, in which only scrolling, help, exit
+    // and leaving the view are handled
+    pub view_only: bool,
     pub last_input_time: Instant,
+    // Inactivity after which the conversation is saved, and whether the app
+    // then exits; None disables the timeout
+    pub idle_timeout: Option<Duration>,
+    pub exit_on_idle: bool,
+    // Set once the timeout has fired, until the next key press
+    pub idle_handled: bool,
+    // Shown until a streamed reply's first token; empty for none
+    pub thinking_placeholder: String,
 }
 
 impl Default for TuiState {
     fn default() -> Self {
         Self {
             input_buffer: String::new(),
+            cursor_from_end: 0,
             scroll_position: 0,
+            seen_message_count: 0,
+            unseen_messages: false,
             command_mode: false,
             status_message: None,
             show_help: false,
+            preview: None,
+            preview_scroll: 0,
+            picker: None,
+            comparison: None,
+            confirm: None,
+            setup: None,
+            patterns: None,
+            message_draft: String::new(),
+            command_draft: String::new(),
+            frame_interval: Duration::from_millis(DEFAULT_FRAME_INTERVAL_MS),
+            keybindings: KeyBindings::default(),
+            busy: false,
+            view_only: false,
             last_input_time: Instant::now(),
+            idle_timeout: None,
+            exit_on_idle: false,
+            idle_handled: false,
+            thinking_placeholder: String::new(),
         }
     }
 }
 
+// Selection state of the stored-conversation picker overlay
+#[derive(Debug, Clone)]
+pub struct ConversationPicker {
+    pub entries: Vec<ConversationSummary>,
+    pub selected: usize,
+}
+
+impl ConversationPicker {
+    pub fn new(entries: Vec<ConversationSummary>) -> Self {
+        Self { entries, selected: 0 }
+    }
+
+    pub fn move_up(&mut self) {
+        self.selected = self.selected.saturating_sub(1);
+    }
+
+    pub fn move_down(&mut self) {
+        if self.selected + 1 < self.entries.len() {
+            self.selected += 1;
+        }
+    }
+
+    pub fn selected_entry(&self) -> Option<&ConversationSummary> {
+        self.entries.get(self.selected)
+    }
+}
+
+// Providers offered by the setup wizard, in display order
+const SETUP_PROVIDERS: [ProviderType; 3] =
+    [ProviderType::OpenAi, ProviderType::Anthropic, ProviderType::Echo];
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SetupStep {
+    Provider,
+    ApiKey,
+    Model,
+}
+
+// Form collecting a provider on first run or after `/setup`, one field per step
+#[derive(Debug, Clone)]
+pub struct SetupWizard {
+    pub step: SetupStep,
+    pub provider_index: usize,
+    pub api_key: String,
+    pub model: String,
+    // Why the last step was not accepted
+    pub error: Option<String>,
+}
+
+impl Default for SetupWizard {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl SetupWizard {
+    pub fn new() -> Self {
+        Self {
+            step: SetupStep::Provider,
+            provider_index: 0,
+            api_key: String::new(),
+            model: String::new(),
+            error: None,
+        }
+    }
+
+    pub fn provider_type(&self) -> ProviderType {
+        SETUP_PROVIDERS[self.provider_index].clone()
+    }
+
+    pub fn move_up(&mut self) {
+        if self.step == SetupStep::Provider {
+            self.provider_index = self.provider_index.saturating_sub(1);
+        }
+    }
+
+    pub fn move_down(&mut self) {
+        if self.step == SetupStep::Provider && self.provider_index + 1 < SETUP_PROVIDERS.len() {
+            self.provider_index += 1;
+        }
+    }
+
+    pub fn push_char(&mut self, c: char) {
+        match self.step {
+            SetupStep::Provider => {}
+            SetupStep::ApiKey => self.api_key.push(c),
+            SetupStep::Model => self.model.push(c),
+        }
+    }
+
+    pub fn pop_char(&mut self) {
+        match self.step {
+            SetupStep::Provider => {}
+            SetupStep::ApiKey => {
+                self.api_key.pop();
+            }
+            SetupStep::Model => {
+                self.model.pop();
+            }
+        }
+    }
+
+    // The provider as entered so far; an empty model means the provider default
+    pub fn provider(&self) -> LlmProvider {
+        LlmProvider {
+            provider_type: self.provider_type(),
+            api_key: self.api_key.trim().to_string(),
+            model: self.model.trim().to_string(),
+            base_url: None,
+            max_tokens: None,
+            temperature: None,
+            stop: Vec::new(),
+            reasoning_effort: None,
+            thinking_budget_tokens: None,
+            echo_prefix: None,
+        }
+    }
+
+    /// Accepts the current step. Each step is checked with the config's
+    /// provider validation before moving on, and the last one returns the
+    /// finished provider.
+    pub fn advance(&mut self) -> Option<LlmProvider> {
+        let provider = self.provider();
+        if self.step != SetupStep::Provider {
+            if let Err(e) = ConfigManager::validate_llm_provider(&provider) {
+                self.error = Some(e.to_string());
+                return None;
+            }
+        }
+        self.error = None;
+        self.step = match self.step {
+            // The offline echo provider needs no key
+            SetupStep::Provider if provider.provider_type == ProviderType::Echo => SetupStep::Model,
+            SetupStep::Provider => SetupStep::ApiKey,
+            SetupStep::ApiKey => SetupStep::Model,
+            SetupStep::Model => return Some(provider),
+        };
+        None
+    }
+}
+
+// Overlay for editing the global include and exclude patterns. Edits are made
+// to its own copy of the lists and returned for the controller to save.
+#[derive(Debug, Clone)]
+pub struct PatternEditor {
+    pub include: Vec<String>,
+    pub exclude: Vec<String>,
+    // List that Up/Down, Delete and Enter act on
+    pub kind: PatternKind,
+    pub selected: usize,
+    pub input: String,
+    // Why the input can't be added, updated as it is typed
+    pub error: Option<String>,
+}
+
+impl PatternEditor {
+    pub fn new(include: Vec<String>, exclude: Vec<String>) -> Self {
+        Self {
+            include,
+            exclude,
+            kind: PatternKind::Include,
+            selected: 0,
+            input: String::new(),
+            error: None,
+        }
+    }
+
+    pub fn patterns(&self) -> &[String] {
+        match self.kind {
+            PatternKind::Include => &self.include,
+            PatternKind::Exclude => &self.exclude,
+        }
+    }
+
+    fn patterns_mut(&mut self) -> &mut Vec<String> {
+        match self.kind {
+            PatternKind::Include => &mut self.include,
+            PatternKind::Exclude => &mut self.exclude,
+        }
+    }
+
+    pub fn switch_list(&mut self) {
+        self.kind = match self.kind {
+            PatternKind::Include => PatternKind::Exclude,
+            PatternKind::Exclude => PatternKind::Include,
+        };
+        self.selected = 0;
+        self.validate();
+    }
+
+    pub fn move_up(&mut self) {
+        self.selected = self.selected.saturating_sub(1);
+    }
+
+    pub fn move_down(&mut self) {
+        if self.selected + 1 < self.patterns().len() {
+            self.selected += 1;
+        }
+    }
+
+    pub fn push_char(&mut self, c: char) {
+        self.input.push(c);
+        self.validate();
+    }
+
+    pub fn pop_char(&mut self) {
+        self.input.pop();
+        self.validate();
+    }
+
+    fn validate(&mut self) {
+        self.error = if self.input.is_empty() {
+            None
+        } else if self.patterns().contains(&self.input) {
+            Some(format!("{} is already in the list", self.input))
+        } else {
+            // The last line of a regex error names the problem
+            regex::Regex::new(&self.input)
+                .err()
+                .map(|e| e.to_string().lines().last().unwrap_or_default().to_string())
+        };
+    }
+
+    /// Adds the typed pattern to the focused list if it is a valid regex
+    pub fn add(&mut self) -> Option<PatternEdit> {
+        self.validate();
+        if self.input.is_empty() || self.error.is_some() {
+            return None;
+        }
+        let pattern = std::mem::take(&mut self.input);
+        self.patterns_mut().push(pattern.clone());
+        self.selected = self.patterns().len() - 1;
+        Some(PatternEdit::Add(self.kind, pattern))
+    }
+
+    pub fn remove(&mut self) -> Option<PatternEdit> {
+        if self.selected >= self.patterns().len() {
+            return None;
+        }
+        let selected = self.selected;
+        let pattern = self.patterns_mut().remove(selected);
+        self.selected = self.selected.min(self.patterns().len().saturating_sub(1));
+        self.validate();
+        Some(PatternEdit::Remove(self.kind, pattern))
+    }
+
+    /// Moves the selected pattern one place, keeping it selected
+    pub fn reorder(&mut self, up: bool) -> Option<PatternEdit> {
+        let selected = self.selected;
+        let target = if up { selected.checked_sub(1) } else { Some(selected + 1) };
+        let target = target.filter(|target| *target < self.patterns().len())?;
+        self.patterns_mut().swap(selected, target);
+        self.selected = target;
+        Some(PatternEdit::Move(self.kind, self.patterns()[target].clone(), up))
+    }
+}
+
+pub const DEFAULT_FRAME_INTERVAL_MS: u64 = 100;
+
+// Floor for the input poll timeout while busy, roughly 60 frames per second
+const MIN_FRAME_INTERVAL: Duration = Duration::from_millis(16);
+
+// Lines scrolled per Page Up/Down in the preview popup
+const PREVIEW_SCROLL_STEP: usize = 10;
+
+impl TuiState {
+    pub fn open_preview(&mut self, preview: FilePreview) {
+        self.preview = Some(preview);
+        self.preview_scroll = 0;
+    }
+
+    // Replaces the open preview of the same file with fresher content, keeping
+    // the scroll position; a preview that was closed or replaced stays so
+    pub fn refresh_preview(&mut self, preview: FilePreview) {
+        if let Some(open) = &mut self.preview {
+            if open.path == preview.path {
+                *open = preview;
+            }
+        }
+    }
+
+    pub fn open_picker(&mut self, entries: Vec<ConversationSummary>) {
+        self.picker = Some(ConversationPicker::new(entries));
+    }
+
+    pub fn open_comparison(&mut self, replies: Vec<ComparisonReply>) {
+        self.comparison = Some(replies);
+    }
+
+    pub fn open_setup(&mut self) {
+        self.setup = Some(SetupWizard::new());
+    }
+
+    pub fn open_patterns(&mut self, include: Vec<String>, exclude: Vec<String>) {
+        self.patterns = Some(PatternEditor::new(include, exclude));
+    }
+
+    pub fn open_confirm(&mut self, prompt: String) {
+        self.confirm = Some(prompt);
+    }
+
+    // Restores a stored scroll offset, clamped to the messages now loaded
+    pub fn restore_scroll_position(&mut self, position: usize, message_count: usize) {
+        self.scroll_position = position.min(message_count.saturating_sub(1));
+        self.seen_message_count = message_count;
+        self.unseen_messages = false;
+    }
+
+    pub fn at_bottom(&self) -> bool {
+        self.scroll_position + 1 >= self.seen_message_count
+    }
+
+    /// Updates the view for the current message count. New messages move the
+    /// view to the bottom when auto-scroll is on or the view is already there;
+    /// otherwise the view stays put and they are flagged as unseen.
+    pub fn track_messages(&mut self, message_count: usize, auto_scroll: bool) {
+        if message_count > self.seen_message_count && !auto_scroll && !self.at_bottom() {
+            self.unseen_messages = true;
+        } else if message_count != self.seen_message_count {
+            self.scroll_position = message_count.saturating_sub(1);
+            self.unseen_messages = false;
+        }
+        self.seen_message_count = message_count;
+    }
+
+    // Moves the view a message at a time; reaching the bottom clears the
+    // unseen flag
+    pub fn scroll_messages(&mut self, up: bool) {
+        self.scroll_position = if up {
+            self.scroll_position.saturating_sub(1)
+        } else {
+            (self.scroll_position + 1).min(self.seen_message_count.saturating_sub(1))
+        };
+        if self.at_bottom() {
+            self.unseen_messages = false;
+        }
+    }
+
+    pub fn close_preview(&mut self) {
+        self.preview = None;
+        self.preview_scroll = 0;
+    }
+
+    // Applies a key press to the state, returning an action for the controller if any
+    pub fn handle_key(&mut self, key: KeyEvent) -> Result<Option<UserAction>, TuiError> {
+        // Only handle key press events, not release
+        if key.kind != KeyEventKind::Press {
+            return Ok(None);
+        }
+        self.last_input_time = Instant::now();
+        self.idle_handled = false;
+
+        match key.code {
+            KeyCode::Char('c') if key.modifiers.contains(KeyModifiers::CONTROL) => {
+                return Ok(Some(UserAction::Exit));
+            }
+            KeyCode::Char('l') if key.modifiers.contains(KeyModifiers::CONTROL) => {
+                return Ok(Some(UserAction::Redraw));
+            }
+            KeyCode::Char(c)
+                if key.modifiers.contains(KeyModifiers::CONTROL)
+                    && c == self.keybindings.toggle_view =>
+            {
+                return Ok(Some(UserAction::ExecuteCommand(Command::ToggleView)));
+            }
+            KeyCode::F(1) => {
+                self.show_help = !self.show_help;
+                return Ok(None);
+            }
+            KeyCode::Char(_)
+            | KeyCode::Enter
+            | KeyCode::Tab
+            | KeyCode::Backspace
+            | KeyCode::Delete
+            | KeyCode::Left
+            | KeyCode::Right
+            | KeyCode::Home
+            | KeyCode::End
+                if self.view_only =>
+            {
+                return Ok(None);
+            }
+            KeyCode::Char(c)
+                if key.modifiers.contains(KeyModifiers::CONTROL)
+                    && c == self.keybindings.toggle_rag =>
+            {
+                return Ok(Some(UserAction::ExecuteCommand(Command::ToggleRag)));
+            }
+            KeyCode::Char(c)
+                if key.modifiers.contains(KeyModifiers::CONTROL)
+                    && c == self.keybindings.toggle_provisional =>
+            {
+                return Ok(Some(UserAction::ExecuteCommand(Command::ToggleProvisional)));
+            }
+            KeyCode::Char(c)
+                if key.modifiers.contains(KeyModifiers::CONTROL)
+                    && c == self.keybindings.toggle_scratch =>
+            {
+                return Ok(Some(UserAction::ExecuteCommand(Command::ToggleScratch)));
+            }
+            KeyCode::Char('y') | KeyCode::Char('Y') | KeyCode::Enter if self.confirm.is_some() => {
+                self.confirm = None;
+                return Ok(Some(UserAction::Confirm(true)));
+            }
+            KeyCode::Char('n') | KeyCode::Char('N') | KeyCode::Esc if self.confirm.is_some() => {
+                self.confirm = None;
+                return Ok(Some(UserAction::Confirm(false)));
+            }
+            _ if self.confirm.is_some() => {
+                return Ok(None);
+            }
+            _ if self.setup.is_some() => {
+                return Ok(self.handle_setup_key(key.code));
+            }
+            _ if self.patterns.is_some() => {
+                return Ok(self.handle_patterns_key(key));
+            }
+            KeyCode::Esc => {
+                if self.show_help {
+                    self.show_help = false;
+                } else if self.picker.is_some() {
+                    self.picker = None;
+                } else if self.comparison.is_some() {
+                    self.comparison = None;
+                } else if self.preview.is_some() {
+                    self.close_preview();
+                } else if !self.input_buffer.is_empty() {
+                    self.clear_input();
+                } else {
+                    return Ok(Some(UserAction::Exit));
+                }
+                return Ok(None);
+            }
+            KeyCode::Tab => {
+                self.toggle_command_mode();
+                return Ok(None);
+            }
+            KeyCode::Up if self.picker.is_some() => {
+                if let Some(picker) = &mut self.picker {
+                    picker.move_up();
+                }
+                return Ok(None);
+            }
+            KeyCode::Down if self.picker.is_some() => {
+                if let Some(picker) = &mut self.picker {
+                    picker.move_down();
+                }
+                return Ok(None);
+            }
+            KeyCode::Enter if self.picker.is_some() => {
+                let id = self
+                    .picker
+                    .take()
+                    .and_then(|picker| picker.selected_entry().map(|entry| entry.id.clone()));
+                return Ok(id.map(|id| UserAction::ExecuteCommand(Command::LoadConversation(id))));
+            }
+            KeyCode::Enter => {
+                if !self.input_buffer.is_empty() {
+                    let input = self.input_buffer.clone();
+                    self.clear_input();
+
+                    if self.command_mode || input.starts_with('/') {
+                        // Parse as command
+                        let command_str = input.strip_prefix('/').unwrap_or(&input);
+
+                        let command = parse_command(command_str)?;
+                        return Ok(Some(UserAction::ExecuteCommand(command)));
+                    } else {
+                        // Alt+Enter sends a throwaway message, Ctrl+Enter forces one
+                        // to be kept; plain Enter follows the global mode
+                        let provisional = if key.modifiers.contains(KeyModifiers::ALT) {
+                            Some(true)
+                        } else if key.modifiers.contains(KeyModifiers::CONTROL) {
+                            Some(false)
+                        } else {
+                            None
+                        };
+                        return Ok(Some(UserAction::SendMessage { content: input, provisional }));
+                    }
+                }
+                return Ok(None);
+            }
+            KeyCode::Backspace => {
+                let index = self.cursor_byte_index();
+                if let Some((start, _)) = self.input_buffer[..index].char_indices().next_back() {
+                    self.input_buffer.remove(start);
+                }
+                return Ok(None);
+            }
+            KeyCode::Delete => {
+                let index = self.cursor_byte_index();
+                if index < self.input_buffer.len() {
+                    self.input_buffer.remove(index);
+                    self.cursor_from_end -= 1;
+                }
+                return Ok(None);
+            }
+            KeyCode::Left => {
+                self.cursor_from_end = (self.cursor_from_end + 1).min(self.input_buffer.chars().count());
+                return Ok(None);
+            }
+            KeyCode::Right => {
+                self.cursor_from_end = self.cursor_from_end.saturating_sub(1);
+                return Ok(None);
+            }
+            KeyCode::Home => {
+                self.cursor_from_end = self.input_buffer.chars().count();
+                return Ok(None);
+            }
+            KeyCode::End => {
+                self.cursor_from_end = 0;
+                return Ok(None);
+            }
+            KeyCode::PageUp if self.preview.is_some() => {
+                self.scroll_preview(true);
+                return Ok(None);
+            }
+            KeyCode::PageDown if self.preview.is_some() => {
+                self.scroll_preview(false);
+                return Ok(None);
+            }
+            KeyCode::PageUp => {
+                self.scroll_messages(true);
+                return Ok(Some(UserAction::ScrollUp));
+            }
+            KeyCode::PageDown => {
+                self.scroll_messages(false);
+                return Ok(Some(UserAction::ScrollDown));
+            }
+            KeyCode::Char(c) => {
+                let index = self.cursor_byte_index();
+                self.input_buffer.insert(index, c);
+                self.last_input_time = Instant::now();
+                return Ok(None);
+            }
+            _ => {}
+        }
+        Ok(None)
+    }
+
+    // Keys while the setup wizard is open; Esc skips setup entirely
+    fn handle_setup_key(&mut self, code: KeyCode) -> Option<UserAction> {
+        let wizard = self.setup.as_mut()?;
+        match code {
+            KeyCode::Esc => self.setup = None,
+            KeyCode::Up => wizard.move_up(),
+            KeyCode::Down => wizard.move_down(),
+            KeyCode::Backspace => wizard.pop_char(),
+            KeyCode::Char(c) => wizard.push_char(c),
+            KeyCode::Enter => {
+                if let Some(provider) = wizard.advance() {
+                    self.setup = None;
+                    return Some(UserAction::SaveProvider(provider));
+                }
+            }
+            _ => {}
+        }
+        None
+    }
+
+    // Keys while the pattern editor is open: Tab switches lists, Enter adds the
+    // typed pattern, Delete removes and Shift+Up/Down moves the selected one
+    fn handle_patterns_key(&mut self, key: KeyEvent) -> Option<UserAction> {
+        let editor = self.patterns.as_mut()?;
+        let shift = key.modifiers.contains(KeyModifiers::SHIFT);
+        let edit = match key.code {
+            KeyCode::Esc => {
+                self.patterns = None;
+                None
+            }
+            KeyCode::Tab => {
+                editor.switch_list();
+                None
+            }
+            KeyCode::Up if shift => editor.reorder(true),
+            KeyCode::Down if shift => editor.reorder(false),
+            KeyCode::Up => {
+                editor.move_up();
+                None
+            }
+            KeyCode::Down => {
+                editor.move_down();
+                None
+            }
+            KeyCode::Delete => editor.remove(),
+            KeyCode::Enter => editor.add(),
+            KeyCode::Backspace => {
+                editor.pop_char();
+                None
+            }
+            KeyCode::Char(c) => {
+                editor.push_char(c);
+                None
+            }
+            _ => None,
+        };
+        edit.map(UserAction::EditPatterns)
+    }
+
+    /// Fires the idle timeout once per stretch of inactivity, as of `now`
+    pub fn check_idle(&mut self, now: Instant) -> Option<UserAction> {
+        let timeout = self.idle_timeout?;
+        if self.idle_handled || now.saturating_duration_since(self.last_input_time) < timeout {
+            return None;
+        }
+        self.idle_handled = true;
+        Some(UserAction::IdleTimeout { exit: self.exit_on_idle })
+    }
+
+    /// Buffer for a reply about to stream, showing the configured placeholder
+    /// until its first token
+    pub fn start_stream(&self) -> StreamBuffer {
+        StreamBuffer::new(STREAM_FLUSH_INTERVAL).with_placeholder(&self.thinking_placeholder)
+    }
+
+    // How long to wait for input before the next frame: the configured
+    // interval when idle, a quarter of it while something is animating
+    pub fn poll_interval(&self) -> Duration {
+        if self.busy {
+            (self.frame_interval / 4).max(MIN_FRAME_INTERVAL).min(self.frame_interval)
+        } else {
+            self.frame_interval
+        }
+    }
+
+    // Byte offset of the cursor in the input, clamped to the buffer
+    pub fn cursor_byte_index(&self) -> usize {
+        let len = self.input_buffer.chars().count();
+        let chars_before = len - self.cursor_from_end.min(len);
+        self.input_buffer
+            .char_indices()
+            .nth(chars_before)
+            .map_or(self.input_buffer.len(), |(index, _)| index)
+    }
+
+    pub fn clear_input(&mut self) {
+        self.input_buffer.clear();
+        self.cursor_from_end = 0;
+    }
+
+    // Switches modes, parking the current input as that mode's draft and
+    // restoring the draft of the mode being entered
+    pub fn toggle_command_mode(&mut self) {
+        let current = std::mem::take(&mut self.input_buffer);
+        if self.command_mode {
+            self.command_draft = current;
+            self.input_buffer = std::mem::take(&mut self.message_draft);
+        } else {
+            self.message_draft = current;
+            self.input_buffer = std::mem::take(&mut self.command_draft);
+        }
+        self.command_mode = !self.command_mode;
+        self.cursor_from_end = 0;
+    }
+
+    // Tail previews count the scroll up from their last line
+    pub fn scroll_preview(&mut self, up: bool) {
+        let line_count = self.preview.as_ref().map_or(0, |preview| preview.lines().len());
+        let tail = self.preview.as_ref().is_some_and(|preview| preview.tail);
+        self.preview_scroll = if up != tail {
+            self.preview_scroll.saturating_sub(PREVIEW_SCROLL_STEP)
+        } else {
+            (self.preview_scroll + PREVIEW_SCROLL_STEP).min(line_count.saturating_sub(1))
+        };
+    }
+}
+
 // Data passed from app controller to UI for rendering
-#[derive(Debug, Default)]
+#[derive(Debug)]
 pub struct AppDisplayData {
     pub messages: Vec<Message>,
     pub provisional_mode: bool,
+    // The scratch conversation is shown in place of the main one
+    pub scratch: bool,
+    // Read-only view is on; input other than scrolling, help and exit is ignored
+    pub view_only: bool,
     pub rag_enabled: bool,
     pub current_status: String,
     pub streaming_response: Option<String>, // Partial response being streamed
+    pub indexing_progress: Option<IndexProgress>,
+    pub assistant_name: String,
+    pub user_name: String,
+    pub theme: Theme,
+    // Shown in place of messages while the conversation is empty; never sent
+    pub banner: Option<String>,
+    // Widest the message text may wrap to, however wide the terminal is
+    pub max_content_width: Option<u16>,
+    // Index of the message to mark, and how many messages before it were
+    // dropped from the context
+    pub omitted_marker: Option<(usize, usize)>,
+    // Show only the first line of each system message
+    pub collapse_system_messages: bool,
+    // Show a line-number gutter inside fenced code blocks
+    pub code_line_numbers: bool,
+    pub auto_scroll: bool,
+}
+
+impl Default for AppDisplayData {
+    fn default() -> Self {
+        Self {
+            messages: Vec::new(),
+            provisional_mode: false,
+            scratch: false,
+            view_only: false,
+            rag_enabled: false,
+            current_status: String::new(),
+            streaming_response: None,
+            indexing_progress: None,
+            assistant_name: "Assistant".to_string(),
+            user_name: "You".to_string(),
+            theme: Theme::default(),
+            banner: None,
+            max_content_width: None,
+            omitted_marker: None,
+            collapse_system_messages: false,
+            code_line_numbers: false,
+            auto_scroll: true,
+        }
+    }
+}
+
+// Styles for each part of the UI. Monochrome drops every colour and tells
+// roles apart by modifiers alone, for dumb terminals and colourblind users.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Theme {
+    pub monochrome: bool,
+}
+
+impl Theme {
+    /// Monochrome if configured, or if `NO_COLOR` is set to a non-empty value
+    pub fn from_env(monochrome: bool) -> Self {
+        let no_color = std::env::var_os("NO_COLOR").is_some_and(|value| !value.is_empty());
+        Self {
+            monochrome: monochrome || no_color,
+        }
+    }
+
+    // Header style for a message from `role`
+    pub fn role_style(&self, role: &MessageRole) -> Style {
+        let bold = Style::default().add_modifier(Modifier::BOLD);
+        if self.monochrome {
+            return match role {
+                MessageRole::User => bold.add_modifier(Modifier::UNDERLINED),
+                MessageRole::Assistant => bold,
+                MessageRole::System => bold.add_modifier(Modifier::ITALIC),
+                MessageRole::Tool => bold.add_modifier(Modifier::DIM),
+            };
+        }
+        match role {
+            MessageRole::User => bold.fg(Color::Cyan),
+            MessageRole::Assistant => bold.fg(Color::Green),
+            MessageRole::System => bold.fg(Color::Yellow),
+            MessageRole::Tool => bold.fg(Color::Magenta),
+        }
+    }
+
+    pub fn text_style(&self) -> Style {
+        if self.monochrome {
+            Style::default()
+        } else {
+            Style::default().fg(Color::White)
+        }
+    }
+
+    pub fn input_style(&self, command_mode: bool) -> Style {
+        match (self.monochrome, command_mode) {
+            (true, true) => Style::default().add_modifier(Modifier::BOLD),
+            (false, true) => Style::default().fg(Color::Yellow),
+            (_, false) => self.text_style(),
+        }
+    }
+
+    // Muted so system text reads as separate from the conversation itself
+    pub fn system_message_style(&self) -> Style {
+        let muted = Style::default().add_modifier(Modifier::DIM | Modifier::ITALIC);
+        if self.monochrome {
+            muted
+        } else {
+            muted.fg(Color::Yellow)
+        }
+    }
+
+    pub fn omitted_marker_style(&self) -> Style {
+        let dim = Style::default().add_modifier(Modifier::DIM | Modifier::ITALIC);
+        if self.monochrome {
+            dim
+        } else {
+            dim.fg(Color::DarkGray)
+        }
+    }
+
+    // Style for one line of a unified diff, picked by its leading marker
+    pub fn diff_line_style(&self, line: &str) -> Style {
+        let marker = if line.starts_with("+++") || line.starts_with("---") {
+            None
+        } else {
+            line.chars().next()
+        };
+        match (self.monochrome, marker) {
+            (true, Some('+')) => Style::default().add_modifier(Modifier::BOLD),
+            (true, Some('-')) => Style::default().add_modifier(Modifier::DIM),
+            (false, Some('+')) => Style::default().fg(Color::Green),
+            (false, Some('-')) => Style::default().fg(Color::Red),
+            (false, Some('@')) => Style::default().fg(Color::Cyan),
+            _ => Style::default(),
+        }
+    }
+
+    pub fn status_bar_style(&self) -> Style {
+        if self.monochrome {
+            Style::default().add_modifier(Modifier::REVERSED)
+        } else {
+            Style::default().bg(Color::DarkGray).fg(Color::White)
+        }
+    }
 }
 
 // TUI renderer trait for abstraction
@@ -58,19 +928,54 @@ pub trait TuiRenderer {
     fn initialize(&mut self) -> Result<(), TuiError>;
 }
 
+// Optional terminal features that were switched on, so cleanup only undoes those
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct TerminalFeatures {
+    pub alternate_screen: bool,
+    pub mouse_capture: bool,
+}
+
+// Tries each optional feature on its own; minimal terminals that reject one
+// still get a usable UI, drawn on the main screen or without mouse support.
+// Mouse capture is only requested with `enable_mouse`, since it takes over
+// the terminal's own text selection.
+fn enable_terminal_features<W: Write>(out: &mut W, enable_mouse: bool) -> TerminalFeatures {
+    let alternate_screen = match execute!(out, EnterAlternateScreen) {
+        Ok(()) => true,
+        Err(e) => {
+            warn!("Alternate screen unavailable, drawing on the main screen: {}", e);
+            false
+        }
+    };
+    let mouse_capture = enable_mouse
+        && match execute!(out, EnableMouseCapture) {
+            Ok(()) => true,
+            Err(e) => {
+                warn!("Mouse capture unavailable: {}", e);
+                false
+            }
+        };
+    TerminalFeatures {
+        alternate_screen,
+        mouse_capture,
+    }
+}
+
 // Ratatui-based implementation
 pub struct RatatuiRenderer {
     terminal: Terminal<CrosstermBackend<Stdout>>,
     state: TuiState,
+    features: TerminalFeatures,
 }
 
 impl RatatuiRenderer {
-    pub fn new() -> Result<Self, TuiError> {
-        // Set up terminal
+    /// Sets up the terminal; without `enable_mouse` the terminal keeps native
+    /// text selection and mouse scrolling is inactive
+    pub fn new(enable_mouse: bool) -> Result<Self, TuiError> {
+        // Raw mode is the one thing the UI cannot work without
         enable_raw_mode().map_err(|e| TuiError::TerminalInit(e.to_string()))?;
         let mut stdout = io::stdout();
-        execute!(stdout, EnterAlternateScreen, EnableMouseCapture)
-            .map_err(|e| TuiError::TerminalInit(e.to_string()))?;
+        let features = enable_terminal_features(&mut stdout, enable_mouse);
         let backend = CrosstermBackend::new(stdout);
         let terminal = Terminal::new(backend)
             .map_err(|e| TuiError::TerminalInit(e.to_string()))?;
@@ -78,9 +983,14 @@ impl RatatuiRenderer {
         Ok(Self {
             terminal,
             state: TuiState::default(),
+            features,
         })
     }
 
+    pub fn terminal_features(&self) -> TerminalFeatures {
+        self.features
+    }
+
     fn render_help_static(f: &mut Frame) {
         let help_text = vec![
             Line::from(vec![
@@ -91,17 +1001,57 @@ impl RatatuiRenderer {
             Line::from("  /help          - Show this help message"),
             Line::from("  /config        - Open configuration"),
             Line::from("  /clear         - Clear conversation history"),
+            Line::from("  /reset         - Clear conversation and session toggles"),
             Line::from("  /toggle-rag    - Toggle RAG functionality"),
             Line::from("  /toggle-prov   - Toggle provisional mode"),
+            Line::from("  /toggle-autoscroll - Toggle jumping to new messages"),
+            Line::from("  /scratch       - Switch to or from a throwaway scratch conversation"),
+            Line::from("  /image <path>  - Attach an image to the next message (vision models)"),
+            Line::from("  /view          - Read-only view: ignore input except scrolling, help and exit"),
             Line::from("  /add-source    - Add file/directory source"),
             Line::from("  /remove-source - Remove file/directory source"),
             Line::from("  /list-sources  - List configured sources"),
+            Line::from("  /clear-cache   - Clear cached LLM responses"),
+            Line::from("  /reload-prompt - Re-read the system prompt file"),
+            Line::from("  /set-system <prompt> - Set and save the global system prompt"),
+            Line::from("  /clear-system  - Remove the global system prompt"),
+            Line::from("  /reindex       - Refresh the index (optionally one source)"),
+            Line::from("  /open          - Preview a file's content"),
+            Line::from("  /diff <a> <b>  - Show a unified diff of two source files"),
+            Line::from("  /save-block <n> <path> - Save the nth code block of the last reply"),
+            Line::from("  /conversations [tag] - Browse and load stored conversations"),
+            Line::from("  /tag <name>    - Tag the current conversation"),
+            Line::from("  /untag <name>  - Remove a tag from the current conversation"),
+            Line::from("  /index-status  - Show indexed file counts and size"),
+            Line::from("  /rag-stats     - Show corpus statistics and the most selected files"),
+            Line::from("  /export-sources [path] - Write indexed file metadata to JSON or CSV"),
+            Line::from("  /patterns      - Edit the include and exclude patterns"),
+            Line::from("  /raw <message> - Send one message without retrieval"),
+            Line::from("  /show-prompt <message> - Preview the request without sending it"),
+            Line::from("  /prefill <text> - Start the next reply with the given text"),
+            Line::from("  /temp <value>  - Set the sampling temperature (0.0-2.0) for this session"),
+            Line::from("  /max-tokens <n> - Set the reply length limit for this session"),
+            Line::from("  /once-max <n> <message> - Send with a length limit for this reply only"),
+            Line::from("  /redo [instruction] - Replace the last reply, optionally steered by an instruction"),
+            Line::from("  /compare <message> - Send to every provider profile and show the replies side by side"),
+            Line::from("  /setup         - Choose the LLM provider, API key and model"),
+            Line::from("  /copy-conversation - Copy the conversation as Markdown"),
+            Line::from("  /ping          - Check the provider connection"),
+            Line::from("  /last-raw      - Show the last raw API response (needs retain_last_response)"),
+            Line::from("  /logs          - Follow the end of the log file"),
             Line::from("  /exit          - Exit application"),
             Line::from(""),
             Line::from("Keyboard Shortcuts:"),
             Line::from("  Enter          - Send message"),
+            Line::from("  Alt+Enter      - Send without storing it"),
+            Line::from("  Ctrl+Enter     - Send and store, even in provisional mode"),
             Line::from("  Escape         - Close help/cancel input"),
             Line::from("  Ctrl+C         - Exit application"),
+            Line::from("  Ctrl+L         - Redraw the screen"),
+            Line::from("  Ctrl+G         - Toggle RAG (configurable)"),
+            Line::from("  Ctrl+P         - Toggle provisional mode (configurable)"),
+            Line::from("  Ctrl+S         - Switch to or from a scratch conversation (configurable)"),
+            Line::from("  Ctrl+R         - Toggle the read-only view (configurable)"),
             Line::from("  Page Up/Down   - Scroll conversation"),
             Line::from("  Tab            - Toggle command mode"),
             Line::from(""),
@@ -116,7 +1066,189 @@ impl RatatuiRenderer {
             .block(Block::default().title("Help").borders(Borders::ALL))
             .wrap(Wrap { trim: true });
 
+        let popup_area = Self::popup_area(f.size());
+        f.render_widget(Clear, popup_area);
+        f.render_widget(help_paragraph, popup_area);
+    }
+
+    fn render_preview_static(f: &mut Frame, preview: &FilePreview, scroll: usize, theme: &Theme) {
+        let lines: Vec<Line> = preview
+            .lines()
+            .into_iter()
+            .map(|line| {
+                if preview.diff {
+                    let style = theme.diff_line_style(&line);
+                    Line::styled(line, style)
+                } else {
+                    Line::from(line)
+                }
+            })
+            .collect();
+        let title = format!("{} (Esc to close, Page Up/Down to scroll)", preview.path.display());
+        let popup_area = Self::popup_area(f.size());
+        let top = if preview.tail {
+            let height = popup_area.height.saturating_sub(2) as usize;
+            lines.len().saturating_sub(height + scroll)
+        } else {
+            scroll
+        };
+        let preview_paragraph = Paragraph::new(lines)
+            .block(Block::default().title(title).borders(Borders::ALL))
+            .scroll((top.min(u16::MAX as usize) as u16, 0));
+
+        f.render_widget(Clear, popup_area);
+        f.render_widget(preview_paragraph, popup_area);
+    }
+
+    fn render_picker_static(f: &mut Frame, picker: &ConversationPicker) {
+        let items: Vec<ListItem> = if picker.entries.is_empty() {
+            vec![ListItem::new("No stored conversations")]
+        } else {
+            picker
+                .entries
+                .iter()
+                .map(|entry| {
+                    let tags: String = entry.tags.iter().map(|tag| format!("  #{}", tag)).collect();
+                    ListItem::new(format!(
+                        "{}  {:>3} msgs  {}{}",
+                        entry.created_at.format("%Y-%m-%d %H:%M"),
+                        entry.message_count,
+                        entry.preview,
+                        tags
+                    ))
+                })
+                .collect()
+        };
+
+        let list = List::new(items)
+            .block(
+                Block::default()
+                    .title("Conversations (Up/Down to select, Enter to load, Esc to cancel)")
+                    .borders(Borders::ALL),
+            )
+            .highlight_style(Style::default().add_modifier(Modifier::REVERSED));
+        let mut list_state = ListState::default().with_selected(Some(picker.selected));
+
+        let popup_area = Self::popup_area(f.size());
+        f.render_widget(Clear, popup_area);
+        f.render_stateful_widget(list, popup_area, &mut list_state);
+    }
+
+    // One column per profile, each labelled with its name
+    fn render_comparison_static(f: &mut Frame, replies: &[ComparisonReply]) {
+        let popup_area = Self::popup_area(f.size());
+        f.render_widget(Clear, popup_area);
+        let columns = Layout::default()
+            .direction(Direction::Horizontal)
+            .constraints(vec![Constraint::Ratio(1, replies.len().max(1) as u32); replies.len()])
+            .split(popup_area);
+        for (reply, area) in replies.iter().zip(columns.iter()) {
+            let (text, style) = match &reply.response {
+                Ok(text) => (text.clone(), Style::default()),
+                Err(e) => (format!("Error: {}", e), Style::default().add_modifier(Modifier::DIM)),
+            };
+            let paragraph = Paragraph::new(text)
+                .style(style)
+                .wrap(Wrap { trim: false })
+                .block(Block::default().title(reply.profile.clone()).borders(Borders::ALL));
+            f.render_widget(paragraph, *area);
+        }
+    }
+
+    fn render_setup_static(f: &mut Frame, wizard: &SetupWizard) {
+        let mut lines = vec![
+            Line::from("No LLM provider is configured yet. Esc skips; /setup runs this again."),
+            Line::from(""),
+        ];
+        match wizard.step {
+            SetupStep::Provider => {
+                lines.push(Line::from("Provider (Up/Down to choose, Enter to continue):"));
+                for (index, provider_type) in SETUP_PROVIDERS.iter().enumerate() {
+                    let marker = if index == wizard.provider_index { ">" } else { " " };
+                    lines.push(Line::from(format!("{} {:?}", marker, provider_type)));
+                }
+            }
+            SetupStep::ApiKey => {
+                lines.push(Line::from(format!("{:?} API key:", wizard.provider_type())));
+                lines.push(Line::from("*".repeat(wizard.api_key.chars().count())));
+            }
+            SetupStep::Model => {
+                let default = default_model(&wizard.provider_type()).unwrap_or("none");
+                lines.push(Line::from(format!("Model (empty for {}):", default)));
+                lines.push(Line::from(wizard.model.clone()));
+            }
+        }
+        if let Some(error) = &wizard.error {
+            lines.push(Line::from(""));
+            lines.push(Line::styled(error.clone(), Style::default().add_modifier(Modifier::BOLD)));
+        }
+
+        let paragraph = Paragraph::new(lines)
+            .wrap(Wrap { trim: false })
+            .block(Block::default().title("Setup").borders(Borders::ALL));
+        let popup_area = Self::popup_area(f.size());
+        f.render_widget(Clear, popup_area);
+        f.render_widget(paragraph, popup_area);
+    }
+
+    fn render_patterns_static(f: &mut Frame, editor: &PatternEditor) {
+        let mut lines = Vec::new();
+        for (kind, patterns) in [
+            (PatternKind::Include, &editor.include),
+            (PatternKind::Exclude, &editor.exclude),
+        ] {
+            let focused = kind == editor.kind;
+            let heading = format!("{} patterns{}", kind.as_str(), if focused { ":" } else { "" });
+            lines.push(Line::styled(
+                heading,
+                Style::default().add_modifier(if focused { Modifier::BOLD } else { Modifier::empty() }),
+            ));
+            if patterns.is_empty() {
+                lines.push(Line::from("  (none)"));
+            }
+            for (index, pattern) in patterns.iter().enumerate() {
+                let marker = if focused && index == editor.selected { ">" } else { " " };
+                lines.push(Line::from(format!("{} {}", marker, pattern)));
+            }
+            lines.push(Line::from(""));
+        }
+        lines.push(Line::from(format!("New {} pattern: {}", editor.kind.as_str(), editor.input)));
+        if let Some(error) = &editor.error {
+            lines.push(Line::styled(error.clone(), Style::default().add_modifier(Modifier::BOLD)));
+        }
+        lines.push(Line::from(""));
+        lines.push(Line::from(
+            "Enter adds, Delete removes, Shift+Up/Down reorders, Tab switches list, Esc closes",
+        ));
+
+        let paragraph = Paragraph::new(lines)
+            .wrap(Wrap { trim: false })
+            .block(Block::default().title("Patterns").borders(Borders::ALL));
+        let popup_area = Self::popup_area(f.size());
+        f.render_widget(Clear, popup_area);
+        f.render_widget(paragraph, popup_area);
+    }
+
+    fn render_confirm_static(f: &mut Frame, prompt: &str) {
+        let paragraph = Paragraph::new(prompt.to_string())
+            .wrap(Wrap { trim: true })
+            .block(Block::default().title("Confirm (y/n)").borders(Borders::ALL));
+
         let area = f.size();
+        let width = area.width.min(60);
+        let height = area.height.min(5);
+        let dialog_area = Rect::new(
+            area.x + (area.width - width) / 2,
+            area.y + (area.height - height) / 2,
+            width,
+            height,
+        );
+        f.render_widget(Clear, dialog_area);
+        f.render_widget(paragraph, dialog_area);
+    }
+
+    // Centered area covering 80% of the screen in each direction, used for overlays
+    fn popup_area(area: Rect) -> Rect {
         let popup_area = Layout::default()
             .direction(Direction::Vertical)
             .constraints([
@@ -126,17 +1258,14 @@ impl RatatuiRenderer {
             ])
             .split(area)[1];
 
-        let popup_area = Layout::default()
+        Layout::default()
             .direction(Direction::Horizontal)
             .constraints([
                 Constraint::Percentage(10),
                 Constraint::Percentage(80),
                 Constraint::Percentage(10),
             ])
-            .split(popup_area)[1];
-
-        f.render_widget(Clear, popup_area);
-        f.render_widget(help_paragraph, popup_area);
+            .split(popup_area)[1]
     }
 
     fn render_main_ui_static(f: &mut Frame, app_data: &AppDisplayData, state: &TuiState) {
@@ -150,77 +1279,154 @@ impl RatatuiRenderer {
             .split(f.size());
 
         // Render messages area
-        Self::render_messages_static(f, chunks[0], app_data);
+        let scroll = (!state.at_bottom()).then_some(state.scroll_position);
+        Self::render_messages_static(f, chunks[0], app_data, scroll, state.unseen_messages);
 
         // Render input area
-        Self::render_input_static(f, chunks[1], state);
+        Self::render_input_static(f, chunks[1], state, &app_data.theme);
 
         // Render status bar
         Self::render_status_bar_static(f, chunks[2], app_data);
     }
 
-    fn render_messages_static(f: &mut Frame, area: ratatui::layout::Rect, app_data: &AppDisplayData) {
+    // `scroll` is the message to keep in view, or None to follow the bottom
+    fn render_messages_static(
+        f: &mut Frame,
+        area: ratatui::layout::Rect,
+        app_data: &AppDisplayData,
+        scroll: Option<usize>,
+        unseen: bool,
+    ) {
+        let area = reading_column(area, app_data.max_content_width);
+        // Inside the borders
+        let wrap_width = area.width.saturating_sub(2) as usize;
         let mut items = Vec::new();
 
+        let omitted_item = |count: usize| {
+            let noun = if count == 1 { "message" } else { "messages" };
+            ListItem::new(vec![
+                Line::styled(
+                    format!("— {} earlier {} omitted from context —", count, noun),
+                    app_data.theme.omitted_marker_style(),
+                ),
+                Line::from(""),
+            ])
+        };
+
         // Add conversation messages
-        for message in &app_data.messages {
-            let role_style = match message.role {
-                MessageRole::User => Style::default().fg(Color::Cyan),
-                MessageRole::Assistant => Style::default().fg(Color::Green),
-                MessageRole::System => Style::default().fg(Color::Yellow),
-            };
+        for (index, message) in app_data.messages.iter().enumerate() {
+            if let Some((boundary, count)) = app_data.omitted_marker {
+                if index == boundary {
+                    items.push(omitted_item(count));
+                }
+            }
+            let mut lines = Self::message_lines(message, app_data, wrap_width);
+            lines.push(Line::from("")); // Empty line for spacing
+            items.push(ListItem::new(lines));
+        }
 
-            let timestamp = message.timestamp.format("%H:%M:%S");
-            let role_prefix = match message.role {
-                MessageRole::User => "You",
-                MessageRole::Assistant => "Assistant",
-                MessageRole::System => "System",
-            };
+        if let Some((boundary, count)) = app_data.omitted_marker {
+            if boundary >= app_data.messages.len() {
+                items.push(omitted_item(count));
+            }
+        }
 
-            let provisional_indicator = if message.provisional { " [PROV]" } else { "" };
-            
-            items.push(ListItem::new(vec![
-                Line::from(vec![
-                    Span::styled(
-                        format!("[{}] {}{}: ", timestamp, role_prefix, provisional_indicator),
-                        role_style.add_modifier(Modifier::BOLD)
-                    )
-                ]),
-                Line::from(Span::raw(&message.content)),
-                Line::from(""), // Empty line for spacing
-            ]));
+        if app_data.messages.is_empty() && app_data.streaming_response.is_none() {
+            if let Some(banner) = &app_data.banner {
+                let style = app_data.theme.role_style(&MessageRole::System);
+                let lines: Vec<Line> = banner
+                    .lines()
+                    .map(|line| Line::from(Span::styled(line.to_string(), style)))
+                    .collect();
+                items.push(ListItem::new(lines));
+            }
         }
 
         // Add streaming response if present
         if let Some(streaming_content) = &app_data.streaming_response {
-            items.push(ListItem::new(vec![
-                Line::from(vec![
-                    Span::styled(
-                        "Assistant (streaming): ",
-                        Style::default().fg(Color::Green).add_modifier(Modifier::BOLD)
-                    )
-                ]),
-                Line::from(Span::raw(streaming_content)),
-                Line::from(""),
-            ]));
+            let mut lines = vec![Line::from(vec![Span::styled(
+                format!("{} (streaming): ", app_data.assistant_name),
+                app_data.theme.role_style(&MessageRole::Assistant),
+            )])];
+            lines.extend(wrap_text(streaming_content, wrap_width).into_iter().map(Line::from));
+            lines.push(Line::from(""));
+            items.push(ListItem::new(lines));
         }
 
+        // Selecting an item scrolls the list just enough to show it
+        let selected = match scroll {
+            Some(index) => {
+                let marker_above = app_data.omitted_marker.is_some_and(|(boundary, _)| boundary <= index);
+                index + usize::from(marker_above)
+            }
+            None => items.len().saturating_sub(1),
+        };
+        let mut list_state = ListState::default().with_selected(Some(selected));
+
+        let title = if unseen { "Conversation (new messages below)" } else { "Conversation" };
         let messages_list = List::new(items)
-            .block(Block::default().title("Conversation").borders(Borders::ALL))
-            .style(Style::default().fg(Color::White));
+            .block(Block::default().title(title).borders(Borders::ALL))
+            .style(app_data.theme.text_style());
 
-        f.render_widget(messages_list, area);
+        f.render_stateful_widget(messages_list, area, &mut list_state);
     }
 
-    fn render_input_static(f: &mut Frame, area: ratatui::layout::Rect, state: &TuiState) {
-        let input_style = if state.command_mode {
-            Style::default().fg(Color::Yellow)
+    // Header and wrapped content of one message. System messages are set
+    // apart in a muted style under a `[system]` marker, and can be collapsed
+    // to their first line.
+    fn message_lines(
+        message: &Message,
+        app_data: &AppDisplayData,
+        wrap_width: usize,
+    ) -> Vec<Line<'static>> {
+        let timestamp = message.timestamp.format("%H:%M:%S");
+        let provisional_indicator = if message.provisional { " [PROV]" } else { "" };
+
+        if matches!(message.role, MessageRole::System) {
+            let style = app_data.theme.system_message_style();
+            let mut lines = vec![Line::from(Span::styled(
+                format!("[system] [{}]{}", timestamp, provisional_indicator),
+                style,
+            ))];
+            let mut content = wrap_text(&message.content, wrap_width);
+            if app_data.collapse_system_messages && content.len() > 1 {
+                content.truncate(1);
+                content[0].push_str(" …");
+            }
+            lines.extend(content.into_iter().map(|line| Line::from(Span::styled(line, style))));
+            return lines;
+        }
+
+        let role_prefix = match (&message.role, &message.tool_call) {
+            (MessageRole::User, _) => app_data.user_name.clone(),
+            (MessageRole::Assistant, _) => app_data.assistant_name.clone(),
+            (MessageRole::System, _) => "System".to_string(),
+            (MessageRole::Tool, Some(call)) => format!("Tool ({})", call.name),
+            (MessageRole::Tool, None) => "Tool".to_string(),
+        };
+        let mut lines = vec![Line::from(vec![Span::styled(
+            format!("[{}] {}{}: ", timestamp, role_prefix, provisional_indicator),
+            app_data.theme.role_style(&message.role),
+        )])];
+        let content = if app_data.code_line_numbers {
+            number_code_blocks(&message.content)
         } else {
-            Style::default().fg(Color::White)
+            message.content.clone()
         };
+        lines.extend(wrap_text(&content, wrap_width).into_iter().map(Line::from));
+        lines.extend(message.images.iter().map(|image| Line::from(format!("[image: {}]", image.name))));
+        lines
+    }
+
+    fn render_input_static(
+        f: &mut Frame,
+        area: ratatui::layout::Rect,
+        state: &TuiState,
+        theme: &Theme,
+    ) {
+        let input_style = theme.input_style(state.command_mode);
 
-        let mode_indicator = if state.command_mode { "CMD" } else { "MSG" };
-        let title = format!("Input [{}]", mode_indicator);
+        let title = input_title(&state.input_buffer, state.command_mode);
 
         let input = Paragraph::new(state.input_buffer.as_str())
             .style(input_style)
@@ -229,8 +1435,9 @@ impl RatatuiRenderer {
         f.render_widget(input, area);
 
         // Set cursor position
+        let cursor_column = state.input_buffer[..state.cursor_byte_index()].chars().count();
         f.set_cursor(
-            area.x + state.input_buffer.len() as u16 + 1,
+            area.x + cursor_column as u16 + 1,
             area.y + 1,
         );
     }
@@ -239,15 +1446,24 @@ impl RatatuiRenderer {
         let rag_status = if app_data.rag_enabled { "RAG: ON" } else { "RAG: OFF" };
         let prov_status = if app_data.provisional_mode { "PROV: ON" } else { "PROV: OFF" };
         
+        let current_status = match app_data.indexing_progress {
+            Some(progress) => format_index_progress(progress, 20),
+            None => app_data.current_status.clone(),
+        };
+        
+        let scratch_status = if app_data.scratch { "SCRATCH | " } else { "" };
+        let view_status = if app_data.view_only { "VIEW ONLY | " } else { "" };
         let status_text = format!(
-            " {} | {} | {} | Press Tab for command mode, F1 for help",
+            " {}{}{} | {} | {} | Press Tab for command mode, F1 for help",
+            view_status,
+            scratch_status,
             rag_status,
             prov_status,
-            app_data.current_status
+            current_status
         );
 
         let status_paragraph = Paragraph::new(status_text)
-            .style(Style::default().bg(Color::DarkGray).fg(Color::White));
+            .style(app_data.theme.status_bar_style());
 
         f.render_widget(status_paragraph, area);
     }
@@ -261,6 +1477,10 @@ impl TuiRenderer for RatatuiRenderer {
     }
 
     fn render(&mut self, app_data: &AppDisplayData) -> Result<(), TuiError> {
+        self.state.busy =
+            app_data.streaming_response.is_some() || app_data.indexing_progress.is_some();
+        self.state.track_messages(app_data.messages.len(), app_data.auto_scroll);
+        self.state.view_only = app_data.view_only;
         let show_help = self.state.show_help;
         let state = &self.state;
         
@@ -270,6 +1490,24 @@ impl TuiRenderer for RatatuiRenderer {
                     Self::render_help_static(f);
                 } else {
                     Self::render_main_ui_static(f, app_data, state);
+                    if let Some(preview) = &state.preview {
+                        Self::render_preview_static(f, preview, state.preview_scroll, &app_data.theme);
+                    }
+                    if let Some(picker) = &state.picker {
+                        Self::render_picker_static(f, picker);
+                    }
+                    if let Some(replies) = &state.comparison {
+                        Self::render_comparison_static(f, replies);
+                    }
+                    if let Some(wizard) = &state.setup {
+                        Self::render_setup_static(f, wizard);
+                    }
+                    if let Some(editor) = &state.patterns {
+                        Self::render_patterns_static(f, editor);
+                    }
+                    if let Some(prompt) = &state.confirm {
+                        Self::render_confirm_static(f, prompt);
+                    }
                 }
             })
             .map_err(|e| TuiError::Rendering(e.to_string()))?;
@@ -278,137 +1516,494 @@ impl TuiRenderer for RatatuiRenderer {
 
     fn handle_input(&mut self) -> Result<Option<UserAction>, TuiError> {
         // Check for input with a timeout to avoid blocking
-        if event::poll(Duration::from_millis(100))
+        if event::poll(self.state.poll_interval())
             .map_err(|e| TuiError::InputHandling(e.to_string()))?
         {
             if let Event::Key(key) = event::read()
                 .map_err(|e| TuiError::InputHandling(e.to_string()))?
             {
-                // Only handle key press events, not release
-                if key.kind != KeyEventKind::Press {
-                    return Ok(None);
+                let action = self.state.handle_key(key)?;
+                if matches!(action, Some(UserAction::Redraw)) {
+                    // Drop whatever is on screen so the next render repaints every cell
+                    self.terminal.clear().map_err(|e| TuiError::Rendering(e.to_string()))?;
                 }
+                return Ok(action);
+            }
+        }
+        Ok(self.state.check_idle(Instant::now()))
+    }
 
-                match key.code {
-                    KeyCode::Char('c') if key.modifiers.contains(crossterm::event::KeyModifiers::CONTROL) => {
-                        return Ok(Some(UserAction::Exit));
-                    }
-                    KeyCode::F(1) => {
-                        self.state.show_help = !self.state.show_help;
-                        return Ok(None);
-                    }
-                    KeyCode::Esc => {
-                        if self.state.show_help {
-                            self.state.show_help = false;
-                        } else if !self.state.input_buffer.is_empty() {
-                            self.state.input_buffer.clear();
-                        } else {
-                            return Ok(Some(UserAction::Exit));
-                        }
-                        return Ok(None);
-                    }
-                    KeyCode::Tab => {
-                        self.state.command_mode = !self.state.command_mode;
-                        return Ok(None);
-                    }
-                    KeyCode::Enter => {
-                        if !self.state.input_buffer.is_empty() {
-                            let input = self.state.input_buffer.clone();
-                            self.state.input_buffer.clear();
-                            
-                            if self.state.command_mode || input.starts_with('/') {
-                                // Parse as command
-                                let command_str = if input.starts_with('/') {
-                                    &input[1..]
-                                } else {
-                                    &input
-                                };
-                                
-                                let command = self.parse_command(command_str)?;
-                                return Ok(Some(UserAction::ExecuteCommand(command)));
-                            } else {
-                                // Regular message
-                                return Ok(Some(UserAction::SendMessage));
-                            }
-                        }
-                        return Ok(None);
-                    }
-                    KeyCode::Backspace => {
-                        self.state.input_buffer.pop();
-                        return Ok(None);
-                    }
-                    KeyCode::PageUp => {
-                        return Ok(Some(UserAction::ScrollUp));
-                    }
-                    KeyCode::PageDown => {
-                        return Ok(Some(UserAction::ScrollDown));
-                    }
-                    KeyCode::Char(c) => {
-                        self.state.input_buffer.push(c);
-                        self.state.last_input_time = Instant::now();
-                        return Ok(None);
-                    }
-                    _ => {}
+    fn cleanup(&mut self) -> Result<(), TuiError> {
+        disable_raw_mode().map_err(|e| TuiError::TerminalInit(e.to_string()))?;
+        if self.features.alternate_screen {
+            execute!(self.terminal.backend_mut(), LeaveAlternateScreen)
+                .map_err(|e| TuiError::TerminalInit(e.to_string()))?;
+        }
+        if self.features.mouse_capture {
+            execute!(self.terminal.backend_mut(), DisableMouseCapture)
+                .map_err(|e| TuiError::TerminalInit(e.to_string()))?;
+        }
+        self.terminal.show_cursor().map_err(|e| TuiError::TerminalInit(e.to_string()))?;
+        Ok(())
+    }
+}
+
+impl RatatuiRenderer {
+    pub fn get_input_buffer(&self) -> &str {
+        &self.state.input_buffer
+    }
+
+    pub fn clear_input_buffer(&mut self) {
+        self.state.clear_input();
+    }
+
+    pub fn set_status_message(&mut self, message: Option<String>) {
+        self.state.status_message = message;
+    }
+
+    pub fn open_preview(&mut self, preview: FilePreview) {
+        self.state.open_preview(preview);
+    }
+
+    pub fn refresh_preview(&mut self, preview: FilePreview) {
+        self.state.refresh_preview(preview);
+    }
+
+    pub fn open_picker(&mut self, entries: Vec<ConversationSummary>) {
+        self.state.open_picker(entries);
+    }
+
+    pub fn open_comparison(&mut self, replies: Vec<ComparisonReply>) {
+        self.state.open_comparison(replies);
+    }
+
+    pub fn open_patterns(&mut self, include: Vec<String>, exclude: Vec<String>) {
+        self.state.open_patterns(include, exclude);
+    }
+
+    pub fn restore_scroll_position(&mut self, position: usize, message_count: usize) {
+        self.state.restore_scroll_position(position, message_count);
+    }
+
+    pub fn scroll_position(&self) -> usize {
+        self.state.scroll_position
+    }
+
+    pub fn set_frame_interval(&mut self, frame_interval: Duration) {
+        self.state.frame_interval = frame_interval;
+    }
+
+    pub fn set_keybindings(&mut self, keybindings: KeyBindings) {
+        self.state.keybindings = keybindings;
+    }
+
+    pub fn set_idle_timeout(&mut self, timeout: Option<Duration>, exit: bool) {
+        self.state.idle_timeout = timeout;
+        self.state.exit_on_idle = exit;
+    }
+
+    pub fn set_thinking_placeholder(&mut self, placeholder: String) {
+        self.state.thinking_placeholder = placeholder;
+    }
+
+    pub fn start_stream(&self) -> StreamBuffer {
+        self.state.start_stream()
+    }
+
+    /// Places `text` on the system clipboard through the terminal, which also
+    /// works over SSH where no local clipboard is reachable. When the terminal
+    /// can't be written the text goes to a new temp file instead, and the
+    /// returned notice says where.
+    pub fn copy_to_clipboard(&mut self, text: &str) -> Result<Option<String>, TuiError> {
+        copy_with_fallback(self.terminal.backend_mut(), text, &std::env::temp_dir())
+    }
+}
+
+// Start of the name of each file copied text is written to when the clipboard
+// can't be reached
+pub const CLIPBOARD_FALLBACK_PREFIX: &str = "llm-tui-copy-";
+
+// Sends `text` to the clipboard through `out`, falling back to writing it to
+// a new file in `fallback_dir`; returns a status notice when the fallback was
+// used. The file gets a random name and is readable only by the user.
+fn copy_with_fallback(
+    out: &mut impl Write,
+    text: &str,
+    fallback_dir: &std::path::Path,
+) -> Result<Option<String>, TuiError> {
+    let sent = out
+        .write_all(osc52_sequence(text).as_bytes())
+        .and_then(|_| out.flush());
+    match sent {
+        Ok(()) => Ok(None),
+        Err(e) => {
+            warn!("Clipboard unavailable: {}", e);
+            let written = tempfile::Builder::new()
+                .prefix(CLIPBOARD_FALLBACK_PREFIX)
+                .suffix(".txt")
+                .tempfile_in(fallback_dir)
+                .and_then(|mut file| {
+                    file.write_all(text.as_bytes())?;
+                    file.keep().map_err(|e| e.error)
+                });
+            let (_, path) = written.map_err(|write_err| {
+                TuiError::Rendering(format!(
+                    "Clipboard unavailable and no file could be written in {}: {}",
+                    fallback_dir.display(),
+                    write_err
+                ))
+            })?;
+            Ok(Some(format!(
+                "Clipboard unavailable (falling back to writing to {})",
+                path.display()
+            )))
+        }
+    }
+}
+
+// OSC 52 escape asking the terminal to set the clipboard to `text`
+fn osc52_sequence(text: &str) -> String {
+    use base64::Engine;
+    format!(
+        "\x1b]52;c;{}\x07",
+        base64::engine::general_purpose::STANDARD.encode(text)
+    )
+}
+
+// Longest a partial word may sit in the stream buffer before it is shown anyway
+pub const STREAM_FLUSH_INTERVAL: Duration = Duration::from_millis(50);
+
+// How long each step of the placeholder's dot animation lasts
+const THINKING_DOT_INTERVAL: Duration = Duration::from_millis(400);
+
+// Buffers streamed tokens so the display advances in whole words rather than
+// on every token; text is released at whitespace/punctuation boundaries or
+// once the flush interval has passed
+#[derive(Debug)]
+pub struct StreamBuffer {
+    visible: String,
+    pending: String,
+    last_flush: Instant,
+    interval: Duration,
+    // Shown until the first token arrives
+    placeholder: Option<String>,
+    started: Instant,
+}
+
+impl StreamBuffer {
+    pub fn new(interval: Duration) -> Self {
+        Self {
+            visible: String::new(),
+            pending: String::new(),
+            last_flush: Instant::now(),
+            interval,
+            placeholder: None,
+            started: Instant::now(),
+        }
+    }
+
+    /// Shows `placeholder` while waiting for the first token; empty shows none
+    pub fn with_placeholder(mut self, placeholder: &str) -> Self {
+        self.placeholder = (!placeholder.is_empty()).then(|| placeholder.to_string());
+        self
+    }
+
+    fn waiting(&self) -> bool {
+        self.visible.is_empty() && self.pending.is_empty()
+    }
+
+    /// Animates the placeholder in the display data until a token arrives
+    pub fn tick(&self, app_data: &mut AppDisplayData, now: Instant) {
+        if let (Some(placeholder), true) = (&self.placeholder, self.waiting()) {
+            let step = now.duration_since(self.started).as_millis() / THINKING_DOT_INTERVAL.as_millis();
+            let dots = ".".repeat((step % 4) as usize);
+            app_data.streaming_response = Some(format!("{}{}", placeholder, dots));
+        }
+    }
+
+    /// Adds a token and returns true if the visible text changed
+    pub fn push(&mut self, token: &str, now: Instant) -> bool {
+        self.pending.push_str(token);
+
+        let split = if now.duration_since(self.last_flush) >= self.interval {
+            self.pending.len()
+        } else {
+            match self.pending.rfind(is_flush_boundary) {
+                Some(index) => index + self.pending[index..].chars().next().map_or(0, char::len_utf8),
+                None => return false,
+            }
+        };
+
+        self.visible.push_str(&self.pending[..split]);
+        self.pending.drain(..split);
+        self.last_flush = now;
+        split > 0
+    }
+
+    /// Text released for display so far
+    pub fn visible(&self) -> &str {
+        &self.visible
+    }
+
+    /// Releases any buffered partial word and returns the complete text
+    pub fn finish(mut self) -> String {
+        self.visible.push_str(&self.pending);
+        self.visible
+    }
+
+    /// Pushes a token and mirrors the visible text into the display data. The
+    /// first token replaces the placeholder at once, partial word or not.
+    pub fn update(&mut self, token: &str, app_data: &mut AppDisplayData) {
+        if self.placeholder.is_some() && self.waiting() && !token.is_empty() {
+            self.visible.push_str(token);
+            self.last_flush = Instant::now();
+            app_data.streaming_response = Some(self.visible.clone());
+        } else if self.push(token, Instant::now()) {
+            app_data.streaming_response = Some(self.visible.clone());
+        }
+    }
+}
+
+// Narrows `area` to at most `max_width` columns of text plus borders, centered
+fn reading_column(area: Rect, max_width: Option<u16>) -> Rect {
+    let Some(max_width) = max_width else {
+        return area;
+    };
+    let width = area.width.min(max_width.saturating_add(2));
+    Rect {
+        x: area.x + (area.width - width) / 2,
+        width,
+        ..area
+    }
+}
+
+/// Wraps `text` to lines at most `width` terminal cells wide, breaking at
+/// spaces where possible. Existing line breaks are kept.
+pub fn wrap_text(text: &str, width: usize) -> Vec<String> {
+    let width = width.max(1);
+    let mut lines = Vec::new();
+    for source_line in text.split('\n') {
+        let mut line = String::new();
+        let mut line_width = 0;
+        for word in source_line.split(' ') {
+            let gap = usize::from(line_width > 0);
+            if line_width > 0 && line_width + gap + word.width() > width {
+                lines.push(std::mem::take(&mut line));
+                line_width = 0;
+            } else if gap == 1 {
+                line.push(' ');
+                line_width += 1;
+            }
+            // Words wider than a line, such as URLs, are broken between
+            // graphemes; one wider than the whole line still gets a line of its own
+            for grapheme in word.graphemes(true) {
+                let grapheme_width = grapheme.width();
+                if line_width > 0 && line_width + grapheme_width > width {
+                    lines.push(std::mem::take(&mut line));
+                    line_width = 0;
                 }
+                line.push_str(grapheme);
+                line_width += grapheme_width;
             }
         }
-        Ok(None)
-    }
-
-    fn cleanup(&mut self) -> Result<(), TuiError> {
-        disable_raw_mode().map_err(|e| TuiError::TerminalInit(e.to_string()))?;
-        execute!(
-            self.terminal.backend_mut(),
-            LeaveAlternateScreen,
-            DisableMouseCapture
-        )
-        .map_err(|e| TuiError::TerminalInit(e.to_string()))?;
-        self.terminal.show_cursor().map_err(|e| TuiError::TerminalInit(e.to_string()))?;
-        Ok(())
-    }
-}
-
-impl RatatuiRenderer {
-    fn parse_command(&self, command_str: &str) -> Result<Command, TuiError> {
-        let parts: Vec<&str> = command_str.split_whitespace().collect();
-        if parts.is_empty() {
-            return Err(TuiError::InputHandling("Empty command".to_string()));
-        }
-
-        match parts[0] {
-            "help" => Ok(Command::Help),
-            "config" => Ok(Command::Config),
-            "clear" => Ok(Command::Clear),
-            "toggle-rag" => Ok(Command::ToggleRag),
-            "toggle-prov" | "toggle-provisional" => Ok(Command::ToggleProvisional),
-            "add-source" => {
-                if parts.len() < 2 {
-                    return Err(TuiError::InputHandling("add-source requires a path argument".to_string()));
-                }
-                Ok(Command::AddSource(parts[1].into()))
+        lines.push(line);
+    }
+    lines
+}
+
+fn is_flush_boundary(c: char) -> bool {
+    c.is_whitespace() || matches!(c, '.' | ',' | ';' | ':' | '!' | '?' | ')' | ']' | '}')
+}
+
+// Input box title with the buffer size, e.g. "Input [MSG] 3 words, 17 chars".
+// Characters are counted, not bytes, so multibyte text reads as typed.
+fn input_title(buffer: &str, command_mode: bool) -> String {
+    let mode_indicator = if command_mode { "CMD" } else { "MSG" };
+    if buffer.is_empty() {
+        return format!("Input [{}]", mode_indicator);
+    }
+
+    let words = buffer.split_whitespace().count();
+    let chars = buffer.chars().count();
+    format!(
+        "Input [{}] {} word{}, {} char{}",
+        mode_indicator,
+        words,
+        if words == 1 { "" } else { "s" },
+        chars,
+        if chars == 1 { "" } else { "s" }
+    )
+}
+
+// Renders indexing progress as a fixed-width text bar, e.g. "Indexing [####    ] 4/8"
+pub fn format_index_progress(progress: IndexProgress, bar_width: usize) -> String {
+    let filled = (progress.processed.min(progress.total) * bar_width)
+        .checked_div(progress.total)
+        .unwrap_or(bar_width);
+    format!(
+        "Indexing [{}{}] {}/{}",
+        "#".repeat(filled),
+        " ".repeat(bar_width - filled),
+        progress.processed,
+        progress.total
+    )
+}
+
+// Parses a command string (without the leading '/') into a Command
+pub fn parse_command(command_str: &str) -> Result<Command, TuiError> {
+    let parts: Vec<&str> = command_str.split_whitespace().collect();
+    if parts.is_empty() {
+        return Err(TuiError::InputHandling("Empty command".to_string()));
+    }
+
+    match parts[0] {
+        "help" => Ok(Command::Help),
+        "config" => Ok(Command::Config),
+        "clear" => Ok(Command::Clear),
+        "reset" => Ok(Command::Reset),
+        "toggle-rag" => Ok(Command::ToggleRag),
+        "toggle-prov" | "toggle-provisional" => Ok(Command::ToggleProvisional),
+        "toggle-autoscroll" => Ok(Command::ToggleAutoScroll),
+        "scratch" => Ok(Command::ToggleScratch),
+        "image" => {
+            if parts.len() < 2 {
+                return Err(TuiError::InputHandling("image requires a path argument".to_string()));
+            }
+            Ok(Command::AttachImage(parts[1].into()))
+        }
+        "view" => Ok(Command::ToggleView),
+        "add-source" => {
+            if parts.len() < 2 {
+                return Err(TuiError::InputHandling("add-source requires a path argument".to_string()));
+            }
+            Ok(Command::AddSource(parts[1].into()))
+        }
+        "remove-source" => {
+            if parts.len() < 2 {
+                return Err(TuiError::InputHandling("remove-source requires a path argument".to_string()));
+            }
+            Ok(Command::RemoveSource(parts[1].into()))
+        }
+        "list-sources" => Ok(Command::ListSources),
+        "clear-cache" => Ok(Command::ClearCache),
+        "reload-prompt" => Ok(Command::ReloadPrompt),
+        "clear-system" => Ok(Command::ClearSystem),
+        "set-system" => {
+            let prompt = command_str.trim_start()[parts[0].len()..].trim();
+            if prompt.is_empty() {
+                return Err(TuiError::InputHandling(
+                    "set-system requires a prompt; use /clear-system to remove it".to_string(),
+                ));
+            }
+            Ok(Command::SetSystem(prompt.to_string()))
+        }
+        "reindex" => Ok(Command::Reindex(parts.get(1).map(|path| path.into()))),
+        "open" => {
+            if parts.len() < 2 {
+                return Err(TuiError::InputHandling("open requires a path argument".to_string()));
+            }
+            Ok(Command::Open(parts[1].into()))
+        }
+        "diff" => {
+            if parts.len() < 3 {
+                return Err(TuiError::InputHandling("diff requires two path arguments".to_string()));
+            }
+            Ok(Command::Diff(parts[1].into(), parts[2].into()))
+        }
+        "save-block" => {
+            if parts.len() < 3 {
+                return Err(TuiError::InputHandling(
+                    "save-block requires a block number and a path".to_string(),
+                ));
+            }
+            let index = parts[1]
+                .parse::<usize>()
+                .ok()
+                .filter(|index| *index > 0)
+                .ok_or_else(|| TuiError::InputHandling(format!("Invalid block number: {}", parts[1])))?;
+            Ok(Command::SaveBlock(index, parts[2].into()))
+        }
+        "conversations" => {
+            let tag = command_str.trim_start()[parts[0].len()..].trim();
+            Ok(Command::Conversations((!tag.is_empty()).then(|| tag.to_string())))
+        }
+        "tag" | "untag" => {
+            let tag = command_str.trim_start()[parts[0].len()..].trim().to_string();
+            if tag.is_empty() {
+                return Err(TuiError::InputHandling(format!("{} requires a tag", parts[0])));
+            }
+            if parts[0] == "tag" {
+                return Ok(Command::Tag(tag));
+            }
+            Ok(Command::Untag(tag))
+        }
+        "index-status" => Ok(Command::IndexStatus),
+        "rag-stats" => Ok(Command::RagStats),
+        "patterns" => Ok(Command::Patterns),
+        "export-sources" => Ok(Command::ExportSources(parts.get(1).map(|path| path.into()))),
+        "ping" => Ok(Command::Ping),
+        "last-raw" => Ok(Command::LastRaw),
+        "compare" => {
+            let message = command_str.trim_start()[parts[0].len()..].trim();
+            if message.is_empty() {
+                return Err(TuiError::InputHandling("compare requires a message".to_string()));
+            }
+            Ok(Command::Compare(message.to_string()))
+        }
+        "logs" => Ok(Command::Logs),
+        "setup" => Ok(Command::Setup),
+        "copy-conversation" => Ok(Command::CopyConversation),
+        "raw" | "show-prompt" | "prefill" => {
+            // Everything after the command name is the message, whitespace included
+            let message = command_str.trim_start()[parts[0].len()..].trim().to_string();
+            if message.is_empty() {
+                return Err(TuiError::InputHandling(format!("{} requires a message", parts[0])));
+            }
+            Ok(match parts[0] {
+                "raw" => Command::Raw(message),
+                "show-prompt" => Command::ShowPrompt(message),
+                _ => Command::Prefill(message),
+            })
+        }
+        "temp" => {
+            let Some(value) = parts.get(1) else {
+                return Err(TuiError::InputHandling("temp requires a value".to_string()));
+            };
+            let temperature = value.parse().map_err(|_| {
+                TuiError::InputHandling(format!("Invalid temperature: {}", value))
+            })?;
+            Ok(Command::Temperature(temperature))
+        }
+        "max-tokens" | "once-max" => {
+            let Some(value) = parts.get(1) else {
+                return Err(TuiError::InputHandling(format!("{} requires a token count", parts[0])));
+            };
+            let max_tokens = value.parse().map_err(|_| {
+                TuiError::InputHandling(format!("Invalid token count: {}", value))
+            })?;
+            if parts[0] == "max-tokens" {
+                return Ok(Command::MaxTokens(max_tokens));
             }
-            "remove-source" => {
-                if parts.len() < 2 {
-                    return Err(TuiError::InputHandling("remove-source requires a path argument".to_string()));
-                }
-                Ok(Command::RemoveSource(parts[1].into()))
+            let message = command_str.trim_start()[parts[0].len()..].trim_start()[value.len()..]
+                .trim()
+                .to_string();
+            if message.is_empty() {
+                return Err(TuiError::InputHandling("once-max requires a message".to_string()));
             }
-            "list-sources" => Ok(Command::ListSources),
-            "exit" | "quit" => Ok(Command::Exit),
-            _ => Err(TuiError::InputHandling(format!("Unknown command: {}", parts[0]))),
+            Ok(Command::OnceMax(max_tokens, message))
         }
-    }
-
-    pub fn get_input_buffer(&self) -> &str {
-        &self.state.input_buffer
-    }
-
-    pub fn clear_input_buffer(&mut self) {
-        self.state.input_buffer.clear();
-    }
-
-    pub fn set_status_message(&mut self, message: Option<String>) {
-        self.state.status_message = message;
+        "redo" => {
+            let instruction = command_str.trim_start()[parts[0].len()..].trim();
+            Ok(Command::Redo((!instruction.is_empty()).then(|| instruction.to_string())))
+        }
+        "load" => {
+            if parts.len() < 2 {
+                return Err(TuiError::InputHandling("load requires a conversation id".to_string()));
+            }
+            Ok(Command::LoadConversation(parts[1].to_string()))
+        }
+        "exit" | "quit" => Ok(Command::Exit),
+        _ => Err(TuiError::InputHandling(format!("Unknown command: {}", parts[0]))),
     }
 }
 
@@ -416,6 +2011,7 @@ impl RatatuiRenderer {
 mod tests {
     use super::*;
     use chrono::Utc;
+    use std::path::{Path, PathBuf};
     use std::time::Duration;
 
     // Helper function to create test messages
@@ -426,6 +2022,8 @@ mod tests {
             timestamp: Utc::now(),
             provisional,
             context_files: vec![],
+            tool_call: None,
+            images: Vec::new(),
         }
     }
 
@@ -441,7 +2039,454 @@ mod tests {
             rag_enabled: true,
             current_status: "Ready".to_string(),
             streaming_response: None,
+            indexing_progress: None,
+            ..AppDisplayData::default()
+        }
+    }
+
+    // Renders the conversation pane into an in-memory buffer and returns its text
+    fn render_messages_to_text(app_data: &AppDisplayData) -> String {
+        let mut terminal = Terminal::new(ratatui::backend::TestBackend::new(60, 20)).unwrap();
+        terminal
+            .draw(|f| RatatuiRenderer::render_messages_static(f, f.size(), app_data, None, false))
+            .unwrap();
+        let buffer = terminal.backend().buffer().clone();
+        buffer.content().iter().map(|cell| cell.symbol()).collect()
+    }
+
+    #[test]
+    fn test_banner_shows_only_for_empty_conversation() {
+        let mut data = AppDisplayData {
+            banner: Some("Model: gpt-4o-mini | Press F1 for help".to_string()),
+            ..AppDisplayData::default()
+        };
+        assert!(render_messages_to_text(&data).contains("Model: gpt-4o-mini | Press F1 for help"));
+
+        data.messages = create_test_app_data().messages;
+        let text = render_messages_to_text(&data);
+        assert!(!text.contains("Press F1 for help"));
+        assert!(text.contains("Hello"));
+    }
+
+    #[test]
+    fn test_wrap_text_breaks_at_width() {
+        assert_eq!(wrap_text("one two three", 7), vec!["one two", "three"]);
+        assert_eq!(wrap_text("abcdefghij", 4), vec!["abcd", "efgh", "ij"]);
+        assert_eq!(wrap_text("first\nsecond line", 20), vec!["first", "second line"]);
+    }
+
+    #[test]
+    fn test_wrap_text_hard_breaks_long_multibyte_runs() {
+        // Mixed one- and two-cell characters plus a combining accent
+        let blob: String = "aé漢e\u{301}".chars().cycle().take(500).collect();
+        let lines = wrap_text(&blob, 17);
+        assert!(lines.len() > 1);
+        assert!(lines.iter().all(|line| line.width() <= 17));
+        assert_eq!(lines.concat(), blob);
+        assert_eq!(wrap_text("漢漢漢", 1), vec!["漢", "漢", "漢"]);
+    }
+
+    #[test]
+    fn test_max_content_width_caps_wrapping() {
+        let words = "lorem ipsum dolor sit amet consectetur adipiscing elit";
+        let mut data = AppDisplayData {
+            messages: vec![Message {
+                role: MessageRole::User,
+                content: words.to_string(),
+                timestamp: chrono::Utc::now(),
+                provisional: false,
+                context_files: Vec::new(),
+                tool_call: None,
+                images: Vec::new(),
+            }],
+            ..AppDisplayData::default()
+        };
+        // Uncapped, the whole message fits on one row of the 60-column pane
+        assert!(render_messages_to_text(&data).contains(words));
+
+        data.max_content_width = Some(20);
+        let text = render_messages_to_text(&data);
+        assert!(!text.contains(words));
+        let rows: Vec<String> = text
+            .chars()
+            .collect::<Vec<_>>()
+            .chunks(60)
+            .map(|row| row.iter().collect::<String>())
+            .collect();
+        for line in wrap_text(words, 20) {
+            assert!(rows.iter().any(|row| row.contains(&format!("│{:<20}│", line))));
+        }
+    }
+
+    #[test]
+    fn test_omitted_marker_sits_above_boundary_message() {
+        let mut data = create_test_app_data();
+        data.omitted_marker = Some((1, 4));
+
+        let text = render_messages_to_text(&data);
+        let marker = text.find("4 earlier messages omitted from context").expect("Expected marker");
+        assert!(text.find("Hello").unwrap() < marker);
+        assert!(marker < text.find("Hi there").unwrap());
+    }
+
+    #[test]
+    fn test_system_message_has_marker_and_muted_style() {
+        let mut data = AppDisplayData::default();
+        let message = create_test_message(MessageRole::System, "Context\nsecond line", false);
+
+        let lines = RatatuiRenderer::message_lines(&message, &data, 40);
+        assert!(lines[0].spans[0].content.starts_with("[system] ["));
+        assert_eq!(lines.len(), 3);
+        for line in &lines {
+            let style = line.spans[0].style;
+            assert!(style.add_modifier.contains(Modifier::DIM | Modifier::ITALIC));
+        }
+
+        // Regular messages keep the plain content style
+        let user = create_test_message(MessageRole::User, "Hello", false);
+        let lines = RatatuiRenderer::message_lines(&user, &data, 40);
+        assert!(!lines[1].spans[0].style.add_modifier.contains(Modifier::ITALIC));
+
+        data.collapse_system_messages = true;
+        let lines = RatatuiRenderer::message_lines(&message, &data, 40);
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[1].spans[0].content, "Context …");
+    }
+
+    #[test]
+    fn test_custom_names_appear_in_role_prefix() {
+        let mut data = create_test_app_data();
+        data.assistant_name = "Jarvis".to_string();
+        data.user_name = "Tony".to_string();
+        data.streaming_response = Some("Working".to_string());
+
+        let text = render_messages_to_text(&data);
+        assert!(text.contains("Tony: "));
+        assert!(text.contains("Jarvis: "));
+        assert!(text.contains("Jarvis (streaming): "));
+        assert!(!text.contains("Assistant"));
+    }
+
+    #[test]
+    fn test_ctrl_l_requests_redraw_without_touching_state() {
+        let mut state = TuiState {
+            input_buffer: "draft".to_string(),
+            command_mode: true,
+            ..TuiState::default()
+        };
+
+        let action = state
+            .handle_key(KeyEvent::new(KeyCode::Char('l'), KeyModifiers::CONTROL))
+            .unwrap();
+        assert!(matches!(action, Some(UserAction::Redraw)));
+        assert_eq!(state.input_buffer, "draft");
+        assert!(state.command_mode);
+
+        // A plain 'l' is still just typed
+        state.handle_key(KeyEvent::new(KeyCode::Char('l'), KeyModifiers::NONE)).unwrap();
+        assert_eq!(state.input_buffer, "draftl");
+    }
+
+    fn press(state: &mut TuiState, code: KeyCode) {
+        state.handle_key(KeyEvent::new(code, KeyModifiers::NONE)).unwrap();
+    }
+
+    #[test]
+    fn test_delete_removes_character_right_of_cursor() {
+        let mut state = TuiState { input_buffer: "hello".to_string(), ..TuiState::default() };
+        press(&mut state, KeyCode::Left);
+        press(&mut state, KeyCode::Left);
+        press(&mut state, KeyCode::Delete);
+        assert_eq!(state.input_buffer, "helo");
+
+        // Backspace still removes to the left, and typing inserts at the cursor
+        press(&mut state, KeyCode::Backspace);
+        press(&mut state, KeyCode::Char('L'));
+        assert_eq!(state.input_buffer, "heLo");
+    }
+
+    #[test]
+    fn test_delete_and_backspace_at_buffer_edges_are_noops() {
+        let mut state = TuiState { input_buffer: "abc".to_string(), ..TuiState::default() };
+        press(&mut state, KeyCode::Delete);
+        assert_eq!(state.input_buffer, "abc");
+
+        press(&mut state, KeyCode::Home);
+        press(&mut state, KeyCode::Backspace);
+        assert_eq!(state.input_buffer, "abc");
+        press(&mut state, KeyCode::Delete);
+        assert_eq!(state.input_buffer, "bc");
+    }
+
+    #[test]
+    fn test_delete_handles_multibyte_characters() {
+        let mut state = TuiState { input_buffer: "héllo😀!".to_string(), ..TuiState::default() };
+        press(&mut state, KeyCode::Left);
+        press(&mut state, KeyCode::Left);
+        press(&mut state, KeyCode::Delete);
+        assert_eq!(state.input_buffer, "héllo!");
+
+        press(&mut state, KeyCode::Home);
+        press(&mut state, KeyCode::Right);
+        press(&mut state, KeyCode::Delete);
+        assert_eq!(state.input_buffer, "hllo!");
+        assert_eq!(state.cursor_byte_index(), 1);
+    }
+
+    #[test]
+    fn test_toggle_keys_emit_toggle_commands() {
+        let mut state = TuiState::default();
+        let ctrl = |c| KeyEvent::new(KeyCode::Char(c), KeyModifiers::CONTROL);
+
+        let action = state.handle_key(ctrl('g')).unwrap();
+        assert!(matches!(action, Some(UserAction::ExecuteCommand(Command::ToggleRag))));
+        let action = state.handle_key(ctrl('p')).unwrap();
+        assert!(matches!(action, Some(UserAction::ExecuteCommand(Command::ToggleProvisional))));
+        let action = state.handle_key(ctrl('s')).unwrap();
+        assert!(matches!(action, Some(UserAction::ExecuteCommand(Command::ToggleScratch))));
+        assert!(state.input_buffer.is_empty());
+
+        // Rebinding moves the toggle and frees the old key for typing
+        state.keybindings = KeyBindings { toggle_rag: 'x', ..KeyBindings::default() };
+        let action = state.handle_key(ctrl('x')).unwrap();
+        assert!(matches!(action, Some(UserAction::ExecuteCommand(Command::ToggleRag))));
+        assert!(state.handle_key(ctrl('g')).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_view_only_ignores_typing_but_scrolls() {
+        let mut state = TuiState { view_only: true, ..TuiState::default() };
+        state.restore_scroll_position(5, 10);
+
+        press(&mut state, KeyCode::Char('x'));
+        press(&mut state, KeyCode::Backspace);
+        assert!(state.input_buffer.is_empty());
+        assert!(state.handle_key(KeyEvent::new(KeyCode::Enter, KeyModifiers::NONE)).unwrap().is_none());
+        assert!(state.handle_key(KeyEvent::new(KeyCode::Char('g'), KeyModifiers::CONTROL)).unwrap().is_none());
+
+        let action = state.handle_key(KeyEvent::new(KeyCode::PageUp, KeyModifiers::NONE)).unwrap();
+        assert!(matches!(action, Some(UserAction::ScrollUp)));
+        assert_eq!(state.scroll_position, 4);
+        press(&mut state, KeyCode::F(1));
+        assert!(state.show_help);
+
+        // The toggle key still works so the view can be left
+        let action = state.handle_key(KeyEvent::new(KeyCode::Char('r'), KeyModifiers::CONTROL)).unwrap();
+        assert!(matches!(action, Some(UserAction::ExecuteCommand(Command::ToggleView))));
+        state.view_only = false;
+        press(&mut state, KeyCode::Char('x'));
+        assert_eq!(state.input_buffer, "x");
+    }
+
+    #[test]
+    fn test_message_draft_survives_command_mode_round_trip() {
+        let mut state = TuiState::default();
+        for c in "half a thought".chars() {
+            state.handle_key(KeyEvent::new(KeyCode::Char(c), KeyModifiers::NONE)).unwrap();
+        }
+
+        state.handle_key(KeyEvent::new(KeyCode::Tab, KeyModifiers::NONE)).unwrap();
+        assert!(state.command_mode);
+        assert_eq!(state.input_buffer, "");
+        state.input_buffer.push_str("list-sou");
+
+        state.handle_key(KeyEvent::new(KeyCode::Tab, KeyModifiers::NONE)).unwrap();
+        assert!(!state.command_mode);
+        assert_eq!(state.input_buffer, "half a thought");
+        assert_eq!(state.command_draft, "list-sou");
+
+        state.toggle_command_mode();
+        assert_eq!(state.input_buffer, "list-sou");
+    }
+
+    #[test]
+    fn test_poll_interval_is_shorter_while_busy() {
+        let mut state = TuiState {
+            frame_interval: Duration::from_millis(200),
+            ..TuiState::default()
+        };
+        assert_eq!(state.poll_interval(), Duration::from_millis(200));
+
+        state.busy = true;
+        assert_eq!(state.poll_interval(), Duration::from_millis(50));
+
+        // Never faster than the floor, never slower than the idle interval
+        state.frame_interval = Duration::from_millis(20);
+        assert_eq!(state.poll_interval(), MIN_FRAME_INTERVAL);
+        state.frame_interval = Duration::from_millis(5);
+        assert_eq!(state.poll_interval(), Duration::from_millis(5));
+    }
+
+    fn summary(id: &str) -> ConversationSummary {
+        ConversationSummary {
+            id: id.to_string(),
+            preview: format!("About {}", id),
+            created_at: Utc::now(),
+            message_count: 2,
+            tags: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn test_tail_preview_scrolls_from_the_end_and_refreshes_in_place() {
+        let mut state = TuiState::default();
+        let path = PathBuf::from("app.log");
+        state.open_preview(FilePreview::from_tail(path.clone(), "line\n".repeat(30)));
+        state.scroll_preview(true);
+        assert_eq!(state.preview_scroll, PREVIEW_SCROLL_STEP);
+
+        state.refresh_preview(FilePreview::from_tail(path, "line\n".repeat(31)));
+        assert_eq!(state.preview.as_ref().unwrap().content.lines().count(), 31);
+        assert_eq!(state.preview_scroll, PREVIEW_SCROLL_STEP);
+        state.scroll_preview(false);
+        assert_eq!(state.preview_scroll, 0);
+
+        // Content for another file does not replace what is open
+        state.refresh_preview(FilePreview::from_text("other.txt", String::new()));
+        assert_eq!(state.preview.as_ref().unwrap().path, PathBuf::from("app.log"));
+    }
+
+    #[test]
+    fn test_picker_navigation_is_bounded() {
+        let mut picker = ConversationPicker::new(vec![summary("a"), summary("b"), summary("c")]);
+        picker.move_up();
+        assert_eq!(picker.selected, 0);
+        picker.move_down();
+        picker.move_down();
+        picker.move_down();
+        assert_eq!(picker.selected_entry().unwrap().id, "c");
+        picker.move_up();
+        assert_eq!(picker.selected_entry().unwrap().id, "b");
+
+        assert!(ConversationPicker::new(Vec::new()).selected_entry().is_none());
+    }
+
+    #[test]
+    fn test_enter_modifiers_override_provisional_mode() {
+        let mut state = TuiState::default();
+        for (modifiers, expected) in [
+            (KeyModifiers::NONE, None),
+            (KeyModifiers::ALT, Some(true)),
+            (KeyModifiers::CONTROL, Some(false)),
+        ] {
+            state.input_buffer = "Hello".to_string();
+            let action = state.handle_key(KeyEvent::new(KeyCode::Enter, modifiers)).unwrap();
+            assert!(matches!(
+                action,
+                Some(UserAction::SendMessage { content, provisional })
+                    if content == "Hello" && provisional == expected
+            ));
+        }
+    }
+
+    #[test]
+    fn test_picker_enter_loads_selected_conversation() {
+        let mut state = TuiState::default();
+        state.open_picker(vec![summary("a"), summary("b")]);
+
+        state.handle_key(KeyEvent::new(KeyCode::Down, KeyModifiers::NONE)).unwrap();
+        let action = state.handle_key(KeyEvent::new(KeyCode::Enter, KeyModifiers::NONE)).unwrap();
+        assert!(matches!(
+            action,
+            Some(UserAction::ExecuteCommand(Command::LoadConversation(id))) if id == "b"
+        ));
+        assert!(state.picker.is_none());
+
+        state.open_picker(vec![summary("a")]);
+        let action = state.handle_key(KeyEvent::new(KeyCode::Esc, KeyModifiers::NONE)).unwrap();
+        assert!(action.is_none());
+        assert!(state.picker.is_none());
+    }
+
+    #[test]
+    fn test_setup_wizard_collects_openai_provider() {
+        let mut wizard = SetupWizard::new();
+        assert!(wizard.advance().is_none());
+        assert_eq!(wizard.step, SetupStep::ApiKey);
+
+        // An empty key fails the provider validation and keeps the step
+        assert!(wizard.advance().is_none());
+        assert_eq!(wizard.step, SetupStep::ApiKey);
+        assert!(wizard.error.is_some());
+
+        "sk-testx".chars().for_each(|c| wizard.push_char(c));
+        wizard.pop_char();
+        assert!(wizard.advance().is_none());
+        assert_eq!(wizard.step, SetupStep::Model);
+        assert!(wizard.error.is_none());
+
+        let provider = wizard.advance().expect("provider");
+        assert_eq!(provider.provider_type, ProviderType::OpenAi);
+        assert_eq!(provider.api_key, "sk-test");
+        assert!(provider.model.is_empty());
+        assert!(ConfigManager::validate_llm_provider(&provider).is_ok());
+    }
+
+    #[test]
+    fn test_setup_wizard_skips_key_for_echo() {
+        let mut state = TuiState::default();
+        state.open_setup();
+        for code in [KeyCode::Down, KeyCode::Down, KeyCode::Down, KeyCode::Enter] {
+            state.handle_key(KeyEvent::new(code, KeyModifiers::NONE)).unwrap();
+        }
+        assert_eq!(state.setup.as_ref().unwrap().step, SetupStep::Model);
+
+        state.handle_key(KeyEvent::new(KeyCode::Char('x'), KeyModifiers::NONE)).unwrap();
+        let action = state.handle_key(KeyEvent::new(KeyCode::Enter, KeyModifiers::NONE)).unwrap();
+        assert!(matches!(
+            action,
+            Some(UserAction::SaveProvider(provider))
+                if provider.provider_type == ProviderType::Echo && provider.model == "x"
+        ));
+        assert!(state.setup.is_none());
+
+        state.open_setup();
+        let action = state.handle_key(KeyEvent::new(KeyCode::Esc, KeyModifiers::NONE)).unwrap();
+        assert!(action.is_none());
+        assert!(state.setup.is_none());
+    }
+
+    #[test]
+    fn test_pattern_editor_validates_typed_regex() {
+        let mut state = TuiState::default();
+        state.open_patterns(vec![r"\.rs$".to_string()], Vec::new());
+        for c in "([".chars() {
+            state.handle_key(KeyEvent::new(KeyCode::Char(c), KeyModifiers::NONE)).unwrap();
+        }
+        assert!(state.patterns.as_ref().unwrap().error.is_some());
+        let action = state.handle_key(KeyEvent::new(KeyCode::Enter, KeyModifiers::NONE)).unwrap();
+        assert!(action.is_none());
+
+        for code in [KeyCode::Backspace, KeyCode::Backspace, KeyCode::Char('a')] {
+            state.handle_key(KeyEvent::new(code, KeyModifiers::NONE)).unwrap();
         }
+        assert!(state.patterns.as_ref().unwrap().error.is_none());
+        let action = state.handle_key(KeyEvent::new(KeyCode::Enter, KeyModifiers::NONE)).unwrap();
+        assert!(matches!(action, Some(UserAction::EditPatterns(edit)) if edit == PatternEdit::Add(PatternKind::Include, "a".to_string())));
+
+        let action = state.handle_key(KeyEvent::new(KeyCode::Up, KeyModifiers::SHIFT)).unwrap();
+        assert!(matches!(action, Some(UserAction::EditPatterns(edit)) if edit == PatternEdit::Move(PatternKind::Include, "a".to_string(), true)));
+        assert_eq!(state.patterns.as_ref().unwrap().include, vec!["a", r"\.rs$"]);
+
+        let action = state.handle_key(KeyEvent::new(KeyCode::Delete, KeyModifiers::NONE)).unwrap();
+        assert!(matches!(action, Some(UserAction::EditPatterns(edit)) if edit == PatternEdit::Remove(PatternKind::Include, "a".to_string())));
+        state.handle_key(KeyEvent::new(KeyCode::Esc, KeyModifiers::NONE)).unwrap();
+        assert!(state.patterns.is_none());
+    }
+
+    #[test]
+    fn test_tool_messages_render_with_tool_name() {
+        let mut data = create_test_app_data();
+        let mut message = create_test_message(MessageRole::Tool, "file contents", false);
+        message.tool_call = Some(ToolCall {
+            id: "call_1".to_string(),
+            name: "read_file".to_string(),
+            arguments: String::new(),
+        });
+        data.messages = vec![message];
+
+        let text = render_messages_to_text(&data);
+        assert!(text.contains("Tool (read_file): "));
     }
 
     #[test]
@@ -457,6 +2502,24 @@ mod tests {
         assert!(state.last_input_time.elapsed() < Duration::from_secs(1));
     }
 
+    #[test]
+    fn test_preview_scroll_is_bounded() {
+        let mut state = TuiState::default();
+        let content = (1..=15).map(|n| n.to_string()).collect::<Vec<_>>().join("\n");
+        state.open_preview(FilePreview::from_bytes(PathBuf::from("a.txt"), content.as_bytes(), 35, 1024));
+
+        state.scroll_preview(false);
+        assert_eq!(state.preview_scroll, 10);
+        state.scroll_preview(false);
+        assert_eq!(state.preview_scroll, 14);
+        state.scroll_preview(true);
+        state.scroll_preview(true);
+        assert_eq!(state.preview_scroll, 0);
+
+        state.close_preview();
+        assert!(state.preview.is_none());
+    }
+
     #[test]
     fn test_app_display_data_default() {
         let data = AppDisplayData::default();
@@ -466,6 +2529,86 @@ mod tests {
         assert!(!data.rag_enabled);
         assert_eq!(data.current_status, "");
         assert!(data.streaming_response.is_none());
+        assert!(data.indexing_progress.is_none());
+        assert_eq!(data.assistant_name, "Assistant");
+        assert_eq!(data.user_name, "You");
+    }
+
+    #[test]
+    fn test_stream_buffer_flushes_on_word_boundaries() {
+        let start = Instant::now();
+        let mut buffer = StreamBuffer::new(Duration::from_secs(60));
+
+        assert!(!buffer.push("Hel", start));
+        assert!(!buffer.push("lo", start));
+        assert_eq!(buffer.visible(), "");
+        assert!(buffer.push(" wor", start));
+        assert_eq!(buffer.visible(), "Hello ");
+        assert!(buffer.push("ld, and", start));
+        assert_eq!(buffer.visible(), "Hello world, ");
+        assert!(!buffer.push("mo", start));
+        assert_eq!(buffer.finish(), "Hello world, andmo");
+    }
+
+    #[test]
+    fn test_stream_buffer_flushes_after_interval() {
+        let start = Instant::now();
+        let mut buffer = StreamBuffer::new(Duration::from_millis(50));
+        buffer.last_flush = start;
+
+        assert!(!buffer.push("Supercali", start + Duration::from_millis(10)));
+        assert!(buffer.push("fragilistic", start + Duration::from_millis(60)));
+        assert_eq!(buffer.visible(), "Supercalifragilistic");
+        assert!(!buffer.push("expi", start + Duration::from_millis(70)));
+    }
+
+    #[test]
+    fn test_placeholder_shows_until_first_token() {
+        let mut buffer = StreamBuffer::new(Duration::from_secs(60)).with_placeholder("Thinking");
+        let start = buffer.started;
+        let mut data = AppDisplayData::default();
+
+        buffer.tick(&mut data, start);
+        assert_eq!(data.streaming_response.as_deref(), Some("Thinking"));
+        buffer.tick(&mut data, start + THINKING_DOT_INTERVAL * 2);
+        assert_eq!(data.streaming_response.as_deref(), Some("Thinking.."));
+
+        // The first token shows even without a word boundary, and ends the animation
+        buffer.update("Hel", &mut data);
+        assert_eq!(data.streaming_response.as_deref(), Some("Hel"));
+        buffer.tick(&mut data, start + THINKING_DOT_INTERVAL * 3);
+        assert_eq!(data.streaming_response.as_deref(), Some("Hel"));
+        buffer.update("lo", &mut data);
+        assert_eq!(data.streaming_response.as_deref(), Some("Hel"));
+
+        let mut plain = StreamBuffer::new(Duration::from_secs(60)).with_placeholder("");
+        let mut data = AppDisplayData::default();
+        plain.tick(&mut data, Instant::now());
+        assert!(data.streaming_response.is_none());
+        plain.update("Hel", &mut data);
+        assert!(data.streaming_response.is_none());
+
+        // The UI's buffers take the configured placeholder
+        let mut state = TuiState { thinking_placeholder: "Pondering".to_string(), ..TuiState::default() };
+        let mut data = AppDisplayData::default();
+        state.start_stream().tick(&mut data, Instant::now());
+        assert_eq!(data.streaming_response.as_deref(), Some("Pondering"));
+        state.thinking_placeholder.clear();
+        let mut data = AppDisplayData::default();
+        state.start_stream().tick(&mut data, Instant::now());
+        assert!(data.streaming_response.is_none());
+    }
+
+    #[test]
+    fn test_format_index_progress() {
+        let half = IndexProgress { processed: 4, total: 8 };
+        assert_eq!(format_index_progress(half, 8), "Indexing [####    ] 4/8");
+
+        let done = IndexProgress { processed: 8, total: 8 };
+        assert_eq!(format_index_progress(done, 4), "Indexing [####] 8/8");
+
+        let empty = IndexProgress { processed: 0, total: 0 };
+        assert_eq!(format_index_progress(empty, 4), "Indexing [####] 0/0");
     }
 
     #[test]
@@ -476,12 +2619,38 @@ mod tests {
         assert!(matches!(renderer.parse_command("help"), Ok(Command::Help)));
         assert!(matches!(renderer.parse_command("config"), Ok(Command::Config)));
         assert!(matches!(renderer.parse_command("clear"), Ok(Command::Clear)));
+        assert!(matches!(renderer.parse_command("reset"), Ok(Command::Reset)));
         assert!(matches!(renderer.parse_command("toggle-rag"), Ok(Command::ToggleRag)));
         assert!(matches!(renderer.parse_command("toggle-prov"), Ok(Command::ToggleProvisional)));
         assert!(matches!(renderer.parse_command("toggle-provisional"), Ok(Command::ToggleProvisional)));
+        assert!(matches!(renderer.parse_command("toggle-autoscroll"), Ok(Command::ToggleAutoScroll)));
         assert!(matches!(renderer.parse_command("list-sources"), Ok(Command::ListSources)));
         assert!(matches!(renderer.parse_command("exit"), Ok(Command::Exit)));
         assert!(matches!(renderer.parse_command("quit"), Ok(Command::Exit)));
+        assert!(matches!(renderer.parse_command("clear-cache"), Ok(Command::ClearCache)));
+        assert!(matches!(renderer.parse_command("reload-prompt"), Ok(Command::ReloadPrompt)));
+        assert!(matches!(renderer.parse_command("reindex"), Ok(Command::Reindex(None))));
+        assert!(matches!(renderer.parse_command("index-status"), Ok(Command::IndexStatus)));
+        assert!(matches!(renderer.parse_command("conversations"), Ok(Command::Conversations(None))));
+        assert!(matches!(
+            renderer.parse_command("conversations work notes"),
+            Ok(Command::Conversations(Some(tag))) if tag == "work notes"
+        ));
+        assert!(matches!(renderer.parse_command("tag rust"), Ok(Command::Tag(tag)) if tag == "rust"));
+        assert!(renderer.parse_command("untag").is_err());
+        assert!(matches!(renderer.parse_command("ping"), Ok(Command::Ping)));
+        assert!(matches!(renderer.parse_command("last-raw"), Ok(Command::LastRaw)));
+        assert!(matches!(renderer.parse_command("compare Hi there"), Ok(Command::Compare(m)) if m == "Hi there"));
+        assert!(renderer.parse_command("compare").is_err());
+        assert!(matches!(renderer.parse_command("export-sources"), Ok(Command::ExportSources(None))));
+        assert!(matches!(
+            renderer.parse_command("export-sources files.csv"),
+            Ok(Command::ExportSources(Some(path))) if path == Path::new("files.csv")
+        ));
+        assert!(matches!(
+            renderer.parse_command("copy-conversation"),
+            Ok(Command::CopyConversation)
+        ));
     }
 
     #[test]
@@ -496,6 +2665,41 @@ mod tests {
             _ => panic!("Expected AddSource command"),
         }
 
+        match renderer.parse_command("raw  what is   2+2? ") {
+            Ok(Command::Raw(message)) => assert_eq!(message, "what is   2+2?"),
+            _ => panic!("Expected Raw command"),
+        }
+        assert!(renderer.parse_command("raw").is_err());
+        assert!(matches!(
+            renderer.parse_command("show-prompt why?"),
+            Ok(Command::ShowPrompt(message)) if message == "why?"
+        ));
+        assert!(matches!(
+            renderer.parse_command("prefill {\"name\":"),
+            Ok(Command::Prefill(text)) if text == "{\"name\":"
+        ));
+        assert!(matches!(
+            renderer.parse_command("set-system  You are terse.  "),
+            Ok(Command::SetSystem(prompt)) if prompt == "You are terse."
+        ));
+        let err = renderer.parse_command("set-system  ").unwrap_err();
+        assert!(err.to_string().contains("/clear-system"));
+        assert!(matches!(renderer.parse_command("clear-system"), Ok(Command::ClearSystem)));
+
+        match renderer.parse_command("open notes.md") {
+            Ok(Command::Open(path)) => {
+                assert_eq!(path.to_string_lossy(), "notes.md");
+            }
+            _ => panic!("Expected Open command"),
+        }
+
+        match renderer.parse_command("reindex /some/source") {
+            Ok(Command::Reindex(Some(path))) => {
+                assert_eq!(path.to_string_lossy(), "/some/source");
+            }
+            _ => panic!("Expected Reindex command with a path"),
+        }
+
         match renderer.parse_command("remove-source /another/path") {
             Ok(Command::RemoveSource(path)) => {
                 assert_eq!(path.to_string_lossy(), "/another/path");
@@ -517,6 +2721,51 @@ mod tests {
         // Test commands missing required arguments
         assert!(renderer.parse_command("add-source").is_err());
         assert!(renderer.parse_command("remove-source").is_err());
+        assert!(renderer.parse_command("temp").is_err());
+        assert!(renderer.parse_command("temp warm").is_err());
+    }
+
+    #[test]
+    fn test_temp_command_parsing() {
+        let renderer = create_mock_renderer();
+        match renderer.parse_command("temp 0.7") {
+            Ok(Command::Temperature(value)) => assert_eq!(value, 0.7),
+            _ => panic!("Expected Temperature command"),
+        }
+    }
+
+    #[test]
+    fn test_save_block_command_parsing() {
+        let renderer = create_mock_renderer();
+        match renderer.parse_command("save-block 2 out.rs") {
+            Ok(Command::SaveBlock(2, path)) => assert_eq!(path, PathBuf::from("out.rs")),
+            _ => panic!("Expected SaveBlock command"),
+        }
+        assert!(renderer.parse_command("save-block 0 out.rs").is_err());
+        assert!(renderer.parse_command("save-block 1").is_err());
+    }
+
+    #[test]
+    fn test_max_tokens_command_parsing() {
+        let renderer = create_mock_renderer();
+        assert!(matches!(renderer.parse_command("max-tokens 512"), Ok(Command::MaxTokens(512))));
+        match renderer.parse_command("once-max 4096  Explain  this") {
+            Ok(Command::OnceMax(4096, message)) => assert_eq!(message, "Explain  this"),
+            _ => panic!("Expected OnceMax command"),
+        }
+        assert!(renderer.parse_command("once-max 4096").is_err());
+        assert!(renderer.parse_command("max-tokens many").is_err());
+        assert!(matches!(renderer.parse_command("redo"), Ok(Command::Redo(None))));
+        assert!(matches!(
+            renderer.parse_command("redo  make it shorter "),
+            Ok(Command::Redo(Some(instruction))) if instruction == "make it shorter"
+        ));
+    }
+
+    #[test]
+    fn test_setup_command_parsing() {
+        let renderer = create_mock_renderer();
+        assert!(matches!(renderer.parse_command("setup"), Ok(Command::Setup)));
     }
 
     #[test]
@@ -640,6 +2889,8 @@ mod tests {
             timestamp: now,
             provisional: false,
             context_files: vec![],
+            tool_call: None,
+            images: Vec::new(),
         };
         
         let msg2 = Message {
@@ -648,6 +2899,8 @@ mod tests {
             timestamp: now + chrono::Duration::seconds(1),
             provisional: false,
             context_files: vec![],
+            tool_call: None,
+            images: Vec::new(),
         };
         
         // Verify timestamp ordering
@@ -667,6 +2920,8 @@ mod tests {
             timestamp: Utc::now(),
             provisional: false,
             context_files: context_files.clone(),
+            tool_call: None,
+            images: Vec::new(),
         };
         
         assert_eq!(msg.context_files.len(), 2);
@@ -688,6 +2943,48 @@ mod tests {
         // based on the number of messages and terminal height
     }
 
+    #[test]
+    fn test_new_message_keeps_scrolled_view_without_autoscroll() {
+        let mut state = TuiState::default();
+        state.track_messages(5, false);
+        assert_eq!(state.scroll_position, 4);
+
+        // At the bottom, new messages are followed even with auto-scroll off
+        state.track_messages(6, false);
+        assert_eq!(state.scroll_position, 5);
+        assert!(!state.unseen_messages);
+
+        state.scroll_messages(true);
+        state.scroll_messages(true);
+        state.track_messages(7, false);
+        assert_eq!(state.scroll_position, 3);
+        assert!(state.unseen_messages);
+
+        state.scroll_messages(false);
+        state.scroll_messages(false);
+        assert!(state.unseen_messages);
+        state.scroll_messages(false);
+        assert!(state.at_bottom());
+        assert!(!state.unseen_messages);
+
+        state.scroll_messages(true);
+        state.track_messages(8, true);
+        assert_eq!(state.scroll_position, 7);
+        assert!(!state.unseen_messages);
+    }
+
+    #[test]
+    fn test_restore_scroll_position_clamps_to_messages() {
+        let mut state = TuiState::default();
+
+        state.restore_scroll_position(3, 10);
+        assert_eq!(state.scroll_position, 3);
+        state.restore_scroll_position(30, 10);
+        assert_eq!(state.scroll_position, 9);
+        state.restore_scroll_position(4, 0);
+        assert_eq!(state.scroll_position, 0);
+    }
+
     #[test]
     fn test_input_timing_tracking() {
         let mut state = TuiState::default();
@@ -700,6 +2997,26 @@ mod tests {
         assert!(state.last_input_time > initial_time);
     }
 
+    #[test]
+    fn test_idle_timeout_fires_once_per_inactive_stretch() {
+        let mut state = TuiState::default();
+        let start = state.last_input_time;
+        assert!(state.check_idle(start + Duration::from_secs(3600)).is_none());
+
+        state.idle_timeout = Some(Duration::from_secs(300));
+        assert!(state.check_idle(start + Duration::from_secs(299)).is_none());
+        let action = state.check_idle(start + Duration::from_secs(300));
+        assert!(matches!(action, Some(UserAction::IdleTimeout { exit: false })));
+        assert!(state.check_idle(start + Duration::from_secs(900)).is_none());
+
+        state.exit_on_idle = true;
+        state.handle_key(KeyEvent::new(KeyCode::Char('a'), KeyModifiers::NONE)).unwrap();
+        let typed = state.last_input_time;
+        assert!(state.check_idle(typed + Duration::from_secs(60)).is_none());
+        let action = state.check_idle(typed + Duration::from_secs(301));
+        assert!(matches!(action, Some(UserAction::IdleTimeout { exit: true })));
+    }
+
     // Mock renderer for testing that doesn't require terminal initialization
     struct MockRenderer {
         state: TuiState,
@@ -713,33 +3030,7 @@ mod tests {
         }
 
         fn parse_command(&self, command_str: &str) -> Result<Command, TuiError> {
-            let parts: Vec<&str> = command_str.split_whitespace().collect();
-            if parts.is_empty() {
-                return Err(TuiError::InputHandling("Empty command".to_string()));
-            }
-
-            match parts[0] {
-                "help" => Ok(Command::Help),
-                "config" => Ok(Command::Config),
-                "clear" => Ok(Command::Clear),
-                "toggle-rag" => Ok(Command::ToggleRag),
-                "toggle-prov" | "toggle-provisional" => Ok(Command::ToggleProvisional),
-                "add-source" => {
-                    if parts.len() < 2 {
-                        return Err(TuiError::InputHandling("add-source requires a path argument".to_string()));
-                    }
-                    Ok(Command::AddSource(parts[1].into()))
-                }
-                "remove-source" => {
-                    if parts.len() < 2 {
-                        return Err(TuiError::InputHandling("remove-source requires a path argument".to_string()));
-                    }
-                    Ok(Command::RemoveSource(parts[1].into()))
-                }
-                "list-sources" => Ok(Command::ListSources),
-                "exit" | "quit" => Ok(Command::Exit),
-                _ => Err(TuiError::InputHandling(format!("Unknown command: {}", parts[0]))),
-            }
+            super::parse_command(command_str)
         }
 
         fn get_input_buffer(&self) -> &str {
@@ -755,6 +3046,149 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_osc52_sequence_encodes_text() {
+        assert_eq!(osc52_sequence("hi"), "\x1b]52;c;aGk=\x07");
+        assert_eq!(osc52_sequence(""), "\x1b]52;c;\x07");
+    }
+
+    // Writer standing in for a terminal that can't be reached
+    struct BrokenTerminal;
+
+    impl Write for BrokenTerminal {
+        fn write(&mut self, _buf: &[u8]) -> io::Result<usize> {
+            Err(io::Error::new(io::ErrorKind::BrokenPipe, "no terminal"))
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_clipboard_failure_falls_back_to_file() {
+        let temp_dir = tempfile::TempDir::new().unwrap();
+
+        let notice = copy_with_fallback(&mut BrokenTerminal, "copied text", temp_dir.path()).unwrap();
+        let files: Vec<_> = std::fs::read_dir(temp_dir.path()).unwrap().map(|entry| entry.unwrap().path()).collect();
+        assert_eq!(files.len(), 1);
+        let fallback = &files[0];
+        assert!(fallback.file_name().unwrap().to_string_lossy().starts_with(CLIPBOARD_FALLBACK_PREFIX));
+        assert_eq!(
+            notice,
+            Some(format!("Clipboard unavailable (falling back to writing to {})", fallback.display()))
+        );
+        assert_eq!(std::fs::read_to_string(fallback).unwrap(), "copied text");
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = std::fs::metadata(fallback).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+
+        // Each copy gets its own file
+        copy_with_fallback(&mut BrokenTerminal, "again", temp_dir.path()).unwrap();
+        assert_eq!(std::fs::read_dir(temp_dir.path()).unwrap().count(), 2);
+
+        // A working terminal gets the escape sequence and no file is written
+        let mut out = Vec::new();
+        let other = tempfile::TempDir::new().unwrap();
+        assert_eq!(copy_with_fallback(&mut out, "hi", other.path()).unwrap(), None);
+        assert_eq!(out, osc52_sequence("hi").into_bytes());
+        assert_eq!(std::fs::read_dir(other.path()).unwrap().count(), 0);
+    }
+
+    #[test]
+    fn test_input_title_counts_characters() {
+        assert_eq!(input_title("", false), "Input [MSG]");
+        assert_eq!(input_title("Hello there world", false), "Input [MSG] 3 words, 17 chars");
+        assert_eq!(input_title("a", true), "Input [CMD] 1 word, 1 char");
+        // 5 characters, 15 bytes in UTF-8
+        assert_eq!(input_title("こんにちは", false), "Input [MSG] 1 word, 5 chars");
+        assert_eq!(input_title("naïve café", false), "Input [MSG] 2 words, 10 chars");
+    }
+
+    #[test]
+    fn test_monochrome_role_styles_use_modifiers_only() {
+        let theme = Theme { monochrome: true };
+        let user = theme.role_style(&MessageRole::User);
+        let assistant = theme.role_style(&MessageRole::Assistant);
+
+        for style in [user, assistant, theme.text_style(), theme.status_bar_style()] {
+            assert_eq!(style.fg, None);
+            assert_eq!(style.bg, None);
+        }
+        assert!(user.add_modifier.contains(Modifier::UNDERLINED));
+        assert!(assistant.add_modifier.contains(Modifier::BOLD));
+        assert_ne!(user.add_modifier, assistant.add_modifier);
+
+        let colored = Theme::default();
+        assert_eq!(colored.role_style(&MessageRole::User).fg, Some(Color::Cyan));
+    }
+
+    // Terminal output that rejects the mouse-capture escape sequence, like a
+    // minimal terminal without mouse support
+    struct NoMouseTerminal(std::rc::Rc<std::cell::RefCell<Vec<u8>>>);
+
+    impl Write for NoMouseTerminal {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            if buf.windows(6).any(|window| window == b"?1000h") {
+                return Err(io::Error::new(io::ErrorKind::Unsupported, "no mouse support"));
+            }
+            self.0.borrow_mut().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_mouse_capture_follows_enable_flag() {
+        let mut out = Vec::new();
+        let features = enable_terminal_features(&mut out, false);
+        assert!(!features.mouse_capture);
+        assert!(!out.windows(6).any(|window| window == b"?1000h"));
+
+        let mut out = Vec::new();
+        let features = enable_terminal_features(&mut out, true);
+        assert!(features.mouse_capture);
+        assert!(out.windows(6).any(|window| window == b"?1000h"));
+    }
+
+    #[test]
+    fn test_mouse_capture_failure_is_not_fatal() {
+        let output = std::rc::Rc::new(std::cell::RefCell::new(Vec::new()));
+        let mut out = NoMouseTerminal(output.clone());
+        let features = enable_terminal_features(&mut out, true);
+        assert_eq!(
+            features,
+            TerminalFeatures {
+                alternate_screen: true,
+                mouse_capture: false,
+            }
+        );
+
+        // The same output still carries a full frame
+        let mut terminal = Terminal::with_options(
+            CrosstermBackend::new(out),
+            ratatui::TerminalOptions {
+                viewport: ratatui::Viewport::Fixed(Rect::new(0, 0, 40, 10)),
+            },
+        )
+        .unwrap();
+        let data = create_test_app_data();
+        // Viewed from the first message, which the small frame would otherwise scroll past
+        let mut state = TuiState::default();
+        state.restore_scroll_position(0, data.messages.len());
+        terminal
+            .draw(|f| RatatuiRenderer::render_main_ui_static(f, &data, &state))
+            .unwrap();
+        let written = String::from_utf8_lossy(&output.borrow()).to_string();
+        assert!(written.contains("\x1b[?1049h"));
+        assert!(written.contains("Hello"));
+    }
+
     // Helper function to create a mock renderer for testing
     fn create_mock_renderer() -> MockRenderer {
         MockRenderer::new()